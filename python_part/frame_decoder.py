@@ -0,0 +1,145 @@
+"""Decodes the length prefixed messages the Rust side sends once a client
+switched to another encoding than JSON (see `src/encoding.rs`).
+
+Every message starts with its length (u32) and its kind (u8), little
+endian. The fixed binary layout is decoded with `struct` only, MessagePack
+and CBOR need the `msgpack` and `cbor2` packages.
+"""
+import json
+import struct
+
+KIND_JSON = 0
+KIND_MSGPACK = 1
+KIND_CBOR = 2
+KIND_BINARY = 3
+
+BINARY_MAGIC = b"IWRF"
+BINARY_SCHEMA_VERSION = 1
+
+# The bit of each section in the header, in the order they are written.
+SECTIONS = [
+    "pointcloud",
+    "side_info",
+    "range_profile",
+    "noise_profile",
+    "azimuth_heatmap",
+    "azimuth_elevation_heatmap",
+    "range_doppler_heatmap",
+    "stats",
+    "temperature",
+]
+
+STATS_FIELDS = [
+    "inter_frame_processing_time",
+    "transmit_output_time",
+    "inter_frame_processing_margin",
+    "inter_chirp_processing_margin",
+    "active_frame_cpu_load",
+    "inter_frame_cpu_load",
+]
+
+
+def read_exactly(stream, size):
+    """Reads `size` bytes from a file like object, None at the end."""
+    data = b""
+    while len(data) < size:
+        chunk = stream.read(size - len(data))
+        if not chunk:
+            return None
+        data += chunk
+    return data
+
+
+def read_message(stream):
+    """Reads the next message, returns its kind and payload, or None when
+    the connection was closed."""
+    header = read_exactly(stream, 5)
+    if header is None:
+        return None
+    length, kind = struct.unpack("<IB", header)
+    payload = read_exactly(stream, length)
+    if payload is None:
+        return None
+    return kind, payload
+
+
+def decode_message(kind, payload):
+    """Decodes a message into the same dictionary the JSON encoding gives."""
+    if kind == KIND_JSON:
+        return json.loads(payload)
+    if kind == KIND_MSGPACK:
+        import msgpack
+        return msgpack.unpackb(payload)
+    if kind == KIND_CBOR:
+        import cbor2
+        return cbor2.loads(payload)
+    if kind == KIND_BINARY:
+        return decode_binary_frame(payload)
+    raise ValueError(f"Unknown message kind {kind}")
+
+
+def decode_binary_frame(payload):
+    """Decodes a frame in the fixed little endian layout."""
+    magic, version, sections, frame_num, subframe_num, time_cpu_cycles = struct.unpack_from(
+        "<4sHHIII", payload, 0
+    )
+    if magic != BINARY_MAGIC:
+        raise ValueError("Not a binary frame")
+    if version != BINARY_SCHEMA_VERSION:
+        raise ValueError(f"Unsupported binary frame version {version}")
+    frame = {
+        "frame_num": frame_num,
+        "subframe_num": subframe_num,
+        "time_cpu_cycles": time_cpu_cycles,
+    }
+    offset = 20
+    for bit, name in enumerate(SECTIONS):
+        if not sections & (1 << bit):
+            frame[name] = None
+            continue
+        (count,) = struct.unpack_from("<I", payload, offset)
+        offset += 4
+        frame[name], offset = decode_section(name, count, payload, offset)
+    return frame
+
+
+def decode_section(name, count, payload, offset):
+    """Decodes a single section, returns it and the offset after it."""
+    if name == "pointcloud":
+        values = struct.unpack_from(f"<{count * 4}f", payload, offset)
+        points = [
+            {"x": values[i], "y": values[i + 1], "z": values[i + 2], "d": values[i + 3]}
+            for i in range(0, len(values), 4)
+        ]
+        return points, offset + count * 16
+    if name == "side_info":
+        values = struct.unpack_from(f"<{count * 2}h", payload, offset)
+        side_info = [
+            {"snr": values[i], "noise": values[i + 1]} for i in range(0, len(values), 2)
+        ]
+        return side_info, offset + count * 4
+    if name in ("range_profile", "noise_profile"):
+        return list(struct.unpack_from(f"<{count}f", payload, offset)), offset + count * 4
+    if name in ("azimuth_heatmap", "azimuth_elevation_heatmap"):
+        values = struct.unpack_from(f"<{count * 2}h", payload, offset)
+        samples = [
+            {"imag": values[i], "real": values[i + 1]} for i in range(0, len(values), 2)
+        ]
+        return samples, offset + count * 4
+    if name == "range_doppler_heatmap":
+        return list(struct.unpack_from(f"<{count}H", payload, offset)), offset + count * 2
+    if name == "stats":
+        values = struct.unpack_from(f"<{count}I", payload, offset)
+        return dict(zip(STATS_FIELDS, values)), offset + count * 4
+    if name == "temperature":
+        values = struct.unpack_from("<iI10H", payload, offset)
+        temperature = {
+            "report_valid": values[0],
+            "time": values[1],
+            "rx": list(values[2:6]),
+            "tx": list(values[6:9]),
+            "pm": values[9],
+            "digital": list(values[10:12]),
+        }
+        return temperature, offset + count * 28
+    raise ValueError(f"Unknown section {name}")
@@ -7,48 +7,57 @@ import json
 import matplotlib.pyplot as plt
 import numpy as np
 
+from frame_decoder import decode_message, read_message
+
 
 def ipc_receiver(queue_sender):
-    # Path for the Unix socket.
+    # Path for the Unix socket, bound by the Rust side (`ipc.socket_path`).
     SOCKET_PATH = "/tmp/fmcw_ipc_socket"
 
-    # Ensure the socket does not already exist.
-    if os.path.exists(SOCKET_PATH):
-        os.remove(SOCKET_PATH)
-
-    # Create a Unix socket.
-    with socket.socket(socket.AF_UNIX, socket.SOCK_STREAM) as server_socket:
-        server_socket.bind(SOCKET_PATH)
-        server_socket.listen(1)
-        print("Python server listening...")
-
-        conn, _ = server_socket.accept()
-        with conn:
-            print("Connection established.")
-            buffer = ""
-
-            while True:
-                # Read data from the client.
-
-                data = conn.recv(1024).decode("utf-8")
-                if not data:
-                    break
-
-                # Accumulate data and split by newline for complete messages.
-                buffer += data
-                while "\n" in buffer:
-                    message, buffer = buffer.split("\n", 1)
-                    
-                    # Deserialize the message.
-                    try:
-                        message = json.loads(message)
-                        print(f"Received from Rust: {message}")
-
-                        # Process the message (user-defined logic goes here).
-                        queue_sender.put(message)
-
-                    except json.JSONDecodeError:
-                        print("Invalid message format received.")
+    # Connect to the Unix socket, waiting until the Rust side is running.
+    with socket.socket(socket.AF_UNIX, socket.SOCK_STREAM) as conn:
+        while True:
+            try:
+                conn.connect(SOCKET_PATH)
+                break
+            except (FileNotFoundError, ConnectionRefusedError):
+                print("Waiting for the Rust side...")
+                time.sleep(1)
+
+        print("Connection established.")
+
+        # Ask for the compact binary encoding, the response is the last
+        # message sent as a line of JSON.
+        stream = conn.makefile("rwb")
+        stream.write(b'{"id": 0, "command": "encoding", "encoding": "binary"}\n')
+        stream.flush()
+        while True:
+            line = stream.readline()
+            if not line:
+                return
+            message = json.loads(line)
+            if message.get("id") == 0:
+                break
+
+        while True:
+            # Read data from the server.
+            received = read_message(stream)
+            if received is None:
+                break
+            message = decode_message(*received)
+
+            if "connection_state" in message:
+                print(f"Sensor is now {message['connection_state']}")
+                continue
+            if "id" in message:
+                # The response to a command sent over the same
+                # connection, e.g. {"id": 1, "command": "stats"}
+                print(f"Response from Rust: {message}")
+                continue
+            print(f"Received from Rust: frame {message['frame_num']}")
+
+            # Process the message (user-defined logic goes here).
+            queue_sender.put(message)
 
 def data_renderer(queue_receiver):
     while True:
@@ -0,0 +1,150 @@
+"""Reads the frames the Rust side writes into shared memory (see
+`src/shm_ring.rs`), with `ipc.shm_enabled = true`.
+
+The shared memory file is mapped once, the heatmaps of a frame are numpy
+arrays viewing the mapping, so nothing is copied. A notification socket
+tells which slot holds each new frame.
+
+    for frame in ShmReader():
+        heatmap = frame["range_doppler_heatmap"]  # a numpy view
+        ...
+        if not frame.intact():  # the slot was overwritten meanwhile
+            continue
+"""
+import json
+import mmap
+import socket
+import struct
+import time
+
+import numpy as np
+
+SHM_PATH = "/dev/shm/iwr68xx_frames"
+NOTIFY_PATH = "/tmp/iwr68xx_shm_socket"
+
+SHM_MAGIC = b"IWRSHM01"
+HEADER_SIZE = 64
+SLOT_HEADER_SIZE = 16
+BINARY_MAGIC = b"IWRF"
+BINARY_SCHEMA_VERSION = 1
+
+# The sections of a binary frame in the order of their bits, with the
+# numpy type of their elements.
+SECTIONS = [
+    ("pointcloud", np.dtype([("x", "<f4"), ("y", "<f4"), ("z", "<f4"), ("d", "<f4")])),
+    ("side_info", np.dtype([("snr", "<i2"), ("noise", "<i2")])),
+    ("range_profile", np.dtype("<f4")),
+    ("noise_profile", np.dtype("<f4")),
+    ("azimuth_heatmap", np.dtype([("imag", "<i2"), ("real", "<i2")])),
+    ("azimuth_elevation_heatmap", np.dtype([("imag", "<i2"), ("real", "<i2")])),
+    ("range_doppler_heatmap", np.dtype("<u2")),
+    ("stats", np.dtype("<u4")),
+    (
+        "temperature",
+        np.dtype(
+            [
+                ("report_valid", "<i4"),
+                ("time", "<u4"),
+                ("rx", "<u2", 4),
+                ("tx", "<u2", 3),
+                ("pm", "<u2"),
+                ("digital", "<u2", 2),
+            ]
+        ),
+    ),
+]
+
+
+class ShmFrame(dict):
+    """A frame in a slot, its sections are numpy views into the shared
+    memory which stay valid as long as `intact()` is true."""
+
+    def __init__(self, reader, slot, state):
+        super().__init__()
+        self._reader = reader
+        self._slot = slot
+        self._state = state
+
+    def intact(self):
+        """Whether the slot still holds this frame, check this after
+        using the views."""
+        return self._reader.slot_state(self._slot) == self._state
+
+
+class ShmReader:
+    def __init__(self, shm_path=SHM_PATH, notify_path=NOTIFY_PATH):
+        self.socket = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+        while True:
+            try:
+                self.socket.connect(notify_path)
+                break
+            except (FileNotFoundError, ConnectionRefusedError):
+                print("Waiting for the Rust side...")
+                time.sleep(1)
+        self.notifications = self.socket.makefile("rb")
+
+        with open(shm_path, "rb") as f:
+            self.memory = mmap.mmap(f.fileno(), 0, access=mmap.ACCESS_READ)
+        magic, self.slots, version, self.slot_size = struct.unpack_from(
+            "<8sIIQ", self.memory, 0
+        )
+        if magic != SHM_MAGIC:
+            raise ValueError(f"{shm_path} is not a frame ring")
+        if version != BINARY_SCHEMA_VERSION:
+            raise ValueError(f"Unsupported binary frame version {version}")
+        self.stride = (SLOT_HEADER_SIZE + self.slot_size + 63) // 64 * 64
+
+    def slot_state(self, slot):
+        return struct.unpack_from("<Q", self.memory, HEADER_SIZE + slot * self.stride)[0]
+
+    def latest_sequence(self):
+        return struct.unpack_from("<Q", self.memory, 24)[0]
+
+    def read_slot(self, slot, sequence):
+        """Returns the frame with this sequence number, or None if the
+        slot holds another frame by now."""
+        base = HEADER_SIZE + slot * self.stride
+        state = self.slot_state(slot)
+        if state != sequence << 1:
+            return None
+        (length,) = struct.unpack_from("<I", self.memory, base + 8)
+        frame = ShmFrame(self, slot, state)
+        offset = base + SLOT_HEADER_SIZE
+        magic, version, sections, frame_num, subframe_num, cycles = struct.unpack_from(
+            "<4sHHIII", self.memory, offset
+        )
+        if magic != BINARY_MAGIC or version != BINARY_SCHEMA_VERSION:
+            return None
+        frame["frame_num"] = frame_num
+        frame["subframe_num"] = subframe_num
+        frame["time_cpu_cycles"] = cycles
+        offset += 20
+        for bit, (name, dtype) in enumerate(SECTIONS):
+            if not sections & (1 << bit):
+                frame[name] = None
+                continue
+            (count,) = struct.unpack_from("<I", self.memory, offset)
+            offset += 4
+            frame[name] = np.frombuffer(self.memory, dtype=dtype, count=count, offset=offset)
+            offset += count * dtype.itemsize
+        if offset > base + SLOT_HEADER_SIZE + length or not frame.intact():
+            return None
+        return frame
+
+    def __iter__(self):
+        """Yields every frame as it is written, skipping the frames which
+        were overwritten before they could be read."""
+        for line in self.notifications:
+            message = json.loads(line)
+            if "sequence" not in message:
+                continue
+            frame = self.read_slot(message["slot"], message["sequence"])
+            if frame is not None:
+                yield frame
+
+
+if __name__ == "__main__":
+    for frame in ShmReader():
+        heatmap = frame["range_doppler_heatmap"]
+        shape = None if heatmap is None else heatmap.shape
+        print(f"Frame {frame['frame_num']}, range-doppler heatmap {shape}")
@@ -0,0 +1,394 @@
+use futures_core::Stream;
+use serial2::SerialPort;
+use std::future::{poll_fn, Future};
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout_at, Instant};
+
+use super::device_discovery::resolve_ports;
+use super::file_reader::{Config, Settings};
+use super::frame_log::is_frame_log;
+use super::pipeline::{processors_from_settings, Pipeline};
+use super::recording::is_recording;
+use super::tlv_translator::{decode_frame, Frame, TlvError};
+use super::udp::AsyncUdpSender;
+
+/// How much is read from the data at once.
+const READ_CHUNK: usize = 4096;
+
+/// How long the FMCW gets to answer a CLI command.
+const CLI_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A serial port which is read and written without blocking a thread,
+/// through the tokio reactor.
+pub struct AsyncSerial {
+    port: AsyncFd<SerialPort>,
+}
+
+impl AsyncSerial {
+    /// Opens a port, this has to happen inside a tokio runtime.
+    pub fn open(path: &Path, baud: u32) -> Result<AsyncSerial, Error> {
+        let mut port = SerialPort::open(path, baud)?;
+        // Only read and write once the reactor says the port is ready
+        port.set_read_timeout(Duration::ZERO)?;
+        port.set_write_timeout(Duration::ZERO)?;
+        // Registering is unsafe as the reactor only knows the raw file
+        // descriptor. This is safe, as the `SerialPort` owns it and is
+        // only closed when the `AsyncFd` is dropped.
+        let port = unsafe { AsyncFd::register(port) }?;
+        Ok(AsyncSerial { port })
+    }
+}
+
+/// The ports report nothing to do as a timeout, which the reactor
+/// should wait out like any other `WouldBlock`.
+fn would_block<T>(result: Result<T, Error>) -> Result<T, Error> {
+    result.map_err(|e| match e.kind() {
+        ErrorKind::TimedOut => ErrorKind::WouldBlock.into(),
+        _ => e,
+    })
+}
+
+impl AsyncRead for AsyncSerial {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        loop {
+            let mut guard = ready!(self.port.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|port| would_block(port.get_ref().read(unfilled))) {
+                Ok(Ok(n)) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for AsyncSerial {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        loop {
+            let mut guard = ready!(self.port.poll_write_ready(cx))?;
+            match guard.try_io(|port| would_block(port.get_ref().write(buf))) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The frames decoded from a reader of TLV data, such as the data port
+/// or a raw capture.
+///
+/// Problems in the data are yielded as errors, after which decoding
+/// continues at the next frame. The stream ends after the reader
+/// reached its end or failed with a `TlvError::Io`.
+pub struct FrameStream<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    chunk: Vec<u8>,
+    ended: bool,
+}
+
+impl<R: AsyncRead + Unpin> FrameStream<R> {
+    pub fn new(reader: R) -> FrameStream<R> {
+        FrameStream {
+            reader,
+            buffer: vec![],
+            chunk: vec![0; READ_CHUNK],
+            ended: false,
+        }
+    }
+
+    /// Waits for the next frame or error, `None` once the stream ended.
+    pub async fn next(&mut self) -> Option<Result<Frame, TlvError>> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for FrameStream<R> {
+    type Item = Result<Frame, TlvError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(decoded) = decode_frame(&mut this.buffer) {
+                return Poll::Ready(Some(decoded));
+            }
+            if this.ended {
+                return Poll::Ready(None);
+            }
+            let mut chunk = ReadBuf::new(&mut this.chunk);
+            match ready!(Pin::new(&mut this.reader).poll_read(cx, &mut chunk)) {
+                Ok(()) if chunk.filled().is_empty() => this.ended = true,
+                Ok(()) => this.buffer.extend_from_slice(chunk.filled()),
+                Err(e) => {
+                    this.ended = true;
+                    return Poll::Ready(Some(Err(TlvError::Io(e))));
+                }
+            }
+        }
+    }
+}
+
+/// The cfg port of the FMCW, through which it is configured.
+pub struct CliPort {
+    port: AsyncSerial,
+    config: Config,
+}
+
+impl CliPort {
+    /// Sends the configuration line by line, like `Fmcw::send_config`.
+    pub async fn send_config(&mut self) -> Result<(), Error> {
+        println!("    Printing config");
+        let lines: Vec<String> = self
+            .config
+            .raw_input
+            .lines()
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty() && !l.starts_with('%'))
+            .collect();
+        for line in lines {
+            println!("{}", line);
+            let response = self.send(&line).await?;
+            if !response.contains("Done") {
+                eprintln!("    FMCW responded with: {}", response.trim());
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        println!("\nFinished sending Config to the FMCW\n");
+        Ok(())
+    }
+
+    /// Sends a single CLI command, returning the response of the FMCW,
+    /// or whatever it answered within `CLI_TIMEOUT`.
+    pub async fn send(&mut self, command: &str) -> Result<String, Error> {
+        let line = format!("{}\n", command.trim_end());
+        self.port.write_all(line.as_bytes()).await?;
+
+        let deadline = Instant::now() + CLI_TIMEOUT;
+        let mut buf = [0u8; 256];
+        let mut response = String::new();
+        while let Ok(read) = timeout_at(deadline, self.port.read(&mut buf)).await {
+            match read? {
+                0 => break,
+                n => response.push_str(&String::from_utf8_lossy(&buf[..n])),
+            }
+            if ["Done", "Error", "not recognized"]
+                .iter()
+                .any(|s| response.contains(s))
+            {
+                break;
+            }
+        }
+        Ok(response)
+    }
+}
+
+/// The FMCW on a tokio runtime: the CLI on the cfg port and the frames
+/// from the data port, which can be used at the same time.
+///
+/// Unlike `Fmcw` it does not reconnect, a lost port ends `frames`.
+/// Dropping it cancels everything.
+pub struct AsyncFmcw {
+    pub cli: CliPort,
+    pub frames: FrameStream<AsyncSerial>,
+}
+
+impl AsyncFmcw {
+    /// Opens the ports of `settings.serial`, either can be `"auto"`.
+    /// The configuration is only sent by `CliPort::send_config`.
+    pub fn open(settings: &Settings, config: Config) -> Result<AsyncFmcw, Error> {
+        let ports = resolve_ports(&settings.serial.cfg_port, &settings.serial.data_port)?;
+        let cfg = AsyncSerial::open(&ports.cfg_port, settings.serial.cfg_baud)?;
+        let data = AsyncSerial::open(&ports.data_port, settings.serial.data_baud)?;
+        Ok(AsyncFmcw {
+            cli: CliPort { port: cfg, config },
+            frames: FrameStream::new(data),
+        })
+    }
+}
+
+/// A consumer of frames on a tokio runtime.
+pub trait AsyncSink {
+    fn send(&mut self, frame: &Frame) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+/// Hands the frames to another task, waiting while its channel is full.
+impl AsyncSink for mpsc::Sender<Frame> {
+    async fn send(&mut self, frame: &Frame) -> Result<(), Error> {
+        mpsc::Sender::send(self, frame.clone())
+            .await
+            .map_err(|_| Error::new(ErrorKind::BrokenPipe, "the receiver is gone"))
+    }
+}
+
+/// Runs the tool without a thread per stage: configures the FMCW, or
+/// reads `processing.tlv_file` as fast as possible when
+/// `processing.read_from_file` is set, and passes every frame through
+/// the processors of `pipeline.processors` to the UDP sender when
+/// `udp.enabled` is set and to a task printing a line per frame.
+/// Ctrl-C stops the sensor and returns.
+///
+/// Only raw captures can be read, recordings and frame logs need `run`.
+pub async fn run_async(settings: &Settings) -> Result<(), Error> {
+    let (printer, mut printed) = mpsc::channel::<Frame>(64);
+    let printer_task = tokio::spawn(async move {
+        while let Some(frame) = printed.recv().await {
+            println!(
+                "Frame {}: {} points",
+                frame.frame_num,
+                frame.pointcloud.as_ref().map_or(0, |pc| pc.len())
+            );
+        }
+    });
+    let mut sinks = Sinks {
+        processors: Pipeline::new(processors_from_settings(settings)?),
+        udp: if settings.udp.enabled {
+            Some(AsyncUdpSender::bind(&settings.udp)?)
+        } else {
+            None
+        },
+        printer,
+    };
+
+    if settings.processing.read_from_file {
+        let path = &settings.processing.tlv_file;
+        if is_recording(path) || is_frame_log(path) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} is not a raw capture, replay it with `run`",
+                    path.display()
+                ),
+            ));
+        }
+        let mut frames = FrameStream::new(tokio::fs::File::open(path).await?);
+        forward(&mut frames, &mut sinks).await?;
+    } else {
+        let config = Config::from_file(&settings.serial.config_file)?;
+        let mut fmcw = AsyncFmcw::open(settings, config)?;
+        fmcw.cli.send_config().await?;
+        forward(&mut fmcw.frames, &mut sinks).await?;
+        fmcw.cli.send("sensorStop").await?;
+    }
+    drop(sinks);
+    _ = printer_task.await;
+    Ok(())
+}
+
+struct Sinks {
+    /// Only the processors are used, the sinks follow
+    processors: Pipeline,
+    udp: Option<AsyncUdpSender>,
+    printer: mpsc::Sender<Frame>,
+}
+
+/// Passes the frames through the processors to the sinks until the
+/// stream ends or Ctrl-C is pressed. Problems in the data are reported
+/// and skipped.
+async fn forward<R: AsyncRead + Unpin>(
+    frames: &mut FrameStream<R>,
+    sinks: &mut Sinks,
+) -> Result<(), Error> {
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        let next = tokio::select! {
+            next = frames.next() => next,
+            _ = &mut ctrl_c => break,
+        };
+        let frame = match next {
+            Some(Ok(frame)) => frame,
+            Some(Err(TlvError::Io(e))) => return Err(e),
+            Some(Err(e)) => {
+                eprintln!("{}", e);
+                continue;
+            }
+            None => break,
+        };
+        let Some(frame) = sinks.processors.process(frame) else {
+            continue;
+        };
+        if let Some(ref mut udp) = sinks.udp {
+            if let Err(e) = udp.send(&frame).await {
+                eprintln!("Could not send frame {} over UDP: {}", frame.frame_num, e);
+            }
+        }
+        AsyncSink::send(&mut sinks.printer, &frame).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tlv_encoder::encode_frame;
+
+    fn frame_nums(results: &[Result<Frame, TlvError>]) -> Vec<usize> {
+        results
+            .iter()
+            .filter_map(|r| r.as_ref().ok().map(|f| f.frame_num))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn decodes_the_frames_of_a_reader() {
+        let mut data = vec![7u8; 10];
+        data.extend(encode_frame(&Frame::empty(1)));
+        data.extend(encode_frame(&Frame::empty(2)));
+        // A frame cut off by the end of the data is never yielded
+        let third = encode_frame(&Frame::empty(3));
+        data.extend_from_slice(&third[..third.len() / 2]);
+
+        let mut stream = FrameStream::new(data.as_slice());
+        let mut results = vec![];
+        while let Some(result) = stream.next().await {
+            results.push(result);
+        }
+        assert!(matches!(results[0], Err(TlvError::Skipped(10))));
+        assert_eq!(frame_nums(&results), [1, 2]);
+        assert_eq!(results.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn decodes_frames_split_over_many_reads() {
+        let frames: Vec<u8> = (0..3)
+            .flat_map(|i| encode_frame(&Frame::empty(i)))
+            .collect();
+        // Every read returns at most a few bytes
+        let (mut writer, reader) = tokio::io::duplex(7);
+        tokio::spawn(async move { writer.write_all(&frames).await });
+
+        let mut stream = FrameStream::new(reader);
+        let mut results = vec![];
+        while let Some(result) = stream.next().await {
+            results.push(result);
+        }
+        assert_eq!(frame_nums(&results), [0, 1, 2]);
+        assert_eq!(results.len(), 3);
+    }
+}
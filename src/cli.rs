@@ -0,0 +1,316 @@
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+
+const USAGE: &str = "\
+Usage: IWR_68xx_rust [COMMAND] [OPTIONS]
+
+Commands:
+    run                      Configure the FMCW and process its data (default)
+    run-async                The same on a tokio runtime, printing the frames and sending them
+                             to the [udp] group, reads raw captures only
+    list-devices             List the USB serial ports and which belong to a sensor
+    simulate                 Run against a simulated sensor instead of the hardware
+    inspect <PATH>           Print the metadata and index of a recording, or the frames in a frame log
+    export-pointcloud <PATH> Write the point clouds of a recording, raw capture or frame log
+                             as PCD or PLY files, as set in the [export] settings
+    convert <PATH>           Write the points, range profiles and telemetry of a recording,
+                             raw capture or frame log as CSV or Parquet tables
+    render <PATH>            Render the plots of the [render] settings for every frame of a
+                             recording, raw capture or frame log
+    receive-udp              Receive the frames sent to the [udp] group and report the lost ones
+
+Simulate options:
+    --replay <PATH>          Stream the frames of a recording or raw capture instead of synthetic ones
+    --scene <PATH>           The scene to generate synthetic frames of [default: built in]
+
+Inspect options:
+    --frame <NUM>            Print the frame with this frame number as JSON
+    --at <SECONDS>           Print the first frame received at or after this time as JSON
+
+Options:
+    --settings <PATH>        The settings file to use [default: ./settings.toml]
+    --set <SECTION.KEY=VAL>  Override a single setting, may be repeated
+    --print-settings         Print the effective settings and exit
+    -h, --help               Print this help message
+
+Settings can also be overridden with environment variables of the form
+IWR68XX_SECTION__KEY=VALUE, command line overrides take precedence.";
+
+/// The subcommand the tool was started with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Run,
+    RunAsync,
+    ListDevices,
+    Simulate {
+        replay: Option<PathBuf>,
+        scene: Option<PathBuf>,
+    },
+    Inspect {
+        path: PathBuf,
+        frame: Option<u32>,
+        at: Option<f64>,
+    },
+    ExportPointcloud {
+        path: PathBuf,
+    },
+    Convert {
+        path: PathBuf,
+    },
+    Render {
+        path: PathBuf,
+    },
+    ReceiveUdp,
+}
+
+/// The parsed command line arguments.
+#[derive(Debug, Clone)]
+pub struct Cli {
+    pub command: Command,
+    pub settings_path: PathBuf,
+    pub overrides: Vec<(String, String)>,
+    pub print_settings: bool,
+}
+
+impl Cli {
+    /// Parses the arguments the process was started with, printing the
+    /// usage and exiting when `--help` is passed.
+    pub fn from_env() -> Result<Cli, Error> {
+        Cli::parse(std::env::args().skip(1))
+    }
+
+    /// Parses a list of arguments, excluding the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Cli, Error> {
+        let mut cli = Cli {
+            command: Command::Run,
+            settings_path: PathBuf::from("./settings.toml"),
+            overrides: vec![],
+            print_settings: false,
+        };
+
+        let mut args = args.into_iter();
+        let mut command: Option<Command> = None;
+        let mut replay: Option<PathBuf> = None;
+        let mut scene: Option<PathBuf> = None;
+        let mut frame: Option<u32> = None;
+        let mut at: Option<f64> = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--settings" => cli.settings_path = PathBuf::from(value_for(&arg, args.next())?),
+                "--set" => {
+                    let assignment = value_for(&arg, args.next())?;
+                    let Some((key, value)) = assignment.split_once('=') else {
+                        return Err(invalid(format!(
+                            "`--set` expects `section.key=value`, got \"{assignment}\""
+                        )));
+                    };
+                    cli.overrides
+                        .push((key.trim().to_string(), value.trim().to_string()));
+                }
+                "--print-settings" => cli.print_settings = true,
+                "--replay" => replay = Some(PathBuf::from(value_for(&arg, args.next())?)),
+                "--scene" => scene = Some(PathBuf::from(value_for(&arg, args.next())?)),
+                "--frame" => frame = Some(parse_number(&arg, args.next())?),
+                "--at" => at = Some(parse_number(&arg, args.next())?),
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    std::process::exit(0);
+                }
+                other if other.starts_with('-') => {
+                    return Err(invalid(format!("Unknown option \"{other}\"\n\n{USAGE}")))
+                }
+                other if command.is_none() => command = Some(parse_command(other)?),
+                other => match command {
+                    Some(
+                        Command::Inspect { ref mut path, .. }
+                        | Command::ExportPointcloud { ref mut path }
+                        | Command::Convert { ref mut path }
+                        | Command::Render { ref mut path },
+                    ) if path.as_os_str().is_empty() => *path = PathBuf::from(other),
+                    _ => return Err(invalid(format!("Unexpected argument \"{other}\""))),
+                },
+            }
+        }
+        if let Some(command) = command {
+            cli.command = command;
+        }
+        match cli.command {
+            Command::Simulate {
+                replay: ref mut r,
+                scene: ref mut s,
+            } => {
+                *r = replay;
+                *s = scene;
+            }
+            _ if replay.is_some() || scene.is_some() => {
+                return Err(invalid(
+                    "`--replay` and `--scene` can only be used with `simulate`".to_string(),
+                ))
+            }
+            _ => {}
+        }
+        if let Command::Inspect { ref path, .. }
+        | Command::ExportPointcloud { ref path }
+        | Command::Convert { ref path }
+        | Command::Render { ref path } = cli.command
+        {
+            if path.as_os_str().is_empty() {
+                return Err(invalid(format!(
+                    "`{}` expects the path of a recording",
+                    command_name(&cli.command)
+                )));
+            }
+        }
+        match cli.command {
+            Command::Inspect {
+                frame: ref mut f,
+                at: ref mut a,
+                ..
+            } => {
+                *f = frame;
+                *a = at;
+            }
+            _ if frame.is_some() || at.is_some() => {
+                return Err(invalid(
+                    "`--frame` and `--at` can only be used with `inspect`".to_string(),
+                ))
+            }
+            _ => {}
+        }
+        Ok(cli)
+    }
+}
+
+fn parse_command(name: &str) -> Result<Command, Error> {
+    match name {
+        "run" => Ok(Command::Run),
+        "run-async" => Ok(Command::RunAsync),
+        "list-devices" => Ok(Command::ListDevices),
+        "simulate" => Ok(Command::Simulate {
+            replay: None,
+            scene: None,
+        }),
+        "inspect" => Ok(Command::Inspect {
+            path: PathBuf::new(),
+            frame: None,
+            at: None,
+        }),
+        "export-pointcloud" => Ok(Command::ExportPointcloud {
+            path: PathBuf::new(),
+        }),
+        "convert" => Ok(Command::Convert {
+            path: PathBuf::new(),
+        }),
+        "render" => Ok(Command::Render {
+            path: PathBuf::new(),
+        }),
+        "receive-udp" => Ok(Command::ReceiveUdp),
+        other => Err(invalid(format!("Unknown command \"{other}\"\n\n{USAGE}"))),
+    }
+}
+
+fn command_name(command: &Command) -> &'static str {
+    match command {
+        Command::Run => "run",
+        Command::RunAsync => "run-async",
+        Command::ListDevices => "list-devices",
+        Command::Simulate { .. } => "simulate",
+        Command::Inspect { .. } => "inspect",
+        Command::ExportPointcloud { .. } => "export-pointcloud",
+        Command::Convert { .. } => "convert",
+        Command::Render { .. } => "render",
+        Command::ReceiveUdp => "receive-udp",
+    }
+}
+
+fn value_for(option: &str, value: Option<String>) -> Result<String, Error> {
+    value.ok_or_else(|| invalid(format!("Option `{option}` expects a value")))
+}
+
+fn parse_number<T: std::str::FromStr>(option: &str, value: Option<String>) -> Result<T, Error> {
+    let value = value_for(option, value)?;
+    value.parse().map_err(|_| {
+        invalid(format!(
+            "Option `{option}` expects a number, got \"{value}\""
+        ))
+    })
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, Error> {
+        Cli::parse(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn runs_with_the_default_settings_without_arguments() {
+        let cli = parse(&[]).unwrap();
+        assert_eq!(cli.command, Command::Run);
+        assert_eq!(cli.settings_path, PathBuf::from("./settings.toml"));
+        assert!(cli.overrides.is_empty());
+        assert!(!cli.print_settings);
+    }
+
+    #[test]
+    fn options_may_come_before_and_after_the_command() {
+        let cli = parse(&[
+            "--settings",
+            "other.toml",
+            "inspect",
+            "capture.iwrrec",
+            "--set",
+            "serial.cfg_baud = 9600",
+            "--frame",
+            "12",
+            "--print-settings",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.command,
+            Command::Inspect {
+                path: PathBuf::from("capture.iwrrec"),
+                frame: Some(12),
+                at: None,
+            }
+        );
+        assert_eq!(cli.settings_path, PathBuf::from("other.toml"));
+        assert_eq!(
+            cli.overrides,
+            vec![("serial.cfg_baud".to_string(), "9600".to_string())]
+        );
+        assert!(cli.print_settings);
+
+        let cli = parse(&["simulate", "--replay", "capture.dat"]).unwrap();
+        assert_eq!(
+            cli.command,
+            Command::Simulate {
+                replay: Some(PathBuf::from("capture.dat")),
+                scene: None,
+            }
+        );
+    }
+
+    #[test]
+    fn mistakes_are_reported() {
+        for args in [
+            &["launch"][..],
+            &["--verbose"],
+            &["--set", "serial.cfg_baud"],
+            &["--settings"],
+            &["inspect"],
+            &["inspect", "a", "b"],
+            &["inspect", "a", "--frame", "twelve"],
+            &["run", "--replay", "capture.dat"],
+            &["convert", "a", "--at", "1"],
+        ] {
+            let e = parse(args).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidInput, "{args:?}");
+        }
+    }
+}
@@ -0,0 +1,321 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use super::encoding::Encoding;
+use super::file_reader::Config;
+use super::file_source::{PlaybackCommand, PlaybackHandle};
+use super::fmcw_manager::{ConnectionState, FmcwCommand, FmcwHandle};
+use super::ipc::Topic;
+use super::tlv_translator::ParserCommand;
+
+/// How long the parser gets to answer a command. It can be held up by
+/// a full queue with `DropPolicy::Block` in front of the IPC server,
+/// which only empties that queue once it has the answer.
+const PARSER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Where the commands which control the sensor are sent to.
+pub enum Sensor {
+    Fmcw(FmcwHandle),
+    /// A capture is replayed instead, only start and stop are supported
+    Playback(PlaybackHandle),
+    /// The FMCW could not be connected
+    None,
+}
+
+/// A command sent by an IPC client, as a single line of JSON such as
+/// `{"id": 7, "command": "cli", "line": "sensorStop"}`. The `id` can be
+/// any JSON value and is copied into the response.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    /// Start the sensor, or resume the replay
+    Start,
+    /// Stop the sensor, or pause the replay
+    Stop,
+    /// Send a single CLI command to the FMCW
+    Cli { line: String },
+    /// Configure the FMCW with another `.cfg` file
+    SwitchConfig { path: PathBuf },
+    /// Start (`true`), stop (`false`) or toggle (left out) recording
+    Record { enabled: Option<bool> },
+    /// The `RadarParameters` of the current configuration
+    RadarParameters,
+    /// Statistics of the stream
+    Stats,
+    /// Receive the frames in another encoding from the next message on
+    Encoding { encoding: Encoding },
+    /// Receive only these sections of the frames from the next frame on
+    Subscribe { topics: Vec<Topic> },
+}
+
+impl Command {
+    /// Whether the command changes the sensor or the recording, rather
+    /// than only inspecting the stream.
+    pub fn is_control(&self) -> bool {
+        match self {
+            Command::Start
+            | Command::Stop
+            | Command::Cli { .. }
+            | Command::SwitchConfig { .. }
+            | Command::Record { .. } => true,
+            Command::RadarParameters
+            | Command::Stats
+            | Command::Encoding { .. }
+            | Command::Subscribe { .. } => false,
+        }
+    }
+}
+
+/// The answer to a request.
+pub struct Response {
+    /// The JSON object holding the `id` and the `result` or `error`
+    pub json: Vec<u8>,
+    /// The encoding the client asked for, to be used after `json`
+    pub encoding: Option<Encoding>,
+    /// The topics the client subscribed to, to be used after `json`
+    pub topics: Option<Vec<Topic>>,
+}
+
+/// Carries out the commands of the IPC clients.
+///
+/// Every request is answered with a single line of JSON holding the
+/// `id` of the request and either a `result` or an `error`, e.g.
+/// `{"id": 7, "result": {"response": "sensorStop\nDone"}}`.
+pub struct Controller {
+    sensor: Sensor,
+    parser: mpsc::Sender<ParserCommand>,
+    config: Config,
+    state: Option<watch::Receiver<ConnectionState>>,
+    started: Instant,
+}
+
+impl Controller {
+    /// # Arguments
+    /// * `sensor`: Where the start, stop, CLI and configuration commands
+    ///   are sent to
+    /// * `parser`: The commands of the `parse_stream` thread
+    /// * `config`: The configuration the sensor was started with
+    /// * `state`: The connection state of the FMCW, if there is one
+    pub fn new(
+        sensor: Sensor,
+        parser: mpsc::Sender<ParserCommand>,
+        config: Config,
+        state: Option<watch::Receiver<ConnectionState>>,
+    ) -> Controller {
+        Controller {
+            sensor,
+            parser,
+            config,
+            state,
+            started: Instant::now(),
+        }
+    }
+
+    /// The configuration the sensor currently runs with.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Handles a single request line. Without `control`, the commands
+    /// for which `Command::is_control` holds are refused.
+    ///
+    /// This blocks until the command has been carried out, which can
+    /// take a few seconds when a configuration is sent, so `ipc_server`
+    /// calls it on a thread of its own.
+    pub fn handle(&mut self, line: &str, ipc_clients: usize, control: bool) -> Response {
+        let mut response = Response {
+            json: vec![],
+            encoding: None,
+            topics: None,
+        };
+        let (id, result) = match serde_json::from_str::<Value>(line) {
+            Ok(mut request) => {
+                let id = request
+                    .as_object_mut()
+                    .and_then(|r| r.remove("id"))
+                    .unwrap_or(Value::Null);
+                let result = Command::deserialize(request)
+                    .map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+                    .and_then(|command| match command {
+                        command if command.is_control() && !control => Err(Error::new(
+                            ErrorKind::PermissionDenied,
+                            "this connection may not control the sensor",
+                        )),
+                        command => self.execute(command, ipc_clients, &mut response),
+                    });
+                (id, result)
+            }
+            Err(e) => (Value::Null, Err(Error::new(ErrorKind::InvalidData, e))),
+        };
+        let json = match result {
+            Ok(result) => json!({ "id": id, "result": result }),
+            Err(e) => json!({ "id": id, "error": e.to_string() }),
+        };
+        response.json = serde_json::to_vec(&json).unwrap();
+        response
+    }
+
+    /// Carries out a command, the encoding and topics are only put into
+    /// `response`, the IPC server applies them.
+    fn execute(
+        &mut self,
+        command: Command,
+        ipc_clients: usize,
+        response: &mut Response,
+    ) -> Result<Value, Error> {
+        match command {
+            Command::Start => self.sensor_command(FmcwCommand::Start, PlaybackCommand::Resume),
+            Command::Stop => self.sensor_command(FmcwCommand::Stop, PlaybackCommand::Pause),
+            Command::Cli { line } => match self.sensor {
+                Sensor::Fmcw(ref fmcw) => {
+                    Ok(json!({ "response": fmcw.request(FmcwCommand::Cli(line))? }))
+                }
+                _ => Err(no_fmcw()),
+            },
+            Command::SwitchConfig { path } => {
+                let Sensor::Fmcw(ref fmcw) = self.sensor else {
+                    return Err(no_fmcw());
+                };
+                let config = Config::from_file(&path)?;
+                // Refuse a configuration the frames could not be
+                // interpreted with, before the FMCW is reconfigured
+                let params = config.radar_parameters()?;
+                fmcw.request(FmcwCommand::SwitchConfig(config.clone()))?;
+                println!("Switched to the configuration {}", path.display());
+                self.config = config;
+                Ok(json!(params))
+            }
+            Command::Record { enabled } => {
+                let (reply, response) = mpsc::channel();
+                self.parser_command(ParserCommand::Record {
+                    enabled,
+                    config: self.config.clone(),
+                    reply,
+                })?;
+                let recording = parser_reply(&response)??;
+                Ok(json!({ "recording": recording }))
+            }
+            Command::RadarParameters => Ok(json!(self.config.radar_parameters()?)),
+            Command::Encoding { encoding } => {
+                response.encoding = Some(encoding);
+                Ok(json!({ "encoding": encoding }))
+            }
+            Command::Subscribe { topics } => {
+                let result = json!({ "topics": topics });
+                response.topics = Some(topics);
+                Ok(result)
+            }
+            Command::Stats => {
+                let (reply, response) = mpsc::channel();
+                self.parser_command(ParserCommand::Stats { reply })?;
+                let stream = parser_reply(&response)?;
+                Ok(json!({
+                    "stream": stream,
+                    "ipc_clients": ipc_clients,
+                    "connection_state": self.state.as_ref().map(|s| *s.borrow()),
+                    "uptime_s": self.started.elapsed().as_secs_f64(),
+                }))
+            }
+        }
+    }
+
+    fn sensor_command(&self, fmcw: FmcwCommand, playback: PlaybackCommand) -> Result<Value, Error> {
+        match self.sensor {
+            Sensor::Fmcw(ref handle) => Ok(json!({ "response": handle.request(fmcw)? })),
+            Sensor::Playback(ref handle) => {
+                if handle.send(playback) {
+                    Ok(json!({}))
+                } else {
+                    Err(Error::new(ErrorKind::NotConnected, "the replay has ended"))
+                }
+            }
+            Sensor::None => Err(no_fmcw()),
+        }
+    }
+
+    fn parser_command(&self, command: ParserCommand) -> Result<(), Error> {
+        self.parser.send(command).map_err(|_| parser_stopped())
+    }
+}
+
+fn no_fmcw() -> Error {
+    Error::new(ErrorKind::Unsupported, "not connected to an FMCW")
+}
+
+fn parser_stopped() -> Error {
+    Error::new(ErrorKind::NotConnected, "the stream has ended")
+}
+
+/// Waits at most `PARSER_TIMEOUT` for the answer to a `ParserCommand`.
+fn parser_reply<T>(response: &mpsc::Receiver<T>) -> Result<T, Error> {
+    response.recv_timeout(PARSER_TIMEOUT).map_err(|e| match e {
+        mpsc::RecvTimeoutError::Timeout => Error::new(
+            ErrorKind::TimedOut,
+            "the parser did not answer in time, it is held up by a full queue",
+        ),
+        mpsc::RecvTimeoutError::Disconnected => parser_stopped(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller() -> Controller {
+        let config = Config {
+            raw_input: include_str!("../iwr6843_config.cfg").to_string(),
+        };
+        Controller::new(Sensor::None, mpsc::channel().0, config, None)
+    }
+
+    fn result(response: &Response) -> Value {
+        serde_json::from_slice(&response.json).unwrap()
+    }
+
+    #[test]
+    fn the_encoding_and_topics_are_left_to_the_server() {
+        let mut controller = controller();
+        let response = controller.handle(
+            r#"{"id": 1, "command": "encoding", "encoding": "cbor"}"#,
+            0,
+            false,
+        );
+        assert_eq!(response.encoding, Some(Encoding::Cbor));
+        assert_eq!(
+            result(&response),
+            json!({ "id": 1, "result": { "encoding": "cbor" } })
+        );
+
+        let response = controller.handle(
+            r#"{"command": "subscribe", "topics": ["points"]}"#,
+            0,
+            false,
+        );
+        assert_eq!(response.topics, Some(vec![Topic::Points]));
+        assert_eq!(response.encoding, None);
+    }
+
+    #[test]
+    fn a_held_up_parser_does_not_hold_up_the_server() {
+        // The parser never answers, like when it waits on a full queue
+        let (parser, _commands) = mpsc::channel();
+        let mut controller = Controller::new(Sensor::None, parser, controller().config, None);
+        let response = controller.handle(r#"{"command": "stats"}"#, 0, true);
+        let error = result(&response)["error"].as_str().unwrap().to_string();
+        assert!(error.contains("did not answer in time"), "{error}");
+    }
+
+    #[test]
+    fn control_commands_need_permission() {
+        let response = controller().handle(r#"{"id": "a", "command": "stop"}"#, 0, false);
+        assert_eq!(
+            result(&response),
+            json!({ "id": "a", "error": "this connection may not control the sensor" })
+        );
+    }
+}
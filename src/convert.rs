@@ -0,0 +1,468 @@
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::data_type::{DoubleType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::types::{Type, TypePtr};
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, File};
+use std::io::{BufWriter, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::file_reader::RadarParameters;
+use super::tlv_translator::{Frame, CPU_CLOCK_HZ};
+
+/// The file format of the tables written by `convert`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TableFormat {
+    Csv,
+    Parquet,
+}
+
+/// The frames are written in batches of this many, one row group each
+/// in Parquet, so hours of frames never have to fit into memory.
+const FRAMES_PER_BATCH: usize = 1000;
+
+/// The values of a single column, `None` is written as an empty CSV
+/// field or a Parquet null.
+enum Values {
+    Int(Vec<Option<i64>>),
+    Float(Vec<Option<f64>>),
+}
+
+impl Values {
+    fn len(&self) -> usize {
+        match self {
+            Values::Int(v) => v.len(),
+            Values::Float(v) => v.len(),
+        }
+    }
+
+    fn clear(&mut self) {
+        match self {
+            Values::Int(v) => v.clear(),
+            Values::Float(v) => v.clear(),
+        }
+    }
+
+    fn csv_field(&self, row: usize) -> String {
+        match self {
+            Values::Int(v) => v[row].map_or(String::new(), |x| x.to_string()),
+            Values::Float(v) => v[row].map_or(String::new(), |x| x.to_string()),
+        }
+    }
+}
+
+/// A table being filled a batch at a time, the rows are appended by
+/// pushing a value onto every column.
+struct Table {
+    name: &'static str,
+    columns: Vec<(&'static str, Values)>,
+}
+
+impl Table {
+    fn new(name: &'static str, columns: &[(&'static str, bool)]) -> Table {
+        Table {
+            name,
+            columns: columns
+                .iter()
+                .map(|&(column, is_int)| {
+                    let values = match is_int {
+                        true => Values::Int(vec![]),
+                        false => Values::Float(vec![]),
+                    };
+                    (column, values)
+                })
+                .collect(),
+        }
+    }
+
+    fn rows(&self) -> usize {
+        self.columns.first().map_or(0, |(_, v)| v.len())
+    }
+
+    /// Appends an integer to a column, which has to be an integer
+    /// column, otherwise the rows of the table would no longer line up.
+    fn push_int(&mut self, column: usize, value: Option<i64>) -> Result<(), Error> {
+        match self.columns.get_mut(column) {
+            Some((_, Values::Int(v))) => {
+                v.push(value);
+                Ok(())
+            }
+            _ => Err(self.mismatch(column, "an integer")),
+        }
+    }
+
+    /// Appends a float to a column, which has to be a float column.
+    fn push_float(&mut self, column: usize, value: Option<f64>) -> Result<(), Error> {
+        match self.columns.get_mut(column) {
+            Some((_, Values::Float(v))) => {
+                v.push(value);
+                Ok(())
+            }
+            _ => Err(self.mismatch(column, "a float")),
+        }
+    }
+
+    fn mismatch(&self, column: usize, kind: &str) -> Error {
+        let column = match self.columns.get(column) {
+            Some((name, _)) => format!("column \"{}\"", name),
+            None => format!("column {}", column),
+        };
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} of the {} table does not hold {} value",
+                column, self.name, kind
+            ),
+        )
+    }
+
+    fn clear(&mut self) {
+        for (_, values) in self.columns.iter_mut() {
+            values.clear();
+        }
+    }
+
+    fn parquet_schema(&self) -> Result<TypePtr, Error> {
+        let fields = self
+            .columns
+            .iter()
+            .map(|(name, values)| {
+                let physical = match values {
+                    Values::Int(_) => parquet::basic::Type::INT64,
+                    Values::Float(_) => parquet::basic::Type::DOUBLE,
+                };
+                Type::primitive_type_builder(name, physical)
+                    .with_repetition(parquet::basic::Repetition::OPTIONAL)
+                    .build()
+                    .map(Arc::new)
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Error::other)?;
+        Type::group_type_builder(self.name)
+            .with_fields(fields)
+            .build()
+            .map(Arc::new)
+            .map_err(Error::other)
+    }
+}
+
+/// Writes the batches of a table into a CSV or Parquet file.
+enum TableWriter {
+    Csv(BufWriter<File>),
+    Parquet(SerializedFileWriter<BufWriter<File>>),
+}
+
+impl TableWriter {
+    fn create(path: &Path, format: TableFormat, table: &Table) -> Result<TableWriter, Error> {
+        let file = BufWriter::new(File::create(path)?);
+        match format {
+            TableFormat::Csv => {
+                let mut file = file;
+                let header: Vec<&str> = table.columns.iter().map(|(name, _)| *name).collect();
+                writeln!(file, "{}", header.join(","))?;
+                Ok(TableWriter::Csv(file))
+            }
+            TableFormat::Parquet => {
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::ZSTD(ZstdLevel::default()))
+                    .build();
+                let writer =
+                    SerializedFileWriter::new(file, table.parquet_schema()?, Arc::new(properties))
+                        .map_err(Error::other)?;
+                Ok(TableWriter::Parquet(writer))
+            }
+        }
+    }
+
+    fn write_batch(&mut self, table: &Table) -> Result<(), Error> {
+        if table.rows() == 0 {
+            return Ok(());
+        }
+        match self {
+            TableWriter::Csv(file) => {
+                for row in 0..table.rows() {
+                    let fields: Vec<String> = table
+                        .columns
+                        .iter()
+                        .map(|(_, v)| v.csv_field(row))
+                        .collect();
+                    writeln!(file, "{}", fields.join(","))?;
+                }
+                Ok(())
+            }
+            TableWriter::Parquet(writer) => {
+                let mut row_group = writer.next_row_group().map_err(Error::other)?;
+                for (_, values) in table.columns.iter() {
+                    let Some(mut column) = row_group.next_column().map_err(Error::other)? else {
+                        break;
+                    };
+                    match values {
+                        Values::Int(v) => {
+                            let (present, levels) = split_nulls(v);
+                            column
+                                .typed::<Int64Type>()
+                                .write_batch(&present, Some(&levels), None)
+                        }
+                        Values::Float(v) => {
+                            let (present, levels) = split_nulls(v);
+                            column
+                                .typed::<DoubleType>()
+                                .write_batch(&present, Some(&levels), None)
+                        }
+                    }
+                    .map_err(Error::other)?;
+                    column.close().map_err(Error::other)?;
+                }
+                row_group.close().map_err(Error::other)?;
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        match self {
+            TableWriter::Csv(mut file) => file.flush(),
+            TableWriter::Parquet(writer) => writer.close().map(|_| ()).map_err(Error::other),
+        }
+    }
+}
+
+/// Splits optional values into the present values and the definition
+/// levels Parquet expects, 1 for a value and 0 for a null.
+fn split_nulls<T: Copy>(values: &[Option<T>]) -> (Vec<T>, Vec<i16>) {
+    let present = values.iter().filter_map(|v| *v).collect();
+    let levels = values.iter().map(|v| v.is_some() as i16).collect();
+    (present, levels)
+}
+
+/// Columns of the points table, `true` for integer columns.
+const POINT_COLUMNS: [(&str, bool); 8] = [
+    ("frame", true),
+    ("time_s", false),
+    ("x", false),
+    ("y", false),
+    ("z", false),
+    ("doppler", false),
+    ("snr_db", false),
+    ("noise_db", false),
+];
+
+const RANGE_PROFILE_COLUMNS: [(&str, bool); 4] = [
+    ("frame", true),
+    ("bin", true),
+    ("range_m", false),
+    ("db", false),
+];
+
+const TELEMETRY_COLUMNS: [(&str, bool); 19] = [
+    ("frame", true),
+    ("time_s", false),
+    ("num_points", true),
+    ("inter_frame_processing_time_us", true),
+    ("transmit_output_time_us", true),
+    ("inter_frame_processing_margin_us", true),
+    ("inter_chirp_processing_margin_us", true),
+    ("active_frame_cpu_load", true),
+    ("inter_frame_cpu_load", true),
+    ("temperature_rx0", true),
+    ("temperature_rx1", true),
+    ("temperature_rx2", true),
+    ("temperature_rx3", true),
+    ("temperature_tx0", true),
+    ("temperature_tx1", true),
+    ("temperature_tx2", true),
+    ("temperature_pm", true),
+    ("temperature_digital0", true),
+    ("temperature_digital1", true),
+];
+
+/// Flattens frames into a table of points, of range profiles and of
+/// telemetry, written as `<name>_points`, `<name>_range_profile` and
+/// `<name>_telemetry` into `directory`.
+///
+/// The time of a frame is taken from its CPU cycle counter, in seconds
+/// after the first frame. The range of a range bin is only known when
+/// `params` is given.
+///
+/// # Returns
+/// The paths of the written tables
+pub fn convert_frames(
+    frames: impl Iterator<Item = Result<Frame, Error>>,
+    directory: &Path,
+    name: &str,
+    format: TableFormat,
+    params: Option<&RadarParameters>,
+) -> Result<Vec<PathBuf>, Error> {
+    create_dir_all(directory)?;
+    let extension = match format {
+        TableFormat::Csv => "csv",
+        TableFormat::Parquet => "parquet",
+    };
+    let mut tables = [
+        Table::new("points", &POINT_COLUMNS),
+        Table::new("range_profile", &RANGE_PROFILE_COLUMNS),
+        Table::new("telemetry", &TELEMETRY_COLUMNS),
+    ];
+    let mut paths: Vec<PathBuf> = vec![];
+    let mut writers: Vec<TableWriter> = vec![];
+    for table in tables.iter() {
+        let path = directory.join(format!("{}_{}.{}", name, table.name, extension));
+        writers.push(TableWriter::create(&path, format, table)?);
+        paths.push(path);
+    }
+
+    let mut clock = FrameClock::default();
+    let mut batch_frames: usize = 0;
+    for frame in frames {
+        let frame = frame?;
+        let time_s = clock.seconds(frame.time_cpu_cycles);
+        let [points, range_profile, telemetry] = &mut tables;
+        push_points(points, &frame, time_s)?;
+        push_range_profile(range_profile, &frame, params)?;
+        push_telemetry(telemetry, &frame, time_s)?;
+
+        batch_frames += 1;
+        if batch_frames >= FRAMES_PER_BATCH {
+            write_batches(&mut writers, &mut tables)?;
+            batch_frames = 0;
+        }
+    }
+    write_batches(&mut writers, &mut tables)?;
+    for writer in writers {
+        writer.finish()?;
+    }
+    Ok(paths)
+}
+
+fn write_batches(writers: &mut [TableWriter], tables: &mut [Table]) -> Result<(), Error> {
+    for (writer, table) in writers.iter_mut().zip(tables.iter_mut()) {
+        writer.write_batch(table)?;
+        table.clear();
+    }
+    Ok(())
+}
+
+fn push_points(table: &mut Table, frame: &Frame, time_s: f64) -> Result<(), Error> {
+    let Some(ref pointcloud) = frame.pointcloud else {
+        return Ok(());
+    };
+    for (i, p) in pointcloud.iter().enumerate() {
+        let side_info = frame.side_info.as_ref().and_then(|s| s.get(i));
+        table.push_int(0, Some(frame.frame_num as i64))?;
+        table.push_float(1, Some(time_s))?;
+        table.push_float(2, Some(p.x as f64))?;
+        table.push_float(3, Some(p.y as f64))?;
+        table.push_float(4, Some(p.z as f64))?;
+        table.push_float(5, Some(p.d as f64))?;
+        table.push_float(6, side_info.map(|s| s.snr as f64 * 0.1))?;
+        table.push_float(7, side_info.map(|s| s.noise as f64 * 0.1))?;
+    }
+    Ok(())
+}
+
+fn push_range_profile(
+    table: &mut Table,
+    frame: &Frame,
+    params: Option<&RadarParameters>,
+) -> Result<(), Error> {
+    let Some(ref profile) = frame.range_profile else {
+        return Ok(());
+    };
+    for (bin, db) in profile.iter().enumerate() {
+        table.push_int(0, Some(frame.frame_num as i64))?;
+        table.push_int(1, Some(bin as i64))?;
+        table.push_float(2, params.map(|p| bin as f64 * p.range_bin_m))?;
+        table.push_float(3, Some(*db))?;
+    }
+    Ok(())
+}
+
+fn push_telemetry(table: &mut Table, frame: &Frame, time_s: f64) -> Result<(), Error> {
+    let stats = frame.stats.map(|s| {
+        [
+            s.inter_frame_processing_time,
+            s.transmit_output_time,
+            s.inter_frame_processing_margin,
+            s.inter_chirp_processing_margin,
+            s.active_frame_cpu_load,
+            s.inter_frame_cpu_load,
+        ]
+    });
+    let temperatures = frame.temperature.map(|t| {
+        let mut all: Vec<u16> = t.rx.to_vec();
+        all.extend_from_slice(&t.tx);
+        all.push(t.pm);
+        all.extend_from_slice(&t.digital);
+        all
+    });
+
+    table.push_int(0, Some(frame.frame_num as i64))?;
+    table.push_float(1, Some(time_s))?;
+    table.push_int(
+        2,
+        Some(frame.pointcloud.as_ref().map_or(0, |pc| pc.len()) as i64),
+    )?;
+    for i in 0..6 {
+        table.push_int(3 + i, stats.map(|s| s[i] as i64))?;
+    }
+    for i in 0..10 {
+        table.push_int(9 + i, temperatures.as_ref().map(|t| t[i] as i64))?;
+    }
+    Ok(())
+}
+
+/// Turns the 32 bit CPU cycle counters of consecutive frames into
+/// seconds after the first frame, counting the wrap arounds.
+#[derive(Default)]
+struct FrameClock {
+    previous: Option<u32>,
+    cycles: u64,
+}
+
+impl FrameClock {
+    fn seconds(&mut self, time_cpu_cycles: u32) -> f64 {
+        if let Some(previous) = self.previous {
+            self.cycles += time_cpu_cycles.wrapping_sub(previous) as u64;
+        }
+        self.previous = Some(time_cpu_cycles);
+        self.cycles as f64 / CPU_CLOCK_HZ
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_value_of_the_wrong_type_is_an_error() {
+        let mut table = Table::new("range_profile", &RANGE_PROFILE_COLUMNS);
+        assert!(table.push_int(0, Some(1)).is_ok());
+        assert!(table.push_float(1, Some(1.0)).is_err());
+        assert!(table
+            .push_int(RANGE_PROFILE_COLUMNS.len(), Some(1))
+            .is_err());
+        assert_eq!(table.columns[1].1.len(), 0);
+    }
+
+    #[test]
+    fn every_column_gets_a_value_per_row() {
+        let mut frame = Frame::empty(3);
+        frame.set_range_profile(vec![1.0, 2.0]);
+        let mut tables = [
+            Table::new("points", &POINT_COLUMNS),
+            Table::new("range_profile", &RANGE_PROFILE_COLUMNS),
+            Table::new("telemetry", &TELEMETRY_COLUMNS),
+        ];
+        push_points(&mut tables[0], &frame, 0.0).unwrap();
+        push_range_profile(&mut tables[1], &frame, None).unwrap();
+        push_telemetry(&mut tables[2], &frame, 0.0).unwrap();
+        for (table, rows) in tables.iter().zip([0, 2, 1]) {
+            for (name, values) in &table.columns {
+                assert_eq!(values.len(), rows, "{} {}", table.name, name);
+            }
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use std::fs::{read_dir, read_to_string};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// The value of `cfg_port` or `data_port` in the settings which asks
+/// for the port to be discovered instead.
+pub const AUTO_PORT: &str = "auto";
+
+/// Where sysfs lists the ttys of the running system.
+const SYSFS_TTY: &str = "/sys/class/tty";
+
+/// The USB to UART bridges through which the IWR68xx boards expose
+/// their ports.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bridge {
+    /// The XDS110 debug probe of the IWR6843ISK-ODS and the launchpads,
+    /// showing up as two `/dev/ttyACM*` ports.
+    Xds110,
+    /// The Silicon Labs CP2105 of the MMWAVEICBOOST carrier (and the
+    /// standalone ISK in some setups), showing up as two `/dev/ttyUSB*`
+    /// ports.
+    Cp2105,
+}
+
+impl Bridge {
+    fn from_ids(vendor_id: u16, product_id: u16) -> Option<Bridge> {
+        match (vendor_id, product_id) {
+            (0x0451, 0xbef3) => Some(Bridge::Xds110),
+            (0x10c4, 0xea70) => Some(Bridge::Cp2105),
+            _ => None,
+        }
+    }
+
+    /// Maps the USB interface number of a port onto its function.
+    ///
+    /// The XDS110 exposes the "Application/User UART" (the CLI) on
+    /// interface 0 and the "Auxiliary Data Port" on interface 3. On the
+    /// CP2105 the enhanced port (interface 0) is wired to the CLI and
+    /// the standard port (interface 1) carries the data.
+    fn role(&self, interface: u8) -> Option<PortRole> {
+        match (self, interface) {
+            (Bridge::Xds110, 0) => Some(PortRole::Cli),
+            (Bridge::Xds110, 3) => Some(PortRole::Data),
+            (Bridge::Cp2105, 0) => Some(PortRole::Cli),
+            (Bridge::Cp2105, 1) => Some(PortRole::Data),
+            _ => None,
+        }
+    }
+}
+
+/// What a serial port of the sensor is used for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PortRole {
+    /// The port over which the configuration is sent
+    Cli,
+    /// The port over which the TLV data is streamed
+    Data,
+}
+
+/// A USB serial port found in sysfs.
+#[derive(Debug, Clone)]
+pub struct SerialDevice {
+    pub path: PathBuf,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub interface: u8,
+    /// The sysfs name of the USB device, e.g. `1-1.2`, this is shared
+    /// by all ports of one board.
+    pub usb_device: String,
+    pub serial_number: Option<String>,
+    pub description: Option<String>,
+    pub bridge: Option<Bridge>,
+    pub role: Option<PortRole>,
+}
+
+/// The CLI and data port of a single board.
+#[derive(Debug, Clone)]
+pub struct SensorPorts {
+    pub cfg_port: PathBuf,
+    pub data_port: PathBuf,
+}
+
+/// Lists all USB serial ports among the ttys in `sysfs_tty`, normally
+/// `/sys/class/tty`, sorted by their path.
+pub fn list_devices(sysfs_tty: &Path) -> Result<Vec<SerialDevice>, Error> {
+    let mut devices = vec![];
+    for entry in read_dir(sysfs_tty)? {
+        let entry = entry?;
+        if let Some(device) = read_device(&entry.path()) {
+            devices.push(device);
+        }
+    }
+    devices.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(devices)
+}
+
+/// Finds the CLI and data port of a connected IWR68xx board among the
+/// ttys in `sysfs_tty`.
+///
+/// When several boards are connected the first one (ordered by the USB
+/// device name) is used, and a warning is printed.
+pub fn find_sensor_ports(sysfs_tty: &Path) -> Result<SensorPorts, Error> {
+    let devices: Vec<SerialDevice> = list_devices(sysfs_tty)?
+        .into_iter()
+        .filter(|d| d.role.is_some())
+        .collect();
+
+    let mut boards: Vec<&str> = devices.iter().map(|d| d.usb_device.as_str()).collect();
+    boards.sort();
+    boards.dedup();
+
+    let mut found: Vec<SensorPorts> = vec![];
+    for board in boards {
+        let port_for = |role: PortRole| {
+            devices
+                .iter()
+                .find(|d| d.usb_device == board && d.role == Some(role))
+                .map(|d| d.path.clone())
+        };
+        if let (Some(cfg_port), Some(data_port)) =
+            (port_for(PortRole::Cli), port_for(PortRole::Data))
+        {
+            found.push(SensorPorts {
+                cfg_port,
+                data_port,
+            });
+        }
+    }
+
+    if found.len() > 1 {
+        eprintln!(
+            "Found {} IWR68xx boards, using the one at {} and {}",
+            found.len(),
+            found[0].cfg_port.display(),
+            found[0].data_port.display()
+        );
+    }
+    found.into_iter().next().ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            "No IWR68xx board (XDS110 or CP2105) was found, check that it is connected",
+        )
+    })
+}
+
+/// Resolves the cfg and data port from the settings, replacing any port
+/// set to `"auto"` with the discovered one.
+pub fn resolve_ports(cfg_port: &str, data_port: &str) -> Result<SensorPorts, Error> {
+    if cfg_port != AUTO_PORT && data_port != AUTO_PORT {
+        return Ok(SensorPorts {
+            cfg_port: PathBuf::from(cfg_port),
+            data_port: PathBuf::from(data_port),
+        });
+    }
+    let found = find_sensor_ports(Path::new(SYSFS_TTY))?;
+    let ports = SensorPorts {
+        cfg_port: match cfg_port {
+            AUTO_PORT => found.cfg_port,
+            port => PathBuf::from(port),
+        },
+        data_port: match data_port {
+            AUTO_PORT => found.data_port,
+            port => PathBuf::from(port),
+        },
+    };
+    println!(
+        "Using {} as cfg port and {} as data port",
+        ports.cfg_port.display(),
+        ports.data_port.display()
+    );
+    Ok(ports)
+}
+
+/// Prints every USB serial port, marking the ones belonging to an
+/// IWR68xx board, for the `list-devices` command.
+pub fn print_devices() -> Result<(), Error> {
+    let devices = list_devices(Path::new(SYSFS_TTY))?;
+    if devices.is_empty() {
+        println!("No USB serial ports found");
+        return Ok(());
+    }
+    for d in devices {
+        let role = match d.role {
+            Some(PortRole::Cli) => "cfg port",
+            Some(PortRole::Data) => "data port",
+            None => "-",
+        };
+        println!(
+            "{:<16} {:04x}:{:04x} if{:02} {:<10} {:<10} {} {}",
+            d.path.display(),
+            d.vendor_id,
+            d.product_id,
+            d.interface,
+            d.bridge.map(|b| format!("{:?}", b)).unwrap_or("-".into()),
+            role,
+            d.serial_number.unwrap_or_default(),
+            d.description.unwrap_or_default(),
+        );
+    }
+    Ok(())
+}
+
+/// Reads the USB information belonging to `<sysfs_tty>/<name>`,
+/// returning `None` for ttys which are not backed by a USB device.
+fn read_device(tty_dir: &Path) -> Option<SerialDevice> {
+    let name = tty_dir.file_name()?.to_str()?.to_string();
+    // For usb-serial ports the `device` link points at the port below
+    // the interface, for cdc-acm ports at the interface itself, so we
+    // walk up until we find the interface and then the device.
+    let mut dir = tty_dir.join("device").canonicalize().ok()?;
+    let interface_dir = loop {
+        if dir.join("bInterfaceNumber").exists() {
+            break dir;
+        }
+        dir = dir.parent()?.to_path_buf();
+    };
+    let usb_dir = interface_dir.parent()?;
+
+    let vendor_id = read_hex(&usb_dir.join("idVendor"))?;
+    let product_id = read_hex(&usb_dir.join("idProduct"))?;
+    let interface = read_hex(&interface_dir.join("bInterfaceNumber"))? as u8;
+    let bridge = Bridge::from_ids(vendor_id, product_id);
+    Some(SerialDevice {
+        path: Path::new("/dev").join(name),
+        vendor_id,
+        product_id,
+        interface,
+        usb_device: usb_dir.file_name()?.to_string_lossy().to_string(),
+        serial_number: read_trimmed(&usb_dir.join("serial")),
+        description: read_trimmed(&interface_dir.join("interface")),
+        bridge,
+        role: bridge.and_then(|b| b.role(interface)),
+    })
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+fn read_hex(path: &Path) -> Option<u16> {
+    u16::from_str_radix(&read_trimmed(path)?, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::os::unix::fs::symlink;
+
+    /// Creates the directory `path` in `root` holding `files`.
+    fn usb_dir(root: &Path, path: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = root.join(path);
+        create_dir_all(&dir).unwrap();
+        for (name, content) in files {
+            write(dir.join(name), format!("{content}\n")).unwrap();
+        }
+        dir
+    }
+
+    /// Adds `class/tty/<name>`, its `device` link pointing at `device`.
+    fn tty(root: &Path, name: &str, device: Option<&Path>) {
+        let dir = root.join("class/tty").join(name);
+        create_dir_all(&dir).unwrap();
+        if let Some(device) = device {
+            symlink(device, dir.join("device")).unwrap();
+        }
+    }
+
+    /// A sysfs tree with an XDS110 (cdc-acm, the `device` link points at
+    /// the interface), a CP2105 (usb-serial, the link points at the port
+    /// below the interface), an unknown USB adapter and two ttys which
+    /// are not USB ports.
+    fn fake_sysfs() -> PathBuf {
+        let root = std::env::temp_dir().join(format!("sysfs_{}", std::process::id()));
+        _ = remove_dir_all(&root);
+
+        let xds = "devices/usb1/1-2";
+        usb_dir(
+            &root,
+            xds,
+            &[
+                ("idVendor", "0451"),
+                ("idProduct", "bef3"),
+                ("serial", "L1234"),
+            ],
+        );
+        for (interface, number, tty_name) in
+            [("1-2:1.0", "00", "ttyACM0"), ("1-2:1.3", "03", "ttyACM1")]
+        {
+            let dir = usb_dir(
+                &root,
+                &format!("{xds}/{interface}"),
+                &[("bInterfaceNumber", number), ("interface", "XDS110 Class")],
+            );
+            tty(&root, tty_name, Some(&dir));
+        }
+
+        let cp2105 = "devices/usb1/1-1";
+        usb_dir(
+            &root,
+            cp2105,
+            &[("idVendor", "10c4"), ("idProduct", "ea70")],
+        );
+        for (interface, number, tty_name) in
+            [("1-1:1.0", "00", "ttyUSB0"), ("1-1:1.1", "01", "ttyUSB1")]
+        {
+            let dir = usb_dir(
+                &root,
+                &format!("{cp2105}/{interface}"),
+                &[("bInterfaceNumber", number)],
+            );
+            let port = usb_dir(&dir, tty_name, &[]);
+            tty(&root, tty_name, Some(&port));
+        }
+
+        let other = "devices/usb2/2-1";
+        usb_dir(&root, other, &[("idVendor", "0403"), ("idProduct", "6001")]);
+        let dir = usb_dir(
+            &root,
+            &format!("{other}/2-1:1.0"),
+            &[("bInterfaceNumber", "00")],
+        );
+        tty(&root, "ttyUSB2", Some(&usb_dir(&dir, "ttyUSB2", &[])));
+
+        let platform = usb_dir(&root, "devices/platform/serial8250", &[]);
+        tty(&root, "ttyS0", Some(&platform));
+        tty(&root, "tty0", None);
+        root
+    }
+
+    #[test]
+    fn usb_ports_are_read_from_sysfs() {
+        let root = fake_sysfs();
+        let devices = list_devices(&root.join("class/tty"));
+        let found = find_sensor_ports(&root.join("class/tty"));
+        remove_dir_all(&root).unwrap();
+        let devices = devices.unwrap();
+
+        let summary: Vec<_> = devices
+            .iter()
+            .map(|d| {
+                (
+                    d.path.to_string_lossy().into_owned(),
+                    d.vendor_id,
+                    d.product_id,
+                    d.interface,
+                    d.usb_device.as_str(),
+                    d.bridge,
+                    d.role,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "/dev/ttyACM0".into(),
+                    0x0451,
+                    0xbef3,
+                    0,
+                    "1-2",
+                    Some(Bridge::Xds110),
+                    Some(PortRole::Cli)
+                ),
+                (
+                    "/dev/ttyACM1".into(),
+                    0x0451,
+                    0xbef3,
+                    3,
+                    "1-2",
+                    Some(Bridge::Xds110),
+                    Some(PortRole::Data)
+                ),
+                (
+                    "/dev/ttyUSB0".into(),
+                    0x10c4,
+                    0xea70,
+                    0,
+                    "1-1",
+                    Some(Bridge::Cp2105),
+                    Some(PortRole::Cli)
+                ),
+                (
+                    "/dev/ttyUSB1".into(),
+                    0x10c4,
+                    0xea70,
+                    1,
+                    "1-1",
+                    Some(Bridge::Cp2105),
+                    Some(PortRole::Data)
+                ),
+                ("/dev/ttyUSB2".into(), 0x0403, 0x6001, 0, "2-1", None, None),
+            ]
+        );
+        assert_eq!(devices[0].serial_number.as_deref(), Some("L1234"));
+        assert_eq!(devices[0].description.as_deref(), Some("XDS110 Class"));
+        assert_eq!(devices[2].serial_number, None);
+
+        // The first board by USB device name
+        let found = found.unwrap();
+        assert_eq!(found.cfg_port, PathBuf::from("/dev/ttyUSB0"));
+        assert_eq!(found.data_port, PathBuf::from("/dev/ttyUSB1"));
+    }
+
+    #[test]
+    fn no_board_is_an_error() {
+        let root = std::env::temp_dir().join(format!("sysfs_empty_{}", std::process::id()));
+        create_dir_all(&root).unwrap();
+        let found = find_sensor_ports(&root);
+        remove_dir_all(&root).unwrap();
+        assert_eq!(found.unwrap_err().kind(), ErrorKind::NotFound);
+        // Ports which are set are not looked up
+        let ports = resolve_ports("/dev/ttyACM0", "/dev/ttyACM1").unwrap();
+        assert_eq!(ports.data_port, PathBuf::from("/dev/ttyACM1"));
+    }
+}
@@ -0,0 +1,347 @@
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+
+use super::tlv_translator::{
+    Cmplx16, Frame, PerformanceStats, PointCloudPoint, SideInfo, TemperatureStats,
+};
+
+/// The version of the `Encoding::Binary` layout, bumped whenever the
+/// layout changes.
+pub const BINARY_SCHEMA_VERSION: u16 = 1;
+
+/// Starts every frame in the `Encoding::Binary` layout.
+pub const BINARY_MAGIC: &[u8; 4] = b"IWRF";
+
+/// How the frames sent to an IPC client are encoded, chosen per client.
+/// The other messages, such as responses, are always JSON.
+///
+/// How the messages are delimited depends on the transport, see
+/// `ipc::Framing`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    Json,
+    Msgpack,
+    Cbor,
+    /// The fixed little endian layout written by `encode_binary`
+    Binary,
+}
+
+/// The kind of a length prefixed message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageKind {
+    /// A JSON object, such as a response or a connection state
+    Json = 0,
+    MsgpackFrame = 1,
+    CborFrame = 2,
+    BinaryFrame = 3,
+}
+
+/// The sections of the `Encoding::Binary` layout, the bit of each is set
+/// in the header when the frame holds it.
+const SECTION_POINTCLOUD: u16 = 1 << 0;
+const SECTION_SIDE_INFO: u16 = 1 << 1;
+const SECTION_RANGE_PROFILE: u16 = 1 << 2;
+const SECTION_NOISE_PROFILE: u16 = 1 << 3;
+const SECTION_AZIMUTH_HEATMAP: u16 = 1 << 4;
+const SECTION_AZIMUTH_ELEVATION_HEATMAP: u16 = 1 << 5;
+const SECTION_RANGE_DOPPLER_HEATMAP: u16 = 1 << 6;
+const SECTION_STATS: u16 = 1 << 7;
+const SECTION_TEMPERATURE: u16 = 1 << 8;
+
+/// Encodes a frame, returning the kind of the message and its payload.
+pub fn encode_frame(frame: &Frame, encoding: Encoding) -> (MessageKind, Vec<u8>) {
+    match encoding {
+        Encoding::Json => (MessageKind::Json, serde_json::to_vec(frame).unwrap()),
+        Encoding::Msgpack => (
+            MessageKind::MsgpackFrame,
+            rmp_serde::to_vec_named(frame).unwrap(),
+        ),
+        Encoding::Cbor => {
+            let mut payload: Vec<u8> = vec![];
+            ciborium::into_writer(frame, &mut payload).unwrap();
+            (MessageKind::CborFrame, payload)
+        }
+        Encoding::Binary => (MessageKind::BinaryFrame, encode_binary(frame)),
+    }
+}
+
+/// Prefixes a message with the length of its payload as a `u32` and its
+/// kind as a byte.
+pub fn length_prefixed(kind: MessageKind, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(payload.len() + 5);
+    message.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    message.push(kind as u8);
+    message.extend_from_slice(payload);
+    message
+}
+
+/// Writes a frame in the fixed little endian layout:
+///
+/// - the header: `BINARY_MAGIC`, the schema version (`u16`), a `u16`
+///   with a bit set for every section present, the frame number, the
+///   subframe number and the time in CPU cycles (all `u32`)
+/// - every present section in the order of their bits, each starting
+///   with its number of elements (`u32`):
+///   - the point cloud, `x`, `y`, `z` and doppler as `f32`
+///   - the side info, SNR and noise as `i16` in units of 0.1 dB
+///   - the range and noise profile in dB as `f32`
+///   - the azimuth and azimuth-elevation heatmaps, imaginary and real
+///     part as `i16`
+///   - the range-doppler heatmap as the `u16` Q9 values
+///   - the stats, 6 `u32`
+///   - the temperature, a single report as sent by the FMCW
+pub fn encode_binary(frame: &Frame) -> Vec<u8> {
+    let mut sections: u16 = 0;
+    let mut body: Vec<u8> = vec![];
+    let mut section = |bit: u16, count: usize, data: Vec<u8>| {
+        sections |= bit;
+        body.extend_from_slice(&(count as u32).to_le_bytes());
+        body.extend_from_slice(&data);
+    };
+
+    if let Some(ref points) = frame.pointcloud {
+        // Field by field, `PointCloudPoint::to_bytes` is in native byte order
+        let data = points
+            .iter()
+            .flat_map(|p| [p.x, p.y, p.z, p.d])
+            .flat_map(f32::to_le_bytes)
+            .collect();
+        section(SECTION_POINTCLOUD, points.len(), data);
+    }
+    if let Some(ref side_info) = frame.side_info {
+        let data = side_info.iter().flat_map(|s| s.to_bytes()).collect();
+        section(SECTION_SIDE_INFO, side_info.len(), data);
+    }
+    if let Some(ref profile) = frame.range_profile {
+        let data = profile
+            .iter()
+            .flat_map(|v| (*v as f32).to_le_bytes())
+            .collect();
+        section(SECTION_RANGE_PROFILE, profile.len(), data);
+    }
+    if let Some(ref profile) = frame.noise_profile {
+        let data = profile
+            .iter()
+            .flat_map(|v| (*v as f32).to_le_bytes())
+            .collect();
+        section(SECTION_NOISE_PROFILE, profile.len(), data);
+    }
+    if let Some(ref heatmap) = frame.azimuth_heatmap {
+        let data = heatmap.iter().flat_map(|c| c.to_bytes()).collect();
+        section(SECTION_AZIMUTH_HEATMAP, heatmap.len(), data);
+    }
+    if let Some(ref heatmap) = frame.azimuth_elevation_heatmap {
+        let data = heatmap.iter().flat_map(|c| c.to_bytes()).collect();
+        section(SECTION_AZIMUTH_ELEVATION_HEATMAP, heatmap.len(), data);
+    }
+    if let Some(ref heatmap) = frame.range_doppler_heatmap {
+        let data = heatmap.iter().flat_map(|v| v.to_le_bytes()).collect();
+        section(SECTION_RANGE_DOPPLER_HEATMAP, heatmap.len(), data);
+    }
+    if let Some(stats) = frame.stats {
+        section(SECTION_STATS, 6, stats.to_bytes());
+    }
+    if let Some(temperature) = frame.temperature {
+        section(SECTION_TEMPERATURE, 1, temperature.to_bytes());
+    }
+
+    let mut result = Vec::with_capacity(body.len() + 20);
+    result.extend_from_slice(BINARY_MAGIC);
+    result.extend_from_slice(&BINARY_SCHEMA_VERSION.to_le_bytes());
+    result.extend_from_slice(&sections.to_le_bytes());
+    result.extend_from_slice(&(frame.frame_num as u32).to_le_bytes());
+    result.extend_from_slice(&(frame.subframe_num as u32).to_le_bytes());
+    result.extend_from_slice(&frame.time_cpu_cycles.to_le_bytes());
+    result.extend_from_slice(&body);
+    result
+}
+
+/// Reads a frame written by `encode_binary`. The range and noise
+/// profiles come back with the precision of an `f32`.
+pub fn decode_binary(data: &[u8]) -> Result<Frame, Error> {
+    if data.len() < 20 || &data[0..4] != BINARY_MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "not a binary frame"));
+    }
+    let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+    let version = u16_at(4);
+    if version != BINARY_SCHEMA_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported binary frame version {}", version),
+        ));
+    }
+    let sections = u16_at(6);
+    let mut frame = Frame::empty(u32_at(8) as usize);
+    frame.subframe_num = u32_at(12) as usize;
+    frame.time_cpu_cycles = u32_at(16);
+
+    let mut rest = &data[20..];
+    let mut next = |bit: u16, element_size: usize| -> Result<Option<Vec<&[u8]>>, Error> {
+        if sections & bit == 0 {
+            return Ok(None);
+        }
+        let truncated = || Error::new(ErrorKind::UnexpectedEof, "the binary frame is truncated");
+        let count_bytes = rest.get(0..4).ok_or_else(truncated)?;
+        let count = u32::from_le_bytes(count_bytes.try_into().unwrap()) as usize;
+        let length = count.checked_mul(element_size).ok_or_else(truncated)?;
+        let body = rest.get(4..4 + length).ok_or_else(truncated)?;
+        rest = &rest[4 + length..];
+        Ok(Some(body.chunks_exact(element_size).collect()))
+    };
+    let f32s = |elements: Vec<&[u8]>| -> Vec<f64> {
+        elements
+            .iter()
+            .map(|b| f32::from_le_bytes((*b).try_into().unwrap()) as f64)
+            .collect()
+    };
+
+    // Field by field, like `encode_binary` writes them
+    let f32_at = |b: &[u8], i: usize| f32::from_le_bytes(b[i..i + 4].try_into().unwrap());
+    frame.pointcloud = next(SECTION_POINTCLOUD, 16)?.map(|elements| {
+        elements
+            .iter()
+            .map(|b| PointCloudPoint {
+                x: f32_at(b, 0),
+                y: f32_at(b, 4),
+                z: f32_at(b, 8),
+                d: f32_at(b, 12),
+            })
+            .collect()
+    });
+    frame.side_info = next(SECTION_SIDE_INFO, 4)?.map(|elements| {
+        elements
+            .iter()
+            .map(|b| SideInfo::from_bytes((*b).try_into().unwrap()))
+            .collect()
+    });
+    frame.range_profile = next(SECTION_RANGE_PROFILE, 4)?.map(f32s);
+    frame.noise_profile = next(SECTION_NOISE_PROFILE, 4)?.map(f32s);
+    let complex = |elements: Vec<&[u8]>| -> Vec<Cmplx16> {
+        elements
+            .iter()
+            .map(|b| Cmplx16::from_bytes((*b).try_into().unwrap()))
+            .collect()
+    };
+    frame.azimuth_heatmap = next(SECTION_AZIMUTH_HEATMAP, 4)?.map(complex);
+    frame.azimuth_elevation_heatmap = next(SECTION_AZIMUTH_ELEVATION_HEATMAP, 4)?.map(complex);
+    frame.range_doppler_heatmap = next(SECTION_RANGE_DOPPLER_HEATMAP, 2)?.map(|elements| {
+        elements
+            .iter()
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            .collect()
+    });
+    frame.stats = next(SECTION_STATS, 4)?
+        .and_then(|elements| PerformanceStats::from_bytes(&elements.concat()));
+    frame.temperature = next(SECTION_TEMPERATURE, TemperatureStats::SIZE)?
+        .and_then(|elements| TemperatureStats::from_bytes(elements.first()?));
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_frame() -> Frame {
+        let mut frame = Frame::empty(42);
+        frame.subframe_num = 1;
+        frame.time_cpu_cycles = 0xdead_beef;
+        frame.pointcloud = Some(vec![
+            PointCloudPoint {
+                x: 1.0,
+                y: -2.5,
+                z: 0.125,
+                d: -0.75,
+            },
+            PointCloudPoint::empty(),
+        ]);
+        frame.side_info = Some(vec![
+            SideInfo {
+                snr: 120,
+                noise: -3,
+            },
+            SideInfo { snr: 0, noise: 7 },
+        ]);
+        // Values an `f32` holds exactly
+        frame.range_profile = Some(vec![10.5, 0.0, 96.25]);
+        frame.noise_profile = Some(vec![3.75]);
+        frame.azimuth_heatmap = Some(vec![Cmplx16 {
+            imag: -1,
+            real: 300,
+        }]);
+        frame.azimuth_elevation_heatmap = Some(vec![
+            Cmplx16 { imag: 5, real: -6 },
+            Cmplx16 {
+                imag: i16::MIN,
+                real: i16::MAX,
+            },
+        ]);
+        frame.range_doppler_heatmap = Some(vec![0, 1, u16::MAX]);
+        frame.stats = PerformanceStats::from_bytes(
+            &(1..=6u32).flat_map(u32::to_le_bytes).collect::<Vec<u8>>(),
+        );
+        frame.temperature = TemperatureStats::from_bytes(&(0..28).collect::<Vec<u8>>());
+        frame
+    }
+
+    #[test]
+    fn a_binary_frame_decodes_to_what_was_encoded() {
+        let frame = full_frame();
+        assert!(frame.stats.is_some() && frame.temperature.is_some());
+        assert_eq!(decode_binary(&encode_binary(&frame)).unwrap(), frame);
+
+        let empty = Frame::empty(3);
+        assert_eq!(decode_binary(&encode_binary(&empty)).unwrap(), empty);
+    }
+
+    #[test]
+    fn a_truncated_binary_frame_is_an_error() {
+        let bytes = encode_binary(&full_frame());
+        for len in [0, 19, 24, bytes.len() - 1] {
+            assert!(decode_binary(&bytes[..len]).is_err(), "{len} bytes decoded");
+        }
+    }
+
+    /// The layout `python_part/frame_decoder.py` reads, byte by byte.
+    #[test]
+    fn the_binary_layout_is_fixed() {
+        let mut frame = Frame::empty(7);
+        frame.subframe_num = 1;
+        frame.time_cpu_cycles = 0x0102_0304;
+        frame.pointcloud = Some(vec![PointCloudPoint {
+            x: 1.0,
+            y: -2.0,
+            z: 0.5,
+            d: 0.25,
+        }]);
+        frame.side_info = Some(vec![SideInfo {
+            snr: 120,
+            noise: -3,
+        }]);
+        frame.range_profile = Some(vec![1.5]);
+        frame.range_doppler_heatmap = Some(vec![0x0102]);
+
+        #[rustfmt::skip]
+        let expected: Vec<u8> = [
+            // Magic, version 1 and the sections 0, 1, 2 and 6
+            b'I', b'W', b'R', b'F', 0x01, 0x00, 0x47, 0x00,
+            // Frame number, subframe number and time
+            0x07, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x04, 0x03, 0x02, 0x01,
+            // One point, x, y, z and d
+            0x01, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x80, 0x3f, 0x00, 0x00, 0x00, 0xc0,
+            0x00, 0x00, 0x00, 0x3f, 0x00, 0x00, 0x80, 0x3e,
+            // One side info, SNR and noise
+            0x01, 0x00, 0x00, 0x00, 0x78, 0x00, 0xfd, 0xff,
+            // One range bin
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0xc0, 0x3f,
+            // One range-doppler bin
+            0x01, 0x00, 0x00, 0x00, 0x02, 0x01,
+        ]
+        .to_vec();
+        assert_eq!(encode_binary(&frame), expected);
+        assert_eq!(decode_binary(&expected).unwrap(), frame);
+    }
+}
@@ -1,138 +1,802 @@
+use serde::{Deserialize, Serialize};
 use std::fs::{read_to_string, File};
-use std::io::{Error, Read};
-use std::path::Path;
+use std::io::{Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
 
+use super::convert::TableFormat;
+use super::frame_log::Compression;
+use super::pointcloud_export::{PointCloudFormat, SensorPose};
+use super::queue::DropPolicy;
+use super::renderer::{AzimuthProjection, ImageFormat, PlotKind, PointColor};
+
+#[derive(Clone)]
 pub struct Config {
     pub raw_input: String,
 }
 
 impl Config {
     pub fn from_file(config_path: &Path) -> Result<Config, Error> {
-        let possible_conf = read_to_string(config_path);
-        if possible_conf.is_err() {
-            println!("config reading error");
-            let err: std::io::Error = possible_conf
-                .err()
-                .expect("Error checking has already been done");
-            return Err(err);
-        }
-        let conf_str: String = possible_conf.expect("checked to be non-eroneous");
-        return Ok(Config::parse_conf_string(conf_str));
+        let conf_str = match read_to_string(config_path) {
+            Ok(v) => v,
+            Err(e) => {
+                println!("config reading error");
+                return Err(e);
+            }
+        };
+        Ok(Config::parse_conf_string(conf_str))
     }
 
     fn parse_conf_string(config: String) -> Config {
         let raw_input = config.clone();
-        let conf = Config { raw_input };
-        return conf;
+        Config { raw_input }
+    }
+
+    /// Returns the arguments of the first line starting with `command`,
+    /// parsed as numbers.
+    fn command_args(&self, command: &str) -> Option<Vec<f64>> {
+        self.commands_args(command).into_iter().next()
+    }
+
+    /// Returns the arguments of every line starting with `command`.
+    fn commands_args(&self, command: &str) -> Vec<Vec<f64>> {
+        self.raw_input
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.split_whitespace();
+                if parts.next()? != command {
+                    return None;
+                }
+                parts.map(|p| p.parse::<f64>().ok()).collect()
+            })
+            .collect()
+    }
+
+    /// Derives the parameters of the radar from the `channelCfg`,
+    /// `profileCfg`, `frameCfg` and optional field of view commands in
+    /// the configuration, using the same formulas as the TI visualizer.
+    pub fn radar_parameters(&self) -> Result<RadarParameters, Error> {
+        let missing = |command: &str| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("The configuration has no valid `{command}` line"),
+            )
+        };
+        let channel = self
+            .command_args("channelCfg")
+            .filter(|a| a.len() >= 2)
+            .ok_or_else(|| missing("channelCfg"))?;
+        let profile = self
+            .command_args("profileCfg")
+            .filter(|a| a.len() >= 11)
+            .ok_or_else(|| missing("profileCfg"))?;
+        let frame = self
+            .command_args("frameCfg")
+            .filter(|a| a.len() >= 5)
+            .ok_or_else(|| missing("frameCfg"))?;
+
+        const SPEED_OF_LIGHT: f64 = 299_792_458.0;
+        let num_rx = (channel[0] as u32).count_ones() as usize;
+        let num_tx = (frame[1] - frame[0]) as usize + 1;
+        let start_freq_ghz = profile[1];
+        let idle_time_us = profile[2];
+        let ramp_end_time_us = profile[4];
+        let freq_slope_mhz_us = profile[7];
+        let num_adc_samples = profile[9] as usize;
+        let sample_rate_ksps = profile[10];
+        let num_loops = frame[2] as usize;
+
+        let num_range_bins = num_adc_samples.next_power_of_two();
+        let num_doppler_bins = num_loops.next_power_of_two();
+        let chirp_time_s = (idle_time_us + ramp_end_time_us) * 1e-6;
+        let wavelength_m = SPEED_OF_LIGHT / (start_freq_ghz * 1e9);
+
+        let range_bin_m = SPEED_OF_LIGHT * sample_rate_ksps * 1e3
+            / (2.0 * freq_slope_mhz_us * 1e12 * num_range_bins as f64);
+        let max_velocity_mps = wavelength_m / (4.0 * chirp_time_s * num_tx as f64);
+
+        // The field of view of the angle of arrival, and of the range
+        // and doppler cfar, default to everything the radar can see.
+        let aoa = self.command_args("aoaFovCfg").filter(|a| a.len() >= 5);
+        let cfar_fov = self.commands_args("cfarFovCfg");
+        let cfar_fov_for = |dimension: f64| {
+            cfar_fov
+                .iter()
+                .find(|a| a.len() >= 4 && a[1] == dimension)
+                .map(|a| (a[2], a[3]))
+        };
+        let max_range_m = range_bin_m * num_range_bins as f64;
+
+        Ok(RadarParameters {
+            num_rx,
+            num_tx,
+            num_virtual_antennas: num_rx * num_tx,
+            num_range_bins,
+            num_doppler_bins,
+            start_freq_ghz,
+            frame_period_ms: frame[4],
+            range_resolution_m: SPEED_OF_LIGHT * sample_rate_ksps * 1e3
+                / (2.0 * freq_slope_mhz_us * 1e12 * num_adc_samples as f64),
+            range_bin_m,
+            max_range_m,
+            doppler_resolution_mps: 2.0 * max_velocity_mps / num_doppler_bins as f64,
+            max_velocity_mps,
+            azimuth_fov_deg: aoa.as_ref().map_or((-90.0, 90.0), |a| (a[1], a[2])),
+            elevation_fov_deg: aoa.as_ref().map_or((-90.0, 90.0), |a| (a[3], a[4])),
+            range_fov_m: cfar_fov_for(0.0).unwrap_or((0.0, max_range_m)),
+            doppler_fov_mps: cfar_fov_for(1.0).unwrap_or((-max_velocity_mps, max_velocity_mps)),
+        })
+    }
+}
+
+/// Which TLVs the FMCW sends, as set by `guiMonitor`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct GuiMonitor {
+    pub detected_points: bool,
+    pub side_info: bool,
+    pub range_profile: bool,
+    pub noise_profile: bool,
+    pub azimuth_heatmap: bool,
+    pub range_doppler_heatmap: bool,
+    pub stats: bool,
+}
+
+impl Config {
+    /// Reads the `guiMonitor` line, `guiMonitor <subFrameIdx>
+    /// <detectedObjects> <logMagRange> <noiseProfile> <rangeAzimuthHeatMap>
+    /// <rangeDopplerHeatMap> <statsInfo>`, where a `detectedObjects` of 1
+    /// also enables the side info and 2 only sends the points.
+    pub fn gui_monitor(&self) -> Result<GuiMonitor, Error> {
+        let args = self
+            .command_args("guiMonitor")
+            .filter(|a| a.len() >= 7)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    "The configuration has no valid `guiMonitor` line",
+                )
+            })?;
+        Ok(GuiMonitor {
+            detected_points: args[1] != 0.0,
+            side_info: args[1] == 1.0,
+            range_profile: args[2] != 0.0,
+            noise_profile: args[3] != 0.0,
+            azimuth_heatmap: args[4] != 0.0,
+            range_doppler_heatmap: args[5] != 0.0,
+            stats: args[6] != 0.0,
+        })
     }
 }
 
-#[derive(Debug)]
+/// The parameters of the radar which follow from its configuration,
+/// needed to interpret the range bins, doppler bins and heatmaps.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RadarParameters {
+    pub num_rx: usize,
+    pub num_tx: usize,
+    pub num_virtual_antennas: usize,
+    pub num_range_bins: usize,
+    pub num_doppler_bins: usize,
+    pub start_freq_ghz: f64,
+    pub frame_period_ms: f64,
+    pub range_resolution_m: f64,
+    /// The distance between two range bins, the range of bin `i` is
+    /// `i * range_bin_m`
+    pub range_bin_m: f64,
+    pub max_range_m: f64,
+    pub doppler_resolution_mps: f64,
+    pub max_velocity_mps: f64,
+    /// `(min, max)` of the azimuth field of view from `aoaFovCfg`
+    pub azimuth_fov_deg: (f64, f64),
+    /// `(min, max)` of the elevation field of view from `aoaFovCfg`
+    pub elevation_fov_deg: (f64, f64),
+    /// `(min, max)` of the range from `cfarFovCfg`
+    pub range_fov_m: (f64, f64),
+    /// `(min, max)` of the doppler velocity from `cfarFovCfg`
+    pub doppler_fov_mps: (f64, f64),
+}
+
+impl RadarParameters {
+    /// The number of virtual antennas along the azimuth, the azimuth
+    /// heatmap holds this many samples per range bin. With three
+    /// transmitters one of them is used for the elevation.
+    pub fn num_virtual_antennas_azimuth(&self) -> usize {
+        if self.num_tx >= 3 {
+            (self.num_tx - 1) * self.num_rx
+        } else {
+            self.num_virtual_antennas
+        }
+    }
+}
+
+/// Prefix of the environment variables which can override settings,
+/// the section and key are separated by a double underscore, e.g.
+/// `IWR68XX_SERIAL__CFG_PORT=/dev/ttyACM0`.
+pub const ENV_PREFIX: &str = "IWR68XX_";
+
+/// All settings of the tool, read from a TOML file such as
+/// `./settings.toml`. Every section and key is optional, missing
+/// values take on the defaults below, but unknown keys are an error.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Settings {
+    pub serial: SerialSettings,
+    pub recording: RecordingSettings,
+    pub ipc: IpcSettings,
+    pub mqtt: MqttSettings,
+    pub udp: UdpSettings,
+    pub pipeline: PipelineSettings,
+    pub sensor_pose: SensorPoseSettings,
+    pub processing: ProcessingSettings,
+    pub export: ExportSettings,
+    pub render: RenderSettings,
+}
+
+/// The serial ports of the FMCW, and the configuration script which
+/// is sent over the cfg port.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SerialSettings {
     pub cfg_port: String,
     pub cfg_baud: u32,
     pub data_port: String,
     pub data_baud: u32,
-    pub read_from_file: bool,
-    pub raw_data_save: bool,
+    pub config_file: PathBuf,
+    /// Without data for this long the connection is considered lost
+    pub stall_timeout_ms: u64,
+    /// The first delay before reopening the ports, doubled on every
+    /// failed attempt up to `reconnect_max_backoff_ms`
+    pub reconnect_backoff_ms: u64,
+    pub reconnect_max_backoff_ms: u64,
+}
+
+/// Which of the received data should be written to disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordingSettings {
+    /// Record the received bytes, with the configuration and timing,
+    /// into a new recording in `directory`
+    pub save_raw_data: bool,
+    /// Log the parsed frames as newline delimited JSON into `directory`
     pub save_frames: bool,
-    pub ipc_send: bool,
+    /// Record the parsed frames into an MCAP file in `directory`,
+    /// which can be opened in Foxglove
+    pub save_mcap: bool,
+    pub directory: PathBuf,
+    /// Start a new frame log after this many bytes, 0 for no limit
+    pub frame_log_max_bytes: u64,
+    /// Start a new frame log after this many seconds, 0 for no limit
+    pub frame_log_max_seconds: u64,
+    pub frame_log_compression: Compression,
 }
 
-impl Settings {
-    /// This function reads the file at the provided path
-    /// and tries to generate settings for the IWR64xx fmcw module.
-    pub fn from_file(settings_file_path: &Path) -> Settings {
-        let possible_contents = read_to_string(settings_file_path);
-        if possible_contents.is_err() {
-            println!("Settings file path was incorrect, returning the default path instead.");
-            return Settings::default();
+/// Settings for sending the parsed frames to other processes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpcSettings {
+    /// Serve the frames over the _Unix Socket_. Each of the transports
+    /// below is started on its own, whether this is set or not
+    pub enabled: bool,
+    /// Where the _Unix Socket_ the clients connect to is bound
+    pub socket_path: PathBuf,
+    /// Messages queued for each client, a client which falls further
+    /// behind is disconnected
+    pub client_queue_size: usize,
+    /// Also serve the frames to TCP clients, length prefixed
+    pub tcp_enabled: bool,
+    pub tcp_address: String,
+    /// Also serve the frames to WebSocket clients
+    pub websocket_enabled: bool,
+    pub websocket_address: String,
+    /// Let the TCP and WebSocket clients control the sensor and the
+    /// recording, not only subscribe to the frames
+    pub remote_control: bool,
+    /// Serve the frames to Foxglove Studio over the Foxglove WebSocket
+    /// protocol
+    pub foxglove_enabled: bool,
+    pub foxglove_address: String,
+    /// Also write the frames into a ring of slots in shared memory
+    pub shm_enabled: bool,
+    /// The shared memory file, e.g. in `/dev/shm`
+    pub shm_path: PathBuf,
+    /// Where the readers of the shared memory are notified of new frames
+    pub shm_notify_path: PathBuf,
+    pub shm_slots: usize,
+    /// The maximum size of a frame in the binary encoding
+    pub shm_slot_bytes: usize,
+}
+
+/// Settings for publishing the frames to an MQTT broker, see
+/// `mqtt_publisher` for the topics.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttSettings {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    /// Left empty, `iwr68xx-<sensor_id>` is used
+    pub client_id: String,
+    /// Left empty, no credentials are sent
+    pub username: String,
+    /// Not part of `Settings::to_toml_string`, so it is never printed or
+    /// recorded
+    pub password: String,
+    /// The topics are `<topic_prefix>/<sensor_id>/...`
+    pub topic_prefix: String,
+    pub sensor_id: String,
+    /// The QoS of the frame topics, 0, 1 or 2
+    pub qos: u8,
+    pub keep_alive_s: u64,
+    /// Publish the range profile of every n-th frame only
+    pub range_profile_every: usize,
+    /// The number of bins the range profile is reduced to
+    pub range_profile_bins: usize,
+    /// Publish the telemetry of every n-th frame only
+    pub telemetry_every: usize,
+}
+
+/// Settings for sending the frames as UDP datagrams to a multicast
+/// group, see `udp_sender`, and for receiving them with `receive-udp`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UdpSettings {
+    pub enabled: bool,
+    /// The multicast group, or the address of a single receiver
+    pub group: String,
+    pub port: u16,
+    /// The address of the interface the group is joined on when
+    /// receiving, `0.0.0.0` lets the system choose
+    pub interface: String,
+    /// How many routers the datagrams may pass, 1 keeps them in the
+    /// local network
+    pub ttl: u32,
+    /// Whether receivers on the sending machine get the datagrams too
+    pub loopback: bool,
+    /// The size of a datagram including its header, frames are split
+    /// into as many datagrams as needed
+    pub max_datagram_bytes: usize,
+}
+
+/// The stages between the FMCW and the sinks, see `pipeline.rs`.
+///
+/// The queues hold the raw data read from the FMCW waiting for the
+/// parser, and the frames waiting for each sink. A full queue applies
+/// its policy, `block` slows down the stage in front of it, the others
+/// drop data and count what they dropped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineSettings {
+    /// The processors every frame passes before the sinks, in order
+    pub processors: Vec<String>,
+    /// The settings of the `snr_filter` and `range_filter` processors
+    pub min_snr_db: f64,
+    pub min_range_m: f64,
+    pub max_range_m: f64,
+    /// The chunks of raw data, dropping one loses the frames it was
+    /// part of
+    pub raw_queue_size: usize,
+    pub raw_policy: DropPolicy,
+    pub ipc_queue_size: usize,
+    pub ipc_policy: DropPolicy,
+    pub mqtt_queue_size: usize,
+    pub mqtt_policy: DropPolicy,
+    pub udp_queue_size: usize,
+    pub udp_policy: DropPolicy,
+    pub render_queue_size: usize,
+    pub render_policy: DropPolicy,
+}
+
+/// How the sensor is mounted, for the `sensor_pose` processor which
+/// moves the points of every frame into room coordinates.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SensorPoseSettings {
+    pub position_m: [f64; 3],
+    pub yaw_deg: f64,
+    pub pitch_deg: f64,
+    pub roll_deg: f64,
+}
+
+/// Settings for the processing of the raw TLV data.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProcessingSettings {
+    /// Replay `tlv_file` instead of reading from the FMCW
+    pub read_from_file: bool,
+    pub tlv_file: PathBuf,
+    /// Multiplier of the replay speed, 0 replays as fast as possible
+    pub replay_speed: f64,
+    /// Start over at the end of `tlv_file`
+    pub replay_loop: bool,
+}
+
+/// Settings for exporting captures into files for other tools.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportSettings {
+    pub directory: PathBuf,
+    pub pointcloud_format: PointCloudFormat,
+    /// Write the point clouds as text instead of binary
+    pub ascii: bool,
+    /// Frames per point cloud file, 0 puts all frames in one file
+    pub accumulate_frames: usize,
+    /// The format of the tables written by `convert`
+    pub table_format: TableFormat,
+    /// The position of the sensor in the exported coordinates
+    pub sensor_position_m: [f64; 3],
+    pub sensor_yaw_deg: f64,
+    pub sensor_pitch_deg: f64,
+    pub sensor_roll_deg: f64,
+}
+
+/// Settings for rendering the frames into images while running.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderSettings {
+    pub enabled: bool,
+    pub directory: PathBuf,
+    pub plots: Vec<PlotKind>,
+    /// Only render every n-th frame
+    pub every_n_frames: usize,
+    /// The size of the images in pixels
+    pub width: u32,
+    pub height: u32,
+    pub format: ImageFormat,
+    /// What the color of a point cloud point shows
+    pub color_by: PointColor,
+    /// The previous frames whose points are drawn faded
+    pub trail_frames: usize,
+    /// The frames drawn into one point cloud image
+    pub grid_frames: usize,
+    /// The span of the heatmap colors below the strongest bin
+    pub dynamic_range_db: f64,
+    pub azimuth_projection: AzimuthProjection,
+}
+
+impl SensorPoseSettings {
+    pub fn pose(&self) -> SensorPose {
+        SensorPose {
+            position_m: self.position_m,
+            yaw_deg: self.yaw_deg,
+            pitch_deg: self.pitch_deg,
+            roll_deg: self.roll_deg,
         }
-        let mut settings = Settings::default();
-        let contents = possible_contents.expect("checked to be non-eroneous");
-        for line in contents.lines() {
-            let kv: Vec<&str> = line.splitn(2, '=').map(|s| s.trim()).collect();
-            match kv[0] {
-                "cfg_port" => settings.cfg_port = kv[1].to_string(),
-                "cfg_baud" => {
-                    let baud = match kv[1].parse::<u32>() {
-                        Ok(val) => val,
-                        Err(_) => continue,
-                    };
-                    settings.cfg_baud = baud
-                }
-                "data_port" => settings.data_port = kv[1].to_string(),
-                "data_baud" => {
-                    let baud = match kv[1].parse::<u32>() {
-                        Ok(val) => val,
-                        Err(_) => continue,
-                    };
-                    settings.data_baud = baud
-                }
-                "read_from_file" => {
-                    let read_file = match kv[1].parse::<bool>() {
-                        Ok(val) => val,
-                        Err(_) => continue,
-                    };
-                    settings.read_from_file = read_file
-                }
-                "save_raw_data" => {
-                    let raw_save = match kv[1].parse::<bool>() {
-                        Ok(val) => val,
-                        Err(_) => continue,
-                    };
-                    settings.raw_data_save = raw_save
-                }
-                "save_frames" => {
-                    let raw_save = match kv[1].parse::<bool>() {
-                        Ok(val) => val,
-                        Err(_) => continue,
-                    };
-                    settings.save_frames = raw_save
-                }
-                "ipc_send" => {
-                    let ipc_send = match kv[1].parse::<bool>() {
-                        Ok(val) => val,
-                        Err(_) => continue,
-                    };
-                    settings.ipc_send = ipc_send
-                }
-                other => {
-                    eprintln!(
-                        "Found an unknown option in the settings: \"{}\" not recognized",
-                        other
-                    )
-                }
-            };
+    }
+}
+
+impl ExportSettings {
+    pub fn sensor_pose(&self) -> SensorPose {
+        SensorPose {
+            position_m: self.sensor_position_m,
+            yaw_deg: self.sensor_yaw_deg,
+            pitch_deg: self.sensor_pitch_deg,
+            roll_deg: self.sensor_roll_deg,
         }
-        println!("Settings are: \n   {:?}", settings);
-        return settings;
-    }
-
-    // Some sane default values when using the code on linux
-    pub fn default() -> Settings {
-        // Settings {
-        //     cfg_port: "bullshit".to_string(),
-        //     cfg_baud: 63,
-        //     data_port: "bullshit".to_string(),
-        //     data_baud: 63,
-        //     raw_data_save: false,
-        //     pointcloud_save: false,
-        //     ipc_send: false,
-        // }
-        Settings {
+    }
+}
+
+// Some sane default values when using the code on linux
+impl Default for SerialSettings {
+    fn default() -> SerialSettings {
+        SerialSettings {
             cfg_port: "/dev/ttyUSB0".to_string(),
             cfg_baud: 115200,
             data_port: "/dev/ttyUSB1".to_string(),
             data_baud: 921600,
-            read_from_file: false,
-            raw_data_save: true,
+            config_file: PathBuf::from("./iwr6843_config.cfg"),
+            stall_timeout_ms: 5000,
+            reconnect_backoff_ms: 500,
+            reconnect_max_backoff_ms: 10000,
+        }
+    }
+}
+
+impl Default for RecordingSettings {
+    fn default() -> RecordingSettings {
+        RecordingSettings {
+            save_raw_data: true,
             save_frames: false,
-            ipc_send: true,
+            save_mcap: false,
+            directory: PathBuf::from("./recordings"),
+            frame_log_max_bytes: 0,
+            frame_log_max_seconds: 0,
+            frame_log_compression: Compression::None,
         }
     }
 }
 
+impl IpcSettings {
+    /// Whether any of the transports is enabled, so the IPC server runs.
+    pub fn any_enabled(&self) -> bool {
+        self.enabled
+            || self.tcp_enabled
+            || self.websocket_enabled
+            || self.foxglove_enabled
+            || self.shm_enabled
+    }
+}
+
+impl Default for IpcSettings {
+    fn default() -> IpcSettings {
+        IpcSettings {
+            enabled: true,
+            socket_path: PathBuf::from("/tmp/fmcw_ipc_socket"),
+            client_queue_size: 64,
+            tcp_enabled: false,
+            tcp_address: "0.0.0.0:5005".to_string(),
+            websocket_enabled: false,
+            websocket_address: "0.0.0.0:5006".to_string(),
+            remote_control: false,
+            foxglove_enabled: false,
+            foxglove_address: "0.0.0.0:8765".to_string(),
+            shm_enabled: false,
+            shm_path: PathBuf::from("/dev/shm/iwr68xx_frames"),
+            shm_notify_path: PathBuf::from("/tmp/iwr68xx_shm_socket"),
+            shm_slots: 16,
+            shm_slot_bytes: 1 << 20,
+        }
+    }
+}
+
+impl Default for MqttSettings {
+    fn default() -> MqttSettings {
+        MqttSettings {
+            enabled: false,
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: String::new(),
+            username: String::new(),
+            password: String::new(),
+            topic_prefix: "iwr68xx".to_string(),
+            sensor_id: "iwr6843".to_string(),
+            qos: 0,
+            keep_alive_s: 30,
+            range_profile_every: 10,
+            range_profile_bins: 64,
+            telemetry_every: 10,
+        }
+    }
+}
+
+impl Default for UdpSettings {
+    fn default() -> UdpSettings {
+        UdpSettings {
+            enabled: false,
+            group: "239.255.68.43".to_string(),
+            port: 5007,
+            interface: "0.0.0.0".to_string(),
+            ttl: 1,
+            loopback: true,
+            max_datagram_bytes: 1472,
+        }
+    }
+}
+
+impl Default for PipelineSettings {
+    fn default() -> PipelineSettings {
+        PipelineSettings {
+            processors: vec![],
+            min_snr_db: 0.0,
+            min_range_m: 0.0,
+            max_range_m: 100.0,
+            raw_queue_size: 1024,
+            raw_policy: DropPolicy::Block,
+            ipc_queue_size: 64,
+            ipc_policy: DropPolicy::DropOldest,
+            mqtt_queue_size: 64,
+            mqtt_policy: DropPolicy::DropOldest,
+            udp_queue_size: 64,
+            udp_policy: DropPolicy::DropOldest,
+            render_queue_size: 8,
+            render_policy: DropPolicy::DropOldest,
+        }
+    }
+}
+
+impl Default for ProcessingSettings {
+    fn default() -> ProcessingSettings {
+        ProcessingSettings {
+            read_from_file: false,
+            tlv_file: PathBuf::from("./tlv_example_file.dat"),
+            replay_speed: 1.0,
+            replay_loop: false,
+        }
+    }
+}
+
+impl Default for ExportSettings {
+    fn default() -> ExportSettings {
+        ExportSettings {
+            directory: PathBuf::from("./export"),
+            pointcloud_format: PointCloudFormat::Pcd,
+            ascii: false,
+            accumulate_frames: 1,
+            table_format: TableFormat::Csv,
+            sensor_position_m: [0.0, 0.0, 0.0],
+            sensor_yaw_deg: 0.0,
+            sensor_pitch_deg: 0.0,
+            sensor_roll_deg: 0.0,
+        }
+    }
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            enabled: false,
+            directory: PathBuf::from("./plots"),
+            plots: vec![PlotKind::RangeProfile],
+            every_n_frames: 1,
+            width: 640,
+            height: 480,
+            format: ImageFormat::Png,
+            color_by: PointColor::Doppler,
+            trail_frames: 0,
+            grid_frames: 1,
+            dynamic_range_db: 40.0,
+            azimuth_projection: AzimuthProjection::Cartesian,
+        }
+    }
+}
+
+impl Settings {
+    /// This function reads the TOML file at the provided path and
+    /// tries to generate settings for the IWR68xx fmcw module.
+    ///
+    /// A missing file, invalid TOML, unknown keys and values of the
+    /// wrong type all result in an `InvalidData` error which names
+    /// the offending line.
+    pub fn from_file(settings_file_path: &Path) -> Result<Settings, Error> {
+        let contents = read_to_string(settings_file_path).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!(
+                    "Could not read the settings file \"{}\": {}",
+                    settings_file_path.display(),
+                    e
+                ),
+            )
+        })?;
+        Settings::from_toml_str(&contents).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("In \"{}\": {}", settings_file_path.display(), e),
+            )
+        })
+    }
+
+    /// Parses settings from the contents of a TOML file.
+    pub fn from_toml_str(contents: &str) -> Result<Settings, Error> {
+        toml::from_str(contents).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+    }
+
+    /// Reads the settings file and applies the overrides on top of it,
+    /// first those from the environment (see `ENV_PREFIX`) and then the
+    /// provided `cli_overrides`, so the command line always wins.
+    ///
+    /// # Arguments
+    /// * `settings_file_path`: The TOML file forming the base layer
+    /// * `cli_overrides`: `(key, value)` pairs where the key is the
+    ///   dotted path of the setting, e.g. `serial.cfg_port`
+    pub fn load(
+        settings_file_path: &Path,
+        cli_overrides: &[(String, String)],
+    ) -> Result<Settings, Error> {
+        Settings::load_with_env(settings_file_path, std::env::vars(), cli_overrides)
+    }
+
+    /// `load`, with the environment variables in `env`.
+    fn load_with_env(
+        settings_file_path: &Path,
+        env: impl IntoIterator<Item = (String, String)>,
+        cli_overrides: &[(String, String)],
+    ) -> Result<Settings, Error> {
+        // Parse the file on its own first, so that any error in it is
+        // reported with the line number in that file.
+        let settings = Settings::from_file(settings_file_path)?;
+
+        let mut overrides = env_overrides(env);
+        overrides.extend_from_slice(cli_overrides);
+        if overrides.is_empty() {
+            return Ok(settings);
+        }
+        settings.with_overrides(&overrides)
+    }
+
+    /// Returns a copy of the settings with every `(key, value)` pair
+    /// applied, where `key` is a dotted path such as `serial.cfg_baud`.
+    /// Values are parsed as TOML (`115200`, `true`, `"text"`), falling
+    /// back to a plain string if that fails.
+    pub fn with_overrides(&self, overrides: &[(String, String)]) -> Result<Settings, Error> {
+        let mut table = toml::Table::try_from(self)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+
+        for (key, value) in overrides {
+            let (section, field) = match key.split_once('.') {
+                Some(v) => v,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Override \"{key}\" should be of the form `section.key`"),
+                    ))
+                }
+            };
+            let section_table = match table.get_mut(section).and_then(|s| s.as_table_mut()) {
+                Some(t) => t,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Override \"{key}\": unknown section \"{section}\""),
+                    ))
+                }
+            };
+            if !section_table.contains_key(field) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Override \"{key}\": unknown key \"{field}\" in [{section}]"),
+                ));
+            }
+            section_table.insert(field.to_string(), parse_override_value(value));
+
+            // Check every override on its own, so the error can name it
+            if let Err(e) = toml::Value::Table(table.clone()).try_into::<Settings>() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid override \"{key}={value}\": {}", e.message()),
+                ));
+            }
+        }
+
+        toml::Value::Table(table)
+            .try_into()
+            .map_err(|e: toml::de::Error| Error::new(ErrorKind::InvalidInput, e.to_string()))
+    }
+
+    /// Renders the settings back into TOML, this is the effective
+    /// configuration after all overrides have been applied.
+    ///
+    /// The output is printed and stored in recordings, so the secrets in
+    /// it, the MQTT password, are left out.
+    pub fn to_toml_string(&self) -> String {
+        let mut table =
+            toml::Table::try_from(self).expect("Settings should always be serializable to TOML");
+        if let Some(mqtt) = table.get_mut("mqtt").and_then(|s| s.as_table_mut()) {
+            mqtt.remove("password");
+        }
+        toml::to_string_pretty(&table).expect("A TOML table should always be serializable")
+    }
+}
+
+/// Collects the `IWR68XX_SECTION__KEY` variables of `vars` as
+/// `("section.key", value)` overrides. Other variables with the prefix
+/// are not meant for us, they are ignored with a warning.
+fn env_overrides(vars: impl IntoIterator<Item = (String, String)>) -> Vec<(String, String)> {
+    let mut overrides = vec![];
+    for (name, value) in vars {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        match key.split_once("__") {
+            Some((section, field)) => overrides.push((
+                format!("{}.{}", section.to_lowercase(), field.to_lowercase()),
+                value,
+            )),
+            None => eprintln!(
+                "Ignoring the environment variable {name}, settings are overridden with {ENV_PREFIX}SECTION__KEY"
+            ),
+        }
+    }
+    // Environment order is unspecified, sort for reproducible results
+    overrides.sort();
+    overrides
+}
+
+fn parse_override_value(value: &str) -> toml::Value {
+    match format!("v = {value}").parse::<toml::Table>() {
+        Ok(mut t) => t.remove("v").expect("The key was just parsed"),
+        Err(_) => toml::Value::String(value.to_string()),
+    }
+}
+
 /// `Read bytes file` reads in a raw bytes file
 /// located at `path` and returns the contents
 /// as a `Vec` if it exists and an `io::Error`
@@ -145,10 +809,146 @@ impl Settings {
 /// # Returns
 ///
 /// * `Result<Vec<u8>, Error>`: Either the bytes
-/// contained in the file or an error
+///   contained in the file or an error
 pub fn read_byte_file(path: &Path) -> Result<Vec<u8>, Error> {
     let mut file = File::open(path)?;
     let mut buffer = Vec::new();
     file.read_to_end(&mut buffer)?;
-    return Ok(buffer);
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_mqtt_password_is_not_in_the_toml() {
+        let mut settings = Settings::default();
+        settings.mqtt.username = "sensor".to_string();
+        settings.mqtt.password = "hunter2".to_string();
+        let toml = settings.to_toml_string();
+        assert!(!toml.contains("hunter2"), "password in {toml}");
+        assert!(toml.contains("sensor"));
+        // It still reads back, without the password
+        let parsed = Settings::from_toml_str(&toml).unwrap();
+        assert_eq!(parsed.mqtt.username, "sensor");
+        assert_eq!(parsed.mqtt.password, "");
+        // Overrides keep it
+        let overridden = settings
+            .with_overrides(&[("mqtt.port".to_string(), "1884".to_string())])
+            .unwrap();
+        assert_eq!(overridden.mqtt.password, "hunter2");
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn missing_settings_take_their_default() {
+        let settings = Settings::from_toml_str("[serial]\ncfg_baud = 9600\n").unwrap();
+        assert_eq!(settings.serial.cfg_baud, 9600);
+        assert_eq!(
+            settings.serial.data_baud,
+            SerialSettings::default().data_baud
+        );
+        assert_eq!(settings.mqtt.port, MqttSettings::default().port);
+        // The shipped settings file is valid
+        Settings::from_toml_str(include_str!("../settings.toml")).unwrap();
+    }
+
+    #[test]
+    fn mistakes_in_the_file_are_reported_with_their_line() {
+        let unknown = "[serial]\ncfg_baud = 9600\ncfg_bud = 9600\n";
+        let e = Settings::from_toml_str(unknown).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        let message = e.to_string();
+        assert!(message.contains("line 3"), "{message}");
+        assert!(message.contains("cfg_bud"), "{message}");
+
+        let unknown_section = "[serail]\ncfg_baud = 9600\n";
+        let message = Settings::from_toml_str(unknown_section)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            message.contains("line 1") && message.contains("serail"),
+            "{message}"
+        );
+
+        let wrong_type = "[serial]\n\ncfg_baud = \"fast\"\n";
+        let message = Settings::from_toml_str(wrong_type).unwrap_err().to_string();
+        assert!(message.contains("line 3"), "{message}");
+    }
+
+    #[test]
+    fn overrides_are_parsed_as_toml() {
+        let settings = Settings::default()
+            .with_overrides(&pairs(&[
+                ("serial.cfg_baud", "9600"),
+                ("mqtt.enabled", "true"),
+                ("serial.cfg_port", "/dev/ttyACM0"),
+                ("mqtt.host", "\"broker\""),
+            ]))
+            .unwrap();
+        assert_eq!(settings.serial.cfg_baud, 9600);
+        assert!(settings.mqtt.enabled);
+        // Not TOML, so taken as a string
+        assert_eq!(settings.serial.cfg_port, "/dev/ttyACM0");
+        assert_eq!(settings.mqtt.host, "broker");
+    }
+
+    #[test]
+    fn invalid_overrides_are_named() {
+        for (key, value, expected) in [
+            ("serial", "1", "section.key"),
+            ("serail.cfg_baud", "1", "unknown section"),
+            ("serial.cfg_bud", "1", "unknown key"),
+            ("serial.cfg_baud", "fast", "serial.cfg_baud=fast"),
+        ] {
+            let e = Settings::default()
+                .with_overrides(&pairs(&[(key, value)]))
+                .unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidInput);
+            assert!(e.to_string().contains(expected), "{e}");
+        }
+    }
+
+    #[test]
+    fn the_command_line_overrides_the_environment_which_overrides_the_file() {
+        let path = std::env::temp_dir().join(format!("settings_{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "[serial]\ncfg_baud = 1\ndata_baud = 1\ncfg_port = \"file\"\n",
+        )
+        .unwrap();
+        let env = pairs(&[
+            ("IWR68XX_SERIAL__CFG_BAUD", "2"),
+            ("IWR68XX_SERIAL__DATA_BAUD", "2"),
+            ("IWR68XX_DEBUG", "1"),
+            ("PATH", "/bin"),
+        ]);
+        let cli = pairs(&[("serial.cfg_baud", "3")]);
+        let settings = Settings::load_with_env(&path, env, &cli).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(settings.serial.cfg_baud, 3);
+        assert_eq!(settings.serial.data_baud, 2);
+        assert_eq!(settings.serial.cfg_port, "file");
+    }
+
+    #[test]
+    fn only_sectioned_environment_variables_are_overrides() {
+        let overrides = env_overrides(pairs(&[
+            ("IWR68XX_MQTT__HOST", "broker"),
+            ("IWR68XX_LOG", "debug"),
+            ("HOME", "/root"),
+            ("IWR68XX_SERIAL__CFG_PORT", "/dev/ttyACM0"),
+        ]));
+        assert_eq!(
+            overrides,
+            pairs(&[("mqtt.host", "broker"), ("serial.cfg_port", "/dev/ttyACM0")])
+        );
+    }
 }
@@ -0,0 +1,367 @@
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use super::queue::QueueSender;
+use super::recording::read_capture_frames;
+use super::tlv_translator::{frame_time, CPU_CLOCK_HZ};
+
+/// Frame intervals beyond this are not trusted, e.g. the first frame
+/// after the sensor was restarted during a capture.
+const MAX_FRAME_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The range of the speed multiplier, apart from `0`, slower or faster
+/// replays are not of any use and would overflow the frame intervals.
+const SPEED_RANGE: (f64, f64) = (0.001, 1000.0);
+
+/// Commands to control a running `FileSource`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlaybackCommand {
+    Pause,
+    Resume,
+    /// Continue from the frame with this index in the file
+    Seek(usize),
+    /// Continue from the frame at this time after the first frame
+    SeekTime(Duration),
+    /// Change the speed multiplier, `0` sends the frames as fast as
+    /// they can be processed
+    SetSpeed(f64),
+    SetLoop(bool),
+    /// Stop playback, `run` returns
+    Stop,
+}
+
+/// A handle through which the playback of a `FileSource` can be
+/// controlled from another thread.
+#[derive(Clone)]
+pub struct PlaybackHandle {
+    commands: mpsc::Sender<PlaybackCommand>,
+}
+
+impl PlaybackHandle {
+    /// Sends a command, returns false if the source has already stopped.
+    pub fn send(&self, command: PlaybackCommand) -> bool {
+        self.commands.send(command).is_ok()
+    }
+}
+
+/// Replays a raw capture, such as `./tlv_example_file.dat`, as if the
+/// bytes came from the FMCW. It takes the place of `Fmcw::run` in
+/// front of `parse_stream`.
+///
+/// The frames are sent with the intervals recorded in their headers,
+/// divided by the speed multiplier. When the time in a header can not
+/// be used, the `fallback_period` is used instead.
+pub struct FileSource {
+    frames: Vec<Vec<u8>>,
+    /// The time each frame should be sent at after the first one, at
+    /// a speed of 1
+    offsets: Vec<Duration>,
+    fallback_period: Duration,
+    speed: f64,
+    looping: bool,
+    commands: mpsc::Receiver<PlaybackCommand>,
+    handle: PlaybackHandle,
+}
+
+impl FileSource {
+    /// Reads the recording or raw capture at `path` and splits it into
+    /// frames.
+    pub fn open(path: &Path, fallback_period: Duration) -> Result<FileSource, Error> {
+        FileSource::from_frames(read_capture_frames(path)?, fallback_period)
+            .map_err(|e| Error::new(e.kind(), format!("\"{}\" {}", path.display(), e)))
+    }
+
+    /// Creates a source from frames that were already split, of which
+    /// there has to be at least one.
+    pub fn from_frames(
+        frames: Vec<Vec<u8>>,
+        fallback_period: Duration,
+    ) -> Result<FileSource, Error> {
+        if frames.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "does not contain any frames",
+            ));
+        }
+        let offsets = frame_offsets(&frames, fallback_period);
+        let (tx, commands) = mpsc::channel();
+        Ok(FileSource {
+            frames,
+            offsets,
+            fallback_period,
+            speed: 1.0,
+            looping: false,
+            commands,
+            handle: PlaybackHandle { commands: tx },
+        })
+    }
+
+    /// Sets the speed multiplier, which is kept within `SPEED_RANGE`,
+    /// `0` or less sends the frames as fast as they can be processed.
+    pub fn with_speed(mut self, speed: f64) -> FileSource {
+        self.speed = clamp_speed(speed);
+        self
+    }
+
+    pub fn with_loop(mut self, looping: bool) -> FileSource {
+        self.looping = looping;
+        self
+    }
+
+    pub fn handle(&self) -> PlaybackHandle {
+        self.handle.clone()
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Sends the frames to `tx`, pacing them according to their time,
+    /// until the end of the file (or forever when looping), a `Stop`
+    /// command or until `tx` is disconnected.
+    ///
+    /// It should be called as a new thread.
+    pub fn run(mut self, tx: QueueSender<Vec<u8>>) {
+        let mut index: usize = 0;
+        let mut paused = false;
+        // The moment the frame at `index` is due
+        let mut due = Instant::now();
+
+        loop {
+            if index >= self.frames.len() {
+                if !self.looping {
+                    println!("Finished replaying the file");
+                    return;
+                }
+                index = 0;
+            }
+
+            // Wait until the frame is due, while handling commands
+            let timeout = if paused {
+                Duration::from_millis(100)
+            } else {
+                due.saturating_duration_since(Instant::now())
+            };
+            match self.commands.recv_timeout(timeout) {
+                Ok(command) => {
+                    match command {
+                        PlaybackCommand::Pause => paused = true,
+                        PlaybackCommand::Resume => paused = false,
+                        PlaybackCommand::Seek(frame) => index = frame.min(self.frames.len() - 1),
+                        PlaybackCommand::SeekTime(time) => {
+                            index = self
+                                .offsets
+                                .partition_point(|offset| *offset < time)
+                                .min(self.frames.len() - 1)
+                        }
+                        PlaybackCommand::SetSpeed(speed) => self.speed = clamp_speed(speed),
+                        PlaybackCommand::SetLoop(looping) => self.looping = looping,
+                        PlaybackCommand::Stop => return,
+                    }
+                    println!("Playback: {:?}, at frame {}", command, index);
+                    // Send the frame we are at right away
+                    due = Instant::now();
+                    continue;
+                }
+                Err(RecvTimeoutError::Timeout) if paused => continue,
+                Err(RecvTimeoutError::Timeout) => {}
+                // Only the handle in `self` is left, which can not send
+                Err(RecvTimeoutError::Disconnected) => {}
+            }
+
+            if tx.send(self.frames[index].clone()).is_err() {
+                return;
+            }
+
+            let next = index + 1;
+            let interval = match self.offsets.get(next) {
+                Some(offset) => offset.saturating_sub(self.offsets[index]),
+                // From the last frame back to the first one when looping
+                None => self.fallback_period,
+            };
+            due = match self.speed {
+                s if s > 0.0 => due + interval.div_f64(s),
+                _ => Instant::now(),
+            };
+            index = next;
+        }
+    }
+}
+
+fn clamp_speed(speed: f64) -> f64 {
+    match speed {
+        s if s.is_nan() || s <= 0.0 => 0.0,
+        s => s.clamp(SPEED_RANGE.0, SPEED_RANGE.1),
+    }
+}
+
+/// Turns the CPU cycle counters in the headers into the offset of each
+/// frame from the first, taking the wrap around of the 32 bit counter
+/// into account.
+fn frame_offsets(frames: &[Vec<u8>], fallback_period: Duration) -> Vec<Duration> {
+    let mut offsets: Vec<Duration> = Vec::with_capacity(frames.len());
+    let mut offset = Duration::ZERO;
+    let mut previous: Option<u32> = None;
+    for frame in frames {
+        let time = frame_time(frame);
+        if let Some(prev) = previous {
+            let interval = match time {
+                Some(t) => Duration::from_secs_f64(t.wrapping_sub(prev) as f64 / CPU_CLOCK_HZ),
+                None => fallback_period,
+            };
+            offset += match interval {
+                i if i.is_zero() || i > MAX_FRAME_INTERVAL => fallback_period,
+                i => i,
+            };
+        }
+        previous = time.or(previous);
+        offsets.push(offset);
+    }
+    offsets
+}
+
+/// Reads playback commands from stdin, one per line, for controlling a
+/// replay from the terminal: `pause`, `resume`, `seek <frame>`,
+/// `seek <seconds>s`, `speed <multiplier>` and `loop <on|off>`.
+///
+/// It should be called as a new thread, and returns when the source
+/// has stopped or stdin is closed.
+pub fn control_from_stdin(handle: PlaybackHandle) {
+    for line in std::io::stdin().lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        let command = match parse_playback_command(&line) {
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+        if !handle.send(command) {
+            return;
+        }
+    }
+}
+
+/// Parses a line of `control_from_stdin`, `None` for an empty line.
+fn parse_playback_command(line: &str) -> Result<Option<PlaybackCommand>, String> {
+    let mut parts = line.split_whitespace();
+    let command = match (parts.next(), parts.next()) {
+        (Some("pause"), _) => PlaybackCommand::Pause,
+        (Some("resume"), _) => PlaybackCommand::Resume,
+        (Some("stop"), _) => PlaybackCommand::Stop,
+        (Some("seek"), Some(n)) => match n.strip_suffix('s') {
+            // Rejects negative, infinite and NaN times
+            Some(secs) => match secs.parse().map(Duration::try_from_secs_f64) {
+                Ok(Ok(time)) => PlaybackCommand::SeekTime(time),
+                _ => return Err(format!("Can not seek to {}, give a time of 0s or more", n)),
+            },
+            None => match n.parse() {
+                Ok(n) => PlaybackCommand::Seek(n),
+                Err(_) => return Err(format!("Can not seek to {}, give a frame of 0 or more", n)),
+            },
+        },
+        (Some("speed"), Some(s)) => match s.parse::<f64>() {
+            Ok(s) if s.is_finite() && s >= 0.0 => PlaybackCommand::SetSpeed(s),
+            _ => return Err(format!("The speed {} should be a multiplier of 0 or more", s)),
+        },
+        (Some("loop"), Some(l)) => PlaybackCommand::SetLoop(l == "on"),
+        (None, _) => return Ok(None),
+        _ => return Err("Unknown playback command, use pause, resume, stop, seek <frame>, seek <seconds>s, speed <multiplier> or loop <on|off>".to_string()),
+    };
+    Ok(Some(command))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::{bounded, DropPolicy};
+    use crate::tlv_encoder::encode_frame;
+    use crate::tlv_translator::Frame;
+
+    fn frames(count: usize) -> Vec<Vec<u8>> {
+        (0..count).map(|i| encode_frame(&Frame::empty(i))).collect()
+    }
+
+    #[test]
+    fn an_empty_source_is_an_error() {
+        assert!(FileSource::from_frames(vec![], Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn replays_every_frame_in_order() {
+        let frames = frames(5);
+        let source = FileSource::from_frames(frames.clone(), Duration::from_millis(100))
+            .unwrap()
+            .with_speed(0.0);
+        let (tx, rx) = bounded("raw", 16, DropPolicy::Block);
+        source.run(tx);
+        assert_eq!(rx.iter().collect::<Vec<_>>(), frames);
+    }
+
+    #[test]
+    fn seeks_past_the_end_to_the_last_frame() {
+        let frames = frames(3);
+        let source = FileSource::from_frames(frames.clone(), Duration::from_millis(100))
+            .unwrap()
+            .with_speed(0.0);
+        source.handle().send(PlaybackCommand::Seek(10));
+        let (tx, rx) = bounded("raw", 16, DropPolicy::Block);
+        source.run(tx);
+        assert_eq!(rx.iter().collect::<Vec<_>>(), frames[2..]);
+    }
+
+    #[test]
+    fn invalid_playback_input_is_rejected() {
+        assert_eq!(
+            parse_playback_command("seek 1.5s"),
+            Ok(Some(PlaybackCommand::SeekTime(Duration::from_millis(1500))))
+        );
+        assert_eq!(
+            parse_playback_command("seek 3"),
+            Ok(Some(PlaybackCommand::Seek(3)))
+        );
+        assert_eq!(parse_playback_command(" "), Ok(None));
+        for line in [
+            "seek -1s",
+            "seek nans",
+            "seek infs",
+            "seek 1e30s",
+            "seek -1",
+            "speed -2",
+            "speed nan",
+            "speed inf",
+            "rewind",
+        ] {
+            assert!(parse_playback_command(line).is_err(), "{line} was accepted");
+        }
+    }
+
+    #[test]
+    fn the_speed_is_kept_in_range() {
+        let source = |speed| {
+            FileSource::from_frames(frames(2), Duration::from_millis(100))
+                .unwrap()
+                .with_speed(speed)
+        };
+        assert_eq!(source(f64::MIN_POSITIVE).speed, SPEED_RANGE.0);
+        assert_eq!(source(f64::INFINITY).speed, SPEED_RANGE.1);
+        assert_eq!(source(f64::NAN).speed, 0.0);
+        assert_eq!(source(-1.0).speed, 0.0);
+        assert_eq!(source(2.0).speed, 2.0);
+
+        // A tiny speed set while running must not overflow the interval
+        let source = source(0.0);
+        let handle = source.handle();
+        handle.send(PlaybackCommand::SetSpeed(f64::MIN_POSITIVE));
+        let (tx, rx) = bounded("raw", 16, DropPolicy::Block);
+        let replay = std::thread::spawn(move || source.run(tx));
+        assert!(rx.recv().is_ok());
+        handle.send(PlaybackCommand::Stop);
+        replay.join().unwrap();
+    }
+}
@@ -1,110 +1,425 @@
+use serde::{Deserialize, Serialize};
 use serial2::SerialPort;
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 use std::sync::{mpsc, Arc};
+use std::time::Instant;
 use std::{thread, time::Duration};
+use tokio::sync::watch;
 
+use super::device_discovery::resolve_ports;
 use super::file_reader::{Config, Settings};
+use super::queue::QueueSender;
+
+/// How long `FmcwHandle::request` waits for a command to be carried
+/// out, sending a whole configuration can take a few seconds.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The state of the connection with the FMCW, published by `Fmcw::run`
+/// whenever it changes.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    /// Both serial ports are open
+    Connected,
+    /// The configuration is being sent to the FMCW
+    Configuring,
+    /// The FMCW has been configured and data is being received
+    Streaming,
+    /// The connection was lost, the ports are being reopened
+    Lost,
+    /// The sensor was stopped on request, no data is expected
+    Stopped,
+}
+
+/// Commands to control a running `Fmcw` from another thread.
+#[derive(Clone)]
+pub enum FmcwCommand {
+    /// Restart the sensor with the configuration it already has
+    Start,
+    Stop,
+    /// Send a single CLI command, e.g. `guiMonitor -1 1 1 0 0 0 1`
+    Cli(String),
+    /// Send a new configuration, which is also used when reconnecting
+    SwitchConfig(Config),
+}
+
+/// A handle through which a running `Fmcw` can be controlled from
+/// another thread.
+#[derive(Clone)]
+pub struct FmcwHandle {
+    commands: mpsc::Sender<(FmcwCommand, mpsc::Sender<Result<String, Error>>)>,
+}
+
+impl FmcwHandle {
+    /// Sends a command and waits until it has been carried out,
+    /// returning the response of the FMCW. Gives up after
+    /// `REQUEST_TIMEOUT`.
+    pub fn request(&self, command: FmcwCommand) -> Result<String, Error> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        let stopped = || Error::new(ErrorKind::NotConnected, "the FMCW is not running");
+        self.commands
+            .send((command, reply_tx))
+            .map_err(|_| stopped())?;
+        match reply_rx.recv_timeout(REQUEST_TIMEOUT) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(Error::new(
+                ErrorKind::TimedOut,
+                "the FMCW did not carry out the command in time",
+            )),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(stopped()),
+        }
+    }
+}
 
-#[allow(dead_code)]
 pub struct Fmcw {
     cfg: SerialPort,
     data: SerialPort,
     config: Config,
+    settings: Arc<Settings>,
+    state: watch::Sender<ConnectionState>,
+    commands: mpsc::Receiver<(FmcwCommand, mpsc::Sender<Result<String, Error>>)>,
+    handle: FmcwHandle,
+    /// Set while the sensor is stopped on request
+    stopped: bool,
 }
 
 impl Fmcw {
     /// create a new FMCW object, based off of a settings struct
+    ///
+    /// Either port can be set to `"auto"`, in which case the port of
+    /// a connected IWR68xx board is looked up.
     pub fn new(settings: Arc<Settings>, config: Config) -> Result<Fmcw, Error> {
-        let cfg = match SerialPort::open(&settings.cfg_port, settings.cfg_baud) {
-            Ok(v) => v,
-            Err(v) => return Err(v),
-        };
-        let data = match SerialPort::open(&settings.data_port, settings.data_baud) {
-            Ok(v) => v,
-            Err(v) => return Err(v),
-        };
-        return Ok(Fmcw { cfg, data, config });
+        let (cfg, data) = Fmcw::open_ports(&settings)?;
+        let (state, _) = watch::channel(ConnectionState::Connected);
+        let (commands_tx, commands) = mpsc::channel();
+        Ok(Fmcw {
+            cfg,
+            data,
+            config,
+            settings,
+            state,
+            commands,
+            handle: FmcwHandle {
+                commands: commands_tx,
+            },
+            stopped: false,
+        })
+    }
+
+    fn open_ports(settings: &Settings) -> Result<(SerialPort, SerialPort), Error> {
+        let ports = resolve_ports(&settings.serial.cfg_port, &settings.serial.data_port)?;
+        let cfg = SerialPort::open(&ports.cfg_port, settings.serial.cfg_baud)?;
+        let data = SerialPort::open(&ports.data_port, settings.serial.data_baud)?;
+        Ok((cfg, data))
+    }
+
+    /// Returns a receiver through which the connection state can be
+    /// observed, from any thread.
+    pub fn subscribe_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    /// Returns a handle through which the FMCW can be controlled while
+    /// it runs.
+    pub fn handle(&self) -> FmcwHandle {
+        self.handle.clone()
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        println!("FMCW connection state: {:?}", state);
+        self.state.send_replace(state);
     }
 
     /// Collects data from the FMCW hardware and continuously
     /// publishes this to the provided channel `tx`.
     ///
-    /// **NOTE** This function never returns. It should be
-    /// called as a new thread.
-    pub fn run(&self, tx: mpsc::Sender<Vec<u8>>) -> ! {
-        self.send_config();
+    /// When the data port fails, or no data arrives for
+    /// `serial.stall_timeout_ms`, the connection is considered lost.
+    /// Both ports are then reopened with an exponential backoff and the
+    /// configuration is sent again, after which streaming resumes.
+    ///
+    /// Commands sent through a `FmcwHandle` are carried out in between
+    /// reading the data.
+    ///
+    /// Returns once the receiving side of `tx` has been dropped. It
+    /// should be called as a new thread.
+    pub fn run(&mut self, tx: QueueSender<Vec<u8>>) {
+        if let Err(e) = self.send_config() {
+            eprintln!("{}", e);
+        }
+        self.set_state(ConnectionState::Streaming);
+
+        let stall_timeout = Duration::from_millis(self.settings.serial.stall_timeout_ms);
+        let mut last_data = Instant::now();
 
         // Continuously receive data
         loop {
+            if self.handle_commands() {
+                last_data = Instant::now();
+            }
             let bytes = match self.receive_bytes() {
                 Ok(v) => v,
                 Err(e) => {
-                    eprintln!("{}", e);
+                    eprintln!("Reading from the data port failed: {}", e);
+                    self.reconnect();
+                    last_data = Instant::now();
                     continue;
                 }
             };
-            if bytes.len() == 0 {
-                println!("received no length");
-                // If there was no data to be read, wait ⅒   of a second
-                thread::sleep(Duration::from_millis(100));
+            if bytes.is_empty() {
+                if !self.stopped && last_data.elapsed() > stall_timeout {
+                    eprintln!(
+                        "No data received for {} ms, re-arming the FMCW",
+                        stall_timeout.as_millis()
+                    );
+                    self.reconnect();
+                    last_data = Instant::now();
+                }
                 continue;
             }
+            last_data = Instant::now();
+
+            if tx.send(bytes).is_err() {
+                println!("Nothing receives the data of the FMCW anymore, stopped reading");
+                return;
+            }
+        }
+    }
 
-            // Try to transmit and print the error if there is any.
-            if let Err(e) = tx.send(bytes) {
-                println!("Error sending");
-                eprint!("!!Channel sending caused an error: {}!!", e);
-            } else {
-                println!("succesfully send bytes");
+    /// Carries out the commands received through the handles, returns
+    /// whether there were any.
+    fn handle_commands(&mut self) -> bool {
+        let mut handled = false;
+        while let Ok((command, reply)) = self.commands.try_recv() {
+            handled = true;
+            let result = match command {
+                FmcwCommand::Start => self.send_expecting_done("sensorStart 0").inspect(|_| {
+                    self.stopped = false;
+                    self.set_state(ConnectionState::Streaming);
+                }),
+                FmcwCommand::Stop => self.send_expecting_done("sensorStop").inspect(|_| {
+                    self.stopped = true;
+                    self.set_state(ConnectionState::Stopped);
+                }),
+                FmcwCommand::Cli(line) => self.send_cli_command(&line).inspect(|response| {
+                    // Keep track of the sensor being started or stopped by hand
+                    if !response.contains("Done") {
+                        return;
+                    }
+                    match line.split_whitespace().next() {
+                        Some("sensorStart") => {
+                            self.stopped = false;
+                            self.set_state(ConnectionState::Streaming);
+                        }
+                        Some("sensorStop") => {
+                            self.stopped = true;
+                            self.set_state(ConnectionState::Stopped);
+                        }
+                        _ => {}
+                    }
+                }),
+                FmcwCommand::SwitchConfig(config) => {
+                    self.config = config;
+                    let result = self.send_config().map(|_| String::new());
+                    self.stopped = false;
+                    self.set_state(ConnectionState::Streaming);
+                    result
+                }
             };
+            _ = reply.send(result);
+        }
+        handled
+    }
+
+    /// Answers the commands received while the connection is lost. A
+    /// new configuration and starting or stopping the sensor take effect
+    /// once reconnected, CLI commands can not be sent.
+    fn defer_command(&mut self, command: FmcwCommand) -> Result<String, Error> {
+        match command {
+            FmcwCommand::Start => self.stopped = false,
+            FmcwCommand::Stop => self.stopped = true,
+            FmcwCommand::SwitchConfig(config) => self.config = config,
+            FmcwCommand::Cli(_) => {
+                return Err(Error::new(
+                    ErrorKind::NotConnected,
+                    "the connection with the FMCW is lost, reconnecting",
+                ))
+            }
         }
+        Ok("The connection with the FMCW is lost, this takes effect once reconnected".to_string())
     }
 
-    pub fn send_config(&self) {
+    /// Reopens both serial ports, retrying with an exponential backoff
+    /// until it succeeds, and re-sends the configuration. Commands are
+    /// answered in the meantime, see `defer_command`.
+    ///
+    /// A sensor that was stopped on request is stopped again after the
+    /// configuration, which ends with `sensorStart`, has been sent.
+    fn reconnect(&mut self) {
+        self.set_state(ConnectionState::Lost);
+        let max_backoff = Duration::from_millis(self.settings.serial.reconnect_max_backoff_ms);
+        let mut backoff = Duration::from_millis(self.settings.serial.reconnect_backoff_ms);
+        loop {
+            let retry_at = Instant::now() + backoff;
+            while let Some(wait) = retry_at.checked_duration_since(Instant::now()) {
+                // `self` holds a handle, so the commands never disconnect
+                if let Ok((command, reply)) = self.commands.recv_timeout(wait) {
+                    _ = reply.send(self.defer_command(command));
+                }
+            }
+            match Fmcw::open_ports(&self.settings) {
+                Ok((cfg, data)) => {
+                    self.cfg = cfg;
+                    self.data = data;
+                    break;
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Reconnecting failed: {}, retrying in {} ms",
+                        e,
+                        backoff.as_millis()
+                    );
+                    backoff = (backoff * 2).min(max_backoff);
+                }
+            }
+        }
+        self.set_state(ConnectionState::Connected);
+        if let Err(e) = self.send_config() {
+            eprintln!("{}", e);
+        }
+        if self.stopped {
+            match self.send_expecting_done("sensorStop") {
+                Ok(_) => self.set_state(ConnectionState::Stopped),
+                Err(e) => eprintln!("Stopping the sensor again failed: {}", e),
+            }
+        } else {
+            self.set_state(ConnectionState::Streaming);
+        }
+    }
+
+    /// Sends the configuration line by line. All lines are sent, even
+    /// when the FMCW rejects one, after which the rejected lines are
+    /// returned as an error.
+    pub fn send_config(&self) -> Result<(), Error> {
+        self.set_state(ConnectionState::Configuring);
         println!("    Printing config");
+        let mut rejected: Vec<&str> = vec![];
         for line in self.config.raw_input.lines() {
-            let line = format!("{}\n", line);
-            let char_buf = line.as_bytes();
-            let buf_size = char_buf.len();
-            match self.cfg.write(char_buf) {
-                Ok(n) => {
-                    print!("{}", line);
-                    if buf_size != n {
-                        println!("Buffersize and written characters not equal, buffer size is {buf_size} but only {n} characters where written");
-                    }
+            // Comments are not understood by the FMCW, like the TI
+            // visualizer we leave them out.
+            if line.trim().is_empty() || line.trim_start().starts_with('%') {
+                continue;
+            }
+            println!("{}", line);
+            match self.send_cli_command(line) {
+                Ok(response) if !response.contains("Done") => {
+                    eprintln!("    FMCW responded with: {}", response.trim());
+                    rejected.push(line.trim());
                 }
-                Err(_) => {
-                    println!("Line write was unsuccesful")
+                Ok(_) => {}
+                Err(e) => {
+                    return Err(Error::new(
+                        e.kind(),
+                        format!("Sending \"{}\" to the FMCW failed: {}", line.trim(), e),
+                    ))
                 }
-            };
+            }
             // Let some time elapse before continuing
             thread::sleep(Duration::from_millis(10));
         }
         println!("\nFinished sending Config to the FMCW\n");
+        if rejected.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::other(format!(
+                "The FMCW rejected these lines of the configuration: {}",
+                rejected.join(", ")
+            )))
+        }
+    }
+
+    /// Sends a single CLI command over the cfg port, returning the
+    /// response of the FMCW.
+    pub fn send_cli_command(&self, command: &str) -> Result<String, Error> {
+        let line = format!("{}\n", command.trim_end());
+        let char_buf = line.as_bytes();
+        let buf_size = char_buf.len();
+        let n = self.cfg.write(char_buf)?;
+        if buf_size != n {
+            println!("Buffersize and written characters not equal, buffer size is {buf_size} but only {n} characters where written");
+        }
+        Ok(self.read_cli_response())
+    }
+
+    // Sends a CLI command, turning any response other than `Done` into
+    // an error.
+    fn send_expecting_done(&self, command: &str) -> Result<String, Error> {
+        let response = self.send_cli_command(command)?;
+        if response.contains("Done") {
+            Ok(response)
+        } else {
+            Err(Error::other(format!(
+                "the FMCW responded with: {}",
+                response.trim()
+            )))
+        }
+    }
+
+    // Reads the response of the FMCW to a single CLI command, the
+    // firmware echoes the command and ends with either `Done` or an
+    // error message. Gives up after a second, returning whatever was
+    // read by then.
+    fn read_cli_response(&self) -> String {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut buf: [u8; 256] = [0; 256];
+        let mut response = String::new();
+        while Instant::now() < deadline {
+            match self.cfg.read(&mut buf) {
+                Ok(n) if n > 0 => response.push_str(&String::from_utf8_lossy(&buf[..n])),
+                Ok(_) => break,
+                Err(e) if e.kind() == ErrorKind::TimedOut => {}
+                Err(_) => break,
+            }
+            if ["Done", "Error", "not recognized"]
+                .iter()
+                .any(|s| response.contains(s))
+            {
+                break;
+            }
+        }
+        response
     }
 
     // Tries to read bytes from the FMCW, passing through any IO
     // errors encountered from reading the serial port.
     //
     // The data can be an empty vector if no data was received
-    // during the call
+    // during the call. A read of zero bytes means the port was
+    // hung up (e.g. the USB cable was pulled) and is returned as
+    // an error.
     pub fn receive_bytes(&self) -> Result<Vec<u8>, Error> {
         let mut buf: [u8; 1024] = [0; 1024];
         let mut result: Vec<u8> = vec![];
         loop {
-            let read_bytes: usize = self.data.read(&mut buf)?;
-            if read_bytes == 0 {
-                println!("received bytes where of size 0");
-                break;
-            }
-            // println!("received {} bytes", read_bytes);
+            let read_bytes: usize = match self.data.read(&mut buf) {
+                Ok(0) => {
+                    return Err(Error::new(
+                        ErrorKind::BrokenPipe,
+                        "the data port was hung up",
+                    ))
+                }
+                Ok(n) => n,
+                // The read timed out, return what we have so far
+                Err(e) if e.kind() == ErrorKind::TimedOut => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
             // Copy all read bytes into the result vec
-            result.append(&mut buf[0..read_bytes].to_vec());
+            result.extend_from_slice(&buf[0..read_bytes]);
             if result.len() > 2048 {
                 break;
             }
         }
-        println!("returning {} bytes", result.len());
         Ok(result)
     }
 }
@@ -0,0 +1,304 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::{json, Value};
+
+use super::file_reader::RadarParameters;
+use super::heatmap::{range_axis, range_azimuth, range_doppler, Heatmap};
+use super::tlv_translator::Frame;
+
+/// The frame id of every message, the coordinates of the sensor.
+pub const FRAME_ID: &str = "iwr6843";
+
+pub const POINTCLOUD_TOPIC: &str = "/iwr6843/pointcloud";
+pub const RANGE_PROFILE_TOPIC: &str = "/iwr6843/range_profile";
+pub const RANGE_DOPPLER_TOPIC: &str = "/iwr6843/range_doppler";
+pub const RANGE_AZIMUTH_TOPIC: &str = "/iwr6843/range_azimuth";
+pub const TELEMETRY_TOPIC: &str = "/iwr6843/telemetry";
+
+/// The `FLOAT32` of the `foxglove.NumericType` enum.
+const FOXGLOVE_FLOAT32: u32 = 7;
+
+/// The fields of every point in a `foxglove.PointCloud`, all `f32`.
+const POINT_FIELDS: [&str; 6] = ["x", "y", "z", "doppler", "snr", "noise"];
+
+/// A topic frames are published on, with the JSON schema of its
+/// messages.
+pub struct Channel {
+    pub topic: &'static str,
+    pub schema_name: &'static str,
+    schema: fn() -> Value,
+}
+
+impl Channel {
+    pub fn schema(&self) -> Value {
+        (self.schema)()
+    }
+}
+
+/// Every topic `frame_messages` publishes on.
+pub const CHANNELS: [Channel; 5] = [
+    Channel {
+        topic: POINTCLOUD_TOPIC,
+        schema_name: "foxglove.PointCloud",
+        schema: pointcloud_schema,
+    },
+    Channel {
+        topic: RANGE_PROFILE_TOPIC,
+        schema_name: "iwr68xx.RangeProfile",
+        schema: range_profile_schema,
+    },
+    Channel {
+        topic: RANGE_DOPPLER_TOPIC,
+        schema_name: "foxglove.RawImage",
+        schema: raw_image_schema,
+    },
+    Channel {
+        topic: RANGE_AZIMUTH_TOPIC,
+        schema_name: "foxglove.RawImage",
+        schema: raw_image_schema,
+    },
+    Channel {
+        topic: TELEMETRY_TOPIC,
+        schema_name: "iwr68xx.Telemetry",
+        schema: telemetry_schema,
+    },
+];
+
+/// The JSON messages of a frame, with their topic, for every topic the
+/// frame has data for.
+///
+/// The point cloud is a `foxglove.PointCloud`, the heatmaps are
+/// `foxglove.RawImage`s with 8 bit grayscale pixels over the fixed
+/// `MONO8_DB_RANGE`, a row for every range bin. The range profile holds
+/// the range and dB of every bin as two arrays, which Foxglove can plot
+/// against each other, and the telemetry holds the statistics and
+/// temperatures.
+///
+/// Without `params` the ranges are bin numbers and the heatmaps are
+/// left out, as they cannot be interpreted.
+pub fn frame_messages(
+    frame: &Frame,
+    params: Option<&RadarParameters>,
+    time_ns: u64,
+) -> Vec<(&'static str, Value)> {
+    let timestamp = json!({
+        "sec": time_ns / 1_000_000_000,
+        "nsec": time_ns % 1_000_000_000,
+    });
+    let mut messages = vec![];
+
+    if let Some(message) = pointcloud_message(frame, &timestamp) {
+        messages.push((POINTCLOUD_TOPIC, message));
+    }
+    if let Some(ref profile) = frame.range_profile {
+        let ranges: Vec<f64> = match params {
+            Some(p) => range_axis(p).into_iter().take(profile.len()).collect(),
+            None => (0..profile.len()).map(|i| i as f64).collect(),
+        };
+        let message = json!({
+            "timestamp": timestamp,
+            "frame": frame.frame_num,
+            "range_m": ranges,
+            "db": profile,
+            "noise_db": frame.noise_profile,
+        });
+        messages.push((RANGE_PROFILE_TOPIC, message));
+    }
+    if let Some(params) = params {
+        if let Some(heatmap) = range_doppler(frame, params) {
+            messages.push((RANGE_DOPPLER_TOPIC, raw_image_message(&heatmap, &timestamp)));
+        }
+        if let Some(heatmap) = range_azimuth(frame, params) {
+            messages.push((RANGE_AZIMUTH_TOPIC, raw_image_message(&heatmap, &timestamp)));
+        }
+    }
+    let telemetry = json!({
+        "timestamp": timestamp,
+        "frame": frame.frame_num,
+        "time_cpu_cycles": frame.time_cpu_cycles,
+        "num_points": frame.pointcloud.as_ref().map_or(0, |pc| pc.len()),
+        "stats": frame.stats,
+        "temperature": frame.temperature,
+    });
+    messages.push((TELEMETRY_TOPIC, telemetry));
+    messages
+}
+
+fn pointcloud_message(frame: &Frame, timestamp: &Value) -> Option<Value> {
+    let pointcloud = frame.pointcloud.as_ref()?;
+    let data: Vec<u8> = pointcloud
+        .iter()
+        .enumerate()
+        .flat_map(|(i, p)| {
+            let side_info = frame.side_info.as_ref().and_then(|s| s.get(i));
+            [
+                p.x,
+                p.y,
+                p.z,
+                p.d,
+                side_info.map_or(f32::NAN, |s| s.snr as f32 * 0.1),
+                side_info.map_or(f32::NAN, |s| s.noise as f32 * 0.1),
+            ]
+        })
+        .flat_map(|v| v.to_le_bytes())
+        .collect();
+    let fields: Vec<Value> = POINT_FIELDS
+        .iter()
+        .enumerate()
+        .map(|(i, name)| json!({ "name": name, "offset": i * 4, "type": FOXGLOVE_FLOAT32 }))
+        .collect();
+    Some(json!({
+        "timestamp": timestamp,
+        "frame_id": FRAME_ID,
+        "pose": {
+            "position": { "x": 0.0, "y": 0.0, "z": 0.0 },
+            "orientation": { "x": 0.0, "y": 0.0, "z": 0.0, "w": 1.0 },
+        },
+        "point_stride": POINT_FIELDS.len() * 4,
+        "fields": fields,
+        "data": BASE64.encode(data),
+    }))
+}
+
+fn raw_image_message(heatmap: &Heatmap, timestamp: &Value) -> Value {
+    json!({
+        "timestamp": timestamp,
+        "frame_id": FRAME_ID,
+        "width": heatmap.cols,
+        "height": heatmap.rows,
+        "encoding": "mono8",
+        "step": heatmap.cols,
+        "data": BASE64.encode(heatmap.to_mono8()),
+    })
+}
+
+fn time_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "sec": { "type": "integer", "minimum": 0 },
+            "nsec": { "type": "integer", "minimum": 0, "maximum": 999_999_999 },
+        },
+    })
+}
+
+fn vector3_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "x": { "type": "number" },
+            "y": { "type": "number" },
+            "z": { "type": "number" },
+        },
+    })
+}
+
+/// The JSON schema of `foxglove.PointCloud`.
+fn pointcloud_schema() -> Value {
+    json!({
+        "title": "foxglove.PointCloud",
+        "type": "object",
+        "properties": {
+            "timestamp": time_schema(),
+            "frame_id": { "type": "string" },
+            "pose": {
+                "type": "object",
+                "properties": {
+                    "position": vector3_schema(),
+                    "orientation": {
+                        "type": "object",
+                        "properties": {
+                            "x": { "type": "number" },
+                            "y": { "type": "number" },
+                            "z": { "type": "number" },
+                            "w": { "type": "number" },
+                        },
+                    },
+                },
+            },
+            "point_stride": { "type": "integer", "minimum": 0 },
+            "fields": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "name": { "type": "string" },
+                        "offset": { "type": "integer", "minimum": 0 },
+                        "type": { "type": "integer" },
+                    },
+                },
+            },
+            "data": { "type": "string", "contentEncoding": "base64" },
+        },
+    })
+}
+
+/// The JSON schema of `foxglove.RawImage`.
+fn raw_image_schema() -> Value {
+    json!({
+        "title": "foxglove.RawImage",
+        "type": "object",
+        "properties": {
+            "timestamp": time_schema(),
+            "frame_id": { "type": "string" },
+            "width": { "type": "integer", "minimum": 0 },
+            "height": { "type": "integer", "minimum": 0 },
+            "encoding": { "type": "string" },
+            "step": { "type": "integer", "minimum": 0 },
+            "data": { "type": "string", "contentEncoding": "base64" },
+        },
+    })
+}
+
+fn range_profile_schema() -> Value {
+    json!({
+        "title": "iwr68xx.RangeProfile",
+        "type": "object",
+        "properties": {
+            "timestamp": time_schema(),
+            "frame": { "type": "integer" },
+            "range_m": { "type": "array", "items": { "type": "number" } },
+            "db": { "type": "array", "items": { "type": "number" } },
+            "noise_db": { "type": ["array", "null"], "items": { "type": "number" } },
+        },
+    })
+}
+
+fn telemetry_schema() -> Value {
+    let u32_object = |fields: &[&str]| {
+        let properties: serde_json::Map<String, Value> = fields
+            .iter()
+            .map(|f| (f.to_string(), json!({ "type": "integer" })))
+            .collect();
+        json!({ "type": ["object", "null"], "properties": properties })
+    };
+    json!({
+        "title": "iwr68xx.Telemetry",
+        "type": "object",
+        "properties": {
+            "timestamp": time_schema(),
+            "frame": { "type": "integer" },
+            "time_cpu_cycles": { "type": "integer" },
+            "num_points": { "type": "integer" },
+            "stats": u32_object(&[
+                "inter_frame_processing_time",
+                "transmit_output_time",
+                "inter_frame_processing_margin",
+                "inter_chirp_processing_margin",
+                "active_frame_cpu_load",
+                "inter_frame_cpu_load",
+            ]),
+            "temperature": {
+                "type": ["object", "null"],
+                "properties": {
+                    "report_valid": { "type": "integer" },
+                    "time": { "type": "integer" },
+                    "rx": { "type": "array", "items": { "type": "integer" } },
+                    "tx": { "type": "array", "items": { "type": "integer" } },
+                    "pm": { "type": "integer" },
+                    "digital": { "type": "array", "items": { "type": "integer" } },
+                },
+            },
+        },
+    })
+}
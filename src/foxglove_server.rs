@@ -0,0 +1,349 @@
+use serde::Deserialize;
+use serde_json::json;
+use std::io::Error;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tungstenite::handshake::server::{Callback, ErrorResponse, Request, Response};
+use tungstenite::http::HeaderValue;
+
+use super::file_reader::RadarParameters;
+use super::foxglove::{frame_messages, CHANNELS};
+use super::ipc::{serve_websocket, Outgoing, WebSocketStream};
+use super::tlv_translator::Frame;
+
+/// The WebSocket subprotocol of the Foxglove WebSocket protocol.
+const SUBPROTOCOL: &str = "foxglove.websocket.v1";
+
+/// Starts a binary message carrying a message of a channel.
+const MESSAGE_DATA: u8 = 0x01;
+
+/// The level of a `status` message telling a client its request failed.
+const STATUS_WARNING: u8 = 1;
+
+/// A request of a client. Only the operations needed to receive the
+/// channels are supported, as no capabilities are announced.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase", rename_all_fields = "camelCase")]
+enum ClientOp {
+    Subscribe { subscriptions: Vec<Subscription> },
+    Unsubscribe { subscription_ids: Vec<u32> },
+}
+
+/// A channel a client subscribed to, under an id the client chose.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Subscription {
+    id: u32,
+    channel_id: u32,
+}
+
+struct Client {
+    id: usize,
+    subscriptions: Vec<Subscription>,
+    queue: mpsc::SyncSender<Outgoing>,
+    /// Used to interrupt the thread serving the client when it is
+    /// dropped
+    connection: TcpStream,
+}
+
+/// The clients, and the channels advertised to them so far. Kept under
+/// a single lock, so a client connecting while a channel is advertised
+/// learns about it exactly once.
+struct State {
+    clients: Vec<Client>,
+    /// The ids of the advertised channels, the index in `CHANNELS` plus one
+    advertised: Vec<u32>,
+}
+
+/// The state shared between the `FoxgloveServer` and its accept thread.
+struct Shared {
+    state: Mutex<State>,
+    queue_size: usize,
+    next_id: AtomicUsize,
+    /// The text messages received from the clients, with the id of the
+    /// client
+    requests: Mutex<mpsc::Sender<(usize, String)>>,
+    /// Sent in the `serverInfo`, so clients can tell a restart
+    session_id: String,
+}
+
+/// A server speaking the Foxglove WebSocket protocol, so Foxglove Studio
+/// can open a "Foxglove WebSocket" connection to the tool and show the
+/// frames live.
+///
+/// The channels are the topics of `foxglove::CHANNELS`, all JSON with a
+/// JSON schema, the same messages `McapRecorder` records. A channel is
+/// advertised once the first frame holding its data is published, so
+/// e.g. the heatmaps only show up when the configuration enables them.
+///
+/// Like the `IpcServer`, every client has its own bounded queue and is
+/// disconnected when it falls behind.
+pub struct FoxgloveServer {
+    shared: Arc<Shared>,
+    requests: mpsc::Receiver<(usize, String)>,
+}
+
+impl FoxgloveServer {
+    /// Listens for clients on `address`, such as `0.0.0.0:8765`.
+    pub fn bind(address: &str, queue_size: usize) -> Result<FoxgloveServer, Error> {
+        let listener = TcpListener::bind(address)?;
+        let (requests_tx, requests) = mpsc::channel();
+        let session_id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis()
+            .to_string();
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                clients: vec![],
+                advertised: vec![],
+            }),
+            queue_size: queue_size.max(1),
+            next_id: AtomicUsize::new(0),
+            requests: Mutex::new(requests_tx),
+            session_id,
+        });
+        let accept_shared = shared.clone();
+        thread::spawn(move || accept_clients(listener, accept_shared));
+        Ok(FoxgloveServer { shared, requests })
+    }
+
+    /// The number of connected clients.
+    pub fn client_count(&self) -> usize {
+        self.shared.state.lock().unwrap().clients.len()
+    }
+
+    /// Queues the messages of a frame for the clients subscribed to
+    /// their channels, after advertising the channels no earlier frame
+    /// had data for. Never blocks.
+    ///
+    /// Without `params` the heatmaps are left out, see `frame_messages`.
+    pub fn publish(&self, frame: &Frame, params: Option<&RadarParameters>) {
+        self.handle_requests();
+        let mut state = self.shared.state.lock().unwrap();
+        if state.clients.is_empty() {
+            return;
+        }
+        let time_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let messages: Vec<(u32, String)> = frame_messages(frame, params, time_ns)
+            .into_iter()
+            .filter_map(|(topic, message)| Some((channel_id(topic)?, message.to_string())))
+            .collect();
+
+        let new_channels: Vec<u32> = messages
+            .iter()
+            .map(|(id, _)| *id)
+            .filter(|id| !state.advertised.contains(id))
+            .collect();
+        if !new_channels.is_empty() {
+            state.advertised.extend(&new_channels);
+            let advertise = Outgoing::text(&advertise_message(&new_channels));
+            state
+                .clients
+                .retain(|client| queue(client, advertise.clone()));
+        }
+
+        for (channel, payload) in messages {
+            state.clients.retain(|client| {
+                client
+                    .subscriptions
+                    .iter()
+                    .filter(|s| s.channel_id == channel)
+                    .all(|s| {
+                        let mut message = Vec::with_capacity(payload.len() + 13);
+                        message.push(MESSAGE_DATA);
+                        message.extend_from_slice(&s.id.to_le_bytes());
+                        message.extend_from_slice(&time_ns.to_le_bytes());
+                        message.extend_from_slice(payload.as_bytes());
+                        queue(client, Outgoing::binary(message))
+                    })
+            });
+        }
+    }
+
+    /// Applies the subscriptions and unsubscriptions the clients sent.
+    fn handle_requests(&self) {
+        while let Ok((client_id, request)) = self.requests.try_recv() {
+            let mut state = self.shared.state.lock().unwrap();
+            let State {
+                ref mut clients,
+                ref advertised,
+            } = *state;
+            let Some(client) = clients.iter_mut().find(|c| c.id == client_id) else {
+                continue;
+            };
+            let problem = match serde_json::from_str::<ClientOp>(&request) {
+                Ok(ClientOp::Subscribe { subscriptions }) => {
+                    let unknown: Vec<u32> = subscriptions
+                        .iter()
+                        .map(|s| s.channel_id)
+                        .filter(|id| !advertised.contains(id))
+                        .collect();
+                    client
+                        .subscriptions
+                        .retain(|s| !subscriptions.iter().any(|new| new.id == s.id));
+                    client.subscriptions.extend(
+                        subscriptions
+                            .into_iter()
+                            .filter(|s| advertised.contains(&s.channel_id)),
+                    );
+                    (!unknown.is_empty()).then(|| format!("Unknown channels {:?}", unknown))
+                }
+                Ok(ClientOp::Unsubscribe { subscription_ids }) => {
+                    client
+                        .subscriptions
+                        .retain(|s| !subscription_ids.contains(&s.id));
+                    None
+                }
+                Err(e) => Some(format!("Unsupported request: {}", e)),
+            };
+            if let Some(problem) = problem {
+                let status = json!({ "op": "status", "level": STATUS_WARNING, "message": problem });
+                if !queue(client, Outgoing::text(status.to_string().as_bytes())) {
+                    clients.retain(|c| c.id != client_id);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for FoxgloveServer {
+    fn drop(&mut self) {
+        for client in self.shared.state.lock().unwrap().clients.drain(..) {
+            _ = client.connection.shutdown(Shutdown::Write);
+        }
+    }
+}
+
+/// The id of the channel of a topic.
+fn channel_id(topic: &str) -> Option<u32> {
+    CHANNELS
+        .iter()
+        .position(|c| c.topic == topic)
+        .map(|i| i as u32 + 1)
+}
+
+fn advertise_message(channel_ids: &[u32]) -> Vec<u8> {
+    let channels: Vec<_> = channel_ids
+        .iter()
+        .map(|&id| {
+            let channel = &CHANNELS[id as usize - 1];
+            json!({
+                "id": id,
+                "topic": channel.topic,
+                "encoding": "json",
+                "schemaName": channel.schema_name,
+                "schema": channel.schema().to_string(),
+                "schemaEncoding": "jsonschema",
+            })
+        })
+        .collect();
+    serde_json::to_vec(&json!({ "op": "advertise", "channels": channels })).unwrap()
+}
+
+/// Queues a message for a client, returns false if the client has to be
+/// dropped.
+fn queue(client: &Client, message: Outgoing) -> bool {
+    match client.queue.try_send(message) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            eprintln!(
+                "Foxglove client {} is too slow, disconnecting it",
+                client.id
+            );
+            _ = client.connection.shutdown(Shutdown::Both);
+            false
+        }
+        Err(TrySendError::Disconnected(_)) => {
+            println!("Foxglove client {} disconnected", client.id);
+            false
+        }
+    }
+}
+
+fn accept_clients(listener: TcpListener, shared: Arc<Shared>) {
+    for stream in listener.incoming() {
+        let streams = stream.and_then(|s| {
+            s.set_nodelay(true)?;
+            Ok((s.try_clone()?, WebSocketStream::new(s)?))
+        });
+        let (connection, stream) = match streams {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("Could not accept a Foxglove client: {}", e);
+                continue;
+            }
+        };
+        let shared = shared.clone();
+        // The handshake waits for the client, so it is not done here
+        thread::spawn(move || {
+            let websocket = match tungstenite::accept_hdr(stream, SelectSubprotocol) {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("Foxglove WebSocket handshake failed: {}", e);
+                    return;
+                }
+            };
+            let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+            let (queue, messages) = mpsc::sync_channel(shared.queue_size);
+            {
+                let mut state = shared.state.lock().unwrap();
+                let server_info = json!({
+                    "op": "serverInfo",
+                    "name": concat!("IWR_68xx_rust ", env!("CARGO_PKG_VERSION")),
+                    "capabilities": [],
+                    "supportedEncodings": [],
+                    "metadata": {},
+                    "sessionId": shared.session_id,
+                });
+                _ = queue.try_send(Outgoing::text(server_info.to_string().as_bytes()));
+                if !state.advertised.is_empty() {
+                    _ = queue.try_send(Outgoing::text(&advertise_message(&state.advertised)));
+                }
+                state.clients.push(Client {
+                    id,
+                    subscriptions: vec![],
+                    queue,
+                    connection,
+                });
+            }
+            println!("Foxglove client {} connected", id);
+            let requests = shared.requests.lock().unwrap().clone();
+            serve_websocket(id, websocket, messages, requests);
+        });
+    }
+}
+
+/// Accepts the Foxglove subprotocol during the handshake if the client
+/// offers it, Foxglove Studio refuses a connection without it.
+struct SelectSubprotocol;
+
+impl Callback for SelectSubprotocol {
+    fn on_request(
+        self,
+        request: &Request,
+        mut response: Response,
+    ) -> Result<Response, ErrorResponse> {
+        let offered = request
+            .headers()
+            .get_all("Sec-WebSocket-Protocol")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|p| p.trim() == SUBPROTOCOL);
+        if offered {
+            response.headers_mut().insert(
+                "Sec-WebSocket-Protocol",
+                HeaderValue::from_static(SUBPROTOCOL),
+            );
+        }
+        Ok(response)
+    }
+}
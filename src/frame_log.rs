@@ -0,0 +1,358 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, File};
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use super::pipeline::FrameSink;
+use super::recording::timestamped_path;
+use super::tlv_translator::Frame;
+
+/// The compression of a frame log.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "ndjson",
+            Compression::Gzip => "ndjson.gz",
+            Compression::Zstd => "ndjson.zst",
+        }
+    }
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// A file being written, through the encoder of its compression.
+enum Encoder {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl Encoder {
+    fn create(path: &Path, compression: Compression) -> Result<Encoder, Error> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(match compression {
+            Compression::None => Encoder::Plain(file),
+            Compression::Gzip => {
+                Encoder::Gzip(GzEncoder::new(file, flate2::Compression::default()))
+            }
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(file, 0)?),
+        })
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), Error> {
+        match self {
+            Encoder::Plain(w) => w.write_all(data),
+            Encoder::Gzip(w) => w.write_all(data),
+            Encoder::Zstd(w) => w.write_all(data),
+        }
+    }
+
+    /// Writes the end of the compressed stream and flushes the file.
+    fn finish(self) -> Result<(), Error> {
+        let mut file = match self {
+            Encoder::Plain(w) => w,
+            Encoder::Gzip(w) => w.finish()?,
+            Encoder::Zstd(w) => w.finish()?,
+        };
+        file.flush()
+    }
+}
+
+/// Writes frames as newline delimited JSON, one `Frame` per line, into
+/// timestamped files named `frames_<date>_<time>.ndjson`.
+///
+/// A new file is started once the current one holds `max_bytes` of
+/// (uncompressed) JSON or is `max_duration` old, a limit of zero means
+/// no limit. Compressed files are only complete once they are rotated
+/// or the writer is finished or dropped.
+pub struct FrameLogWriter {
+    directory: PathBuf,
+    compression: Compression,
+    max_bytes: u64,
+    max_duration: Duration,
+    encoder: Option<Encoder>,
+    bytes_written: u64,
+    opened_at: Instant,
+}
+
+impl FrameLogWriter {
+    /// Creates the first file of a frame log in `directory`, creating
+    /// the directory if needed.
+    ///
+    /// # Arguments
+    /// * `max_bytes`: Rotate after this many bytes, 0 for no limit
+    /// * `max_duration`: Rotate after this long, zero for no limit
+    pub fn create(
+        directory: &Path,
+        compression: Compression,
+        max_bytes: u64,
+        max_duration: Duration,
+    ) -> Result<FrameLogWriter, Error> {
+        create_dir_all(directory)?;
+        let mut writer = FrameLogWriter {
+            directory: directory.to_path_buf(),
+            compression,
+            max_bytes,
+            max_duration,
+            encoder: None,
+            bytes_written: 0,
+            opened_at: Instant::now(),
+        };
+        writer.open_next()?;
+        Ok(writer)
+    }
+
+    /// Appends a frame as a single line, rotating first if the current
+    /// file is full.
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<(), Error> {
+        let size_exceeded = self.max_bytes > 0 && self.bytes_written >= self.max_bytes;
+        let time_exceeded =
+            !self.max_duration.is_zero() && self.opened_at.elapsed() >= self.max_duration;
+        if size_exceeded || time_exceeded {
+            self.open_next()?;
+        }
+
+        let mut line = serde_json::to_vec(frame)?;
+        line.push(b'\n');
+        let Some(ref mut encoder) = self.encoder else {
+            return Err(Error::other("the frame log has already been finished"));
+        };
+        encoder.write_all(&line)?;
+        self.bytes_written += line.len() as u64;
+        Ok(())
+    }
+
+    /// Completes the current file, after which nothing can be written.
+    pub fn finish(&mut self) -> Result<(), Error> {
+        match self.encoder.take() {
+            Some(encoder) => encoder.finish(),
+            None => Ok(()),
+        }
+    }
+
+    fn open_next(&mut self) -> Result<(), Error> {
+        self.finish()?;
+        let path = timestamped_path(&self.directory, "frames", self.compression.extension());
+        self.encoder = Some(Encoder::create(&path, self.compression)?);
+        println!("Writing the frames to {}", path.display());
+        self.bytes_written = 0;
+        self.opened_at = Instant::now();
+        Ok(())
+    }
+}
+
+impl Drop for FrameLogWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            eprintln!("Could not finish the frame log: {}", e);
+        }
+    }
+}
+
+impl FrameSink for FrameLogWriter {
+    fn name(&self) -> &str {
+        "frame log"
+    }
+
+    fn write(&mut self, frame: &Frame) -> Result<(), Error> {
+        self.write_frame(frame)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        FrameLogWriter::finish(self)
+    }
+}
+
+/// Streams the frames of a frame log back, one line at a time. The
+/// compression is recognised from the start of the file.
+pub struct FrameLogReader {
+    lines: std::io::Lines<Box<dyn BufRead>>,
+    line_num: usize,
+}
+
+impl FrameLogReader {
+    pub fn open(path: &Path) -> Result<FrameLogReader, Error> {
+        let mut file = BufReader::new(File::open(path)?);
+        let start = file.fill_buf()?;
+        let reader: Box<dyn BufRead> = if start.starts_with(&GZIP_MAGIC) {
+            Box::new(BufReader::new(GzDecoder::new(file)))
+        } else if start.starts_with(&ZSTD_MAGIC) {
+            Box::new(BufReader::new(zstd::Decoder::with_buffer(file)?))
+        } else {
+            Box::new(file)
+        };
+        Ok(FrameLogReader {
+            lines: reader.lines(),
+            line_num: 0,
+        })
+    }
+}
+
+impl Iterator for FrameLogReader {
+    type Item = Result<Frame, Error>;
+
+    fn next(&mut self) -> Option<Result<Frame, Error>> {
+        loop {
+            self.line_num += 1;
+            let line = match self.lines.next()? {
+                Ok(v) => v,
+                Err(e) => return Some(Err(e)),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Some(serde_json::from_str(&line).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("line {} is not a valid frame: {}", self.line_num, e),
+                )
+            }));
+        }
+    }
+}
+
+/// Returns whether the file at `path` looks like a frame log, judging
+/// from its name.
+pub fn is_frame_log(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    [Compression::None, Compression::Gzip, Compression::Zstd]
+        .iter()
+        .any(|c| name.ends_with(c.extension()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::recording::timestamp_string;
+    use super::super::tlv_translator::PointCloudPoint;
+    use super::*;
+    use std::fs::{read_dir, remove_dir_all};
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+        _ = remove_dir_all(&directory);
+        directory
+    }
+
+    /// The files in `directory`, sorted by name.
+    fn files(directory: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = read_dir(directory)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        files.sort();
+        files
+    }
+
+    fn frames(count: usize) -> Vec<Frame> {
+        (0..count)
+            .map(|i| {
+                let mut frame = Frame::empty(i);
+                frame.pointcloud = Some(vec![PointCloudPoint {
+                    x: i as f32,
+                    y: 0.5,
+                    z: -1.0,
+                    d: 0.25,
+                }]);
+                frame.range_profile = Some(vec![1.0, 2.5]);
+                frame
+            })
+            .collect()
+    }
+
+    #[test]
+    fn frames_read_back_as_written_with_every_compression() {
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let directory = test_directory(&format!("frame_log_{:?}", compression));
+            let written = frames(5);
+            let mut writer =
+                FrameLogWriter::create(&directory, compression, 0, Duration::ZERO).unwrap();
+            for frame in &written {
+                writer.write_frame(frame).unwrap();
+            }
+            writer.finish().unwrap();
+            assert!(writer.write_frame(&written[0]).is_err());
+
+            let files = files(&directory);
+            assert_eq!(files.len(), 1);
+            assert!(is_frame_log(&files[0]));
+            assert!(files[0]
+                .to_string_lossy()
+                .ends_with(compression.extension()));
+            let read: Vec<Frame> = FrameLogReader::open(&files[0])
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            remove_dir_all(&directory).unwrap();
+            assert_eq!(read, written, "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn a_full_file_is_rotated_into_one_of_its_own() {
+        // The names are only predictable within a single second
+        let (stamp, paths, read) = loop {
+            let directory = test_directory("frame_log_rotation");
+            let stamp = timestamp_string();
+            let mut writer =
+                FrameLogWriter::create(&directory, Compression::None, 1, Duration::ZERO).unwrap();
+            for frame in &frames(3) {
+                writer.write_frame(frame).unwrap();
+            }
+            writer.finish().unwrap();
+            if timestamp_string() == stamp {
+                let files = files(&directory);
+                let read: Vec<Vec<usize>> = files
+                    .iter()
+                    .map(|f| {
+                        FrameLogReader::open(f)
+                            .unwrap()
+                            .map(|frame| frame.unwrap().frame_num)
+                            .collect()
+                    })
+                    .collect();
+                remove_dir_all(&directory).unwrap();
+                break (stamp, files, read);
+            }
+        };
+        let names: Vec<String> = paths
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                format!("frames_{stamp}.ndjson"),
+                format!("frames_{stamp}_1.ndjson"),
+                format!("frames_{stamp}_2.ndjson"),
+            ]
+        );
+        assert_eq!(read, vec![vec![0], vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn an_invalid_line_is_reported_with_its_number() {
+        let directory = test_directory("frame_log_invalid");
+        create_dir_all(&directory).unwrap();
+        let path = directory.join("frames.ndjson");
+        let frame = serde_json::to_string(&Frame::empty(1)).unwrap();
+        std::fs::write(&path, format!("{frame}\n\n{{\"frame\": 2}}\n")).unwrap();
+        let mut reader = FrameLogReader::open(&path).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap(), Frame::empty(1));
+        let e = reader.next().unwrap().unwrap_err();
+        remove_dir_all(&directory).unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(e.to_string().starts_with("line 3 "), "{e}");
+    }
+}
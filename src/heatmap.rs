@@ -0,0 +1,176 @@
+use std::f64::consts::PI;
+
+use super::file_reader::RadarParameters;
+use super::tlv_translator::{q9_to_db, Frame};
+
+/// The number of angle bins the azimuth heatmap is transformed into,
+/// like the TI visualizer does.
+pub const NUM_ANGLE_BINS: usize = 64;
+
+/// The dB range `Heatmap::to_mono8` maps onto 0 to 255. It is fixed, so
+/// the brightness of images of different frames and recordings can be
+/// compared. With the 8 azimuth antennas of the IWR6843 the range-azimuth
+/// heatmap can not exceed 111 dB, the range-doppler heatmap in practice
+/// neither.
+pub const MONO8_DB_RANGE: (f64, f64) = (0.0, 120.0);
+
+/// A heatmap in dB, stored row by row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heatmap {
+    pub rows: usize,
+    pub cols: usize,
+    pub values: Vec<f64>,
+}
+
+impl Heatmap {
+    /// The smallest and largest value, `(0, 0)` for an empty heatmap.
+    pub fn min_max(&self) -> (f64, f64) {
+        let min = self.values.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = self
+            .values
+            .iter()
+            .copied()
+            .fold(f64::NEG_INFINITY, f64::max);
+        if min.is_finite() && max.is_finite() {
+            (min, max)
+        } else {
+            (0.0, 0.0)
+        }
+    }
+
+    /// Scales the values in `MONO8_DB_RANGE` into 0 to 255, for an 8 bit
+    /// grayscale image. Values outside of it are clamped.
+    pub fn to_mono8(&self) -> Vec<u8> {
+        let (min, max) = MONO8_DB_RANGE;
+        self.values
+            .iter()
+            .map(|v| ((v - min) / (max - min) * 255.0).round().clamp(0.0, 255.0) as u8)
+            .collect()
+    }
+}
+
+/// The range-doppler heatmap of a frame in dB, a row for every range bin
+/// and a column for every doppler bin. The doppler bins are shifted so
+/// that the most negative velocity comes first and zero velocity is in
+/// the middle.
+pub fn range_doppler(frame: &Frame, params: &RadarParameters) -> Option<Heatmap> {
+    let data = frame.range_doppler_heatmap.as_ref()?;
+    let (rows, cols) = (params.num_range_bins, params.num_doppler_bins);
+    if cols == 0 || data.len() != rows * cols {
+        return None;
+    }
+    let values = data
+        .chunks_exact(cols)
+        .flat_map(|row| {
+            // fftshift, the second half holds the negative velocities
+            row[cols / 2..]
+                .iter()
+                .chain(&row[..cols / 2])
+                .map(|v| q9_to_db(*v))
+        })
+        .collect();
+    Some(Heatmap { rows, cols, values })
+}
+
+/// The range-azimuth heatmap of a frame in dB, a row for every range bin
+/// and a column for each of the `NUM_ANGLE_BINS` angle bins.
+///
+/// The azimuth heatmap holds the samples of the virtual antennas, the
+/// angles follow from a zero padded DFT over them, shifted so the
+/// leftmost angle comes first.
+pub fn range_azimuth(frame: &Frame, params: &RadarParameters) -> Option<Heatmap> {
+    let data = frame.azimuth_heatmap.as_ref()?;
+    let num_antennas = params.num_virtual_antennas_azimuth();
+    let rows = params.num_range_bins;
+    if num_antennas == 0 || data.len() != rows * num_antennas {
+        return None;
+    }
+
+    // The DFT weights of every angle bin, for every antenna
+    let weights: Vec<Vec<(f64, f64)>> = (0..NUM_ANGLE_BINS)
+        .map(|k| {
+            let bin = (k + NUM_ANGLE_BINS / 2) % NUM_ANGLE_BINS;
+            (0..num_antennas)
+                .map(|n| {
+                    let phase = -2.0 * PI * (bin * n) as f64 / NUM_ANGLE_BINS as f64;
+                    (phase.cos(), phase.sin())
+                })
+                .collect()
+        })
+        .collect();
+
+    let values = data
+        .chunks_exact(num_antennas)
+        .flat_map(|samples| {
+            weights.iter().map(move |w| {
+                let (mut real, mut imag) = (0.0, 0.0);
+                for (s, (c, d)) in samples.iter().zip(w) {
+                    let (a, b) = (s.real as f64, s.imag as f64);
+                    real += a * c - b * d;
+                    imag += a * d + b * c;
+                }
+                // Keep the logarithm finite for an all zero row
+                10.0 * (real * real + imag * imag).max(1.0).log10()
+            })
+        })
+        .collect();
+    Some(Heatmap {
+        rows,
+        cols: NUM_ANGLE_BINS,
+        values,
+    })
+}
+
+/// The range of each row of both heatmaps, in meters.
+pub fn range_axis(params: &RadarParameters) -> Vec<f64> {
+    (0..params.num_range_bins)
+        .map(|i| i as f64 * params.range_bin_m)
+        .collect()
+}
+
+/// The velocity of each column of the range-doppler heatmap, in m/s.
+pub fn doppler_axis(params: &RadarParameters) -> Vec<f64> {
+    let n = params.num_doppler_bins as f64;
+    (0..params.num_doppler_bins)
+        .map(|i| (i as f64 - n / 2.0) * params.doppler_resolution_mps)
+        .collect()
+}
+
+/// The azimuth of each column of the range-azimuth heatmap, in
+/// degrees, negative to the left of the sensor.
+pub fn azimuth_axis() -> Vec<f64> {
+    let n = NUM_ANGLE_BINS as f64;
+    (0..NUM_ANGLE_BINS)
+        .map(|k| (2.0 * (k as f64 - n / 2.0) / n).asin().to_degrees())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mono8_does_not_depend_on_the_other_values() {
+        let quiet = Heatmap {
+            rows: 1,
+            cols: 2,
+            values: vec![60.0, 70.0],
+        };
+        let loud = Heatmap {
+            rows: 1,
+            cols: 2,
+            values: vec![60.0, 200.0],
+        };
+        assert_eq!(quiet.to_mono8()[0], loud.to_mono8()[0]);
+        assert_eq!(loud.to_mono8()[1], 255);
+        assert_eq!(
+            Heatmap {
+                rows: 1,
+                cols: 1,
+                values: vec![-5.0]
+            }
+            .to_mono8(),
+            [0]
+        );
+    }
+}
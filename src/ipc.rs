@@ -1,79 +1,1242 @@
+use super::control::{Controller, Response};
+use super::encoding::{encode_binary, encode_frame, length_prefixed, Encoding, MessageKind};
+use super::file_reader::{IpcSettings, RadarParameters};
+use super::fmcw_manager::ConnectionState;
+use super::foxglove_server::FoxgloveServer;
+use super::queue::QueueReceiver;
+use super::shm_ring::ShmRing;
 use super::tlv_translator::Frame;
 use serde::{Deserialize, Serialize};
-use serde_json;
-use std::io::{Read, Write};
-use std::os::unix::net::UnixStream;
-use std::sync::mpsc;
-
-/// Sends all data which is provided through the
-/// `data_input_stream` channel to a different process via
-/// a _Unix Socket_, located at `/tmp/fmcw_ipc_socket`
-pub fn ipc_sender(data_input_stream: mpsc::Receiver<Frame>) -> std::io::Result<()> {
-    // Create a Unix socket connection to the Python server.
-    let socket_path = "/tmp/fmcw_ipc_socket";
-    let mut stream = UnixStream::connect(socket_path)?;
-
-    while let Ok(data) = data_input_stream.recv() {
-        let serialized_frame = serde_json::to_string(&data).unwrap();
-
-        // Send the data to the python program
-        stream.write_all(serialized_frame.as_bytes())?;
-        stream.write_all(b"\n")?; // Add a newline delimiter for easier message framing.
-        stream.flush()?;
-
-        // // Read the response from the server.
-        // let mut buffer = String::new();
-        // stream.read_to_string(&mut buffer)?;
-        //
-        // // Deserialize and print the response.
-        // if !buffer.is_empty() {
-        //     let response: String = serde_json::from_str(&buffer).unwrap();
-        //     println!("Response from Python: {}", response);
-        // }
+use serde_json::json;
+use std::fs::remove_file;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::watch;
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+
+/// The message sent whenever the connection with the FMCW changes,
+/// serialized as `{"connection_state": "lost"}`.
+#[derive(Serialize)]
+struct StateMessage {
+    connection_state: ConnectionState,
+}
+
+/// A request of a client, handed to the thread of the `Controller`.
+struct ControlRequest {
+    /// The index of the server the client is connected to
+    server: usize,
+    client_id: usize,
+    line: String,
+    client_count: usize,
+    control: bool,
+}
+
+/// The answer of the `Controller` to a `ControlRequest`.
+struct ControlReply {
+    server: usize,
+    client_id: usize,
+    response: Response,
+    /// The parameters of the configuration the sensor runs with after
+    /// the request
+    params: Option<RadarParameters>,
+}
+
+/// A group of sections of a frame a client can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    /// The point cloud and its side info
+    Points,
+    /// The range and noise profiles
+    RangeProfile,
+    /// The azimuth, azimuth-elevation and range-doppler heatmaps
+    Heatmaps,
+    /// The performance stats and the temperatures
+    Telemetry,
+}
+
+impl Topic {
+    pub const ALL: [Topic; 4] = [
+        Topic::Points,
+        Topic::RangeProfile,
+        Topic::Heatmaps,
+        Topic::Telemetry,
+    ];
+
+    /// A copy of `frame` holding only the sections of `topics`.
+    pub fn filter(frame: &Frame, topics: &[Topic]) -> Frame {
+        let has = |topic| topics.contains(&topic);
+        let mut filtered = frame.clone();
+        if !has(Topic::Points) {
+            filtered.pointcloud = None;
+            filtered.side_info = None;
+        }
+        if !has(Topic::RangeProfile) {
+            filtered.range_profile = None;
+            filtered.noise_profile = None;
+        }
+        if !has(Topic::Heatmaps) {
+            filtered.azimuth_heatmap = None;
+            filtered.azimuth_elevation_heatmap = None;
+            filtered.range_doppler_heatmap = None;
+        }
+        if !has(Topic::Telemetry) {
+            filtered.stats = None;
+            filtered.temperature = None;
+        }
+        filtered
+    }
+}
+
+/// How the messages are delimited on the connections of an `IpcServer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// A line per message as long as the client receives JSON, every
+    /// message is length prefixed (see `length_prefixed`) once it
+    /// switched to another encoding
+    Lines,
+    /// Every message is length prefixed, the requests as well
+    LengthPrefixed,
+    /// Every message is a WebSocket message, a text message for JSON
+    /// and a binary one for the other encodings
+    WebSocket,
+}
+
+/// A message queued for a client, already framed for its connection.
+#[derive(Clone)]
+pub struct Outgoing {
+    bytes: Arc<[u8]>,
+    /// Whether this is a JSON message, sent as text over a WebSocket
+    text: bool,
+}
+
+impl Outgoing {
+    /// A JSON message, sent as text over a WebSocket.
+    pub fn text(json: &[u8]) -> Outgoing {
+        Outgoing {
+            bytes: Arc::from(json),
+            text: true,
+        }
     }
 
-    Ok(())
+    /// A message sent as is, as a binary message over a WebSocket.
+    pub fn binary(bytes: Vec<u8>) -> Outgoing {
+        Outgoing {
+            bytes: Arc::from(bytes),
+            text: false,
+        }
+    }
+
+    fn new(kind: MessageKind, payload: &[u8], encoding: Encoding, framing: Framing) -> Outgoing {
+        let bytes = match framing {
+            Framing::Lines if encoding == Encoding::Json => {
+                let mut line = payload.to_vec();
+                line.push(b'\n');
+                line
+            }
+            Framing::Lines | Framing::LengthPrefixed => length_prefixed(kind, payload),
+            Framing::WebSocket => payload.to_vec(),
+        };
+        Outgoing {
+            bytes: Arc::from(bytes),
+            text: kind == MessageKind::Json,
+        }
+    }
+}
+
+/// The connection of a client, kept to close it.
+enum Connection {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Connection {
+    fn shutdown(&self, how: Shutdown) {
+        _ = match self {
+            Connection::Unix(stream) => stream.shutdown(how),
+            Connection::Tcp(stream) => stream.shutdown(how),
+        };
+    }
+}
+
+/// A connected client, with the queue of the messages which were not
+/// sent to it yet.
+struct Client {
+    id: usize,
+    encoding: Encoding,
+    /// The topics of the frames sent to the client
+    topics: Vec<Topic>,
+    queue: mpsc::SyncSender<Outgoing>,
+    /// Used to interrupt the threads serving the client when it is
+    /// dropped
+    connection: Connection,
+}
+
+/// The state shared between the `IpcServer` and its accept thread.
+struct Shared {
+    /// How the clients are called in the log
+    name: &'static str,
+    framing: Framing,
+    clients: Mutex<Vec<Client>>,
+    /// The last connection state message as JSON, sent to every new
+    /// client
+    state_message: Mutex<Option<Vec<u8>>>,
+    queue_size: usize,
+    next_id: AtomicUsize,
+    /// The requests received from the clients, with the id of the client
+    requests: Mutex<mpsc::Sender<(usize, String)>>,
+}
+
+impl Shared {
+    /// Adds a client, returning its id and the queue of its messages.
+    fn register(&self, connection: Connection) -> (usize, mpsc::Receiver<Outgoing>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (queue, messages) = mpsc::sync_channel(self.queue_size);
+        if let Some(ref state) = *self.state_message.lock().unwrap() {
+            _ = queue.try_send(Outgoing::new(
+                MessageKind::Json,
+                state,
+                Encoding::Json,
+                self.framing,
+            ));
+        }
+        self.clients.lock().unwrap().push(Client {
+            id,
+            encoding: Encoding::Json,
+            topics: Topic::ALL.to_vec(),
+            queue,
+            connection,
+        });
+        println!("{} client {} connected", self.name, id);
+        (id, messages)
+    }
+
+    fn requests(&self) -> mpsc::Sender<(usize, String)> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// A server which sends every message to all connected clients, over a
+/// _Unix Socket_, TCP or WebSocket.
+///
+/// Clients can connect and disconnect at any time. Every client has its
+/// own queue of `queue_size` messages which is written by its own
+/// thread, a client which falls so far behind that its queue is full is
+/// disconnected, so a slow client never holds up the others or the
+/// parser.
+///
+/// Every client starts out receiving whole frames as JSON, and can
+/// switch to another `Encoding` with `set_encoding` and to a subset of
+/// the frame with `set_topics`. The requests the clients send are
+/// collected, to be answered with `send_json_to`.
+pub struct IpcServer {
+    shared: Arc<Shared>,
+    requests: mpsc::Receiver<(usize, String)>,
+    socket_path: Option<PathBuf>,
+    /// The address a TCP or WebSocket server listens on
+    local_addr: Option<SocketAddr>,
+    control: bool,
+}
+
+impl IpcServer {
+    /// Binds the socket at `socket_path` and starts accepting clients,
+    /// which send their requests as lines.
+    ///
+    /// A socket file left behind by an earlier run is removed, but an
+    /// error is returned if another server is still listening on it.
+    pub fn bind(socket_path: &Path, queue_size: usize) -> Result<IpcServer, Error> {
+        if socket_path.exists() {
+            if UnixStream::connect(socket_path).is_ok() {
+                return Err(Error::new(
+                    ErrorKind::AddrInUse,
+                    format!(
+                        "Another process is already serving on {}",
+                        socket_path.display()
+                    ),
+                ));
+            }
+            remove_file(socket_path)?;
+        }
+        let listener = UnixListener::bind(socket_path)?;
+        let mut server = IpcServer::new("IPC", Framing::Lines, queue_size, |shared| {
+            thread::spawn(move || accept_unix(listener, shared));
+        });
+        server.socket_path = Some(socket_path.to_path_buf());
+        Ok(server)
+    }
+
+    /// Listens for TCP clients on `address`, such as `0.0.0.0:5005`.
+    /// Every message is length prefixed, in both directions.
+    pub fn bind_tcp(address: &str, queue_size: usize) -> Result<IpcServer, Error> {
+        let listener = TcpListener::bind(address)?;
+        let local_addr = listener.local_addr()?;
+        let mut server = IpcServer::new("TCP", Framing::LengthPrefixed, queue_size, |shared| {
+            thread::spawn(move || accept_tcp(listener, shared));
+        });
+        server.local_addr = Some(local_addr);
+        Ok(server)
+    }
+
+    /// Listens for WebSocket clients on `address`, such as
+    /// `0.0.0.0:5006`. The clients send their requests as text messages.
+    pub fn bind_websocket(address: &str, queue_size: usize) -> Result<IpcServer, Error> {
+        let listener = TcpListener::bind(address)?;
+        let local_addr = listener.local_addr()?;
+        let mut server = IpcServer::new("WebSocket", Framing::WebSocket, queue_size, |shared| {
+            thread::spawn(move || accept_websocket(listener, shared));
+        });
+        server.local_addr = Some(local_addr);
+        Ok(server)
+    }
+
+    fn new(
+        name: &'static str,
+        framing: Framing,
+        queue_size: usize,
+        accept: impl FnOnce(Arc<Shared>),
+    ) -> IpcServer {
+        let (requests_tx, requests) = mpsc::channel();
+        let shared = Arc::new(Shared {
+            name,
+            framing,
+            clients: Mutex::new(vec![]),
+            state_message: Mutex::new(None),
+            queue_size: queue_size.max(1),
+            next_id: AtomicUsize::new(0),
+            requests: Mutex::new(requests_tx),
+        });
+        accept(shared.clone());
+        IpcServer {
+            shared,
+            requests,
+            socket_path: None,
+            local_addr: None,
+            control: true,
+        }
+    }
+
+    /// Sets whether the clients may send the commands which control the
+    /// sensor and the recording, rather than only inspect the stream.
+    /// They may by default.
+    pub fn with_control(mut self, control: bool) -> IpcServer {
+        self.control = control;
+        self
+    }
+
+    pub fn allows_control(&self) -> bool {
+        self.control
+    }
+
+    /// The number of connected clients.
+    pub fn client_count(&self) -> usize {
+        self.shared.clients.lock().unwrap().len()
+    }
+
+    /// Queues a frame for every connected client, in the encoding and
+    /// with the topics of the client, disconnecting the clients whose
+    /// queue is full. Never blocks.
+    pub fn broadcast_frame(&self, frame: &Frame) {
+        // Every combination in use is only encoded once
+        let mut encoded: Vec<(Encoding, Vec<Topic>, Outgoing)> = vec![];
+        let framing = self.shared.framing;
+        self.queue_message(|client| {
+            if let Some((_, _, message)) = encoded
+                .iter()
+                .find(|(e, t, _)| *e == client.encoding && *t == client.topics)
+            {
+                return Some(message.clone());
+            }
+            let (kind, payload) = if client.topics.len() == Topic::ALL.len() {
+                encode_frame(frame, client.encoding)
+            } else {
+                encode_frame(&Topic::filter(frame, &client.topics), client.encoding)
+            };
+            let message = Outgoing::new(kind, &payload, client.encoding, framing);
+            encoded.push((client.encoding, client.topics.clone(), message.clone()));
+            Some(message)
+        });
+    }
+
+    /// Queues a JSON object for every connected client.
+    pub fn broadcast_json(&self, json: &[u8]) {
+        let framing = self.shared.framing;
+        self.queue_message(|client| {
+            Some(Outgoing::new(
+                MessageKind::Json,
+                json,
+                client.encoding,
+                framing,
+            ))
+        });
+    }
+
+    /// Broadcasts a connection state message, which is also sent to
+    /// every client connecting later.
+    pub fn broadcast_state(&self, json: &[u8]) {
+        *self.shared.state_message.lock().unwrap() = Some(json.to_vec());
+        self.broadcast_json(json);
+    }
+
+    /// Queues a JSON object for a single client, such as the response
+    /// to one of its requests.
+    pub fn send_json_to(&self, client_id: usize, json: &[u8]) {
+        let framing = self.shared.framing;
+        self.queue_message(|client| {
+            (client.id == client_id)
+                .then(|| Outgoing::new(MessageKind::Json, json, client.encoding, framing))
+        });
+    }
+
+    /// Changes the encoding of the messages queued for a client from now
+    /// on.
+    pub fn set_encoding(&self, client_id: usize, encoding: Encoding) {
+        let mut clients = self.shared.clients.lock().unwrap();
+        if let Some(client) = clients.iter_mut().find(|c| c.id == client_id) {
+            client.encoding = encoding;
+        }
+    }
+
+    /// Changes the sections of the frames queued for a client from now
+    /// on.
+    pub fn set_topics(&self, client_id: usize, topics: &[Topic]) {
+        let mut clients = self.shared.clients.lock().unwrap();
+        if let Some(client) = clients.iter_mut().find(|c| c.id == client_id) {
+            // Kept in a fixed order, so equal subscriptions share their
+            // encoded frames
+            client.topics = Topic::ALL
+                .into_iter()
+                .filter(|t| topics.contains(t))
+                .collect();
+        }
+    }
+
+    /// Returns the next request a client has sent, with the id of the
+    /// client, if there is one.
+    pub fn next_request(&self) -> Option<(usize, String)> {
+        self.requests.try_recv().ok()
+    }
+
+    /// Queues the message `render` returns for every client, skipping the
+    /// clients it returns `None` for.
+    fn queue_message(&self, mut render: impl FnMut(&Client) -> Option<Outgoing>) {
+        let name = self.shared.name;
+        self.shared.clients.lock().unwrap().retain(|client| {
+            let Some(message) = render(client) else {
+                return true;
+            };
+            match client.queue.try_send(message) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    eprintln!(
+                        "{} client {} is too slow, disconnecting it",
+                        name, client.id
+                    );
+                    client.connection.shutdown(Shutdown::Both);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    println!("{} client {} disconnected", name, client.id);
+                    false
+                }
+            }
+        });
+    }
+}
+
+impl Drop for IpcServer {
+    fn drop(&mut self) {
+        for client in self.shared.clients.lock().unwrap().drain(..) {
+            client.connection.shutdown(Shutdown::Write);
+        }
+        if let Some(ref socket_path) = self.socket_path {
+            _ = remove_file(socket_path);
+        }
+    }
+}
+
+fn accept_unix(listener: UnixListener, shared: Arc<Shared>) {
+    for stream in listener.incoming() {
+        let streams = stream.and_then(|s| Ok((s.try_clone()?, s.try_clone()?, s)));
+        let (writer_stream, reader_stream, stream) = match streams {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("Could not accept an IPC client: {}", e);
+                continue;
+            }
+        };
+        let (id, messages) = shared.register(Connection::Unix(stream));
+        thread::spawn(move || write_messages(writer_stream, messages));
+        let requests = shared.requests();
+        thread::spawn(move || {
+            read_lines(id, &reader_stream, requests);
+            // Disconnects a client which sent an invalid request, the
+            // writing side stops and the client is dropped
+            _ = reader_stream.shutdown(Shutdown::Both);
+        });
+    }
+}
+
+fn accept_tcp(listener: TcpListener, shared: Arc<Shared>) {
+    for stream in listener.incoming() {
+        let streams = stream.and_then(|s| {
+            s.set_nodelay(true)?;
+            Ok((s.try_clone()?, s.try_clone()?, s))
+        });
+        let (writer_stream, reader_stream, stream) = match streams {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("Could not accept a TCP client: {}", e);
+                continue;
+            }
+        };
+        let (id, messages) = shared.register(Connection::Tcp(stream));
+        thread::spawn(move || write_messages(writer_stream, messages));
+        let requests = shared.requests();
+        thread::spawn(move || {
+            read_length_prefixed(id, &reader_stream, requests);
+            _ = reader_stream.shutdown(Shutdown::Both);
+        });
+    }
+}
+
+fn accept_websocket(listener: TcpListener, shared: Arc<Shared>) {
+    for stream in listener.incoming() {
+        let stream = match stream.and_then(|s| s.set_nodelay(true).map(|_| s)) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("Could not accept a WebSocket client: {}", e);
+                continue;
+            }
+        };
+        let shared = shared.clone();
+        // The handshake waits for the client, so it is not done here
+        thread::spawn(move || {
+            let streams = stream
+                .try_clone()
+                .and_then(|connection| Ok((connection, WebSocketStream::new(stream)?)));
+            let (connection, stream) = match streams {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("Could not accept a WebSocket client: {}", e);
+                    return;
+                }
+            };
+            let websocket = match tungstenite::accept(stream) {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("WebSocket handshake failed: {}", e);
+                    return;
+                }
+            };
+            let (id, messages) = shared.register(Connection::Tcp(connection));
+            serve_websocket(id, websocket, messages, shared.requests());
+        });
+    }
+}
+
+/// Writes the queued messages to a client, until the client disconnects
+/// or is dropped.
+fn write_messages(mut stream: impl Write, messages: mpsc::Receiver<Outgoing>) {
+    while let Ok(message) = messages.recv() {
+        if stream.write_all(&message.bytes).is_err() {
+            break;
+        }
+    }
+}
+
+/// The largest request a client may send, a longer one disconnects it.
+const MAX_REQUEST_BYTES: usize = 1 << 20;
+
+/// Collects the lines a client sends, until it disconnects or sends a
+/// line which is longer than `MAX_REQUEST_BYTES` or not UTF-8.
+fn read_lines(id: usize, stream: impl Read, requests: mpsc::Sender<(usize, String)>) {
+    let mut reader = BufReader::new(stream);
+    let mut line = vec![];
+    loop {
+        line.clear();
+        // Room for the longest request and its newline, so a longer
+        // line is never buffered whole
+        let mut limited = (&mut reader).take(MAX_REQUEST_BYTES as u64 + 1);
+        match limited.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        if line.last() == Some(&b'\n') {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        } else if line.len() > MAX_REQUEST_BYTES {
+            eprintln!("IPC client {id} sent a request longer than {MAX_REQUEST_BYTES} bytes");
+            break;
+        }
+        let Ok(request) = std::str::from_utf8(&line) else {
+            break;
+        };
+        if request.trim().is_empty() {
+            continue;
+        }
+        if requests.send((id, request.to_string())).is_err() {
+            break;
+        }
+    }
+}
+
+/// Collects the length prefixed JSON messages a client sends, until it
+/// disconnects. Messages of other kinds are ignored.
+fn read_length_prefixed(id: usize, stream: impl Read, requests: mpsc::Sender<(usize, String)>) {
+    let mut reader = BufReader::new(stream);
+    let mut header = [0u8; 5];
+    while reader.read_exact(&mut header).is_ok() {
+        let length = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        if length > MAX_REQUEST_BYTES {
+            break;
+        }
+        let mut payload = vec![0u8; length];
+        if reader.read_exact(&mut payload).is_err() {
+            break;
+        }
+        if header[4] != MessageKind::Json as u8 {
+            continue;
+        }
+        let Ok(request) = String::from_utf8(payload) else {
+            continue;
+        };
+        if requests.send((id, request)).is_err() {
+            break;
+        }
+    }
 }
 
-/// Sends all data which is provided through the
-/// `data_input_stream` channel to a different process via
-/// a _Unix Socket_, located at `/tmp/fmcw_ipc_socket`
-pub fn ipc_test_sender(frame: Frame) -> std::io::Result<()> {
-    // Create a Unix socket connection to the Python server.
-    let socket_path = "/tmp/fmcw_ipc_socket";
-    let mut stream = UnixStream::connect(socket_path)?;
+/// The TCP connection of a WebSocket client, shared by the thread
+/// reading from it and the one writing to it. Every write goes out
+/// whole under a lock, so the pongs and close replies of the reading
+/// side never end up in the middle of a frame.
+pub struct WebSocketStream {
+    read: TcpStream,
+    write: Arc<Mutex<TcpStream>>,
+}
 
-    let serialized_frame = serde_json::to_string(&frame).unwrap();
+impl WebSocketStream {
+    pub fn new(stream: TcpStream) -> Result<WebSocketStream, Error> {
+        Ok(WebSocketStream {
+            read: stream.try_clone()?,
+            write: Arc::new(Mutex::new(stream)),
+        })
+    }
 
-    // Send the data to the python program
-    stream.write_all(serialized_frame.as_bytes())?;
-    stream.write_all(b"\n")?; // Add a newline delimiter for easier message framing.
-    stream.flush()?;
+    fn try_clone(&self) -> Result<WebSocketStream, Error> {
+        Ok(WebSocketStream {
+            read: self.read.try_clone()?,
+            write: self.write.clone(),
+        })
+    }
+}
+
+impl Read for WebSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.read.read(buf)
+    }
+}
 
-    let serialized_frame = serde_json::to_string(&frame).unwrap();
+impl Write for WebSocketStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.write.lock().unwrap().write_all(buf)?;
+        Ok(buf.len())
+    }
 
-    // Send the data to the python program
-    stream.write_all(serialized_frame.as_bytes())?;
-    stream.write_all(b"\n")?; // Add a newline delimiter for easier message framing.
-    stream.flush()?;
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write.lock().unwrap().flush()
+    }
+}
 
-    let serialized_frame = serde_json::to_string(&frame).unwrap();
+/// Sends the queued messages to a WebSocket client and collects the
+/// text messages it sends, on a thread of its own, until it disconnects
+/// or is dropped.
+pub fn serve_websocket(
+    id: usize,
+    websocket: WebSocket<WebSocketStream>,
+    messages: mpsc::Receiver<Outgoing>,
+    requests: mpsc::Sender<(usize, String)>,
+) {
+    // The handshake is done, the writing side only needs the socket
+    let mut writer = match websocket.get_ref().try_clone() {
+        Ok(stream) => WebSocket::from_raw_socket(stream, Role::Server, None),
+        Err(_) => return,
+    };
+    let reader = thread::spawn(move || read_websocket(id, websocket, requests));
+    while let Ok(message) = messages.recv() {
+        let message = if message.text {
+            Message::text(String::from_utf8_lossy(&message.bytes).into_owned())
+        } else {
+            Message::binary(message.bytes.to_vec())
+        };
+        if writer.send(message).is_err() {
+            break;
+        }
+    }
+    _ = writer.close(None);
+    _ = writer.flush();
+    // Ends the read of the reading side
+    _ = writer.get_ref().read.shutdown(Shutdown::Both);
+    _ = reader.join();
+}
 
-    // Send the data to the python program
-    stream.write_all(serialized_frame.as_bytes())?;
-    stream.write_all(b"\n")?; // Add a newline delimiter for easier message framing.
-    stream.flush()?;
+/// Collects the text messages a WebSocket client sends, until it
+/// disconnects.
+fn read_websocket(
+    id: usize,
+    mut websocket: WebSocket<WebSocketStream>,
+    requests: mpsc::Sender<(usize, String)>,
+) {
+    while let Ok(message) = websocket.read() {
+        if let Message::Text(text) = message {
+            if !text.trim().is_empty() && requests.send((id, text.to_string())).is_err() {
+                return;
+            }
+        }
+    }
+}
 
-    loop {}
-    // // Read the response from the server.
-    // let mut buffer = String::new();
-    // stream.read_to_string(&mut buffer)?;
-    //
-    // // Deserialize and print the response.
-    // if !buffer.is_empty() {
-    //     let response: String = serde_json::from_str(&buffer).unwrap();
-    //     println!("Response from Python: {}", response);
-    // }
+/// Serves all data which is provided through the `data_input_stream`
+/// channel to other processes. With `ipc.enabled` they are served via a
+/// _Unix Socket_ bound at `ipc.socket_path`, as newline delimited JSON
+/// unless a client asks for another `Encoding`. With `ipc.tcp_enabled`
+/// and `ipc.websocket_enabled` the same frames are served over TCP and
+/// WebSocket, see `Framing`. Every transport is started on its own.
+///
+/// If a `state` receiver is provided, every change in the
+/// connection state of the FMCW is sent as well.
+///
+/// The clients can send commands over the same connection, which are
+/// carried out by `controller` (see `Controller` for the format) on a
+/// thread of its own, so the frames keep flowing while a command waits
+/// on the FMCW. The commands are carried out in the order they arrive,
+/// the response is only sent to the client which sent the command. Unless
+/// `ipc.remote_control` is set, the TCP and WebSocket clients may only
+/// use the commands which do not change the sensor or the recording.
+///
+/// With `ipc.foxglove_enabled`, the frames are published to Foxglove
+/// Studio as well, see `FoxgloveServer`.
+///
+/// With `ipc.shm_enabled`, every frame is also written into a
+/// `ShmRing` in its binary encoding, and the clients connected to
+/// `ipc.shm_notify_path` get a line of JSON such as
+/// `{"sequence": 12, "slot": 11, "length": 10380, "frame_num": 811}`
+/// for every frame written.
+///
+/// Returns once all senders of `data_input_stream` have been dropped,
+/// or with an error if a socket could not be bound.
+pub fn ipc_server(
+    data_input_stream: QueueReceiver<Frame>,
+    mut state: Option<watch::Receiver<ConnectionState>>,
+    settings: &IpcSettings,
+    controller: Controller,
+) -> std::io::Result<()> {
+    let queue_size = settings.client_queue_size;
+    let mut servers = vec![];
+    if settings.enabled {
+        servers.push(IpcServer::bind(&settings.socket_path, queue_size)?);
+        println!(
+            "Serving frames to IPC clients on {}",
+            settings.socket_path.display()
+        );
+    }
+    if settings.tcp_enabled {
+        let server = IpcServer::bind_tcp(&settings.tcp_address, queue_size)?;
+        println!(
+            "Serving frames to TCP clients on {}",
+            server.local_addr.unwrap()
+        );
+        servers.push(server.with_control(settings.remote_control));
+    }
+    if settings.websocket_enabled {
+        let server = IpcServer::bind_websocket(&settings.websocket_address, queue_size)?;
+        println!(
+            "Serving frames to WebSocket clients on ws://{}",
+            server.local_addr.unwrap()
+        );
+        servers.push(server.with_control(settings.remote_control));
+    }
+    let foxglove = if settings.foxglove_enabled {
+        let server = FoxgloveServer::bind(&settings.foxglove_address, queue_size)?;
+        println!(
+            "Serving frames to Foxglove Studio on ws://{}",
+            settings.foxglove_address
+        );
+        Some(server)
+    } else {
+        None
+    };
+    let mut shm: Option<(ShmRing, IpcServer)> = if settings.shm_enabled {
+        let ring = ShmRing::create(
+            &settings.shm_path,
+            settings.shm_slots,
+            settings.shm_slot_bytes,
+        )?;
+        let notify = IpcServer::bind(&settings.shm_notify_path, queue_size)?;
+        println!(
+            "Writing frames to {}, notifying on {}",
+            ring.path().display(),
+            settings.shm_notify_path.display()
+        );
+        Some((ring, notify))
+    } else {
+        None
+    };
+    if let Some(ref mut state) = state {
+        state.mark_changed();
+    }
+
+    let mut params = controller.config().radar_parameters().ok();
+    let (control_requests, requests) = mpsc::channel();
+    let (replies_tx, replies) = mpsc::channel();
+    // Not joined, it may still wait on the FMCW when the stream ends
+    thread::spawn(move || run_controller(controller, requests, replies_tx));
+
+    loop {
+        for (index, server) in servers.iter().enumerate() {
+            while let Some((client_id, line)) = server.next_request() {
+                let client_count = servers.iter().map(IpcServer::client_count).sum::<usize>()
+                    + foxglove.as_ref().map_or(0, FoxgloveServer::client_count);
+                _ = control_requests.send(ControlRequest {
+                    server: index,
+                    client_id,
+                    line,
+                    client_count,
+                    control: server.allows_control(),
+                });
+            }
+        }
+        while let Ok(reply) = replies.try_recv() {
+            let server = &servers[reply.server];
+            // The response to a change of the encoding is the last
+            // message in the old encoding
+            server.send_json_to(reply.client_id, &reply.response.json);
+            if let Some(encoding) = reply.response.encoding {
+                server.set_encoding(reply.client_id, encoding);
+            }
+            if let Some(ref topics) = reply.response.topics {
+                server.set_topics(reply.client_id, topics);
+            }
+            params = reply.params;
+        }
+
+        if let Some(ref mut state) = state {
+            if state.has_changed().unwrap_or(false) {
+                let connection_state = *state.borrow_and_update();
+                let message = serde_json::to_vec(&StateMessage { connection_state }).unwrap();
+                for server in &servers {
+                    server.broadcast_state(&message);
+                }
+            }
+        }
+
+        let data = match data_input_stream.recv_timeout(Duration::from_millis(100)) {
+            Ok(v) => v,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if let Some((ref mut ring, ref notify)) = shm {
+            // The readers of the shared memory do not send commands
+            while notify.next_request().is_some() {}
+            let binary = encode_binary(&data);
+            match ring.write(&binary) {
+                Ok((sequence, slot)) => {
+                    let message = json!({
+                        "sequence": sequence,
+                        "slot": slot,
+                        "length": binary.len(),
+                        "frame_num": data.frame_num,
+                    });
+                    notify.broadcast_json(message.to_string().as_bytes());
+                }
+                Err(e) => eprintln!(
+                    "Could not write frame {} to the shared memory: {}",
+                    data.frame_num, e
+                ),
+            }
+        }
+        for server in &servers {
+            if server.client_count() > 0 {
+                server.broadcast_frame(&data);
+            }
+        }
+        if let Some(ref foxglove) = foxglove {
+            // The parameters change when another configuration is sent
+            foxglove.publish(&data, params.as_ref());
+        }
+    }
 
     Ok(())
 }
+
+/// Carries out the requests with `controller` one by one, until
+/// `ipc_server` returns.
+fn run_controller(
+    mut controller: Controller,
+    requests: mpsc::Receiver<ControlRequest>,
+    replies: mpsc::Sender<ControlReply>,
+) {
+    for request in requests {
+        let response = controller.handle(&request.line, request.client_count, request.control);
+        let reply = ControlReply {
+            server: request.server,
+            client_id: request.client_id,
+            response,
+            params: controller.config().radar_parameters().ok(),
+        };
+        if replies.send(reply).is_err() {
+            return;
+        }
+    }
+}
+
+/// Serves `frame` to every client connecting to the _Unix Socket_ at
+/// `socket_path`, once a second, so the receiving side can be
+/// inspected.
+pub fn ipc_test_sender(frame: Frame, socket_path: &Path) -> std::io::Result<()> {
+    let server = IpcServer::bind(socket_path, 1)?;
+
+    loop {
+        server.broadcast_frame(&frame);
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::control::Sensor;
+    use super::super::file_reader::Config;
+    use super::super::queue::{self, DropPolicy};
+    use super::*;
+
+    #[test]
+    fn a_websocket_client_is_served_without_polling() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (queue, messages) = mpsc::sync_channel(4);
+        let (requests_tx, requests) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let websocket = tungstenite::accept(WebSocketStream::new(stream).unwrap()).unwrap();
+            serve_websocket(7, websocket, messages, requests_tx);
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        let (mut client, _) = tungstenite::client(format!("ws://{address}"), stream).unwrap();
+        client
+            .send(Message::text(r#"{"command": "stats"}"#))
+            .unwrap();
+        assert_eq!(
+            requests.recv_timeout(Duration::from_secs(5)).unwrap(),
+            (7, r#"{"command": "stats"}"#.to_string())
+        );
+
+        // The reading side answers a ping while nothing is being sent
+        client.send(Message::Ping(vec![1, 2].into())).unwrap();
+        assert_eq!(client.read().unwrap(), Message::Pong(vec![1, 2].into()));
+
+        queue.send(Outgoing::text(b"{}")).unwrap();
+        queue.send(Outgoing::binary(vec![1, 2, 3])).unwrap();
+        assert_eq!(client.read().unwrap(), Message::text("{}"));
+        assert_eq!(client.read().unwrap(), Message::binary(vec![1, 2, 3]));
+
+        // Dropping the client closes the connection
+        drop(queue);
+        server.join().unwrap();
+        assert!(matches!(client.read(), Ok(Message::Close(_))));
+    }
+
+    /// Binds an `IpcServer` on a socket of its own in the temp dir.
+    fn bind_unix(name: &str, queue_size: usize) -> IpcServer {
+        let path = std::env::temp_dir().join(format!("ipc_{}_{}.sock", name, std::process::id()));
+        IpcServer::bind(&path, queue_size).unwrap()
+    }
+
+    fn connect(server: &IpcServer) -> BufReader<UnixStream> {
+        let count = server.client_count();
+        let stream = UnixStream::connect(server.socket_path.as_ref().unwrap()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        wait_for(|| server.client_count() == count + 1);
+        BufReader::new(stream)
+    }
+
+    fn wait_for(condition: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if condition() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Timed out");
+    }
+
+    fn read_line(client: &mut BufReader<UnixStream>) -> String {
+        let mut line = String::new();
+        client.read_line(&mut line).unwrap();
+        line
+    }
+
+    fn next_request(server: &IpcServer) -> (usize, String) {
+        for _ in 0..500 {
+            if let Some(request) = server.next_request() {
+                return request;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("No request received");
+    }
+
+    #[test]
+    fn every_client_gets_the_broadcasts_and_only_its_own_responses() {
+        let server = bind_unix("clients", 4);
+        let mut first = connect(&server);
+        let mut second = connect(&server);
+
+        server.broadcast_json(br#"{"n": 1}"#);
+        assert_eq!(read_line(&mut first), "{\"n\": 1}\n");
+        assert_eq!(read_line(&mut second), "{\"n\": 1}\n");
+
+        first.get_mut().write_all(b"first\r\n\n").unwrap();
+        let (first_id, request) = next_request(&server);
+        assert_eq!(request, "first");
+        second.get_mut().write_all(b"second\n").unwrap();
+        let (second_id, request) = next_request(&server);
+        assert_eq!(request, "second");
+        assert_ne!(first_id, second_id);
+
+        server.send_json_to(second_id, br#"{"to": "second"}"#);
+        server.broadcast_json(br#"{"n": 2}"#);
+        assert_eq!(read_line(&mut first), "{\"n\": 2}\n");
+        assert_eq!(read_line(&mut second), "{\"to\": \"second\"}\n");
+        assert_eq!(read_line(&mut second), "{\"n\": 2}\n");
+
+        drop(first);
+        server.broadcast_json(br#"{"n": 3}"#);
+        wait_for(|| {
+            server.broadcast_json(b"{}");
+            server.client_count() == 1
+        });
+    }
+
+    #[test]
+    fn a_slow_client_is_disconnected_without_holding_up_the_others() {
+        let server = bind_unix("slow", 4);
+        let mut slow = connect(&server);
+        let mut fast = connect(&server);
+        let reader = thread::spawn(move || {
+            let mut count = 0;
+            loop {
+                let line = read_line(&mut fast);
+                if line == "{}\n" {
+                    return count;
+                }
+                count += 1;
+            }
+        });
+
+        // Fills the socket buffer of the client which does not read,
+        // then its queue
+        let message = format!("\"{}\"", "x".repeat(1 << 16));
+        let mut sent = 0;
+        while server.client_count() == 2 {
+            assert!(sent < 10_000, "The slow client was not disconnected");
+            server.broadcast_json(message.as_bytes());
+            sent += 1;
+            thread::sleep(Duration::from_millis(1));
+        }
+        server.broadcast_json(b"{}");
+        assert_eq!(reader.join().unwrap(), sent);
+
+        // The slow client gets what was sent before, then the end
+        let mut rest = vec![];
+        slow.read_to_end(&mut rest).unwrap();
+        assert!(rest.len() < sent * message.len());
+    }
+
+    #[test]
+    fn a_client_sending_an_overlong_line_is_disconnected() {
+        let server = bind_unix("overlong", 4);
+        let mut client = connect(&server);
+        client.get_mut().write_all(b"stats\n").unwrap();
+        assert_eq!(next_request(&server).1, "stats");
+
+        // Exactly the limit is still a request
+        let mut line = vec![b'a'; MAX_REQUEST_BYTES];
+        line.push(b'\n');
+        client.get_mut().write_all(&line).unwrap();
+        assert_eq!(next_request(&server).1.len(), MAX_REQUEST_BYTES);
+
+        _ = client
+            .get_mut()
+            .write_all(&vec![b'x'; MAX_REQUEST_BYTES + 1]);
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        assert!(server.next_request().is_none());
+    }
+
+    fn read_length_prefixed_message(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut header = [0u8; 5];
+        stream.read_exact(&mut header).unwrap();
+        let mut payload = vec![0u8; u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize];
+        stream.read_exact(&mut payload).unwrap();
+        (header[4], payload)
+    }
+
+    fn connect_tcp(server: &IpcServer) -> TcpStream {
+        let count = server.client_count();
+        let stream = TcpStream::connect(server.local_addr.unwrap()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        wait_for(|| server.client_count() == count + 1);
+        stream
+    }
+
+    #[test]
+    fn tcp_messages_are_length_prefixed_both_ways() {
+        let server = IpcServer::bind_tcp("127.0.0.1:0", 4).unwrap();
+        let mut client = connect_tcp(&server);
+
+        server.broadcast_json(br#"{"n": 1}"#);
+        assert_eq!(
+            read_length_prefixed_message(&mut client),
+            (MessageKind::Json as u8, br#"{"n": 1}"#.to_vec())
+        );
+
+        // Only JSON requests are taken, in as many writes as it takes
+        let mut requests = length_prefixed(MessageKind::CborFrame, &[1, 2, 3]);
+        requests.extend(length_prefixed(
+            MessageKind::Json,
+            br#"{"command": "stats"}"#,
+        ));
+        let (first, second) = requests.split_at(12);
+        client.write_all(first).unwrap();
+        client.flush().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        client.write_all(second).unwrap();
+        let (id, request) = next_request(&server);
+        assert_eq!(request, r#"{"command": "stats"}"#);
+
+        // Frames in another encoding are prefixed with their kind
+        server.set_encoding(id, Encoding::Binary);
+        let frame = Frame::empty(7);
+        server.broadcast_frame(&frame);
+        assert_eq!(
+            read_length_prefixed_message(&mut client),
+            (MessageKind::BinaryFrame as u8, encode_binary(&frame))
+        );
+
+        // A length above the limit disconnects the client
+        client
+            .write_all(&((MAX_REQUEST_BYTES + 1) as u32).to_le_bytes())
+            .unwrap();
+        client.write_all(&[MessageKind::Json as u8]).unwrap();
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn clients_only_get_the_topics_they_subscribed_to() {
+        let mut frame = Frame::empty(3);
+        frame.pointcloud = Some(vec![]);
+        frame.range_profile = Some(vec![1.0, 2.0]);
+        frame.noise_profile = Some(vec![0.5]);
+        frame.range_doppler_heatmap = Some(vec![4, 5]);
+
+        let filtered = Topic::filter(&frame, &[Topic::Points, Topic::Heatmaps]);
+        assert_eq!(filtered.pointcloud, frame.pointcloud);
+        assert_eq!(filtered.range_doppler_heatmap, frame.range_doppler_heatmap);
+        assert!(filtered.range_profile.is_none() && filtered.noise_profile.is_none());
+        assert_eq!(Topic::filter(&frame, &Topic::ALL), frame);
+
+        let server = IpcServer::bind_tcp("127.0.0.1:0", 4).unwrap();
+        let mut subscribed = connect_tcp(&server);
+        let mut everything = connect_tcp(&server);
+        subscribed
+            .write_all(&length_prefixed(MessageKind::Json, b"subscribe"))
+            .unwrap();
+        let (id, _) = next_request(&server);
+        // The order the topics are given in does not matter
+        server.set_topics(id, &[Topic::RangeProfile, Topic::Points]);
+
+        server.broadcast_frame(&frame);
+        let (_, json) = read_length_prefixed_message(&mut subscribed);
+        let received: Frame = serde_json::from_slice(&json).unwrap();
+        assert_eq!(
+            received,
+            Topic::filter(&frame, &[Topic::Points, Topic::RangeProfile])
+        );
+        assert!(received.range_doppler_heatmap.is_none());
+        let (_, json) = read_length_prefixed_message(&mut everything);
+        assert_eq!(serde_json::from_slice::<Frame>(&json).unwrap(), frame);
+    }
+
+    #[test]
+    fn frames_keep_flowing_while_a_command_waits() {
+        let settings = IpcSettings {
+            socket_path: std::env::temp_dir()
+                .join(format!("ipc_waits_{}.sock", std::process::id())),
+            ..IpcSettings::default()
+        };
+        let config = Config {
+            raw_input: include_str!("../iwr6843_config.cfg").to_string(),
+        };
+        // The parser never answers, so `stats` waits for its timeout
+        let (parser, _commands) = mpsc::channel();
+        let controller = Controller::new(Sensor::None, parser, config, None);
+        let (frames, frames_rx) = queue::bounded("ipc", 16, DropPolicy::DropNewest);
+        let socket_path = settings.socket_path.clone();
+        let server = thread::spawn(move || ipc_server(frames_rx, None, &settings, controller));
+
+        wait_for(|| UnixStream::connect(&socket_path).is_ok());
+        let stream = UnixStream::connect(&socket_path).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut client = BufReader::new(stream);
+        let sent = std::time::Instant::now();
+        client
+            .get_mut()
+            .write_all(b"{\"id\": 1, \"command\": \"stats\"}\n")
+            .unwrap();
+
+        // Frames sent before the client is registered are not served to
+        // it, so keep sending until one arrives
+        let sender = thread::spawn(move || {
+            for i in 0..150 {
+                frames.send(Frame::empty(i)).unwrap();
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+        let first: serde_json::Value = serde_json::from_str(&read_line(&mut client)).unwrap();
+        assert!(first.get("frame_num").is_some(), "{first}");
+        assert!(sent.elapsed() < Duration::from_secs(2));
+        let reply = loop {
+            let message: serde_json::Value = serde_json::from_str(&read_line(&mut client)).unwrap();
+            if message.get("id").is_some() {
+                break message;
+            }
+        };
+        assert!(reply["error"]
+            .as_str()
+            .unwrap()
+            .contains("did not answer in time"));
+
+        sender.join().unwrap();
+        server.join().unwrap().unwrap();
+    }
+}
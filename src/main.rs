@@ -1,16 +1,51 @@
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
+use std::sync::Arc;
 use std::thread;
-use std::{path::Path, sync::Arc};
+use std::time::Duration;
 
+mod async_fmcw;
+mod cli;
+mod control;
+mod convert;
+mod device_discovery;
+mod encoding;
 mod file_reader;
+mod file_source;
 mod fmcw_manager;
+mod foxglove;
+mod foxglove_server;
+mod frame_log;
+mod heatmap;
 mod ipc;
+mod mcap_recorder;
+mod mqtt;
+mod pipeline;
+mod pointcloud_export;
+mod queue;
+mod recording;
 mod renderer;
+mod scene;
+mod shm_ring;
+mod simulator;
+mod tlv_encoder;
 mod tlv_translator;
+mod udp;
 
-use file_reader::{read_byte_file, Config, Settings};
+use cli::{Cli, Command};
+use control::{Controller, Sensor};
+use file_reader::{Config, IpcSettings, Settings};
+use file_source::FileSource;
 use fmcw_manager::Fmcw;
-use tlv_translator::{translate_tlv, Frame};
+use frame_log::{is_frame_log, FrameLogReader};
+use pipeline::{processors_from_settings, FrameSink, Pipeline};
+use queue::DropPolicy;
+use recording::{is_recording, Recording};
+use renderer::RenderSink;
+use scene::Scene;
+use simulator::{SimulatedDevice, SimulatedFrames};
+use tlv_translator::{translate_tlv, ParserCommand};
 
 fn main() {
     // Test the IPC code with random data.
@@ -18,53 +53,329 @@ fn main() {
         test_ipc();
     }
 
-    let settings_path = Path::new("./settings.toml");
-    let settings: Arc<Settings> = Arc::new(Settings::from_file(&settings_path));
+    let cli = get_result(Cli::from_env());
+    let settings: Arc<Settings> = Arc::new(get_result(Settings::load(
+        &cli.settings_path,
+        &cli.overrides,
+    )));
+    if cli.print_settings {
+        print!("{}", settings.to_toml_string());
+        return;
+    }
     println!("Settings read succesfully");
 
-    // let config_path = Path::new("./iwr68xx_config.cfg");
-    let config_path = Path::new("./iwr6843_config.cfg");
-    let config: Config = get_result(Config::from_file(&config_path));
-    println!("Config read succesfully");
+    match cli.command {
+        Command::Run => run(settings),
+        Command::RunAsync => get_result(run_async(&settings)),
+        Command::ListDevices => get_result(device_discovery::print_devices()),
+        Command::Simulate { replay, scene } => simulate(settings, replay, scene),
+        Command::Inspect { path, frame, at } => get_result(inspect(&path, frame, at)),
+        Command::Convert { path } => get_result(convert_capture(&path, &settings)),
+        Command::Render { path } => get_result(render_capture(&path, &settings)),
+        Command::ReceiveUdp => get_result(udp::receive_udp(&settings.udp)),
+        Command::ExportPointcloud { path } => {
+            let frames = get_result(recording::load_frames(&path));
+            let name = path
+                .file_stem()
+                .map_or("frames".to_string(), |s| s.to_string_lossy().to_string());
+            let written = get_result(pointcloud_export::export_pointclouds(
+                frames,
+                &settings.export,
+                &name,
+            ));
+            println!(
+                "Wrote {} point cloud files to {}",
+                written.len(),
+                settings.export.directory.display()
+            );
+        }
+    }
+}
+
+/// Prints what is stored in a recording or frame log, or a single
+/// frame of it.
+///
+/// # Arguments
+/// * `frame`: Print the frame with this frame number
+/// * `at`: Print the first frame received this many seconds into the
+///   recording, only for recordings
+fn inspect(path: &Path, frame: Option<u32>, at: Option<f64>) -> Result<(), io::Error> {
+    if is_frame_log(path) {
+        return inspect_frame_log(path, frame);
+    }
+    let mut recording = Recording::open(path)?;
+    let position = match (frame, at) {
+        (Some(frame_num), _) => recording.frame_by_number(frame_num),
+        (None, Some(secs)) => {
+            let time = Duration::try_from_secs_f64(secs.max(0.0)).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is not a time", secs),
+                )
+            })?;
+            recording.frame_at_time(time)
+        }
+        (None, None) => {
+            let metadata = recording.metadata();
+            let index = recording.index();
+            println!("Recording:        {}", path.display());
+            println!("Format version:   {}", metadata.format_version);
+            println!("Software version: {}", metadata.software_version);
+            println!(
+                "Started at:       {} ms after the unix epoch",
+                metadata.start_time_unix_ms
+            );
+            println!("Frames:           {}", recording.frame_count());
+            if let (Some(first), Some(last)) = (index.first(), index.last()) {
+                println!(
+                    "Frame numbers:    {} to {}",
+                    first.frame_num, last.frame_num
+                );
+                println!(
+                    "Duration:         {:.3} s",
+                    (last.host_time_us - first.host_time_us) as f64 / 1e6
+                );
+            }
+            println!("\nConfiguration:\n{}", metadata.config.trim_end());
+            println!("\nSettings:\n{}", metadata.settings.trim_end());
+            return Ok(());
+        }
+    };
+    let Some(position) = position else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "the recording does not contain such a frame",
+        ));
+    };
+    let mut bytes = recording.read_frame(position)?;
+    for frame in translate_tlv(&mut bytes) {
+        println!("{}", serde_json::to_string_pretty(&frame)?);
+    }
+    Ok(())
+}
+
+/// Converts a recording, raw capture or frame log into tables in the
+/// export directory. The range of the range bins follows from the
+/// configuration stored in a recording, or else `serial.config_file`.
+fn convert_capture(path: &Path, settings: &Settings) -> Result<(), io::Error> {
+    let config = if is_recording(path) {
+        Recording::open(path)?.config()
+    } else {
+        Config::from_file(&settings.serial.config_file)?
+    };
+    let params = config.radar_parameters().ok();
+    let name = path
+        .file_stem()
+        .map_or("frames".to_string(), |s| s.to_string_lossy().to_string());
+    let written = convert::convert_frames(
+        recording::load_frames(path)?,
+        &settings.export.directory,
+        &name,
+        settings.export.table_format,
+        params.as_ref(),
+    )?;
+    for path in written {
+        println!("Wrote {}", path.display());
+    }
+    Ok(())
+}
 
-    // Test the tlv parser on a local raw data file.
-    if settings.read_from_file {
-        let tlv_path = Path::new("./tlv_example_file.dat");
-        read_tlv_file(tlv_path);
+/// Renders the plots of `[render]` for the frames of a recording, raw
+/// capture or frame log, like `convert_capture` picks the configuration.
+/// No frame is dropped, however long rendering takes.
+fn render_capture(path: &Path, settings: &Settings) -> Result<(), io::Error> {
+    let config = if is_recording(path) {
+        Recording::open(path)?.config()
+    } else {
+        Config::from_file(&settings.serial.config_file)?
+    };
+    let mut sink = RenderSink::spawn(
+        &settings.render,
+        config.radar_parameters().ok(),
+        settings.pipeline.render_queue_size,
+        DropPolicy::Block,
+    )?;
+    let mut count: usize = 0;
+    for frame in recording::load_frames(path)? {
+        sink.write(&frame?)?;
+        count += 1;
+    }
+    sink.finish()?;
+    println!(
+        "Rendered {} frames into {}",
+        count.div_ceil(settings.render.every_n_frames.max(1)),
+        settings.render.directory.display()
+    );
+    Ok(())
+}
+
+fn inspect_frame_log(path: &Path, frame_num: Option<u32>) -> Result<(), io::Error> {
+    let mut count: usize = 0;
+    let mut frame_nums: Option<(usize, usize)> = None;
+    for frame in FrameLogReader::open(path)? {
+        let frame = frame?;
+        if frame_num == Some(frame.frame_num as u32) {
+            println!("{}", serde_json::to_string_pretty(&frame)?);
+            return Ok(());
+        }
+        count += 1;
+        frame_nums = Some(
+            frame_nums.map_or((frame.frame_num, frame.frame_num), |(first, _)| {
+                (first, frame.frame_num)
+            }),
+        );
     }
+    if frame_num.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "the frame log does not contain such a frame",
+        ));
+    }
+    println!("Frame log:        {}", path.display());
+    println!("Frames:           {}", count);
+    if let Some((first, last)) = frame_nums {
+        println!("Frame numbers:    {} to {}", first, last);
+    }
+    Ok(())
+}
+
+/// Runs the full pipeline against a simulated sensor, by pointing the
+/// serial ports in the settings at the simulator.
+fn simulate(settings: Arc<Settings>, replay: Option<PathBuf>, scene: Option<PathBuf>) {
+    let frames = match (replay, scene) {
+        (Some(path), _) => SimulatedFrames::Replay(path),
+        (None, Some(path)) => SimulatedFrames::Synthetic(get_result(Scene::from_file(&path))),
+        (None, None) => SimulatedFrames::Synthetic(Scene::default()),
+    };
+    let device = get_result(SimulatedDevice::spawn(frames, settings.serial.data_baud));
+    println!(
+        "Simulated FMCW running, cfg port: {}, data port: {}",
+        device.cfg_port.display(),
+        device.data_port.display()
+    );
+
+    let mut settings = (*settings).clone();
+    settings.serial.cfg_port = device.cfg_port.to_string_lossy().to_string();
+    settings.serial.data_port = device.data_port.to_string_lossy().to_string();
+    run(Arc::new(settings));
+}
+
+/// Runs `async_fmcw::run_async` on a tokio runtime of its own.
+fn run_async(settings: &Settings) -> io::Result<()> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(async_fmcw::run_async(settings))
+}
+
+fn run(settings: Arc<Settings>) {
+    let replay_file = &settings.processing.tlv_file;
+    let config: Config = if settings.processing.read_from_file && is_recording(replay_file) {
+        // Replay with the configuration the recording was made with
+        let recording = get_result(Recording::open(replay_file));
+        println!(
+            "Using the configuration stored in {}, recorded with version {}",
+            replay_file.display(),
+            recording.metadata().software_version
+        );
+        recording.config()
+    } else {
+        get_result(Config::from_file(&settings.serial.config_file))
+    };
+    println!("Config read succesfully");
+    let mut pipeline = Pipeline::new(get_result(processors_from_settings(&settings)));
 
     println!("\n    Data transfer starting: ");
     // Byte capture
-    let (fmcw_tx, fmcw_rx) = mpsc::channel::<Vec<u8>>();
-    let fmcw_thread: thread::JoinHandle<()> = match Fmcw::new(settings.clone(), config) {
-        Ok(fmcw) => {
-            println!("FMCW module loaded succesfully\n");
-            thread::spawn(move || fmcw.run(fmcw_tx))
-        }
-        Err(e) => {
-            eprintln!("FMCW module could not connect, with error: {}\n    This error is most likely caused due to the FMCW not being connected.", e);
-            thread::spawn(|| {})
+    let queues = &settings.pipeline;
+    let (fmcw_tx, fmcw_rx) =
+        queue::bounded::<Vec<u8>>("raw", queues.raw_queue_size, queues.raw_policy);
+    let mut fmcw_state = None;
+    let mut sensor = Sensor::None;
+    // The source is not joined, reading from the FMCW only ends with the process
+    let _fmcw_thread: thread::JoinHandle<()> = if settings.processing.read_from_file {
+        // Replay a raw capture instead of reading from the FMCW
+        let frame_period = config
+            .radar_parameters()
+            .map(|p| Duration::from_secs_f64(p.frame_period_ms / 1000.0))
+            .unwrap_or(Duration::from_millis(100));
+        let source = get_result(FileSource::open(
+            &settings.processing.tlv_file,
+            frame_period,
+        ))
+        .with_speed(settings.processing.replay_speed)
+        .with_loop(settings.processing.replay_loop);
+        println!(
+            "Replaying {} frames from {}, control the playback with pause, resume, seek <frame>, seek <seconds>s, speed <multiplier>, loop <on|off> or stop",
+            source.frame_count(),
+            settings.processing.tlv_file.display()
+        );
+        let handle = source.handle();
+        sensor = Sensor::Playback(handle.clone());
+        thread::spawn(move || file_source::control_from_stdin(handle));
+        thread::spawn(move || source.run(fmcw_tx))
+    } else {
+        match Fmcw::new(settings.clone(), config.clone()) {
+            Ok(mut fmcw) => {
+                println!("FMCW module loaded succesfully\n");
+                fmcw_state = Some(fmcw.subscribe_state());
+                sensor = Sensor::Fmcw(fmcw.handle());
+                thread::spawn(move || fmcw.run(fmcw_tx))
+            }
+            Err(e) => {
+                eprintln!("FMCW module could not connect, with error: {}\n    This error is most likely caused due to the FMCW not being connected.", e);
+                thread::spawn(|| {})
+            }
         }
     };
 
-    let (ipc_tx, ipc_rx) = mpsc::channel::<Frame>();
+    // Every sink gets its own copy of the frames
+    let (sinks, ipc_rx) = get_result(pipeline::sinks_from_settings(
+        &settings,
+        config.radar_parameters().ok(),
+        fmcw_state.clone(),
+    ));
+    for sink in sinks {
+        pipeline.add_sink(sink);
+    }
 
     // Byte processing
+    let (parser_tx, parser_rx) = mpsc::channel();
+    // On Ctrl-C the parser stops, so the recordings are finalized and the
+    // sinks drain their queues, a second Ctrl-C exits right away
+    let stop_tx = parser_tx.clone();
+    let mut stopping = false;
+    let handler = ctrlc::set_handler(move || {
+        if stopping {
+            std::process::exit(130);
+        }
+        stopping = true;
+        println!("Stopping, press Ctrl-C again to exit right away");
+        _ = stop_tx.send(ParserCommand::Stop);
+    });
+    if let Err(e) = handler {
+        eprintln!("Could not install the Ctrl-C handler: {}", e);
+    }
+    let controller = Controller::new(sensor, parser_tx, config.clone(), fmcw_state.clone());
     let tlv_set = settings.clone();
-    let tlv_reader_thread =
-        thread::spawn(move || tlv_translator::parse_stream(fmcw_rx, ipc_tx, tlv_set));
-    let ipc_thread = if settings.ipc_send {
-        thread::spawn(move || ipc::ipc_sender(ipc_rx))
-    } else {
-        thread::spawn(|| Ok(()))
+    let tlv_reader_thread = thread::spawn(move || {
+        tlv_translator::parse_stream(fmcw_rx, pipeline, tlv_set, config, parser_rx)
+    });
+    let ipc_set = settings.clone();
+    let ipc_thread = match ipc_rx {
+        Some(ipc_rx) => {
+            thread::spawn(move || ipc::ipc_server(ipc_rx, fmcw_state, &ipc_set.ipc, controller))
+        }
+        None => thread::spawn(|| Ok(())),
     };
 
     // ipc thread is joined firstly, as this is the only one who can potentially return a result
-    // (error)
+    // (error), e.g. when the socket is already served by another instance. It returns once the
+    // parser has stopped.
     if let Err(e) = ipc_thread.join().unwrap() {
-        eprintln!("Error received in the IPC thread: {}\n    This is most likely occuring due to the python script not yet running", e);
+        eprintln!("Error received in the IPC thread: {}", e);
     }
-    fmcw_thread.join().unwrap();
+    // The parser finishes the sinks, which waits for their threads
     tlv_reader_thread.join().unwrap();
 }
 
@@ -80,20 +391,16 @@ fn test_ipc() {
     frame.set_pointcloud(vec![pc1, pc2]);
     frame.set_range_profile(vec![6.3f64, 3.6f64, 63.0f64]);
 
-    ipc::ipc_test_sender(frame);
-}
-
-fn read_tlv_file(filepath: &Path) {
-    let mut tlv_bytes: Vec<u8> = get_result(read_byte_file(filepath));
-    println!("Size of buf: {}", tlv_bytes.len());
-    translate_tlv(&mut tlv_bytes);
+    if let Err(e) = ipc::ipc_test_sender(frame, &IpcSettings::default().socket_path) {
+        eprintln!("{}", e);
+    }
 }
 
 fn get_result<T>(maybe_result: Result<T, std::io::Error>) -> T {
     match maybe_result {
         Ok(res) => res,
         Err(e) => {
-            eprintln!("{}", e.to_string());
+            eprintln!("{}", e);
             std::process::exit(-1);
         }
     }
@@ -0,0 +1,160 @@
+use mcap::records::{MessageHeader, Metadata};
+use mcap::Writer;
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, File};
+use std::io::{BufWriter, Error};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::file_reader::{Config, RadarParameters, Settings};
+use super::foxglove::{frame_messages, CHANNELS};
+use super::pipeline::FrameSink;
+use super::recording::timestamped_path;
+use super::tlv_translator::Frame;
+
+/// How often the current chunk is closed and written to disk, so that
+/// an interrupted recording only loses the last moments.
+const CHUNK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Records frames into an MCAP file which can be opened in Foxglove.
+///
+/// All messages are JSON, on the topics of `foxglove::CHANNELS`, see
+/// `frame_messages`.
+///
+/// The `.cfg` text and the settings are stored as the `iwr68xx`
+/// metadata of the file.
+pub struct McapRecorder {
+    writer: Writer<BufWriter<File>>,
+    /// The channel id of every topic
+    channels: Vec<(&'static str, u16)>,
+    params: Option<RadarParameters>,
+    sequence: u32,
+    last_chunk: Instant,
+    finished: bool,
+}
+
+impl McapRecorder {
+    /// Creates a new MCAP file at `path`, overwriting any existing file.
+    pub fn create(
+        path: &Path,
+        config: &Config,
+        settings: &Settings,
+    ) -> Result<McapRecorder, Error> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut writer = Writer::new(file).map_err(Error::other)?;
+
+        let mut channels = vec![];
+        for channel in &CHANNELS {
+            let schema_id = writer
+                .add_schema(
+                    channel.schema_name,
+                    "jsonschema",
+                    channel.schema().to_string().as_bytes(),
+                )
+                .map_err(Error::other)?;
+            let channel_id = writer
+                .add_channel(schema_id, channel.topic, "json", &BTreeMap::new())
+                .map_err(Error::other)?;
+            channels.push((channel.topic, channel_id));
+        }
+
+        let metadata = Metadata {
+            name: "iwr68xx".to_string(),
+            metadata: BTreeMap::from([
+                ("config".to_string(), config.raw_input.clone()),
+                ("settings".to_string(), settings.to_toml_string()),
+                (
+                    "software_version".to_string(),
+                    env!("CARGO_PKG_VERSION").to_string(),
+                ),
+            ]),
+        };
+        writer.write_metadata(&metadata).map_err(Error::other)?;
+
+        Ok(McapRecorder {
+            writer,
+            channels,
+            params: config.radar_parameters().ok(),
+            sequence: 0,
+            last_chunk: Instant::now(),
+            finished: false,
+        })
+    }
+
+    /// Creates a new MCAP file with a timestamped name in `directory`,
+    /// creating the directory if needed.
+    pub fn create_in(
+        directory: &Path,
+        config: &Config,
+        settings: &Settings,
+    ) -> Result<(McapRecorder, PathBuf), Error> {
+        create_dir_all(directory)?;
+        let path = timestamped_path(directory, "capture", "mcap");
+        Ok((McapRecorder::create(&path, config, settings)?, path))
+    }
+
+    /// Writes a message on every topic the frame has data for, stamped
+    /// with the current time.
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<(), Error> {
+        let now_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        for (topic, message) in frame_messages(frame, self.params.as_ref(), now_ns) {
+            let Some(&(_, channel_id)) = self.channels.iter().find(|(t, _)| *t == topic) else {
+                continue;
+            };
+            self.write(channel_id, now_ns, message.to_string().as_bytes())?;
+        }
+
+        if self.last_chunk.elapsed() >= CHUNK_PERIOD {
+            self.last_chunk = Instant::now();
+            self.writer.flush().map_err(Error::other)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, channel_id: u16, log_time: u64, message: &[u8]) -> Result<(), Error> {
+        let header = MessageHeader {
+            channel_id,
+            sequence: self.sequence,
+            log_time,
+            publish_time: log_time,
+        };
+        self.sequence = self.sequence.wrapping_add(1);
+        self.writer
+            .write_to_known_channel(&header, message)
+            .map_err(Error::other)
+    }
+
+    /// Writes the summary and footer, after which nothing can be written.
+    pub fn finish(&mut self) -> Result<(), Error> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.writer.finish().map(|_| ()).map_err(Error::other)
+    }
+}
+
+impl Drop for McapRecorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            eprintln!("Could not finish the MCAP file: {}", e);
+        }
+    }
+}
+
+impl FrameSink for McapRecorder {
+    fn name(&self) -> &str {
+        "MCAP"
+    }
+
+    fn write(&mut self, frame: &Frame) -> Result<(), Error> {
+        self.write_frame(frame)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        McapRecorder::finish(self)
+    }
+}
@@ -0,0 +1,257 @@
+use rumqttc::{Client, Connection, Event, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use serde_json::{json, Value};
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+
+use super::file_reader::{MqttSettings, RadarParameters};
+use super::fmcw_manager::ConnectionState;
+use super::queue::QueueReceiver;
+use super::tlv_translator::Frame;
+
+/// Messages waiting to be sent to the broker, further messages are
+/// dropped while the broker is unreachable.
+const REQUEST_CAPACITY: usize = 256;
+
+/// The wait before reconnecting to the broker after an error.
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+/// Publishes the frames provided through `frames` to an MQTT broker,
+/// on topics under `<mqtt.topic_prefix>/<mqtt.sensor_id>`:
+///
+/// - `detections`: every frame, the points as `[x, y, z, doppler, snr]`
+///   arrays, in m, m/s and dB, e.g.
+///   `{"frame": 12, "timestamp": 1792347506.79, "points": [[0.5, 2.1, 0.0, -0.13, 24.3]]}`
+/// - `range_profile`: every `mqtt.range_profile_every` frames, reduced
+///   to `mqtt.range_profile_bins` bins holding the maximum in dB
+/// - `telemetry`: every `mqtt.telemetry_every` frames, the statistics
+///   and temperatures
+/// - `status`: `online` while connected, retained. The broker sets it to
+///   `offline` as the last will when the tool disappears.
+/// - `connection_state`: the connection state of the FMCW from `state`,
+///   retained
+///
+/// The broker is reconnected to whenever the connection is lost, the
+/// frames never wait for it: while it is unreachable the newest frames
+/// are dropped once `REQUEST_CAPACITY` messages are waiting.
+///
+/// Returns once all senders of `frames` have been dropped, or with an
+/// error if the settings are invalid.
+pub fn mqtt_publisher(
+    frames: QueueReceiver<Frame>,
+    mut state: Option<watch::Receiver<ConnectionState>>,
+    settings: &MqttSettings,
+    params: Option<RadarParameters>,
+) -> Result<(), Error> {
+    let qos = match settings.qos {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        2 => QoS::ExactlyOnce,
+        other => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("mqtt.qos must be 0, 1 or 2, not {}", other),
+            ))
+        }
+    };
+    let base = format!("{}/{}", settings.topic_prefix, settings.sensor_id);
+    let status_topic = format!("{}/status", base);
+    let client_id = if settings.client_id.is_empty() {
+        format!("iwr68xx-{}", settings.sensor_id)
+    } else {
+        settings.client_id.clone()
+    };
+
+    let mut options = MqttOptions::new(client_id, &settings.host, settings.port);
+    options
+        .set_keep_alive(Duration::from_secs(settings.keep_alive_s.max(5)))
+        .set_last_will(LastWill::new(
+            &status_topic,
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+    if !settings.username.is_empty() {
+        options.set_credentials(&settings.username, &settings.password);
+    }
+    let (client, connection) = Client::new(options, REQUEST_CAPACITY);
+    let stopping = Arc::new(AtomicBool::new(false));
+    let connection_thread = {
+        let client = client.clone();
+        let status_topic = status_topic.clone();
+        let stopping = stopping.clone();
+        thread::spawn(move || drive_connection(connection, client, status_topic, stopping))
+    };
+    println!(
+        "Publishing frames to the MQTT broker at {}:{} under {}/",
+        settings.host, settings.port, base
+    );
+
+    let publish = |topic: &str, qos: QoS, retain: bool, payload: Vec<u8>| {
+        // Only fails when too many messages are waiting for the broker
+        _ = client.try_publish(format!("{}/{}", base, topic), qos, retain, payload);
+    };
+    if let Some(ref mut state) = state {
+        state.mark_changed();
+    }
+    let mut frame_count: usize = 0;
+    loop {
+        if let Some(ref mut state) = state {
+            if state.has_changed().unwrap_or(false) {
+                let connection_state = *state.borrow_and_update();
+                let payload = serde_json::to_vec(&connection_state).unwrap();
+                publish("connection_state", QoS::AtLeastOnce, true, payload);
+            }
+        }
+
+        let frame = match frames.recv_timeout(Duration::from_millis(100)) {
+            Ok(v) => v,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        publish("detections", qos, false, detections(&frame, timestamp));
+        if frame_count.is_multiple_of(settings.range_profile_every.max(1)) {
+            if let Some(payload) = range_profile(
+                &frame,
+                timestamp,
+                settings.range_profile_bins,
+                params.as_ref(),
+            ) {
+                publish("range_profile", qos, false, payload);
+            }
+        }
+        if frame_count.is_multiple_of(settings.telemetry_every.max(1)) {
+            publish("telemetry", qos, false, telemetry(&frame, timestamp));
+        }
+        frame_count += 1;
+    }
+
+    // A clean shutdown does not trigger the last will
+    publish_status(&client, &status_topic, "offline");
+    stopping.store(true, Ordering::Relaxed);
+    _ = client.disconnect();
+    connection_thread.join().unwrap();
+    Ok(())
+}
+
+/// Runs the event loop of the MQTT client, marking the tool online on
+/// every (re)connection, until it is disconnected.
+fn drive_connection(
+    mut connection: Connection,
+    client: Client,
+    status_topic: String,
+    stopping: Arc<AtomicBool>,
+) {
+    let mut connected = false;
+    // Only the first error of every outage is reported
+    let mut reported = false;
+    for event in connection.iter() {
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                println!("Connected to the MQTT broker");
+                connected = true;
+                reported = false;
+                publish_status(&client, &status_topic, "online");
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+            Ok(_) => {}
+            Err(e) => {
+                if stopping.load(Ordering::Relaxed) {
+                    break;
+                }
+                if !reported {
+                    if connected {
+                        eprintln!("Lost the connection to the MQTT broker: {}", e);
+                    } else {
+                        eprintln!("Could not connect to the MQTT broker: {}", e);
+                    }
+                    println!("Retrying every {} s", RECONNECT_DELAY.as_secs());
+                    reported = true;
+                }
+                connected = false;
+                thread::sleep(RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+fn publish_status(client: &Client, status_topic: &str, status: &str) {
+    _ = client.try_publish(status_topic, QoS::AtLeastOnce, true, status);
+}
+
+fn detections(frame: &Frame, timestamp: f64) -> Vec<u8> {
+    let points: Vec<Value> = frame
+        .pointcloud
+        .iter()
+        .flatten()
+        .enumerate()
+        .map(|(i, p)| {
+            let snr = frame
+                .side_info
+                .as_ref()
+                .and_then(|s| s.get(i))
+                .map(|s| round(s.snr as f64 * 0.1, 1));
+            json!([
+                round(p.x as f64, 3),
+                round(p.y as f64, 3),
+                round(p.z as f64, 3),
+                round(p.d as f64, 3),
+                snr
+            ])
+        })
+        .collect();
+    serde_json::to_vec(&json!({
+        "frame": frame.frame_num,
+        "timestamp": timestamp,
+        "points": points,
+    }))
+    .unwrap()
+}
+
+/// The range profile reduced to at most `bins` bins, each holding the
+/// maximum of the bins it covers, `None` if the frame has none.
+fn range_profile(
+    frame: &Frame,
+    timestamp: f64,
+    bins: usize,
+    params: Option<&RadarParameters>,
+) -> Option<Vec<u8>> {
+    let profile = frame.range_profile.as_ref()?;
+    let group = profile.len().div_ceil(bins.max(1)).max(1);
+    let db: Vec<f64> = profile
+        .chunks(group)
+        .map(|c| round(c.iter().copied().fold(f64::MIN, f64::max), 1))
+        .collect();
+    let message = json!({
+        "frame": frame.frame_num,
+        "timestamp": timestamp,
+        "bin_width_m": params.map(|p| p.range_resolution_m * group as f64),
+        "db": db,
+    });
+    Some(serde_json::to_vec(&message).unwrap())
+}
+
+fn telemetry(frame: &Frame, timestamp: f64) -> Vec<u8> {
+    serde_json::to_vec(&json!({
+        "frame": frame.frame_num,
+        "timestamp": timestamp,
+        "num_points": frame.pointcloud.as_ref().map_or(0, |pc| pc.len()),
+        "stats": frame.stats,
+        "temperature": frame.temperature,
+    }))
+    .unwrap()
+}
+
+fn round(value: f64, decimals: i32) -> f64 {
+    let scale = 10f64.powi(decimals);
+    (value * scale).round() / scale
+}
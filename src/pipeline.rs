@@ -0,0 +1,576 @@
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::thread;
+use tokio::sync::watch;
+
+use super::file_reader::{Config, RadarParameters, Settings};
+use super::fmcw_manager::ConnectionState;
+use super::frame_log::FrameLogWriter;
+use super::mcap_recorder::McapRecorder;
+use super::mqtt;
+use super::pointcloud_export::SensorPose;
+use super::queue::{self, DropPolicy, QueueReceiver, QueueSender, QueueStats};
+use super::recording::{RecordingMetadata, RecordingWriter};
+use super::renderer::RenderSink;
+use super::tlv_translator::{Frame, PointCloudPoint, SideInfo};
+use super::udp;
+
+/// A step between decoding and the sinks, such as a filter or a
+/// transform.
+pub trait FrameProcessor: Send {
+    /// Changes a frame, or drops it by returning `None`.
+    fn process(&mut self, frame: Frame) -> Option<Frame>;
+}
+
+/// Where the processed frames end up.
+pub trait FrameSink: Send {
+    /// Shown when writing a frame fails
+    fn name(&self) -> &str;
+
+    /// Called with the bytes as they were received, before the frames
+    /// in them are decoded. Only a recorder of the raw data needs them.
+    fn write_chunk(&mut self, _bytes: &[u8]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn write(&mut self, frame: &Frame) -> Result<(), Error>;
+
+    /// Called once the stream has ended.
+    fn finish(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// The queue in front of the sink, if it runs on another thread.
+    fn queue_stats(&self) -> Option<Arc<QueueStats>> {
+        None
+    }
+}
+
+/// Hands the frames to a sink on another thread, such as the IPC server.
+impl FrameSink for QueueSender<Frame> {
+    fn name(&self) -> &str {
+        self.stats().name()
+    }
+
+    fn write(&mut self, frame: &Frame) -> Result<(), Error> {
+        self.send(frame.clone())
+            .map_err(|_| Error::new(ErrorKind::BrokenPipe, "the receiving thread has stopped"))
+    }
+
+    fn queue_stats(&self) -> Option<Arc<QueueStats>> {
+        Some(self.stats())
+    }
+}
+
+/// The recorders or the sinks of a `Pipeline`.
+pub type Sinks = Vec<Box<dyn FrameSink>>;
+
+/// Hands the frames to a function running on a thread of its own, such
+/// as the MQTT publisher, through a queue. Finishing waits until the
+/// function has returned.
+pub struct ThreadSink {
+    name: &'static str,
+    queue: Option<QueueSender<Frame>>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl ThreadSink {
+    pub fn spawn(
+        name: &'static str,
+        queue_size: usize,
+        policy: DropPolicy,
+        work: impl FnOnce(QueueReceiver<Frame>) + Send + 'static,
+    ) -> ThreadSink {
+        let (queue, frames) = queue::bounded(name, queue_size, policy);
+        ThreadSink {
+            name,
+            queue: Some(queue),
+            worker: Some(thread::spawn(move || work(frames))),
+        }
+    }
+}
+
+impl FrameSink for ThreadSink {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn write(&mut self, frame: &Frame) -> Result<(), Error> {
+        match self.queue {
+            Some(ref mut queue) => queue.write(frame),
+            None => Err(Error::other("the sink has been finished")),
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        self.queue = None;
+        if let Some(worker) = self.worker.take() {
+            worker
+                .join()
+                .map_err(|_| Error::other(format!("the {} thread panicked", self.name)))?;
+        }
+        Ok(())
+    }
+
+    fn queue_stats(&self) -> Option<Arc<QueueStats>> {
+        self.queue.as_ref().map(|q| q.stats())
+    }
+}
+
+/// Drops the points below `pipeline.min_snr_db`. Frames without side
+/// info are passed on unchanged.
+pub struct SnrFilter {
+    min_snr_db: f64,
+}
+
+impl FrameProcessor for SnrFilter {
+    fn process(&mut self, mut frame: Frame) -> Option<Frame> {
+        retain_points(&mut frame, |_, side_info| {
+            side_info.is_none_or(|s| s.snr as f64 * 0.1 >= self.min_snr_db)
+        });
+        Some(frame)
+    }
+}
+
+/// Drops the points closer than `pipeline.min_range_m` or further than
+/// `pipeline.max_range_m` from the sensor, so it has to come before
+/// `sensor_pose`.
+pub struct RangeFilter {
+    min_range_m: f64,
+    max_range_m: f64,
+}
+
+impl FrameProcessor for RangeFilter {
+    fn process(&mut self, mut frame: Frame) -> Option<Frame> {
+        retain_points(&mut frame, |point, _| {
+            let range = (point.x as f64).hypot(point.y as f64).hypot(point.z as f64);
+            (self.min_range_m..=self.max_range_m).contains(&range)
+        });
+        Some(frame)
+    }
+}
+
+/// Moves the points into the coordinates the `[sensor_pose]` settings
+/// describe.
+pub struct PoseTransform {
+    pose: SensorPose,
+}
+
+impl FrameProcessor for PoseTransform {
+    fn process(&mut self, mut frame: Frame) -> Option<Frame> {
+        for point in frame.pointcloud.iter_mut().flatten() {
+            let [x, y, z] = self
+                .pose
+                .transform([point.x as f64, point.y as f64, point.z as f64]);
+            (point.x, point.y, point.z) = (x as f32, y as f32, z as f32);
+        }
+        Some(frame)
+    }
+}
+
+/// Keeps the points, and their side info, for which `keep` is true.
+fn retain_points(
+    frame: &mut Frame,
+    mut keep: impl FnMut(&PointCloudPoint, Option<&SideInfo>) -> bool,
+) {
+    let Some(points) = frame.pointcloud.take() else {
+        return;
+    };
+    let side_info = frame.side_info.take();
+    let mut kept_points = vec![];
+    let mut kept_side_info = vec![];
+    for (i, point) in points.into_iter().enumerate() {
+        let info = side_info.as_ref().and_then(|s| s.get(i));
+        if keep(&point, info) {
+            kept_points.push(point);
+            kept_side_info.extend(info.copied());
+        }
+    }
+    frame.pointcloud = Some(kept_points);
+    frame.side_info = side_info.map(|_| kept_side_info);
+}
+
+/// The processors named in `pipeline.processors`, in that order. These
+/// are `snr_filter`, `range_filter` and `sensor_pose`.
+pub fn processors_from_settings(
+    settings: &Settings,
+) -> Result<Vec<Box<dyn FrameProcessor>>, Error> {
+    let pipeline = &settings.pipeline;
+    pipeline
+        .processors
+        .iter()
+        .map(|name| -> Result<Box<dyn FrameProcessor>, Error> {
+            match name.as_str() {
+                "snr_filter" => Ok(Box::new(SnrFilter {
+                    min_snr_db: pipeline.min_snr_db,
+                })),
+                "range_filter" => Ok(Box::new(RangeFilter {
+                    min_range_m: pipeline.min_range_m,
+                    max_range_m: pipeline.max_range_m,
+                })),
+                "sensor_pose" => Ok(Box::new(PoseTransform {
+                    pose: settings.sensor_pose.pose(),
+                })),
+                other => Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Unknown processor \"{}\" in pipeline.processors, use snr_filter, range_filter or sensor_pose",
+                        other
+                    ),
+                )),
+            }
+        })
+        .collect()
+}
+
+/// The recorders selected in `settings.recording`: the raw data with
+/// `save_raw_data`, a JSON frame log with `save_frames` and an MCAP file
+/// with `save_mcap`. A recorder which can not be opened is reported and
+/// left out.
+pub fn recorders_from_settings(settings: &Settings, config: &Config) -> Sinks {
+    let recording = &settings.recording;
+    let mut recorders: Sinks = vec![];
+    if recording.save_raw_data {
+        let metadata = RecordingMetadata::new(config, settings);
+        match RecordingWriter::create_in(&recording.directory, &metadata) {
+            Ok((writer, path)) => {
+                println!("Recording the raw data to {}", path.display());
+                recorders.push(Box::new(writer));
+            }
+            Err(e) => eprintln!("Could not create a recording: {}", e),
+        }
+    }
+    if recording.save_frames {
+        match FrameLogWriter::create(
+            &recording.directory,
+            recording.frame_log_compression,
+            recording.frame_log_max_bytes,
+            std::time::Duration::from_secs(recording.frame_log_max_seconds),
+        ) {
+            Ok(writer) => recorders.push(Box::new(writer)),
+            Err(e) => eprintln!("Could not create a frame log: {}", e),
+        }
+    }
+    if recording.save_mcap {
+        match McapRecorder::create_in(&recording.directory, config, settings) {
+            Ok((writer, path)) => {
+                println!("Recording the frames to {}", path.display());
+                recorders.push(Box::new(writer));
+            }
+            Err(e) => eprintln!("Could not create an MCAP file: {}", e),
+        }
+    }
+    recorders
+}
+
+/// The sinks enabled in the settings: the renderer of `[render]`, the
+/// MQTT publisher of `[mqtt]` and the UDP sender of `[udp]`, each on a
+/// thread of its own behind the queue set in `[pipeline]`.
+///
+/// When any of the transports of `[ipc]` is enabled the frames for the
+/// IPC server are queued as well, the receiving side of that queue is
+/// returned for `ipc_server`.
+pub fn sinks_from_settings(
+    settings: &Settings,
+    params: Option<RadarParameters>,
+    state: Option<watch::Receiver<ConnectionState>>,
+) -> Result<(Sinks, Option<QueueReceiver<Frame>>), Error> {
+    let queues = &settings.pipeline;
+    let mut sinks: Sinks = vec![];
+    if settings.render.enabled {
+        sinks.push(Box::new(RenderSink::spawn(
+            &settings.render,
+            params.clone(),
+            queues.render_queue_size,
+            queues.render_policy,
+        )?));
+    }
+    let ipc = if settings.ipc.any_enabled() {
+        let (ipc_tx, ipc_rx) = queue::bounded("ipc", queues.ipc_queue_size, queues.ipc_policy);
+        sinks.push(Box::new(ipc_tx));
+        Some(ipc_rx)
+    } else {
+        None
+    };
+    if settings.mqtt.enabled {
+        let mqtt_settings = settings.mqtt.clone();
+        sinks.push(Box::new(ThreadSink::spawn(
+            "mqtt",
+            queues.mqtt_queue_size,
+            queues.mqtt_policy,
+            move |frames| {
+                if let Err(e) = mqtt::mqtt_publisher(frames, state, &mqtt_settings, params) {
+                    eprintln!("Error received in the MQTT thread: {}", e);
+                }
+            },
+        )));
+    }
+    if settings.udp.enabled {
+        let udp_settings = settings.udp.clone();
+        sinks.push(Box::new(ThreadSink::spawn(
+            "udp",
+            queues.udp_queue_size,
+            queues.udp_policy,
+            move |frames| {
+                if let Err(e) = udp::udp_sender(frames, &udp_settings) {
+                    eprintln!("Error received in the UDP thread: {}", e);
+                }
+            },
+        )));
+    }
+    Ok((sinks, ipc))
+}
+
+/// Records every decoded frame as is, runs it through the processors,
+/// and hands what is left of it to every sink.
+pub struct Pipeline {
+    /// Get the received bytes and the frames before the processors
+    recorders: Sinks,
+    processors: Vec<Box<dyn FrameProcessor>>,
+    sinks: Sinks,
+}
+
+impl Pipeline {
+    pub fn new(processors: Vec<Box<dyn FrameProcessor>>) -> Pipeline {
+        Pipeline {
+            recorders: vec![],
+            processors,
+            sinks: vec![],
+        }
+    }
+
+    pub fn add_sink(&mut self, sink: Box<dyn FrameSink>) {
+        self.sinks.push(sink);
+    }
+
+    pub fn add_recorder(&mut self, recorder: Box<dyn FrameSink>) {
+        self.recorders.push(recorder);
+    }
+
+    pub fn is_recording(&self) -> bool {
+        !self.recorders.is_empty()
+    }
+
+    /// Finishes the recorders and removes them.
+    pub fn stop_recording(&mut self) {
+        finish_sinks(&mut self.recorders);
+        self.recorders.clear();
+    }
+
+    /// Hands received bytes to the recorders. A recorder which fails is
+    /// reported and removed.
+    pub fn write_chunk(&mut self, bytes: &[u8]) {
+        self.recorders
+            .retain_mut(|recorder| match recorder.write_chunk(bytes) {
+                Ok(()) => true,
+                Err(e) => {
+                    eprintln!(
+                        "Could not write to the {} recorder, no longer writing to it: {}",
+                        recorder.name(),
+                        e
+                    );
+                    false
+                }
+            });
+    }
+
+    /// Runs a frame through the processors, `None` if one dropped it.
+    pub fn process(&mut self, frame: Frame) -> Option<Frame> {
+        self.processors
+            .iter_mut()
+            .try_fold(frame, |frame, processor| processor.process(frame))
+    }
+
+    /// Records a frame, processes it and writes it to the sinks. A
+    /// recorder or sink which fails is reported and not written to
+    /// again.
+    pub fn push(&mut self, frame: Frame) {
+        write_sinks(&mut self.recorders, &frame);
+        if let Some(frame) = self.process(frame) {
+            write_sinks(&mut self.sinks, &frame);
+        }
+    }
+
+    pub fn finish(&mut self) {
+        self.stop_recording();
+        finish_sinks(&mut self.sinks);
+    }
+
+    /// The queues in front of the sinks which run on other threads.
+    pub fn queue_stats(&self) -> Vec<Arc<QueueStats>> {
+        self.sinks.iter().filter_map(|s| s.queue_stats()).collect()
+    }
+}
+
+fn write_sinks(sinks: &mut Sinks, frame: &Frame) {
+    sinks.retain_mut(|sink| match sink.write(frame) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!(
+                "Could not write frame {} to the {} sink, no longer writing to it: {}",
+                frame.frame_num,
+                sink.name(),
+                e
+            );
+            false
+        }
+    });
+}
+
+fn finish_sinks(sinks: &mut [Box<dyn FrameSink>]) {
+    for sink in sinks {
+        if let Err(e) = sink.finish() {
+            eprintln!("Could not finish the {} sink: {}", sink.name(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Keeps the frame numbers and bytes it is given, fails after
+    /// `fail_after` frames. Its clones share what was given.
+    #[derive(Clone)]
+    struct TestSink {
+        frames: Arc<Mutex<Vec<usize>>>,
+        bytes: Arc<Mutex<usize>>,
+        fail_after: usize,
+    }
+
+    impl TestSink {
+        fn new(fail_after: usize) -> TestSink {
+            TestSink {
+                frames: Arc::new(Mutex::new(vec![])),
+                bytes: Arc::new(Mutex::new(0)),
+                fail_after,
+            }
+        }
+
+        fn frames(&self) -> Vec<usize> {
+            self.frames.lock().unwrap().clone()
+        }
+
+        fn bytes(&self) -> usize {
+            *self.bytes.lock().unwrap()
+        }
+    }
+
+    impl FrameSink for TestSink {
+        fn name(&self) -> &str {
+            "test"
+        }
+
+        fn write_chunk(&mut self, bytes: &[u8]) -> Result<(), Error> {
+            *self.bytes.lock().unwrap() += bytes.len();
+            Ok(())
+        }
+
+        fn write(&mut self, frame: &Frame) -> Result<(), Error> {
+            let mut frames = self.frames.lock().unwrap();
+            if frames.len() >= self.fail_after {
+                return Err(Error::other("full"));
+            }
+            frames.push(frame.frame_num);
+            Ok(())
+        }
+    }
+
+    /// Drops the frames with an odd number.
+    struct EvenFrames;
+
+    impl FrameProcessor for EvenFrames {
+        fn process(&mut self, frame: Frame) -> Option<Frame> {
+            frame.frame_num.is_multiple_of(2).then_some(frame)
+        }
+    }
+
+    #[test]
+    fn recorders_get_every_frame_and_sinks_the_processed_ones() {
+        let mut pipeline = Pipeline::new(vec![Box::new(EvenFrames)]);
+        let recorder = TestSink::new(usize::MAX);
+        let sink = TestSink::new(usize::MAX);
+        pipeline.add_recorder(Box::new(recorder.clone()));
+        pipeline.add_sink(Box::new(sink.clone()));
+
+        pipeline.write_chunk(&[0; 10]);
+        for i in 0..4 {
+            pipeline.push(Frame::empty(i));
+        }
+        assert_eq!(recorder.frames(), vec![0, 1, 2, 3]);
+        assert_eq!(sink.frames(), vec![0, 2]);
+        assert_eq!(recorder.bytes(), 10);
+        assert_eq!(sink.bytes(), 0);
+
+        pipeline.stop_recording();
+        assert!(!pipeline.is_recording());
+        pipeline.push(Frame::empty(4));
+        assert_eq!(recorder.frames().len(), 4);
+        assert_eq!(sink.frames(), vec![0, 2, 4]);
+    }
+
+    #[test]
+    fn a_failing_sink_is_removed_without_affecting_the_others() {
+        let mut pipeline = Pipeline::new(vec![]);
+        let failing = TestSink::new(1);
+        let sink = TestSink::new(usize::MAX);
+        pipeline.add_sink(Box::new(failing.clone()));
+        pipeline.add_sink(Box::new(sink.clone()));
+        for i in 0..3 {
+            pipeline.push(Frame::empty(i));
+        }
+        assert_eq!(failing.frames(), vec![0]);
+        assert_eq!(sink.frames(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn a_thread_sink_finishes_once_its_thread_is_done() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let worker_received = received.clone();
+        let mut sink = ThreadSink::spawn("test", 4, DropPolicy::Block, move |frames| {
+            for frame in frames.iter() {
+                worker_received.lock().unwrap().push(frame.frame_num);
+            }
+        });
+        for i in 0..3 {
+            sink.write(&Frame::empty(i)).unwrap();
+        }
+        sink.finish().unwrap();
+        assert_eq!(*received.lock().unwrap(), vec![0, 1, 2]);
+        assert!(sink.write(&Frame::empty(3)).is_err());
+    }
+
+    #[test]
+    fn the_ipc_frames_are_queued_when_any_transport_is_enabled() {
+        let mut settings = Settings::default();
+        settings.render.enabled = false;
+        settings.mqtt.enabled = false;
+        settings.udp.enabled = false;
+        settings.ipc.enabled = false;
+        let (sinks, ipc) = sinks_from_settings(&settings, None, None).unwrap();
+        assert!(sinks.is_empty() && ipc.is_none());
+
+        settings.ipc.tcp_enabled = true;
+        let (sinks, ipc) = sinks_from_settings(&settings, None, None).unwrap();
+        assert_eq!(sinks.len(), 1);
+        assert!(ipc.is_some());
+    }
+
+    #[test]
+    fn the_sensor_pose_processor_uses_its_own_section() {
+        let mut settings = Settings::default();
+        settings.pipeline.processors = vec!["sensor_pose".to_string()];
+        settings.sensor_pose.position_m = [1.0, 2.0, 3.0];
+        settings.export.sensor_position_m = [10.0, 10.0, 10.0];
+        let mut processors = processors_from_settings(&settings).unwrap();
+        let mut frame = Frame::empty(0);
+        frame.pointcloud = Some(vec![PointCloudPoint::empty()]);
+        let frame = processors[0].process(frame).unwrap();
+        let point = frame.pointcloud.unwrap()[0];
+        assert_eq!((point.x, point.y, point.z), (1.0, 2.0, 3.0));
+
+        settings.pipeline.processors = vec!["pose".to_string()];
+        assert!(processors_from_settings(&settings).is_err());
+    }
+}
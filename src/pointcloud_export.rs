@@ -0,0 +1,331 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, File};
+use std::io::{BufWriter, Error, Write};
+use std::path::PathBuf;
+
+use super::file_reader::ExportSettings;
+use super::tlv_translator::Frame;
+
+/// The file format of an exported point cloud.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PointCloudFormat {
+    /// The Point Cloud Data format of the Point Cloud Library
+    Pcd,
+    /// The Polygon File Format, without any faces
+    Ply,
+}
+
+impl PointCloudFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            PointCloudFormat::Pcd => "pcd",
+            PointCloudFormat::Ply => "ply",
+        }
+    }
+}
+
+/// How the sensor is mounted, used to move the points from the sensor
+/// coordinates (x to the right, y along the boresight, z up) into the
+/// common coordinates of all exported frames.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SensorPose {
+    pub position_m: [f64; 3],
+    /// Rotation around the z axis, positive turns the boresight left
+    pub yaw_deg: f64,
+    /// Rotation around the x axis, positive tilts the boresight up
+    pub pitch_deg: f64,
+    /// Rotation around the boresight
+    pub roll_deg: f64,
+}
+
+impl SensorPose {
+    /// Moves a point from the sensor coordinates into the common
+    /// coordinates, rotating by roll, then pitch, then yaw.
+    pub fn transform(&self, point: [f64; 3]) -> [f64; 3] {
+        let (sin_r, cos_r) = self.roll_deg.to_radians().sin_cos();
+        let (sin_p, cos_p) = self.pitch_deg.to_radians().sin_cos();
+        let (sin_y, cos_y) = self.yaw_deg.to_radians().sin_cos();
+        let [x, y, z] = point;
+        // Roll, around y
+        let (x, z) = (x * cos_r + z * sin_r, -x * sin_r + z * cos_r);
+        // Pitch, around x
+        let (y, z) = (y * cos_p - z * sin_p, y * sin_p + z * cos_p);
+        // Yaw, around z
+        let (x, y) = (x * cos_y - y * sin_y, x * sin_y + y * cos_y);
+        [
+            x + self.position_m[0],
+            y + self.position_m[1],
+            z + self.position_m[2],
+        ]
+    }
+}
+
+/// A single exported point. The SNR and noise are `NaN` when the frame
+/// has no side info.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExportPoint {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub doppler: f32,
+    pub snr_db: f32,
+    pub noise_db: f32,
+    pub frame: u32,
+}
+
+/// The fields of an `ExportPoint`, in the order they are written.
+const FIELDS: [&str; 7] = ["x", "y", "z", "doppler", "snr", "noise", "frame"];
+
+impl ExportPoint {
+    fn floats(&self) -> [f32; 6] {
+        [
+            self.x,
+            self.y,
+            self.z,
+            self.doppler,
+            self.snr_db,
+            self.noise_db,
+        ]
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut result: Vec<u8> = self.floats().iter().flat_map(|f| f.to_le_bytes()).collect();
+        result.extend_from_slice(&self.frame.to_le_bytes());
+        result
+    }
+
+    fn to_ascii(self) -> String {
+        let mut fields: Vec<String> = self.floats().iter().map(|f| f.to_string()).collect();
+        fields.push(self.frame.to_string());
+        fields.join(" ")
+    }
+}
+
+/// Collects the points of a frame, moved into the common coordinates.
+pub fn frame_points(frame: &Frame, pose: &SensorPose) -> Vec<ExportPoint> {
+    let Some(ref pointcloud) = frame.pointcloud else {
+        return vec![];
+    };
+    pointcloud
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            let [x, y, z] = pose.transform([p.x as f64, p.y as f64, p.z as f64]);
+            let side_info = frame.side_info.as_ref().and_then(|s| s.get(i));
+            ExportPoint {
+                x: x as f32,
+                y: y as f32,
+                z: z as f32,
+                doppler: p.d,
+                snr_db: side_info.map_or(f32::NAN, |s| s.snr as f32 * 0.1),
+                noise_db: side_info.map_or(f32::NAN, |s| s.noise as f32 * 0.1),
+                frame: frame.frame_num as u32,
+            }
+        })
+        .collect()
+}
+
+/// Writes the points as a PCD file, version 0.7.
+pub fn write_pcd(
+    writer: &mut impl Write,
+    points: &[ExportPoint],
+    ascii: bool,
+) -> Result<(), Error> {
+    writeln!(writer, "# .PCD v0.7 - Point Cloud Data file format")?;
+    writeln!(writer, "VERSION 0.7")?;
+    writeln!(writer, "FIELDS {}", FIELDS.join(" "))?;
+    writeln!(writer, "SIZE 4 4 4 4 4 4 4")?;
+    writeln!(writer, "TYPE F F F F F F U")?;
+    writeln!(writer, "COUNT 1 1 1 1 1 1 1")?;
+    writeln!(writer, "WIDTH {}", points.len())?;
+    writeln!(writer, "HEIGHT 1")?;
+    writeln!(writer, "VIEWPOINT 0 0 0 1 0 0 0")?;
+    writeln!(writer, "POINTS {}", points.len())?;
+    writeln!(writer, "DATA {}", if ascii { "ascii" } else { "binary" })?;
+    write_points(writer, points, ascii)
+}
+
+/// Writes the points as the vertices of a PLY file.
+pub fn write_ply(
+    writer: &mut impl Write,
+    points: &[ExportPoint],
+    ascii: bool,
+) -> Result<(), Error> {
+    writeln!(writer, "ply")?;
+    writeln!(
+        writer,
+        "format {} 1.0",
+        if ascii {
+            "ascii"
+        } else {
+            "binary_little_endian"
+        }
+    )?;
+    writeln!(writer, "comment Exported from an IWR68xx capture")?;
+    writeln!(writer, "element vertex {}", points.len())?;
+    for field in &FIELDS[..6] {
+        writeln!(writer, "property float {}", field)?;
+    }
+    writeln!(writer, "property uint frame")?;
+    writeln!(writer, "end_header")?;
+    write_points(writer, points, ascii)
+}
+
+fn write_points(writer: &mut impl Write, points: &[ExportPoint], ascii: bool) -> Result<(), Error> {
+    for point in points {
+        if ascii {
+            writeln!(writer, "{}", point.to_ascii())?;
+        } else {
+            writer.write_all(&point.to_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the point clouds of a stream of frames into files, either one
+/// file per frame or `accumulate_frames` frames per file.
+pub struct PointCloudExporter {
+    directory: PathBuf,
+    /// The start of every file name, e.g. the name of the capture
+    name: String,
+    format: PointCloudFormat,
+    ascii: bool,
+    /// Frames per file, 0 puts all frames into a single file
+    accumulate_frames: usize,
+    pose: SensorPose,
+    points: Vec<ExportPoint>,
+    /// The first and last frame number in `points`
+    frame_range: Option<(usize, usize)>,
+    frame_count: usize,
+    /// The position in the stream of the first frame in `points`, the
+    /// frame numbers restart when the sensor is restarted
+    first_index: usize,
+    frames_added: usize,
+}
+
+impl PointCloudExporter {
+    pub fn new(settings: &ExportSettings, name: &str) -> PointCloudExporter {
+        PointCloudExporter {
+            directory: settings.directory.clone(),
+            name: name.to_string(),
+            format: settings.pointcloud_format,
+            ascii: settings.ascii,
+            accumulate_frames: settings.accumulate_frames,
+            pose: settings.sensor_pose(),
+            points: vec![],
+            frame_range: None,
+            frame_count: 0,
+            first_index: 0,
+            frames_added: 0,
+        }
+    }
+
+    /// Adds the points of a frame, returns the path of the file written
+    /// if this frame completed one.
+    pub fn add_frame(&mut self, frame: &Frame) -> Result<Option<PathBuf>, Error> {
+        if self.frame_range.is_none() {
+            self.first_index = self.frames_added;
+        }
+        self.frames_added += 1;
+        self.points.extend(frame_points(frame, &self.pose));
+        self.frame_range = Some(
+            self.frame_range
+                .map_or((frame.frame_num, frame.frame_num), |(first, _)| {
+                    (first, frame.frame_num)
+                }),
+        );
+        self.frame_count += 1;
+        if self.accumulate_frames > 0 && self.frame_count >= self.accumulate_frames {
+            return self.flush();
+        }
+        Ok(None)
+    }
+
+    /// Writes the frames which were not written yet, returns the path of
+    /// the file if there were any.
+    pub fn flush(&mut self) -> Result<Option<PathBuf>, Error> {
+        let Some((first, last)) = self.frame_range.take() else {
+            return Ok(None);
+        };
+        create_dir_all(&self.directory)?;
+        let name = if self.frame_count == 1 {
+            format!(
+                "{}_{:06}_frame_{}.{}",
+                self.name,
+                self.first_index,
+                first,
+                self.format.extension()
+            )
+        } else {
+            format!(
+                "{}_{:06}_frames_{}_{}.{}",
+                self.name,
+                self.first_index,
+                first,
+                last,
+                self.format.extension()
+            )
+        };
+        let path = self.directory.join(name);
+        let mut writer = BufWriter::new(File::create(&path)?);
+        match self.format {
+            PointCloudFormat::Pcd => write_pcd(&mut writer, &self.points, self.ascii)?,
+            PointCloudFormat::Ply => write_ply(&mut writer, &self.points, self.ascii)?,
+        }
+        writer.flush()?;
+        self.points.clear();
+        self.frame_count = 0;
+        Ok(Some(path))
+    }
+}
+
+/// Exports the point clouds of a stream of frames, such as the frames
+/// returned by `load_frames`, returning the paths of the written files.
+///
+/// The files are named after `name`, the position of their first frame
+/// in the stream and the frame numbers they hold.
+pub fn export_pointclouds(
+    frames: impl Iterator<Item = Result<Frame, Error>>,
+    settings: &ExportSettings,
+    name: &str,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut exporter = PointCloudExporter::new(settings, name);
+    let mut written: Vec<PathBuf> = vec![];
+    for frame in frames {
+        written.extend(exporter.add_frame(&frame?)?);
+    }
+    written.extend(exporter.flush()?);
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_restarted_sensor_does_not_overwrite_frames() {
+        let settings = ExportSettings {
+            directory: std::env::temp_dir()
+                .join(format!("pointcloud_export_{}", std::process::id())),
+            ..ExportSettings::default()
+        };
+        // The frame numbers start over after the sensor was restarted
+        let frames = [1, 2, 1].map(|n| Ok(Frame::empty(n)));
+        let written = export_pointclouds(frames.into_iter(), &settings, "capture").unwrap();
+        _ = std::fs::remove_dir_all(&settings.directory);
+
+        let names: Vec<String> = written
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "capture_000000_frame_1.pcd",
+                "capture_000001_frame_2.pcd",
+                "capture_000002_frame_1.pcd"
+            ]
+        );
+    }
+}
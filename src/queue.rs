@@ -0,0 +1,361 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{RecvError, RecvTimeoutError, SendError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// How often a queue which keeps dropping items says so.
+const WARNING_PERIOD: Duration = Duration::from_secs(10);
+
+/// What a full queue does with a new item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropPolicy {
+    /// Wait until the consumer took an item, slowing down the producer
+    Block,
+    /// Drop the oldest queued item to make room
+    DropOldest,
+    /// Drop the new item
+    DropNewest,
+    /// Only keep the newest item, whatever the capacity
+    KeepLatest,
+}
+
+/// The counters of a queue, which can be read while it is in use.
+#[derive(Debug)]
+pub struct QueueStats {
+    name: &'static str,
+    capacity: usize,
+    policy: DropPolicy,
+    depth: AtomicUsize,
+    max_depth: AtomicUsize,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    last_warning: Mutex<Option<Instant>>,
+}
+
+/// The state of a queue at one moment, as reported by the `stats`
+/// command.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueueSnapshot {
+    pub name: &'static str,
+    pub capacity: usize,
+    pub policy: DropPolicy,
+    /// The items waiting for the consumer
+    pub depth: usize,
+    /// The most items which were ever waiting
+    pub max_depth: usize,
+    /// The items accepted into the queue
+    pub sent: u64,
+    /// The items dropped because the queue was full
+    pub dropped: u64,
+}
+
+impl QueueStats {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn snapshot(&self) -> QueueSnapshot {
+        QueueSnapshot {
+            name: self.name,
+            capacity: self.capacity,
+            policy: self.policy,
+            depth: self.depth.load(Ordering::Relaxed),
+            max_depth: self.max_depth.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    fn add_dropped(&self, count: usize) {
+        let total = self.dropped.fetch_add(count as u64, Ordering::Relaxed) + count as u64;
+        let mut last_warning = self.last_warning.lock().unwrap();
+        if last_warning.is_none_or(|t| t.elapsed() >= WARNING_PERIOD) {
+            *last_warning = Some(Instant::now());
+            eprintln!(
+                "The consumer of the {} queue falls behind, {} items dropped so far",
+                self.name, total
+            );
+        }
+    }
+}
+
+struct Inner<T> {
+    items: Mutex<VecDeque<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    /// Only changed while `items` is locked, so a waiting side never
+    /// misses the other side going away
+    senders: AtomicUsize,
+    receiver_alive: AtomicBool,
+    stats: Arc<QueueStats>,
+}
+
+impl<T> Inner<T> {
+    fn set_depth(&self, items: &MutexGuard<VecDeque<T>>) {
+        self.stats.depth.store(items.len(), Ordering::Relaxed);
+        self.stats
+            .max_depth
+            .fetch_max(items.len(), Ordering::Relaxed);
+    }
+}
+
+/// The sending side of a queue made by `bounded`, which can be cloned.
+pub struct QueueSender<T> {
+    inner: Arc<Inner<T>>,
+}
+
+/// The receiving side of a queue made by `bounded`.
+pub struct QueueReceiver<T> {
+    inner: Arc<Inner<T>>,
+}
+
+/// Creates a queue holding at most `capacity` items, which applies
+/// `policy` when it is full. Like `std::sync::mpsc`, the receiver is
+/// told once all senders are gone and the senders once the receiver is.
+///
+/// The `name` identifies the queue in its `QueueStats` and warnings.
+pub fn bounded<T>(
+    name: &'static str,
+    capacity: usize,
+    policy: DropPolicy,
+) -> (QueueSender<T>, QueueReceiver<T>) {
+    let inner = Arc::new(Inner {
+        items: Mutex::new(VecDeque::new()),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        senders: AtomicUsize::new(1),
+        receiver_alive: AtomicBool::new(true),
+        stats: Arc::new(QueueStats {
+            name,
+            capacity: capacity.max(1),
+            policy,
+            depth: AtomicUsize::new(0),
+            max_depth: AtomicUsize::new(0),
+            sent: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            last_warning: Mutex::new(None),
+        }),
+    });
+    (
+        QueueSender {
+            inner: inner.clone(),
+        },
+        QueueReceiver { inner },
+    )
+}
+
+impl<T> QueueSender<T> {
+    /// Queues an item, applying the drop policy when the queue is full.
+    /// Only `DropPolicy::Block` waits.
+    ///
+    /// Returns the item as an error if the receiver is gone. A dropped
+    /// item is not an error.
+    pub fn send(&self, item: T) -> Result<(), SendError<T>> {
+        let inner = &self.inner;
+        let capacity = inner.stats.capacity;
+        let mut items = inner.items.lock().unwrap();
+        if !inner.receiver_alive.load(Ordering::Relaxed) {
+            return Err(SendError(item));
+        }
+        match inner.stats.policy {
+            DropPolicy::Block => {
+                while items.len() >= capacity {
+                    items = inner.not_full.wait(items).unwrap();
+                    if !inner.receiver_alive.load(Ordering::Relaxed) {
+                        return Err(SendError(item));
+                    }
+                }
+            }
+            DropPolicy::DropOldest => {
+                let excess = (items.len() + 1).saturating_sub(capacity);
+                if excess > 0 {
+                    items.drain(..excess);
+                    inner.stats.add_dropped(excess);
+                }
+            }
+            DropPolicy::DropNewest => {
+                if items.len() >= capacity {
+                    inner.stats.add_dropped(1);
+                    return Ok(());
+                }
+            }
+            DropPolicy::KeepLatest => {
+                if !items.is_empty() {
+                    inner.stats.add_dropped(items.len());
+                    items.clear();
+                }
+            }
+        }
+        items.push_back(item);
+        inner.stats.sent.fetch_add(1, Ordering::Relaxed);
+        inner.set_depth(&items);
+        drop(items);
+        inner.not_empty.notify_one();
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Arc<QueueStats> {
+        self.inner.stats.clone()
+    }
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> QueueSender<T> {
+        let _items = self.inner.items.lock().unwrap();
+        self.inner.senders.fetch_add(1, Ordering::Relaxed);
+        QueueSender {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        let _items = self.inner.items.lock().unwrap();
+        self.inner.senders.fetch_sub(1, Ordering::Relaxed);
+        self.inner.not_empty.notify_all();
+    }
+}
+
+impl<T> QueueReceiver<T> {
+    /// Waits for the next item, fails once the queue is empty and all
+    /// senders are gone.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let mut items = self.inner.items.lock().unwrap();
+        loop {
+            if let Some(item) = self.take(&mut items) {
+                return Ok(item);
+            }
+            if self.inner.senders.load(Ordering::Relaxed) == 0 {
+                return Err(RecvError);
+            }
+            items = self.inner.not_empty.wait(items).unwrap();
+        }
+    }
+
+    /// Waits at most `timeout` for the next item.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut items = self.inner.items.lock().unwrap();
+        loop {
+            if let Some(item) = self.take(&mut items) {
+                return Ok(item);
+            }
+            if self.inner.senders.load(Ordering::Relaxed) == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            items = self
+                .inner
+                .not_empty
+                .wait_timeout(items, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// The items as they arrive, until all senders are gone.
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(|| self.recv().ok())
+    }
+
+    pub fn stats(&self) -> Arc<QueueStats> {
+        self.inner.stats.clone()
+    }
+
+    fn take(&self, items: &mut MutexGuard<VecDeque<T>>) -> Option<T> {
+        let item = items.pop_front()?;
+        self.inner.set_depth(items);
+        self.inner.not_full.notify_one();
+        Some(item)
+    }
+}
+
+impl<T> Drop for QueueReceiver<T> {
+    fn drop(&mut self) {
+        let _items = self.inner.items.lock().unwrap();
+        self.inner.receiver_alive.store(false, Ordering::Relaxed);
+        self.inner.not_full.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn filled(policy: DropPolicy) -> QueueReceiver<u32> {
+        let (tx, rx) = bounded("test", 2, policy);
+        for i in 0..4 {
+            tx.send(i).unwrap();
+        }
+        rx
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_newest_items() {
+        let rx = filled(DropPolicy::DropOldest);
+        assert_eq!(rx.iter().collect::<Vec<_>>(), [2, 3]);
+        let stats = rx.stats().snapshot();
+        assert_eq!((stats.sent, stats.dropped, stats.max_depth), (4, 2, 2));
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_oldest_items() {
+        let rx = filled(DropPolicy::DropNewest);
+        assert_eq!(rx.iter().collect::<Vec<_>>(), [0, 1]);
+        let stats = rx.stats().snapshot();
+        assert_eq!((stats.sent, stats.dropped), (2, 2));
+    }
+
+    #[test]
+    fn keep_latest_keeps_a_single_item() {
+        let rx = filled(DropPolicy::KeepLatest);
+        assert_eq!(rx.iter().collect::<Vec<_>>(), [3]);
+        assert_eq!(rx.stats().snapshot().dropped, 3);
+    }
+
+    #[test]
+    fn block_waits_for_the_receiver() {
+        let (tx, rx) = bounded("test", 2, DropPolicy::Block);
+        let sender = thread::spawn(move || {
+            for i in 0..4 {
+                tx.send(i).unwrap();
+            }
+        });
+        // The sender can not get ahead by more than the capacity
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(rx.stats().snapshot().depth, 2);
+        assert_eq!(rx.iter().collect::<Vec<_>>(), [0, 1, 2, 3]);
+        sender.join().unwrap();
+        assert_eq!(rx.stats().snapshot().dropped, 0);
+    }
+
+    #[test]
+    fn a_blocked_sender_fails_once_the_receiver_is_gone() {
+        let (tx, rx) = bounded("test", 1, DropPolicy::Block);
+        tx.send(0).unwrap();
+        let sender = thread::spawn(move || tx.send(1));
+        thread::sleep(Duration::from_millis(50));
+        drop(rx);
+        assert_eq!(sender.join().unwrap(), Err(SendError(1)));
+    }
+
+    #[test]
+    fn the_receiver_times_out_and_sees_the_senders_leave() {
+        let (tx, rx) = bounded::<u32>("test", 1, DropPolicy::Block);
+        let timeout = Duration::from_millis(10);
+        assert_eq!(rx.recv_timeout(timeout), Err(RecvTimeoutError::Timeout));
+        drop(tx);
+        assert_eq!(
+            rx.recv_timeout(timeout),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+}
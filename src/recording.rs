@@ -0,0 +1,794 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{create_dir_all, File};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::file_reader::{read_byte_file, Config, Settings};
+use super::frame_log::{is_frame_log, FrameLogReader};
+use super::pipeline::FrameSink;
+use super::tlv_translator::{
+    frame_number, frame_time, split_frames, translate_tlv, Frame, FRAME_HEADER_LEN, MAGIC_WORD,
+};
+
+/// The first bytes of every recording.
+pub const RECORDING_MAGIC: [u8; 8] = *b"IWRREC01";
+
+/// The last bytes of a recording which was closed properly.
+const TRAILER_MAGIC: [u8; 8] = *b"IWRIDX01";
+
+/// The version of the layout described at `RecordingWriter`.
+pub const FORMAT_VERSION: u32 = 1;
+
+const CHUNK_RECORD: u8 = 1;
+const INDEX_RECORD: u8 = 2;
+
+/// kind (1) + host time in µs (8) + payload length (4)
+const RECORD_HEADER_LEN: usize = 13;
+
+/// index position (8) + stream length (8) + magic (8)
+const TRAILER_LEN: usize = 24;
+
+/// Describes how a recording was made, stored as JSON at the start of
+/// the recording.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordingMetadata {
+    pub format_version: u32,
+    /// The version of this software which made the recording
+    pub software_version: String,
+    /// Milliseconds since the unix epoch at which the recording started
+    pub start_time_unix_ms: u64,
+    /// The full text of the `.cfg` file sent to the FMCW
+    pub config: String,
+    /// The settings in use, as TOML
+    pub settings: String,
+}
+
+impl RecordingMetadata {
+    /// Describes a recording starting now.
+    pub fn new(config: &Config, settings: &Settings) -> RecordingMetadata {
+        RecordingMetadata {
+            format_version: FORMAT_VERSION,
+            software_version: env!("CARGO_PKG_VERSION").to_string(),
+            start_time_unix_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            config: config.raw_input.clone(),
+            settings: settings.to_toml_string(),
+        }
+    }
+}
+
+/// The location of a single frame in a recording.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndexEntry {
+    pub frame_num: u32,
+    pub time_cpu_cycles: u32,
+    /// When the chunk completing the frame header was received, in µs
+    /// after the start of the recording
+    pub host_time_us: u64,
+    /// The position of the frame in the received byte stream
+    pub stream_offset: u64,
+    /// The position in the file of the chunk record the frame starts in
+    pub record_pos: u64,
+    /// The position of the frame within the payload of that chunk
+    pub offset_in_record: u32,
+}
+
+impl IndexEntry {
+    const SIZE: usize = 36;
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(IndexEntry::SIZE);
+        result.extend_from_slice(&self.frame_num.to_le_bytes());
+        result.extend_from_slice(&self.time_cpu_cycles.to_le_bytes());
+        result.extend_from_slice(&self.host_time_us.to_le_bytes());
+        result.extend_from_slice(&self.stream_offset.to_le_bytes());
+        result.extend_from_slice(&self.record_pos.to_le_bytes());
+        result.extend_from_slice(&self.offset_in_record.to_le_bytes());
+        result
+    }
+
+    fn from_bytes(data: &[u8]) -> IndexEntry {
+        let u32_at = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(data[i..i + 8].try_into().unwrap());
+        IndexEntry {
+            frame_num: u32_at(0),
+            time_cpu_cycles: u32_at(4),
+            host_time_us: u64_at(8),
+            stream_offset: u64_at(16),
+            record_pos: u64_at(24),
+            offset_in_record: u32_at(32),
+        }
+    }
+}
+
+/// Finds the frames in a byte stream which arrives in chunks, a frame
+/// header may be split over several chunks.
+struct FrameIndexer {
+    /// The bytes which have not been searched completely yet
+    window: Vec<u8>,
+    /// The position of `window` in the stream
+    window_start: u64,
+    /// The stream position and record position of the chunks which
+    /// overlap with `window`
+    chunks: VecDeque<(u64, u64)>,
+}
+
+impl FrameIndexer {
+    fn new() -> FrameIndexer {
+        FrameIndexer {
+            window: vec![],
+            window_start: 0,
+            chunks: VecDeque::new(),
+        }
+    }
+
+    /// Adds the next chunk of the stream, which is stored at
+    /// `record_pos`, and returns the frames whose header is now
+    /// complete.
+    fn feed(&mut self, chunk: &[u8], record_pos: u64, host_time_us: u64) -> Vec<IndexEntry> {
+        self.chunks
+            .push_back((self.window_start + self.window.len() as u64, record_pos));
+        self.window.extend_from_slice(chunk);
+
+        let mut entries = vec![];
+        let mut pos = 0;
+        let keep_from = loop {
+            let search_end = self.window.len().saturating_sub(7);
+            let Some(i) = (pos..search_end).find(|&i| self.window[i..i + 8] == MAGIC_WORD) else {
+                // Keep the last bytes, they might be the start of a magic word
+                break pos.max(search_end);
+            };
+            if self.window.len() < i + FRAME_HEADER_LEN {
+                break i;
+            }
+            let stream_offset = self.window_start + i as u64;
+            let (chunk_start, chunk_pos) = self.chunk_at(stream_offset);
+            entries.push(IndexEntry {
+                frame_num: frame_number(&self.window[i..]).unwrap_or_default(),
+                time_cpu_cycles: frame_time(&self.window[i..]).unwrap_or_default(),
+                host_time_us,
+                stream_offset,
+                record_pos: chunk_pos,
+                offset_in_record: (stream_offset - chunk_start) as u32,
+            });
+            pos = i + 8;
+        };
+
+        self.window.drain(0..keep_from);
+        self.window_start += keep_from as u64;
+        // Forget the chunks which ended before the window
+        while self.chunks.len() > 1 && self.chunks[1].0 <= self.window_start {
+            self.chunks.pop_front();
+        }
+        entries
+    }
+
+    fn chunk_at(&self, stream_offset: u64) -> (u64, u64) {
+        *self
+            .chunks
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= stream_offset)
+            .unwrap_or(&self.chunks[0])
+    }
+}
+
+/// Writes a recording of the raw bytes received from the FMCW.
+///
+/// A recording is laid out as follows, all numbers are little endian:
+///
+/// Size    -   Content
+/// 8       -   `RECORDING_MAGIC`
+/// 4       -   Length of the metadata
+/// n       -   `RecordingMetadata` as JSON
+/// ...     -   Records, each a kind (1 byte), the host time in µs after
+///             the start (8 bytes), the payload length (4 bytes) and the
+///             payload. Chunk records hold the bytes as they were
+///             received, followed by a single index record holding an
+///             `IndexEntry` for each frame.
+/// 24      -   The position of the index record, the length of the byte
+///             stream and `TRAILER_MAGIC`
+///
+/// The index and trailer are written by `finish`, when a recording is
+/// cut short the index is rebuilt by `Recording::open` instead.
+pub struct RecordingWriter {
+    file: BufWriter<File>,
+    position: u64,
+    start: Instant,
+    stream_len: u64,
+    indexer: FrameIndexer,
+    index: Vec<IndexEntry>,
+    finished: bool,
+}
+
+impl RecordingWriter {
+    /// Creates a new recording at `path`, overwriting any existing file.
+    pub fn create(path: &Path, metadata: &RecordingMetadata) -> Result<RecordingWriter, Error> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let metadata = serde_json::to_vec(metadata)?;
+        file.write_all(&RECORDING_MAGIC)?;
+        file.write_all(&(metadata.len() as u32).to_le_bytes())?;
+        file.write_all(&metadata)?;
+        file.flush()?;
+        Ok(RecordingWriter {
+            file,
+            position: (RECORDING_MAGIC.len() + 4 + metadata.len()) as u64,
+            start: Instant::now(),
+            stream_len: 0,
+            indexer: FrameIndexer::new(),
+            index: vec![],
+            finished: false,
+        })
+    }
+
+    /// Creates a new recording with a timestamped name in `directory`,
+    /// creating the directory if needed.
+    pub fn create_in(
+        directory: &Path,
+        metadata: &RecordingMetadata,
+    ) -> Result<(RecordingWriter, PathBuf), Error> {
+        create_dir_all(directory)?;
+        let path = timestamped_path(directory, "capture", "iwrrec");
+        Ok((RecordingWriter::create(&path, metadata)?, path))
+    }
+
+    /// Appends a chunk of received bytes, timestamped with the current
+    /// time.
+    pub fn write_chunk(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let host_time_us = self.start.elapsed().as_micros() as u64;
+        let record_pos = self.position;
+        self.write_record(CHUNK_RECORD, host_time_us, bytes)?;
+        self.stream_len += bytes.len() as u64;
+        let entries = self.indexer.feed(bytes, record_pos, host_time_us);
+        self.index.extend(entries);
+        // Flush every chunk, so little is lost if the process is killed
+        self.file.flush()
+    }
+
+    /// Writes the index and the trailer, after which nothing can be
+    /// written anymore.
+    pub fn finish(&mut self) -> Result<(), Error> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        let index_pos = self.position;
+        let payload: Vec<u8> = self.index.iter().flat_map(|e| e.to_bytes()).collect();
+        let host_time_us = self.start.elapsed().as_micros() as u64;
+        self.write_record(INDEX_RECORD, host_time_us, &payload)?;
+        self.file.write_all(&index_pos.to_le_bytes())?;
+        self.file.write_all(&self.stream_len.to_le_bytes())?;
+        self.file.write_all(&TRAILER_MAGIC)?;
+        self.file.flush()
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.index.len()
+    }
+
+    fn write_record(&mut self, kind: u8, host_time_us: u64, payload: &[u8]) -> Result<(), Error> {
+        if self.finished && kind == CHUNK_RECORD {
+            return Err(Error::other("the recording has already been finished"));
+        }
+        self.file.write_all(&[kind])?;
+        self.file.write_all(&host_time_us.to_le_bytes())?;
+        self.file.write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.file.write_all(payload)?;
+        self.position += (RECORD_HEADER_LEN + payload.len()) as u64;
+        Ok(())
+    }
+}
+
+impl Drop for RecordingWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            eprintln!("Could not finish the recording: {}", e);
+        }
+    }
+}
+
+/// Records the received bytes, the decoded frames are not needed.
+impl FrameSink for RecordingWriter {
+    fn name(&self) -> &str {
+        "raw recording"
+    }
+
+    fn write_chunk(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        RecordingWriter::write_chunk(self, bytes)
+    }
+
+    fn write(&mut self, _frame: &Frame) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        RecordingWriter::finish(self)?;
+        println!("Recorded {} frames", self.frame_count());
+        Ok(())
+    }
+}
+
+/// A recording opened for reading, the frames can be looked up by their
+/// position in the recording or by the time they were received.
+pub struct Recording {
+    file: BufReader<File>,
+    metadata: RecordingMetadata,
+    index: Vec<IndexEntry>,
+    stream_len: u64,
+    /// Bounds the lengths read from the file, which can be corrupt
+    file_len: u64,
+}
+
+impl Recording {
+    /// Opens the recording at `path`. When the recording has no index,
+    /// e.g. because the program was killed while recording, the index
+    /// is rebuilt from the chunks.
+    pub fn open(path: &Path) -> Result<Recording, Error> {
+        let mut file = BufReader::new(File::open(path)?);
+        let file_len = file.get_ref().metadata()?.len();
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic)?;
+        if magic != RECORDING_MAGIC {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("\"{}\" is not a recording", path.display()),
+            ));
+        }
+        let metadata_len = read_u32(&mut file)? as usize;
+        if metadata_len as u64 > file_len {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "\"{}\" claims {} bytes of metadata, more than the whole file",
+                    path.display(),
+                    metadata_len
+                ),
+            ));
+        }
+        let mut metadata = vec![0u8; metadata_len];
+        file.read_exact(&mut metadata)?;
+        let metadata: RecordingMetadata = serde_json::from_slice(&metadata)?;
+        let records_start = (RECORDING_MAGIC.len() + 4 + metadata_len) as u64;
+
+        let mut recording = Recording {
+            file,
+            metadata,
+            index: vec![],
+            stream_len: 0,
+            file_len,
+        };
+        if !recording.read_index()? {
+            eprintln!(
+                "\"{}\" has no index, rebuilding it from the recorded data",
+                path.display()
+            );
+            recording.rebuild_index(records_start)?;
+        }
+        Ok(recording)
+    }
+
+    /// Reads the index through the trailer, returns false if there is
+    /// no valid trailer.
+    fn read_index(&mut self) -> Result<bool, Error> {
+        let file_len = self.file.seek(SeekFrom::End(0))?;
+        if file_len < TRAILER_LEN as u64 {
+            return Ok(false);
+        }
+        self.file.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+        let index_pos = read_u64(&mut self.file)?;
+        let stream_len = read_u64(&mut self.file)?;
+        let mut magic = [0u8; 8];
+        self.file.read_exact(&mut magic)?;
+        if magic != TRAILER_MAGIC || index_pos >= file_len {
+            return Ok(false);
+        }
+
+        self.file.seek(SeekFrom::Start(index_pos))?;
+        let (kind, _, payload) = read_record(&mut self.file)?;
+        if kind != INDEX_RECORD || payload.len() % IndexEntry::SIZE != 0 {
+            return Ok(false);
+        }
+        self.index = payload
+            .chunks_exact(IndexEntry::SIZE)
+            .map(IndexEntry::from_bytes)
+            .collect();
+        self.stream_len = stream_len;
+        Ok(true)
+    }
+
+    /// Scans all chunk records for frames, a partly written record at
+    /// the end is ignored.
+    fn rebuild_index(&mut self, records_start: u64) -> Result<(), Error> {
+        let mut indexer = FrameIndexer::new();
+        let mut position = records_start;
+        self.index.clear();
+        self.stream_len = 0;
+        self.file.seek(SeekFrom::Start(position))?;
+        loop {
+            let (kind, host_time_us, payload) = match read_record(&mut self.file) {
+                Ok(v) => v,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            if kind != CHUNK_RECORD {
+                break;
+            }
+            self.index
+                .extend(indexer.feed(&payload, position, host_time_us));
+            self.stream_len += payload.len() as u64;
+            position += (RECORD_HEADER_LEN + payload.len()) as u64;
+        }
+        Ok(())
+    }
+
+    pub fn metadata(&self) -> &RecordingMetadata {
+        &self.metadata
+    }
+
+    /// The configuration which was sent to the FMCW for this recording.
+    pub fn config(&self) -> Config {
+        Config {
+            raw_input: self.metadata.config.clone(),
+        }
+    }
+
+    pub fn index(&self) -> &[IndexEntry] {
+        &self.index
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Returns the position of the first frame received at or after
+    /// `time` from the start of the recording.
+    pub fn frame_at_time(&self, time: Duration) -> Option<usize> {
+        let time_us = time.as_micros() as u64;
+        let position = self.index.partition_point(|e| e.host_time_us < time_us);
+        (position < self.index.len()).then_some(position)
+    }
+
+    /// Returns the position of the first frame with the given frame
+    /// number, as sent by the FMCW.
+    pub fn frame_by_number(&self, frame_num: u32) -> Option<usize> {
+        self.index.iter().position(|e| e.frame_num == frame_num)
+    }
+
+    /// Reads the bytes of the frame at `position` in the recording.
+    pub fn read_frame(&mut self, position: usize) -> Result<Vec<u8>, Error> {
+        let Some(entry) = self.index.get(position).copied() else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "frame {} is out of range, the recording holds {} frames",
+                    position,
+                    self.index.len()
+                ),
+            ));
+        };
+        let end = self
+            .index
+            .get(position + 1)
+            .map_or(self.stream_len, |next| next.stream_offset);
+        let len = match end.checked_sub(entry.stream_offset) {
+            Some(len) if len <= self.file_len => len,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("the index entry of frame {} is corrupt", position),
+                ))
+            }
+        };
+        self.read_stream(&entry, len as usize)
+    }
+
+    /// Reads every frame from the frame at `first` onwards.
+    pub fn read_frames_from(&mut self, first: usize) -> Result<Vec<Vec<u8>>, Error> {
+        (first..self.index.len())
+            .map(|position| self.read_frame(position))
+            .collect()
+    }
+
+    /// Reads `len` bytes of the stream, starting at the frame `entry`.
+    fn read_stream(&mut self, entry: &IndexEntry, len: usize) -> Result<Vec<u8>, Error> {
+        let mut result: Vec<u8> = Vec::with_capacity(len);
+        let mut skip = entry.offset_in_record as usize;
+        self.file.seek(SeekFrom::Start(entry.record_pos))?;
+        while result.len() < len {
+            let (kind, _, payload) = read_record(&mut self.file)?;
+            if kind != CHUNK_RECORD {
+                break;
+            }
+            let payload = &payload[skip.min(payload.len())..];
+            let take = payload.len().min(len - result.len());
+            result.extend_from_slice(&payload[..take]);
+            skip = 0;
+        }
+        Ok(result)
+    }
+}
+
+/// Returns whether the file at `path` starts like a recording.
+pub fn is_recording(path: &Path) -> bool {
+    let mut magic = [0u8; 8];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .is_ok()
+        && magic == RECORDING_MAGIC
+}
+
+/// Reads all frames from either a recording or a plain raw capture,
+/// such as `./tlv_example_file.dat`.
+pub fn read_capture_frames(path: &Path) -> Result<Vec<Vec<u8>>, Error> {
+    if is_recording(path) {
+        Recording::open(path)?.read_frames_from(0)
+    } else {
+        Ok(split_frames(&read_byte_file(path)?))
+    }
+}
+
+/// Reads the frames of a recording, a raw capture or a frame log.
+pub fn load_frames(path: &Path) -> Result<Box<dyn Iterator<Item = Result<Frame, Error>>>, Error> {
+    if is_frame_log(path) {
+        return Ok(Box::new(FrameLogReader::open(path)?));
+    }
+    let frames = read_capture_frames(path)?;
+    Ok(Box::new(
+        frames
+            .into_iter()
+            .flat_map(|mut bytes| translate_tlv(&mut bytes))
+            .map(Ok),
+    ))
+}
+
+/// The current UTC time as `YYYYMMDD_HHMMSS`, for naming files.
+pub fn timestamp_string() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, day_secs) = (secs / 86400, secs % 86400);
+
+    // Converts the days since the epoch into a civil date, after
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}{:02}{:02}_{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        day_secs / 3600,
+        day_secs / 60 % 60,
+        day_secs % 60
+    )
+}
+
+/// A path in `directory` named `<prefix>_<timestamp>.<extension>` which
+/// does not exist yet, files created within the same second get a
+/// sequence number, `<prefix>_<timestamp>_<n>.<extension>`.
+pub fn timestamped_path(directory: &Path, prefix: &str, extension: &str) -> PathBuf {
+    let stamp = timestamp_string();
+    let mut path = directory.join(format!("{}_{}.{}", prefix, stamp, extension));
+    let mut sequence = 1;
+    while path.exists() {
+        path = directory.join(format!("{}_{}_{}.{}", prefix, stamp, sequence, extension));
+        sequence += 1;
+    }
+    path
+}
+
+/// Reads a record, the payload only takes as much memory as the file
+/// actually holds, whatever length a corrupt header claims.
+fn read_record(reader: &mut impl Read) -> Result<(u8, u64, Vec<u8>), Error> {
+    let mut kind = [0u8; 1];
+    reader.read_exact(&mut kind)?;
+    let host_time_us = read_u64(reader)?;
+    let len = read_u32(reader)? as usize;
+    let mut payload = vec![];
+    reader.by_ref().take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() != len {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            format!("a record of {} bytes ends after {}", len, payload.len()),
+        ));
+    }
+    Ok((kind[0], host_time_us, payload))
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32, Error> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64, Error> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tlv_encoder::encode_frame;
+    use std::io::Cursor;
+
+    fn test_config() -> Config {
+        Config {
+            raw_input: include_str!("../iwr6843_config.cfg").to_string(),
+        }
+    }
+
+    fn record(kind: u8, host_time_us: u64, len: u32, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![kind];
+        bytes.extend(host_time_us.to_le_bytes());
+        bytes.extend(len.to_le_bytes());
+        bytes.extend(payload);
+        bytes
+    }
+
+    #[test]
+    fn reads_a_record() {
+        let bytes = record(CHUNK_RECORD, 1234, 3, &[1, 2, 3]);
+        let record = read_record(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(record, (CHUNK_RECORD, 1234, vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn a_truncated_record_is_an_eof() {
+        // Claims 4 GiB, which must not be allocated up front
+        let bytes = record(CHUNK_RECORD, 0, u32::MAX, &[1, 2, 3]);
+        let e = read_record(&mut Cursor::new(bytes)).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn a_huge_metadata_length_is_rejected() {
+        let path =
+            std::env::temp_dir().join(format!("huge_metadata_{}.iwrrec", std::process::id()));
+        let mut bytes = RECORDING_MAGIC.to_vec();
+        bytes.extend(u32::MAX.to_le_bytes());
+        bytes.extend(b"{}");
+        std::fs::write(&path, bytes).unwrap();
+        let e = Recording::open(&path).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn a_corrupt_index_entry_is_an_error() {
+        let path =
+            std::env::temp_dir().join(format!("corrupt_index_{}.iwrrec", std::process::id()));
+        let metadata = RecordingMetadata::new(&test_config(), &Settings::default());
+        let mut writer = RecordingWriter::create(&path, &metadata).unwrap();
+        writer.write_chunk(&encode_frame(&Frame::empty(1))).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+        let mut recording = Recording::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // An offset past the end of the stream must not underflow
+        recording.index[0].stream_offset = recording.stream_len + 1;
+        let e = recording.read_frame(0).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        // Nor may a huge stream length be allocated
+        recording.index[0].stream_offset = 0;
+        recording.stream_len = u64::MAX;
+        let e = recording.read_frame(0).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn recordings_started_in_the_same_second_get_their_own_file() {
+        let directory = std::env::temp_dir().join(format!("same_second_{}", std::process::id()));
+        let metadata = RecordingMetadata::new(&test_config(), &Settings::default());
+        let (_, first) = RecordingWriter::create_in(&directory, &metadata).unwrap();
+        let (_, second) = RecordingWriter::create_in(&directory, &metadata).unwrap();
+        let count = std::fs::read_dir(&directory).unwrap().count();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(count, 2);
+    }
+
+    /// Records five frames, numbered 1 to 5, in chunks which do not line
+    /// up with the frames, 10 ms apart. Returns the frames and the length
+    /// of the file before the index was written.
+    fn write_recording(path: &Path) -> (Vec<Vec<u8>>, u64) {
+        let frames: Vec<Vec<u8>> = (1..=5).map(|i| encode_frame(&Frame::empty(i))).collect();
+        let metadata = RecordingMetadata::new(&test_config(), &Settings::default());
+        let mut writer = RecordingWriter::create(path, &metadata).unwrap();
+        for chunk in frames.concat().chunks(frames[0].len() * 2 / 3) {
+            writer.write_chunk(chunk).unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let records_end = std::fs::metadata(path).unwrap().len();
+        writer.finish().unwrap();
+        (frames, records_end)
+    }
+
+    #[test]
+    fn a_recording_reads_back_what_was_written() {
+        let path = std::env::temp_dir().join(format!("round_trip_{}.iwrrec", std::process::id()));
+        let (frames, _) = write_recording(&path);
+        let mut recording = Recording::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(recording.config().raw_input, test_config().raw_input);
+        assert_eq!(recording.metadata().format_version, FORMAT_VERSION);
+        assert_eq!(recording.frame_count(), 5);
+        for (position, frame) in frames.iter().enumerate() {
+            assert_eq!(&recording.read_frame(position).unwrap(), frame);
+        }
+        assert_eq!(recording.read_frames_from(3).unwrap(), frames[3..]);
+        assert!(recording.read_frame(5).is_err());
+    }
+
+    #[test]
+    fn frames_are_found_by_number_and_time() {
+        let path = std::env::temp_dir().join(format!("seek_{}.iwrrec", std::process::id()));
+        write_recording(&path);
+        let recording = Recording::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(recording.frame_by_number(1), Some(0));
+        assert_eq!(recording.frame_by_number(4), Some(3));
+        assert_eq!(recording.frame_by_number(6), None);
+
+        let index = recording.index();
+        assert!(index
+            .windows(2)
+            .all(|e| e[0].host_time_us < e[1].host_time_us));
+        assert_eq!(recording.frame_at_time(Duration::ZERO), Some(0));
+        let third = Duration::from_micros(index[2].host_time_us);
+        assert_eq!(recording.frame_at_time(third), Some(2));
+        assert_eq!(
+            recording.frame_at_time(third - Duration::from_micros(1)),
+            Some(2)
+        );
+        let after_last = Duration::from_micros(index[4].host_time_us + 1);
+        assert_eq!(recording.frame_at_time(after_last), None);
+    }
+
+    #[test]
+    fn the_index_is_rebuilt_without_a_trailer() {
+        let path = std::env::temp_dir().join(format!("no_trailer_{}.iwrrec", std::process::id()));
+        let (frames, records_end) = write_recording(&path);
+        let finished_index = Recording::open(&path).unwrap().index().to_vec();
+
+        // Killed before the index was written
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(records_end)
+            .unwrap();
+        let mut recording = Recording::open(&path).unwrap();
+        assert_eq!(recording.index(), finished_index);
+        assert_eq!(recording.read_frames_from(0).unwrap(), frames);
+
+        // Killed while writing the last chunk, which is left out
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(records_end - 3)
+            .unwrap();
+        let recording = Recording::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(recording.frame_count(), 4);
+        assert_eq!(recording.index(), &finished_index[..4]);
+    }
+}
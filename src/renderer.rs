@@ -1,113 +1,774 @@
-// use super::tlv_translator::PointCloudPoint;
+use plotters::coord::Shift;
 use plotters::prelude::*;
-use std::path::Path;
-
-// pub fn render_pointcloud(pointcloud: Vec<PointCloudPoint>, filename: &Path) {
-//     let x_range = -5f32..5f32;
-//     let y_range = -5f32..15f32;
-//
-//     let root = BitMapBackend::new(filename, (640, 480)).into_drawing_area();
-//     let _ = root.fill(&WHITE);
-//     let root = root.margin(10, 10, 10, 10);
-//
-//     // After this point, we should be able to construct a chart context
-//     let mut chart = match ChartBuilder::on(&root)
-//         // Set the caption of the chart
-//         .caption("FMCW data points", ("sans-serif", 40).into_font())
-//         // Set the size of the label region
-//         .x_label_area_size(20)
-//         .y_label_area_size(40)
-//         // Finally attach a coordinate on the drawing area and make a chart context
-//         .build_cartesian_2d(x_range, y_range)
-//     {
-//         Ok(v) => v,
-//         Err(e) => {
-//             eprintln!("{}", e);
-//             return;
-//         }
-//     };
-//
-//     // Then we can draw a mesh
-//     let _ = chart
-//         .configure_mesh()
-//         // We can customize the maximum number of labels allowed for each axis
-//         .x_labels(5)
-//         .y_labels(5)
-//         // We can also change the format of the label text
-//         .y_label_formatter(&|x| format!("{:.3}", x))
-//         .draw();
-//
-//     for point in pointcloud {
-//         let it: std::slice::Iter<'_, (f32, f32)> = [(point.x, point.y)].iter();
-//         let _: PointElement<&(f64, f64), f64> =
-//             chart.draw_series(PointSeries::new([&(point.x, point.y)], 2., &RED));
-//     }
-//     // And we can draw something in the drawing area
-//     // let _ = chart.draw_series(AreaSeries::new(kde, 0., &RED));
-//     // Similarly, we can draw point series
-//     let _ = root.present();
-// }
-//
-pub fn render_range_profile(data: &Vec<f64>, filename: &str) {
-    // We need to convert our series to a Kernel Density Estimate
-    // Then we want to render the kernel density estimate as an
-    // Area series with the Plotter crate.
-
-    let min = data
-        .iter()
-        .min_by(|a, b| a.total_cmp(b))
-        .expect("The data passed to kde should not contain NaN numbers");
-    let max = data
-        .iter()
-        .max_by(|a, b| a.total_cmp(b))
-        .expect("The data passed to kde should not contain NaN numbers");
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
 
-    let kde = kde_transform(data, min, max);
-    let max_y = kde
-        .iter()
-        .max_by(|(_ax, ay), (_bx, by)| ay.total_cmp(by))
-        .map(|(_x, y)| y)
-        .expect("Y values should always be comparable");
-
-    // Render result with plotters
-    let root = BitMapBackend::new(filename, (640, 480)).into_drawing_area();
-    let _ = root.fill(&WHITE);
-    let root = root.margin(10, 10, 10, 10);
-
-    // After this point, we should be able to construct a chart context
-    let mut chart = match ChartBuilder::on(&root)
-        // Set the caption of the chart
-        .caption("KDE Range Profile", ("sans-serif", 40).into_font())
-        // Set the size of the label region
-        .x_label_area_size(20)
-        .y_label_area_size(40)
-        // Finally attach a coordinate on the drawing area and make a chart context
-        .build_cartesian_2d(*min..*max, 0f64..*max_y)
-    {
-        Ok(v) => v,
-        Err(e) => {
-            eprintln!("{}", e);
+use super::file_reader::{RadarParameters, RenderSettings};
+use super::heatmap::{self, azimuth_axis, doppler_axis, range_axis, Heatmap};
+use super::pipeline::FrameSink;
+use super::queue::{self, DropPolicy, QueueSender, QueueStats};
+use super::tlv_translator::{Frame, SideInfo};
+
+/// The file format of the rendered images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Bmp,
+    Svg,
+}
+
+impl ImageFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Bmp => "bmp",
+            ImageFormat::Svg => "svg",
+        }
+    }
+}
+
+/// What `RenderSink` draws of each frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlotKind {
+    /// The range profile as a kernel density estimate
+    RangeProfile,
+    /// The points seen from above and from the side
+    Pointcloud,
+    /// Range against velocity
+    RangeDoppler,
+    /// Range against azimuth, as set by `render.azimuth_projection`
+    RangeAzimuth,
+}
+
+impl PlotKind {
+    /// The directory below `render.directory` the plots go into.
+    fn directory(self) -> &'static str {
+        match self {
+            PlotKind::RangeProfile => "range_profile",
+            PlotKind::Pointcloud => "pointcloud",
+            PlotKind::RangeDoppler => "range_doppler",
+            PlotKind::RangeAzimuth => "range_azimuth",
+        }
+    }
+}
+
+/// What the color of a point in the point cloud plots shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PointColor {
+    /// Blue when approaching, red when moving away
+    Doppler,
+    /// From purple for weak to yellow for strong reflections, points
+    /// without side info are grey
+    Snr,
+}
+
+/// How the range-azimuth heatmap is drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AzimuthProjection {
+    /// The azimuth along x and the range along y
+    Polar,
+    /// Seen from above in meters, like the point cloud
+    Cartesian,
+}
+
+/// The SNR at the top of the color scale.
+const MAX_SNR_DB: f64 = 40.0;
+
+/// Something which can be drawn onto any plotters backend, and so be
+/// saved in every `ImageFormat`.
+trait Plot {
+    fn draw<DB: DrawingBackend>(&self, root: &DrawingArea<DB, Shift>) -> Result<(), String>;
+}
+
+/// Draws `plot` into the image file at `path`, whose extension has to
+/// match `format`.
+fn save_plot(
+    plot: &impl Plot,
+    path: &Path,
+    size: (u32, u32),
+    format: ImageFormat,
+) -> Result<(), Error> {
+    let result = match format {
+        ImageFormat::Svg => {
+            let root = SVGBackend::new(path, size).into_drawing_area();
+            plot.draw(&root)
+                .and_then(|()| root.present().map_err(|e| e.to_string()))
+        }
+        _ => {
+            let root = BitMapBackend::new(path, size).into_drawing_area();
+            plot.draw(&root)
+                .and_then(|()| root.present().map_err(|e| e.to_string()))
+        }
+    };
+    result.map_err(|e| Error::other(format!("could not render {}: {}", path.display(), e)))
+}
+
+/// Renders the frames on a worker thread into `render.directory`, so
+/// the parser keeps pace. Only every `render.every_n_frames`-th frame
+/// is rendered, and the render queue of `[pipeline]` drops frames when
+/// the worker falls behind.
+///
+/// Each plot of `render.plots` goes into a directory of its own, as
+/// `<plot>/<frame number>.<extension>`.
+pub struct RenderSink {
+    every_n_frames: usize,
+    frame_count: usize,
+    queue: Option<QueueSender<Frame>>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl RenderSink {
+    /// Creates the output directories and starts the worker, which is
+    /// handed the frames through a queue of `queue_size` frames. All
+    /// plots but the range profile need the `params` for their axes.
+    pub fn spawn(
+        settings: &RenderSettings,
+        params: Option<RadarParameters>,
+        queue_size: usize,
+        policy: DropPolicy,
+    ) -> Result<RenderSink, Error> {
+        if settings.width == 0 || settings.height == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "render.width and render.height must be larger than 0",
+            ));
+        }
+        if let (None, Some(plot)) = (
+            &params,
+            settings
+                .plots
+                .iter()
+                .find(|p| **p != PlotKind::RangeProfile),
+        ) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "The {} plot needs the radar parameters of the configuration",
+                    plot.directory()
+                ),
+            ));
+        }
+        for plot in &settings.plots {
+            std::fs::create_dir_all(settings.directory.join(plot.directory()))?;
+        }
+        let (queue, frames) = queue::bounded("render", queue_size, policy);
+        let every_n_frames = settings.every_n_frames.max(1);
+        let settings = settings.clone();
+        let worker = thread::spawn(move || {
+            let mut renderer = Renderer::new(settings, params);
+            for frame in frames.iter() {
+                renderer.render(&frame);
+            }
+            renderer.finish();
+        });
+        Ok(RenderSink {
+            every_n_frames,
+            frame_count: 0,
+            queue: Some(queue),
+            worker: Some(worker),
+        })
+    }
+}
+
+impl FrameSink for RenderSink {
+    fn name(&self) -> &str {
+        "render"
+    }
+
+    fn write(&mut self, frame: &Frame) -> Result<(), Error> {
+        let render = self.frame_count.is_multiple_of(self.every_n_frames);
+        self.frame_count += 1;
+        match self.queue {
+            Some(ref queue) if render => queue
+                .send(frame.clone())
+                .map_err(|_| Error::new(ErrorKind::BrokenPipe, "the render worker has stopped")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Waits until the worker has rendered the queued frames.
+    fn finish(&mut self) -> Result<(), Error> {
+        self.queue = None;
+        if let Some(worker) = self.worker.take() {
+            worker
+                .join()
+                .map_err(|_| Error::other("the render worker panicked"))?;
+        }
+        Ok(())
+    }
+
+    fn queue_stats(&self) -> Option<Arc<QueueStats>> {
+        self.queue.as_ref().map(|q| q.stats())
+    }
+}
+
+/// Draws the plots of `render.plots`, on the worker of `RenderSink`.
+struct Renderer {
+    settings: RenderSettings,
+    params: Option<RadarParameters>,
+    /// The points of the previous `render.trail_frames` frames, the
+    /// newest last
+    trail: VecDeque<Vec<ColoredPoint>>,
+    /// The point clouds waiting to fill the grid of an image
+    grid: Vec<PointcloudCell>,
+}
+
+impl Renderer {
+    fn new(settings: RenderSettings, params: Option<RadarParameters>) -> Renderer {
+        Renderer {
+            settings,
+            params,
+            trail: VecDeque::new(),
+            grid: vec![],
+        }
+    }
+
+    fn render(&mut self, frame: &Frame) {
+        let size = (self.settings.width, self.settings.height);
+        for plot in self.settings.plots.clone() {
+            match plot {
+                PlotKind::RangeProfile => {
+                    if let Some(ref rp) = frame.range_profile {
+                        let path = frame_path(&self.settings, plot, frame.frame_num);
+                        report(save_plot(
+                            &RangeProfilePlot(rp),
+                            &path,
+                            size,
+                            self.settings.format,
+                        ));
+                    }
+                }
+                PlotKind::Pointcloud => self.add_pointcloud(frame),
+                PlotKind::RangeDoppler | PlotKind::RangeAzimuth => self.draw_heatmap(plot, frame),
+            }
+        }
+    }
+
+    fn draw_heatmap(&self, plot: PlotKind, frame: &Frame) {
+        let Some(ref params) = self.params else {
+            return;
+        };
+        let (heatmap, cols) = match plot {
+            PlotKind::RangeDoppler => (heatmap::range_doppler(frame, params), doppler_axis(params)),
+            _ => (heatmap::range_azimuth(frame, params), azimuth_axis()),
+        };
+        let Some(heatmap) = heatmap else {
             return;
+        };
+        let heatmap_plot = HeatmapPlot {
+            frame_num: frame.frame_num,
+            heatmap,
+            ranges: range_axis(params),
+            cols,
+            kind: plot,
+            projection: self.settings.azimuth_projection,
+            dynamic_range_db: self.settings.dynamic_range_db,
+        };
+        let path = frame_path(&self.settings, plot, frame.frame_num);
+        let size = (self.settings.width, self.settings.height);
+        report(save_plot(&heatmap_plot, &path, size, self.settings.format));
+    }
+
+    /// Adds a frame to the grid, which is drawn once it is full.
+    fn add_pointcloud(&mut self, frame: &Frame) {
+        let points = colored_points(frame, self.settings.color_by);
+        self.grid.push(PointcloudCell {
+            frame_num: frame.frame_num,
+            points: points.clone(),
+            trail: self.trail.iter().flatten().copied().collect(),
+        });
+        if self.settings.trail_frames > 0 {
+            if self.trail.len() == self.settings.trail_frames {
+                self.trail.pop_front();
+            }
+            self.trail.push_back(points);
+        }
+        if self.grid.len() >= self.settings.grid_frames.max(1) {
+            self.draw_pointclouds();
+        }
+    }
+
+    fn draw_pointclouds(&mut self) {
+        let (Some(params), Some(first)) = (&self.params, self.grid.first()) else {
+            return;
+        };
+        let axes = PointcloudAxes::new(params);
+        let path = frame_path(&self.settings, PlotKind::Pointcloud, first.frame_num);
+        let plot = PointcloudPlot {
+            cells: std::mem::take(&mut self.grid),
+            axes,
+            color_by: self.settings.color_by,
+        };
+        let size = (self.settings.width, self.settings.height);
+        report(save_plot(&plot, &path, size, self.settings.format));
+    }
+
+    /// Draws the grid which was not yet full when the stream ended.
+    fn finish(&mut self) {
+        self.draw_pointclouds();
+    }
+}
+
+fn report(result: Result<(), Error>) {
+    if let Err(e) = result {
+        eprintln!("{}", e);
+    }
+}
+
+/// The file a plot of a frame is rendered into, below `render.directory`.
+fn frame_path(settings: &RenderSettings, plot: PlotKind, frame_num: usize) -> PathBuf {
+    settings.directory.join(plot.directory()).join(format!(
+        "{}.{}",
+        frame_num,
+        settings.format.extension()
+    ))
+}
+
+/// A point in sensor coordinates, with the value its color shows.
+#[derive(Debug, Clone, Copy)]
+struct ColoredPoint {
+    x: f64,
+    y: f64,
+    z: f64,
+    /// The doppler in m/s or the SNR in dB, NaN when unknown
+    value: f64,
+}
+
+fn colored_points(frame: &Frame, color_by: PointColor) -> Vec<ColoredPoint> {
+    let Some(ref points) = frame.pointcloud else {
+        return vec![];
+    };
+    let side_info = frame.side_info.as_deref().unwrap_or_default();
+    points
+        .iter()
+        .enumerate()
+        .map(|(i, p)| ColoredPoint {
+            x: p.x as f64,
+            y: p.y as f64,
+            z: p.z as f64,
+            value: match color_by {
+                PointColor::Doppler => p.d as f64,
+                PointColor::Snr => side_info
+                    .get(i)
+                    .map_or(f64::NAN, |s: &SideInfo| s.snr as f64 * 0.1),
+            },
+        })
+        .collect()
+}
+
+/// The fixed axes of the point cloud plots, spanning the field of
+/// view of `aoaFovCfg` up to the range of `cfarFovCfg`.
+#[derive(Debug, Clone, Copy)]
+struct PointcloudAxes {
+    max_range_m: f64,
+    azimuth_fov_deg: (f64, f64),
+    elevation_fov_deg: (f64, f64),
+    x_m: (f64, f64),
+    z_m: (f64, f64),
+    doppler_mps: (f64, f64),
+}
+
+impl PointcloudAxes {
+    fn new(params: &RadarParameters) -> PointcloudAxes {
+        let r = params.range_fov_m.1.min(params.max_range_m);
+        // The extent of the field of view at the maximum range, which
+        // always contains the sensor itself
+        let extent = |(min, max): (f64, f64)| {
+            (
+                (r * min.to_radians().sin()).min(-0.5),
+                (r * max.to_radians().sin()).max(0.5),
+            )
+        };
+        PointcloudAxes {
+            max_range_m: r,
+            azimuth_fov_deg: params.azimuth_fov_deg,
+            elevation_fov_deg: params.elevation_fov_deg,
+            x_m: extent(params.azimuth_fov_deg),
+            z_m: extent(params.elevation_fov_deg),
+            doppler_mps: params.doppler_fov_mps,
+        }
+    }
+}
+
+impl PointcloudAxes {
+    /// The edges of a field of view, as lines from the sensor to the
+    /// maximum range in the (along, across) plane.
+    fn outline(&self, (min, max): (f64, f64)) -> [(f64, f64); 3] {
+        let r = self.max_range_m;
+        let edge = |deg: f64| {
+            let (sin, cos) = deg.to_radians().sin_cos();
+            (r * cos, r * sin)
+        };
+        [edge(min), (0.0, 0.0), edge(max)]
+    }
+}
+
+/// One of the two views of a frame.
+struct View {
+    caption: String,
+    x_range: (f64, f64),
+    y_range: (f64, f64),
+    x_desc: &'static str,
+    y_desc: &'static str,
+    /// The edges of the field of view
+    outline: [(f64, f64); 3],
+}
+
+/// The point cloud of one frame in a grid of `PointcloudPlot`.
+struct PointcloudCell {
+    frame_num: usize,
+    points: Vec<ColoredPoint>,
+    /// The points of the frames before it
+    trail: Vec<ColoredPoint>,
+}
+
+/// Point clouds seen from above (x/y) and from the side (y/z), one
+/// frame after the other in a grid.
+struct PointcloudPlot {
+    cells: Vec<PointcloudCell>,
+    axes: PointcloudAxes,
+    color_by: PointColor,
+}
+
+impl PointcloudPlot {
+    fn color(&self, value: f64) -> RGBColor {
+        match self.color_by {
+            _ if value.is_nan() => RGBColor(160, 160, 160),
+            PointColor::Doppler => {
+                let (min, max) = self.axes.doppler_mps;
+                DerivedColorMap::new(&[BLUE, RGBColor(160, 160, 160), RED]).get_color_normalized(
+                    value.clamp(min, max),
+                    min,
+                    max,
+                )
+            }
+            PointColor::Snr => {
+                ViridisRGB::get_color_normalized(value.clamp(0.0, MAX_SNR_DB), 0.0, MAX_SNR_DB)
+            }
+        }
+    }
+
+    /// Draws one view of a cell, `project` picks the coordinates.
+    fn draw_view<DB: DrawingBackend>(
+        &self,
+        area: &DrawingArea<DB, Shift>,
+        view: View,
+        cell: &PointcloudCell,
+        project: impl Fn(&ColoredPoint) -> (f64, f64),
+    ) -> Result<(), String> {
+        let (x, y) = (view.x_range, view.y_range);
+        let mut chart = ChartBuilder::on(area)
+            .caption(view.caption, ("sans-serif", 16).into_font())
+            .margin(5)
+            .x_label_area_size(30)
+            .y_label_area_size(40)
+            .build_cartesian_2d(x.0..x.1, y.0..y.1)
+            .map_err(|e| e.to_string())?;
+        chart
+            .configure_mesh()
+            .x_labels(5)
+            .y_labels(5)
+            .x_desc(view.x_desc)
+            .y_desc(view.y_desc)
+            .draw()
+            .map_err(|e| e.to_string())?;
+        chart
+            .draw_series(LineSeries::new(view.outline, BLACK.mix(0.4)))
+            .map_err(|e| e.to_string())?;
+
+        chart
+            .draw_series(
+                cell.trail
+                    .iter()
+                    .map(|p| Circle::new(project(p), 2, self.color(p.value).mix(0.3).filled())),
+            )
+            .map_err(|e| e.to_string())?;
+        chart
+            .draw_series(
+                cell.points
+                    .iter()
+                    .map(|p| Circle::new(project(p), 3, self.color(p.value).filled())),
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+impl Plot for PointcloudPlot {
+    fn draw<DB: DrawingBackend>(&self, root: &DrawingArea<DB, Shift>) -> Result<(), String> {
+        root.fill(&WHITE).map_err(|e| e.to_string())?;
+        let axes = &self.axes;
+        let range = (0.0, axes.max_range_m);
+        // As square as possible, with the two views of a frame next to
+        // each other
+        let cols = (self.cells.len() as f64).sqrt().ceil() as usize;
+        let rows = self.cells.len().div_ceil(cols);
+        let areas = root.split_evenly((rows, cols));
+        for (cell, area) in self.cells.iter().zip(areas) {
+            let views = area.split_evenly((1, 2));
+            // The azimuth is measured from y towards x
+            let above = View {
+                caption: format!("Frame {} from above", cell.frame_num),
+                x_range: axes.x_m,
+                y_range: range,
+                x_desc: "x [m]",
+                y_desc: "y [m]",
+                outline: axes.outline(axes.azimuth_fov_deg).map(|(y, x)| (x, y)),
+            };
+            self.draw_view(&views[0], above, cell, |p| (p.x, p.y))?;
+            let side = View {
+                caption: format!("Frame {} from the side", cell.frame_num),
+                x_range: range,
+                y_range: axes.z_m,
+                x_desc: "y [m]",
+                y_desc: "z [m]",
+                outline: axes.outline(axes.elevation_fov_deg),
+            };
+            self.draw_view(&views[1], side, cell, |p| (p.y, p.z))?;
         }
+        Ok(())
+    }
+}
+
+/// A heatmap of a frame in dB, a row for every range bin.
+struct HeatmapPlot {
+    frame_num: usize,
+    heatmap: Heatmap,
+    /// The range of every row and the velocity or azimuth of every
+    /// column, at the center of the bins
+    ranges: Vec<f64>,
+    cols: Vec<f64>,
+    kind: PlotKind,
+    projection: AzimuthProjection,
+    /// The span of the color scale below the strongest bin
+    dynamic_range_db: f64,
+}
+
+/// The edges between the bins centered at `centers`, half a bin
+/// beyond the first and last one.
+fn bin_edges(centers: &[f64]) -> Vec<f64> {
+    let Some((first, last)) = centers.first().zip(centers.last()) else {
+        return vec![];
     };
+    let half = |a: f64, b: f64| (b - a) / 2.0;
+    let mut edges = vec![first - centers.get(1).map_or(0.5, |b| half(*first, *b))];
+    edges.extend(centers.windows(2).map(|w| (w[0] + w[1]) / 2.0));
+    let before_last = centers.len().checked_sub(2).map(|i| centers[i]);
+    edges.push(last + before_last.map_or(0.5, |a| half(a, *last)));
+    edges
+}
+
+/// The edges of the azimuth bins, which are even in the sine of the
+/// azimuth rather than the azimuth itself.
+fn azimuth_edges(cols: usize) -> Vec<f64> {
+    let n = cols as f64;
+    (0..=cols)
+        .map(|j| {
+            ((2.0 * j as f64 - n - 1.0) / n)
+                .clamp(-1.0, 1.0)
+                .asin()
+                .to_degrees()
+        })
+        .collect()
+}
+
+impl HeatmapPlot {
+    /// The lowest and highest value of the color scale.
+    fn color_scale(&self) -> (f64, f64) {
+        let (_, max) = self.heatmap.min_max();
+        (max - self.dynamic_range_db.max(f64::EPSILON), max)
+    }
+
+    /// The cells of the heatmap with their value, as polygons in the
+    /// coordinates of the chart.
+    fn cells(&self) -> Vec<(Vec<(f64, f64)>, f64)> {
+        let heatmap = &self.heatmap;
+        let range_edges = bin_edges(&self.ranges);
+        let col_edges = match self.kind {
+            PlotKind::RangeAzimuth => azimuth_edges(heatmap.cols),
+            _ => bin_edges(&self.cols),
+        };
+        let cartesian =
+            self.kind == PlotKind::RangeAzimuth && self.projection == AzimuthProjection::Cartesian;
+        let corner = |col: f64, range: f64| {
+            if cartesian {
+                let (sin, cos) = col.to_radians().sin_cos();
+                (range.max(0.0) * sin, range.max(0.0) * cos)
+            } else {
+                (col, range)
+            }
+        };
+        let mut cells = Vec::with_capacity(heatmap.values.len());
+        for (row, ranges) in range_edges.windows(2).enumerate() {
+            for (col, cols) in col_edges.windows(2).enumerate() {
+                let polygon = vec![
+                    corner(cols[0], ranges[0]),
+                    corner(cols[1], ranges[0]),
+                    corner(cols[1], ranges[1]),
+                    corner(cols[0], ranges[1]),
+                ];
+                cells.push((polygon, heatmap.values[row * heatmap.cols + col]));
+            }
+        }
+        cells
+    }
+
+    /// The axes of the chart and their description.
+    fn axes(&self) -> ((f64, f64), (f64, f64), &'static str, &'static str) {
+        let max_range = self.ranges.last().copied().unwrap_or(1.0).max(f64::EPSILON);
+        let first = self.cols.first().copied().unwrap_or(-1.0);
+        let last = self.cols.last().copied().unwrap_or(1.0);
+        match (self.kind, self.projection) {
+            (PlotKind::RangeAzimuth, AzimuthProjection::Cartesian) => {
+                ((-max_range, max_range), (0.0, max_range), "x [m]", "y [m]")
+            }
+            (PlotKind::RangeAzimuth, AzimuthProjection::Polar) => (
+                (-90.0, 90.0),
+                (0.0, max_range),
+                "azimuth [deg]",
+                "range [m]",
+            ),
+            _ => (
+                (first.min(-last), last.max(-first).max(first + f64::EPSILON)),
+                (0.0, max_range),
+                "velocity [m/s]",
+                "range [m]",
+            ),
+        }
+    }
+
+    fn title(&self) -> String {
+        match self.kind {
+            PlotKind::RangeAzimuth => format!("Frame {} range-azimuth", self.frame_num),
+            _ => format!("Frame {} range-doppler", self.frame_num),
+        }
+    }
+}
+
+impl Plot for HeatmapPlot {
+    fn draw<DB: DrawingBackend>(&self, root: &DrawingArea<DB, Shift>) -> Result<(), String> {
+        root.fill(&WHITE).map_err(|e| e.to_string())?;
+        let (min, max) = self.color_scale();
+        let color = |value: f64| ViridisRGB::get_color_normalized(value.clamp(min, max), min, max);
+
+        let (width, _) = root.dim_in_pixel();
+        let (area, colorbar) = root.split_horizontally(width.saturating_sub(90));
+        let (x, y, x_desc, y_desc) = self.axes();
+        let mut chart = ChartBuilder::on(&area)
+            .caption(self.title(), ("sans-serif", 20).into_font())
+            .margin(10)
+            .x_label_area_size(35)
+            .y_label_area_size(45)
+            .build_cartesian_2d(x.0..x.1, y.0..y.1)
+            .map_err(|e| e.to_string())?;
+        chart
+            .configure_mesh()
+            .disable_mesh()
+            .x_desc(x_desc)
+            .y_desc(y_desc)
+            .draw()
+            .map_err(|e| e.to_string())?;
+        chart
+            .draw_series(
+                self.cells()
+                    .into_iter()
+                    .map(|(polygon, value)| Polygon::new(polygon, color(value).filled())),
+            )
+            .map_err(|e| e.to_string())?;
+
+        // The colorbar, with the dB of the colors along its axis
+        let mut bar = ChartBuilder::on(&colorbar)
+            .margin_top(40)
+            .margin_bottom(45)
+            .margin_right(10)
+            .y_label_area_size(45)
+            .build_cartesian_2d(0.0..1.0, min..max)
+            .map_err(|e| e.to_string())?;
+        bar.configure_mesh()
+            .disable_mesh()
+            .disable_x_axis()
+            .y_desc("dB")
+            .y_label_style(("sans-serif", 12))
+            .axis_desc_style(("sans-serif", 12))
+            .y_label_formatter(&|v| format!("{:.0}", v))
+            .draw()
+            .map_err(|e| e.to_string())?;
+        const STEPS: usize = 64;
+        let step = (max - min) / STEPS as f64;
+        bar.draw_series((0..STEPS).map(|i| {
+            let low = min + i as f64 * step;
+            Rectangle::new(
+                [(0.0, low), (1.0, low + step)],
+                color(low + step / 2.0).filled(),
+            )
+        }))
+        .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+/// A range profile in dB, plotted as a kernel density estimate.
+struct RangeProfilePlot<'a>(&'a [f64]);
+
+impl Plot for RangeProfilePlot<'_> {
+    fn draw<DB: DrawingBackend>(&self, root: &DrawingArea<DB, Shift>) -> Result<(), String> {
+        // We need to convert our series to a Kernel Density Estimate
+        // Then we want to render the kernel density estimate as an
+        // Area series with the Plotter crate.
+        let data = self.0;
+        let min = data.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = data.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        if !(min.is_finite() && max.is_finite()) {
+            return Err("the range profile is empty".to_string());
+        }
+
+        let kde = kde_transform(data, &min, &max);
+        let max_y = kde.iter().map(|(_x, y)| *y).fold(f64::EPSILON, f64::max);
+
+        root.fill(&WHITE).map_err(|e| e.to_string())?;
+        let root = root.margin(10, 10, 10, 10);
+
+        // After this point, we should be able to construct a chart context
+        let mut chart = ChartBuilder::on(&root)
+            // Set the caption of the chart
+            .caption("KDE Range Profile", ("sans-serif", 40).into_font())
+            // Set the size of the label region
+            .x_label_area_size(20)
+            .y_label_area_size(40)
+            // Finally attach a coordinate on the drawing area and make a chart context
+            .build_cartesian_2d(min..max, 0f64..max_y)
+            .map_err(|e| e.to_string())?;
 
-    // Then we can draw a mesh
-    let _ = chart
-        .configure_mesh()
-        // We can customize the maximum number of labels allowed for each axis
-        .x_labels(5)
-        .y_labels(5)
-        // We can also change the format of the label text
-        .y_label_formatter(&|x| format!("{:.3}", x))
-        .draw();
+        // Then we can draw a mesh
+        chart
+            .configure_mesh()
+            // We can customize the maximum number of labels allowed for each axis
+            .x_labels(5)
+            .y_labels(5)
+            // We can also change the format of the label text
+            .y_label_formatter(&|x| format!("{:.3}", x))
+            .draw()
+            .map_err(|e| e.to_string())?;
 
-    // And we can draw something in the drawing area
-    let _ = chart.draw_series(AreaSeries::new(kde, 0., &RED));
-    // Similarly, we can draw point series
-    let _ = root.present();
+        // And we can draw something in the drawing area
+        chart
+            .draw_series(AreaSeries::new(kde, 0., RED))
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
 }
 
-fn kde_transform(data: &Vec<f64>, min: &f64, max: &f64) -> Vec<(f64, f64)> {
+fn kde_transform(data: &[f64], min: &f64, max: &f64) -> Vec<(f64, f64)> {
     // Set variables for KDE
     const SHARPNESS: isize = 3; // Number of points per 1 distance
     const MARGINS: isize = 10; // Margins to both ends of the min and max val
@@ -125,11 +786,11 @@ fn kde_transform(data: &Vec<f64>, min: &f64, max: &f64) -> Vec<(f64, f64)> {
         .map(|x| {
             let mut y: f64 = 0.0f64;
             for p in data {
-                if (p - *x).abs() < KERNEL_SIZE as f64 {
-                    y += ((p - *x) / KERNEL_SIZE as f64 * 2.0).cos();
+                if (p - *x).abs() < KERNEL_SIZE {
+                    y += ((p - *x) / KERNEL_SIZE * 2.0).cos();
                 }
             }
-            (*x, y as f64)
+            (*x, y)
         })
         .collect();
     kde
@@ -0,0 +1,504 @@
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use std::fs::read_to_string;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use super::file_reader::{GuiMonitor, RadarParameters};
+use super::tlv_translator::{
+    db_to_q9, Cmplx16, Frame, PerformanceStats, PointCloudPoint, SideInfo, TemperatureStats,
+    CPU_CLOCK_HZ,
+};
+
+/// A point reflector moving through the scene at a constant velocity.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Reflector {
+    /// Position in meters at the first frame, `y` points away from the
+    /// sensor and `z` up
+    pub position: [f64; 3],
+    /// Velocity in meters per second
+    #[serde(default)]
+    pub velocity: [f64; 3],
+    /// Radar cross section in square meters
+    #[serde(default = "default_rcs")]
+    pub rcs: f64,
+}
+
+fn default_rcs() -> f64 {
+    1.0
+}
+
+/// A simple description of what the radar sees, read from a TOML file
+/// such as `./example_scene.toml`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scene {
+    pub reflectors: Vec<Reflector>,
+    /// The noise floor of the range profile and heatmaps, in dB
+    pub noise_floor_db: f64,
+    /// The standard deviation of the noise around the floor, in dB
+    pub noise_std_db: f64,
+    /// The SNR of a reflector of 1 m² at 1 m, it falls off with the
+    /// fourth power of the range from there
+    pub reference_snr_db: f64,
+    /// Seed of the noise, the same seed gives the same frames
+    pub seed: u64,
+}
+
+impl Default for Scene {
+    /// A person walking past the sensor next to a static reflector
+    fn default() -> Scene {
+        Scene {
+            reflectors: vec![
+                Reflector {
+                    position: [-2.0, 3.0, 0.0],
+                    velocity: [0.4, 0.0, 0.0],
+                    rcs: 1.0,
+                },
+                Reflector {
+                    position: [0.5, 6.0, 0.5],
+                    velocity: [0.0, 0.0, 0.0],
+                    rcs: 5.0,
+                },
+            ],
+            noise_floor_db: 50.0,
+            noise_std_db: 1.5,
+            reference_snr_db: 60.0,
+            seed: 1,
+        }
+    }
+}
+
+impl Scene {
+    pub fn from_file(path: &Path) -> Result<Scene, Error> {
+        let contents = read_to_string(path)?;
+        toml::from_str(&contents).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("In \"{}\": {}", path.display(), e),
+            )
+        })
+    }
+}
+
+/// Where a reflector is at a given moment, as seen from the sensor.
+struct Observation {
+    position: [f64; 3],
+    range: f64,
+    azimuth: f64,
+    radial_velocity: f64,
+    snr_db: f64,
+}
+
+/// Generates the frames the FMCW would produce for a `Scene`, with the
+/// dimensions and resolutions of the `RadarParameters` and only the
+/// TLVs enabled in the `GuiMonitor`.
+///
+/// All parts of a frame are consistent with each other: a reflector
+/// shows up as a detected point, as a peak in the range profile, and
+/// in the heatmaps at the same range, doppler and angle.
+pub struct SceneGenerator {
+    scene: Scene,
+    params: RadarParameters,
+    outputs: GuiMonitor,
+    frame_num: usize,
+    rng: XorShift,
+}
+
+impl SceneGenerator {
+    pub fn new(scene: Scene, params: RadarParameters, outputs: GuiMonitor) -> SceneGenerator {
+        let rng = XorShift::new(scene.seed);
+        SceneGenerator {
+            scene,
+            params,
+            outputs,
+            frame_num: 0,
+            rng,
+        }
+    }
+
+    /// Generates the next frame of the stream.
+    pub fn next_frame(&mut self) -> Frame {
+        let time_s = self.frame_num as f64 * self.params.frame_period_ms / 1000.0;
+        let mut frame = Frame::empty(self.frame_num);
+        frame.time_cpu_cycles = (time_s * CPU_CLOCK_HZ) as u64 as u32;
+        self.frame_num += 1;
+
+        let observations: Vec<Observation> = self
+            .scene
+            .reflectors
+            .iter()
+            .map(|r| self.observe(r, time_s))
+            .filter(|o| self.is_visible(o))
+            .collect();
+
+        if self.outputs.detected_points {
+            frame.pointcloud = Some(observations.iter().map(|o| self.detect(o)).collect());
+        }
+        if self.outputs.side_info {
+            let noise = (self.scene.noise_floor_db * 10.0) as i16;
+            frame.side_info = Some(
+                observations
+                    .iter()
+                    .map(|o| SideInfo {
+                        snr: (o.snr_db * 10.0) as i16,
+                        noise,
+                    })
+                    .collect(),
+            );
+        }
+        if self.outputs.range_profile {
+            frame.range_profile = Some(self.range_profile(&observations));
+        }
+        if self.outputs.noise_profile {
+            frame.noise_profile = Some(self.range_profile(&[]));
+        }
+        if self.outputs.azimuth_heatmap {
+            frame.azimuth_heatmap = Some(self.azimuth_heatmap(&observations));
+        }
+        if self.outputs.range_doppler_heatmap {
+            frame.range_doppler_heatmap = Some(self.range_doppler_heatmap(&observations));
+        }
+        if self.outputs.stats {
+            frame.stats = Some(PerformanceStats {
+                inter_frame_processing_time: 4000 + 50 * observations.len() as u32,
+                transmit_output_time: 1500,
+                inter_frame_processing_margin: 90000,
+                inter_chirp_processing_margin: 20,
+                active_frame_cpu_load: 30,
+                inter_frame_cpu_load: 10,
+            });
+            let temperature = 40 + (time_s / 60.0).min(10.0) as u16;
+            frame.temperature = Some(TemperatureStats {
+                report_valid: 0,
+                time: (time_s * 1000.0) as u32,
+                rx: [temperature; 4],
+                tx: [temperature + 2; 3],
+                pm: temperature,
+                digital: [temperature + 1; 2],
+            });
+        }
+        frame
+    }
+
+    fn observe(&self, reflector: &Reflector, time_s: f64) -> Observation {
+        let p = reflector.position;
+        let v = reflector.velocity;
+        let position = [
+            p[0] + v[0] * time_s,
+            p[1] + v[1] * time_s,
+            p[2] + v[2] * time_s,
+        ];
+        let range = position.iter().map(|c| c * c).sum::<f64>().sqrt().max(1e-3);
+        let radial_velocity = (0..3).map(|i| position[i] * v[i]).sum::<f64>() / range;
+        Observation {
+            position,
+            range,
+            azimuth: position[0].atan2(position[1]),
+            radial_velocity,
+            snr_db: self.scene.reference_snr_db + 10.0 * reflector.rcs.max(1e-6).log10()
+                - 40.0 * range.log10(),
+        }
+    }
+
+    fn is_visible(&self, o: &Observation) -> bool {
+        let p = &self.params;
+        let elevation = (o.position[2] / o.range).asin().to_degrees();
+        let azimuth = o.azimuth.to_degrees();
+        o.position[1] > 0.0
+            && o.snr_db > 0.0
+            && o.range >= p.range_fov_m.0
+            && o.range <= p.range_fov_m.1.min(p.max_range_m)
+            && azimuth >= p.azimuth_fov_deg.0
+            && azimuth <= p.azimuth_fov_deg.1
+            && elevation >= p.elevation_fov_deg.0
+            && elevation <= p.elevation_fov_deg.1
+    }
+
+    /// The point the FMCW would report, with the position jittered by
+    /// the range resolution and the velocity quantized and aliased to
+    /// what the radar can measure.
+    fn detect(&mut self, o: &Observation) -> PointCloudPoint {
+        let jitter = self.params.range_resolution_m / 2.0;
+        let doppler_bin = self.doppler_bin(o.radial_velocity);
+        let half = self.params.num_doppler_bins as isize / 2;
+        let signed_bin = if doppler_bin as isize >= half {
+            doppler_bin as isize - self.params.num_doppler_bins as isize
+        } else {
+            doppler_bin as isize
+        };
+        PointCloudPoint {
+            x: (o.position[0] + self.rng.gaussian() * jitter) as f32,
+            y: (o.position[1] + self.rng.gaussian() * jitter) as f32,
+            z: (o.position[2] + self.rng.gaussian() * jitter) as f32,
+            d: (signed_bin as f64 * self.params.doppler_resolution_mps) as f32,
+        }
+    }
+
+    /// The doppler bin of a velocity, in FFT order.
+    fn doppler_bin(&self, velocity: f64) -> usize {
+        let n = self.params.num_doppler_bins as isize;
+        let bin = (velocity / self.params.doppler_resolution_mps).round() as isize;
+        bin.rem_euclid(n) as usize
+    }
+
+    /// The linear power of the noise floor plus the main lobe of every
+    /// observation around `bin`.
+    fn power_at(
+        &mut self,
+        bin: usize,
+        observations: &[Observation],
+        doppler: Option<usize>,
+    ) -> f64 {
+        let noise_db = self.scene.noise_floor_db + self.rng.gaussian() * self.scene.noise_std_db;
+        let mut power = db_to_linear(noise_db);
+        for o in observations {
+            if let Some(doppler_bin) = doppler {
+                if doppler_bin != self.doppler_bin(o.radial_velocity) {
+                    continue;
+                }
+            }
+            let offset = bin as f64 - o.range / self.params.range_bin_m;
+            power += db_to_linear(self.scene.noise_floor_db + o.snr_db) * sinc(offset).powi(2);
+        }
+        power
+    }
+
+    fn range_profile(&mut self, observations: &[Observation]) -> Vec<f64> {
+        (0..self.params.num_range_bins)
+            .map(|bin| linear_to_db(self.power_at(bin, observations, None)))
+            .collect()
+    }
+
+    fn range_doppler_heatmap(&mut self, observations: &[Observation]) -> Vec<u16> {
+        let mut result =
+            Vec::with_capacity(self.params.num_range_bins * self.params.num_doppler_bins);
+        for range_bin in 0..self.params.num_range_bins {
+            for doppler_bin in 0..self.params.num_doppler_bins {
+                let power = self.power_at(range_bin, observations, Some(doppler_bin));
+                result.push(db_to_q9(linear_to_db(power)));
+            }
+        }
+        result
+    }
+
+    /// The complex samples of every virtual antenna along the azimuth,
+    /// where a reflector adds a phase progression of `π sin(azimuth)`
+    /// per antenna on top of complex noise.
+    fn azimuth_heatmap(&mut self, observations: &[Observation]) -> Vec<Cmplx16> {
+        const NOISE_AMPLITUDE: f64 = 20.0;
+        let num_antennas = self.params.num_virtual_antennas_azimuth();
+        let mut result = Vec::with_capacity(self.params.num_range_bins * num_antennas);
+        for range_bin in 0..self.params.num_range_bins {
+            for antenna in 0..num_antennas {
+                let mut real = self.rng.gaussian() * NOISE_AMPLITUDE;
+                let mut imag = self.rng.gaussian() * NOISE_AMPLITUDE;
+                for o in observations {
+                    let offset = range_bin as f64 - o.range / self.params.range_bin_m;
+                    let amplitude = NOISE_AMPLITUDE * 10f64.powf(o.snr_db / 20.0) * sinc(offset);
+                    let phase = PI * antenna as f64 * o.azimuth.sin();
+                    real += amplitude * phase.cos();
+                    imag += amplitude * phase.sin();
+                }
+                result.push(Cmplx16 {
+                    imag: imag.clamp(i16::MIN as f64, i16::MAX as f64) as i16,
+                    real: real.clamp(i16::MIN as f64, i16::MAX as f64) as i16,
+                });
+            }
+        }
+        result
+    }
+}
+
+impl Iterator for SceneGenerator {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        Some(self.next_frame())
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+fn db_to_linear(db: f64) -> f64 {
+    10f64.powf(db / 10.0)
+}
+
+fn linear_to_db(power: f64) -> f64 {
+    10.0 * power.log10()
+}
+
+/// A small xorshift random number generator, so that the noise of a
+/// scene is reproducible from its seed.
+struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        // The state may never be zero
+        XorShift {
+            state: seed ^ 0x9E37_79B9_7F4A_7C15,
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// A uniform sample in (0, 1]
+    fn uniform(&mut self) -> f64 {
+        ((self.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+
+    /// A standard normal sample, using the Box-Muller transform
+    fn gaussian(&mut self) -> f64 {
+        let (u1, u2) = (self.uniform(), self.uniform());
+        (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_reader::Config;
+
+    fn params() -> RadarParameters {
+        Config {
+            raw_input: include_str!("../iwr6843_config.cfg").to_string(),
+        }
+        .radar_parameters()
+        .unwrap()
+    }
+
+    fn outputs(all: bool) -> GuiMonitor {
+        GuiMonitor {
+            detected_points: true,
+            side_info: all,
+            range_profile: true,
+            noise_profile: all,
+            azimuth_heatmap: all,
+            range_doppler_heatmap: all,
+            stats: all,
+        }
+    }
+
+    fn scene(reflectors: Vec<Reflector>) -> Scene {
+        Scene {
+            reflectors,
+            ..Scene::default()
+        }
+    }
+
+    fn still(position: [f64; 3]) -> Reflector {
+        Reflector {
+            position,
+            velocity: [0.0; 3],
+            rcs: 1.0,
+        }
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_frames() {
+        let a: Vec<Frame> = SceneGenerator::new(Scene::default(), params(), outputs(true))
+            .take(5)
+            .collect();
+        let b: Vec<Frame> = SceneGenerator::new(Scene::default(), params(), outputs(true))
+            .take(5)
+            .collect();
+        assert_eq!(a, b);
+        assert_eq!(
+            a.iter().map(|f| f.frame_num).collect::<Vec<_>>(),
+            [0, 1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn only_writes_the_enabled_outputs() {
+        let frame = SceneGenerator::new(Scene::default(), params(), outputs(false))
+            .next()
+            .unwrap();
+        assert!(frame.pointcloud.is_some() && frame.range_profile.is_some());
+        assert!(frame.side_info.is_none() && frame.noise_profile.is_none());
+        assert!(frame.azimuth_heatmap.is_none() && frame.range_doppler_heatmap.is_none());
+        assert!(frame.stats.is_none() && frame.temperature.is_none());
+
+        let params = params();
+        let frame = SceneGenerator::new(Scene::default(), params.clone(), outputs(true))
+            .next()
+            .unwrap();
+        let points = frame.pointcloud.unwrap().len();
+        assert_eq!(frame.side_info.unwrap().len(), points);
+        assert_eq!(
+            frame.azimuth_heatmap.unwrap().len(),
+            params.num_range_bins * params.num_virtual_antennas_azimuth()
+        );
+        assert_eq!(
+            frame.range_doppler_heatmap.unwrap().len(),
+            params.num_range_bins * params.num_doppler_bins
+        );
+    }
+
+    #[test]
+    fn a_reflector_is_a_point_and_a_range_peak() {
+        let params = params();
+        let mut generator = SceneGenerator::new(
+            scene(vec![still([1.0, 4.0, 0.0])]),
+            params.clone(),
+            outputs(false),
+        );
+        let frame = generator.next().unwrap();
+
+        let points = frame.pointcloud.unwrap();
+        assert_eq!(points.len(), 1);
+        let tolerance = params.range_resolution_m * 3.0;
+        assert!((points[0].x as f64 - 1.0).abs() < tolerance);
+        assert!((points[0].y as f64 - 4.0).abs() < tolerance);
+        assert_eq!(points[0].d, 0.0);
+
+        let profile = frame.range_profile.unwrap();
+        let peak = (0..profile.len())
+            .max_by(|a, b| profile[*a].total_cmp(&profile[*b]))
+            .unwrap();
+        let range = 1f64.hypot(4.0);
+        assert!((peak as f64 * params.range_bin_m - range).abs() <= params.range_bin_m);
+    }
+
+    #[test]
+    fn reflectors_outside_the_field_of_view_are_not_detected() {
+        let params = params();
+        let beyond = params.range_fov_m.1.min(params.max_range_m) + 1.0;
+        let mut generator = SceneGenerator::new(
+            scene(vec![still([0.0, -3.0, 0.0]), still([0.0, beyond, 0.0])]),
+            params,
+            outputs(false),
+        );
+        assert!(generator.next().unwrap().pointcloud.unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_moving_reflector_has_a_doppler() {
+        let params = params();
+        let mut generator = SceneGenerator::new(
+            scene(vec![Reflector {
+                position: [0.0, 3.0, 0.0],
+                velocity: [0.0, 0.5, 0.0],
+                rcs: 1.0,
+            }]),
+            params.clone(),
+            outputs(false),
+        );
+        let points = generator.next().unwrap().pointcloud.unwrap();
+        assert!((points[0].d as f64 - 0.5).abs() <= params.doppler_resolution_mps);
+    }
+}
@@ -0,0 +1,217 @@
+use memmap2::MmapMut;
+use std::fs::{remove_file, OpenOptions};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+
+use super::encoding::BINARY_SCHEMA_VERSION;
+
+/// Starts the shared memory file.
+pub const SHM_MAGIC: &[u8; 8] = b"IWRSHM01";
+
+/// The size of the header at the start of the file.
+pub const HEADER_SIZE: usize = 64;
+
+/// The size of the header at the start of every slot.
+pub const SLOT_HEADER_SIZE: usize = 16;
+
+/// Where the sequence number of the newest frame is stored in the header.
+const LATEST_OFFSET: usize = 24;
+
+/// A ring of fixed size frame slots in a shared memory file, which
+/// other processes on the same machine can map to read the frames
+/// without copying them.
+///
+/// The file starts with a header of `HEADER_SIZE` bytes, all little
+/// endian:
+///
+/// - `SHM_MAGIC`
+/// - the number of slots (`u32`)
+/// - the version of the binary frame layout, see `encode_binary` (`u32`)
+/// - the maximum size of a frame in a slot (`u64`)
+/// - the sequence number of the newest frame, 0 before the first (`u64`)
+///
+/// It is followed by the slots, each `slot_stride` bytes apart: a
+/// `u64` state, the length of the frame (`u32`), 4 reserved bytes and
+/// the frame in the binary layout.
+///
+/// Frames are numbered from 1, frame `n` is written into slot
+/// `(n - 1) % slots`. The state of a slot holds the sequence number of
+/// its frame shifted left by one, with the lowest bit set while the
+/// frame is being written. A reader reads the state before and after
+/// using a frame, the frame is intact if both are equal and even.
+pub struct ShmRing {
+    mmap: MmapMut,
+    path: PathBuf,
+    slots: usize,
+    slot_size: usize,
+    sequence: u64,
+}
+
+impl ShmRing {
+    /// Creates the shared memory file at `path`, e.g. in `/dev/shm`,
+    /// replacing an existing file.
+    pub fn create(path: &Path, slots: usize, slot_size: usize) -> Result<ShmRing, Error> {
+        if slots == 0 || slot_size == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the shared memory ring needs at least one slot of at least one byte",
+            ));
+        }
+        // The file is replaced rather than truncated, so readers which
+        // still map the old one are not hit by a bus error
+        _ = remove_file(path);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        let stride = slot_stride(slot_size);
+        file.set_len((HEADER_SIZE + slots * stride) as u64)?;
+        // Safe as long as no other process truncates the file
+        let mut mmap = unsafe { MmapMut::map_mut(&file)? };
+
+        mmap[0..8].copy_from_slice(SHM_MAGIC);
+        mmap[8..12].copy_from_slice(&(slots as u32).to_le_bytes());
+        mmap[12..16].copy_from_slice(&(BINARY_SCHEMA_VERSION as u32).to_le_bytes());
+        mmap[16..24].copy_from_slice(&(slot_size as u64).to_le_bytes());
+        mmap.flush()?;
+
+        Ok(ShmRing {
+            mmap,
+            path: path.to_path_buf(),
+            slots,
+            slot_size,
+            sequence: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes a frame in the binary layout into the next slot, returning
+    /// its sequence number and slot.
+    pub fn write(&mut self, frame: &[u8]) -> Result<(u64, usize), Error> {
+        if frame.len() > self.slot_size {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "a frame of {} bytes does not fit in a slot of {} bytes",
+                    frame.len(),
+                    self.slot_size
+                ),
+            ));
+        }
+        let sequence = self.sequence + 1;
+        let slot = ((sequence - 1) % self.slots as u64) as usize;
+        let base = HEADER_SIZE + slot * slot_stride(self.slot_size);
+
+        // Mark the slot as being written before touching the frame
+        self.atomic(base)
+            .store(sequence << 1 | 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.mmap[base + 8..base + 12].copy_from_slice(&(frame.len() as u32).to_le_bytes());
+        self.mmap[base + SLOT_HEADER_SIZE..base + SLOT_HEADER_SIZE + frame.len()]
+            .copy_from_slice(frame);
+        self.atomic(base).store(sequence << 1, Ordering::Release);
+        self.atomic(LATEST_OFFSET)
+            .store(sequence, Ordering::Release);
+
+        self.sequence = sequence;
+        Ok((sequence, slot))
+    }
+
+    /// The `u64` at `offset` in the mapping, which must be a multiple of 8.
+    fn atomic(&mut self, offset: usize) -> &AtomicU64 {
+        debug_assert!(offset.is_multiple_of(8) && offset + 8 <= self.mmap.len());
+        // The mapping is page aligned, so an offset which is a multiple
+        // of 8 is aligned as well, and it lives as long as `self`
+        unsafe { AtomicU64::from_ptr(self.mmap.as_mut_ptr().add(offset) as *mut u64) }
+    }
+}
+
+impl Drop for ShmRing {
+    fn drop(&mut self) {
+        _ = remove_file(&self.path);
+    }
+}
+
+/// The distance between two slots, a whole number of cache lines.
+pub fn slot_stride(slot_size: usize) -> usize {
+    (SLOT_HEADER_SIZE + slot_size).div_ceil(64) * 64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn ring_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("shm_{}_{}", name, std::process::id()))
+    }
+
+    /// The frame with sequence number `n`, of a length of its own.
+    fn frame(n: u8) -> Vec<u8> {
+        vec![n; 10 + n as usize]
+    }
+
+    #[test]
+    fn frames_wrap_around_the_slots() {
+        let path = ring_path("wrap");
+        let mut ring = ShmRing::create(&path, 3, 100).unwrap();
+        for n in 1..=7u8 {
+            let (sequence, slot) = ring.write(&frame(n)).unwrap();
+            assert_eq!((sequence, slot), (n as u64, (n as usize - 1) % 3));
+        }
+
+        // Read the way another process sees it
+        let bytes = std::fs::read(&path).unwrap();
+        let stride = slot_stride(100);
+        assert_eq!(stride, 128);
+        assert_eq!(bytes.len(), HEADER_SIZE + 3 * stride);
+        assert_eq!(&bytes[0..8], SHM_MAGIC);
+        assert_eq!(u32_at(&bytes, 8), 3);
+        assert_eq!(u32_at(&bytes, 12), BINARY_SCHEMA_VERSION as u32);
+        assert_eq!(u64_at(&bytes, 16), 100);
+        assert_eq!(u64_at(&bytes, LATEST_OFFSET), 7);
+
+        // The last three frames, 7 has overwritten 1 and 4 in slot 0
+        for (slot, sequence) in [(0, 7u8), (1, 5), (2, 6)] {
+            let base = HEADER_SIZE + slot * stride;
+            let state = u64_at(&bytes, base);
+            assert_eq!(state % 2, 0, "slot {slot} is still being written");
+            assert_eq!(state >> 1, sequence as u64);
+            let length = u32_at(&bytes, base + 8) as usize;
+            let start = base + SLOT_HEADER_SIZE;
+            assert_eq!(bytes[start..start + length], frame(sequence));
+        }
+
+        drop(ring);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn a_frame_larger_than_a_slot_is_rejected() {
+        let path = ring_path("oversized");
+        let mut ring = ShmRing::create(&path, 2, 16).unwrap();
+        assert_eq!(ring.write(&[1; 16]).unwrap(), (1, 0));
+        let e = ring.write(&[2; 17]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+
+        // Nothing was written, the next frame takes its place
+        assert_eq!(ring.write(&[3; 4]).unwrap(), (2, 1));
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(u64_at(&bytes, LATEST_OFFSET), 2);
+        assert_eq!(u64_at(&bytes, HEADER_SIZE + slot_stride(16)), 2 << 1);
+
+        assert!(ShmRing::create(&ring_path("empty"), 0, 16).is_err());
+    }
+}
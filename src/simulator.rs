@@ -0,0 +1,490 @@
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::pty::openpty;
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+use nix::unistd::ttyname;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::file_reader::Config;
+use super::recording::read_capture_frames;
+use super::scene::{Scene, SceneGenerator};
+use super::tlv_encoder::encode_frame;
+
+const PROMPT: &str = "mmwDemo:/>";
+
+/// The commands the out of box demo accepts, anything else is answered
+/// with an error just like the real firmware does.
+const KNOWN_COMMANDS: [&str; 33] = [
+    "sensorStart",
+    "sensorStop",
+    "flushCfg",
+    "dfeDataOutputMode",
+    "channelCfg",
+    "adcCfg",
+    "adcbufCfg",
+    "profileCfg",
+    "chirpCfg",
+    "frameCfg",
+    "advFrameCfg",
+    "subFrameCfg",
+    "lowPower",
+    "guiMonitor",
+    "cfarCfg",
+    "multiObjBeamForming",
+    "clutterRemoval",
+    "calibDcRangeSig",
+    "extendedMaxVelocity",
+    "bpmCfg",
+    "lvdsStreamCfg",
+    "compRangeBiasAndRxChanPhase",
+    "measureRangeBiasAndRxChanPhase",
+    "CQRxSatMonitor",
+    "CQSigImgMonitor",
+    "analogMonitor",
+    "aoaFovCfg",
+    "cfarFovCfg",
+    "calibData",
+    "configDataPort",
+    "queryDemoStatus",
+    "idlePowerCycle",
+    "version",
+];
+
+/// Where the simulated device gets the frames it streams from.
+#[derive(Debug, Clone)]
+pub enum SimulatedFrames {
+    /// Replays the frames of a recording or a raw capture such as
+    /// `./tlv_example_file.dat`, looping at the end.
+    Replay(PathBuf),
+    /// Generates the frames of a scene, with the parameters of the
+    /// configuration the device received.
+    Synthetic(Scene),
+}
+
+/// The state shared between the CLI and the data thread.
+struct DeviceState {
+    streaming: bool,
+    frame_period: Duration,
+    /// Every command received since the last `flushCfg`
+    config: Vec<String>,
+    scene: Option<Scene>,
+    generator: Option<SceneGenerator>,
+}
+
+/// A simulated IWR6843, exposing a CLI and a data port as the two
+/// ends of pseudo terminals. The paths in `cfg_port` and `data_port`
+/// can be used in the settings in place of the real serial ports.
+///
+/// The device answers the configuration like the out of box demo
+/// (echo, `Done` or `Error`, followed by the prompt), and streams frames
+/// at the period of `frameCfg` between `sensorStart` and `sensorStop`.
+///
+/// Dropping the device closes the ports, like unplugging the board.
+pub struct SimulatedDevice {
+    pub cfg_port: PathBuf,
+    pub data_port: PathBuf,
+    // The slave ends are kept open, so the master ends do not see a
+    // hang up whenever the `Fmcw` closes and reopens the ports.
+    _cfg_slave: OwnedFd,
+    _data_slave: OwnedFd,
+    /// Set on drop, the threads then return and close the master ends
+    closed: Arc<AtomicBool>,
+}
+
+impl SimulatedDevice {
+    /// Creates the pseudo terminals and starts the threads serving them.
+    ///
+    /// # Arguments
+    /// * `frames`: The source of the streamed frames
+    /// * `data_baud`: The baud rate of the data port, used to pace the
+    ///   bytes as a real UART would
+    pub fn spawn(frames: SimulatedFrames, data_baud: u32) -> Result<SimulatedDevice, Error> {
+        let (frames, scene): (Vec<Vec<u8>>, Option<Scene>) = match frames {
+            SimulatedFrames::Replay(path) => (read_capture_frames(&path)?, None),
+            SimulatedFrames::Synthetic(scene) => (vec![], Some(scene)),
+        };
+
+        let (cfg_master, cfg_slave, cfg_port) = open_pty()?;
+        let (data_master, data_slave, data_port) = open_pty()?;
+
+        let state = Arc::new(Mutex::new(DeviceState {
+            streaming: false,
+            frame_period: Duration::from_millis(100),
+            config: vec![],
+            scene,
+            generator: None,
+        }));
+
+        // Reading does not block, so the CLI thread notices the close
+        fcntl(cfg_master.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let cli_state = state.clone();
+        let cli_closed = closed.clone();
+        let data_closed = closed.clone();
+        thread::spawn(move || serve_cli(File::from(cfg_master), cli_state, cli_closed));
+        thread::spawn(move || {
+            serve_data(
+                File::from(data_master),
+                state,
+                frames,
+                data_baud,
+                data_closed,
+            )
+        });
+
+        Ok(SimulatedDevice {
+            cfg_port,
+            data_port,
+            _cfg_slave: cfg_slave,
+            _data_slave: data_slave,
+            closed,
+        })
+    }
+}
+
+impl Drop for SimulatedDevice {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Opens a pseudo terminal in raw mode, returning the master, the slave
+/// and the path under which the slave can be opened.
+fn open_pty() -> Result<(OwnedFd, OwnedFd, PathBuf), Error> {
+    let pty = openpty(None, None)?;
+    let mut termios = tcgetattr(&pty.slave)?;
+    cfmakeraw(&mut termios);
+    tcsetattr(&pty.slave, SetArg::TCSANOW, &termios)?;
+    let path = ttyname(&pty.slave)?;
+    Ok((pty.master, pty.slave, path))
+}
+
+fn serve_cli(mut port: File, state: Arc<Mutex<DeviceState>>, closed: Arc<AtomicBool>) {
+    let mut received: Vec<u8> = vec![];
+    let mut buf = [0u8; 256];
+    while !closed.load(Ordering::Relaxed) {
+        match port.read(&mut buf) {
+            Ok(n) if n > 0 => received.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            // Nothing to read yet, or an error as no slave is open, which
+            // happens while the `Fmcw` reconnects, so we wait for it
+            _ => {
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+        }
+        while let Some(end) = received.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = received.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let response = handle_command(line.trim(), &state);
+            // In a single write, so the response never arrives in parts
+            let response = format!("{}\n{}\n{} ", line.trim(), response, PROMPT);
+            let _ = port.write_all(response.as_bytes());
+        }
+    }
+}
+
+/// Executes a single CLI command, returning the response of the
+/// firmware.
+fn handle_command(line: &str, state: &Mutex<DeviceState>) -> String {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let Some(command) = args.first() else {
+        return String::new();
+    };
+    if !KNOWN_COMMANDS.contains(command) {
+        return format!("'{}' is not recognized as a CLI command", command);
+    }
+
+    let mut state = state.lock().unwrap();
+    match *command {
+        "sensorStart" => {
+            if let Some(scene) = state.scene.clone() {
+                // Like the firmware, only start with a complete configuration
+                let config = Config {
+                    raw_input: state.config.join("\n"),
+                };
+                match (config.radar_parameters(), config.gui_monitor()) {
+                    (Ok(params), Ok(outputs)) => {
+                        state.generator = Some(SceneGenerator::new(scene, params, outputs))
+                    }
+                    (Err(e), _) | (_, Err(e)) => return format!("Error -1: {}", e),
+                }
+            }
+            state.streaming = true
+        }
+        "sensorStop" => state.streaming = false,
+        "flushCfg" => state.config.clear(),
+        "frameCfg" => match args.get(5).map(|p| p.parse::<f64>()) {
+            Some(Ok(period)) if period > 0.0 => {
+                state.frame_period = Duration::from_secs_f64(period / 1000.0)
+            }
+            _ => return "Error -1".to_string(),
+        },
+        _ => {}
+    }
+    state.config.push(line.to_string());
+    "Done".to_string()
+}
+
+fn serve_data(
+    mut port: File,
+    state: Arc<Mutex<DeviceState>>,
+    frames: Vec<Vec<u8>>,
+    baud: u32,
+    closed: Arc<AtomicBool>,
+) {
+    let mut frame_num: usize = 0;
+    while !closed.load(Ordering::Relaxed) {
+        let start = Instant::now();
+        let (frame, frame_period) = {
+            let mut s = state.lock().unwrap();
+            let streaming = s.streaming;
+            let frame = match s.generator {
+                _ if !streaming => None,
+                Some(ref mut generator) => Some(encode_frame(&generator.next_frame())),
+                None if !frames.is_empty() => Some(frames[frame_num % frames.len()].clone()),
+                None => None,
+            };
+            (frame, s.frame_period)
+        };
+        let Some(frame) = frame else {
+            thread::sleep(Duration::from_millis(10));
+            continue;
+        };
+        frame_num += 1;
+
+        // Write in small chunks at the speed of the UART, 10 bits per byte
+        for chunk in frame.chunks(256) {
+            if port.write_all(chunk).is_err() {
+                // Nobody has the data port open right now
+                break;
+            }
+            thread::sleep(Duration::from_secs_f64(
+                chunk.len() as f64 * 10.0 / baud as f64,
+            ));
+        }
+        if let Some(remaining) = frame_period.checked_sub(start.elapsed()) {
+            thread::sleep(remaining);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_reader::Settings;
+    use crate::fmcw_manager::{ConnectionState, Fmcw, FmcwCommand};
+    use crate::queue::{bounded, DropPolicy, QueueReceiver};
+    use crate::tlv_translator::{translate_tlv, Frame};
+
+    fn config() -> Config {
+        Config {
+            raw_input: include_str!("../iwr6843_config.cfg").to_string(),
+        }
+    }
+
+    /// An `Fmcw` connected to a simulated device, which has to be kept
+    /// alive as long as the `Fmcw` is used.
+    fn simulated_fmcw() -> (SimulatedDevice, Fmcw) {
+        let device =
+            SimulatedDevice::spawn(SimulatedFrames::Synthetic(Scene::default()), 921600).unwrap();
+        let mut settings = Settings::default();
+        settings.serial.cfg_port = device.cfg_port.to_string_lossy().to_string();
+        settings.serial.data_port = device.data_port.to_string_lossy().to_string();
+        let fmcw = Fmcw::new(Arc::new(settings), config()).unwrap();
+        (device, fmcw)
+    }
+
+    #[test]
+    fn answers_commands_like_the_firmware() {
+        let (_device, fmcw) = simulated_fmcw();
+        let response = fmcw.send_cli_command("flushCfg").unwrap();
+        assert!(response.contains("flushCfg"), "no echo in {response:?}");
+        assert!(response.contains("Done"));
+        assert!(response.contains(PROMPT));
+
+        let response = fmcw.send_cli_command("bogusCfg 1 2").unwrap();
+        assert!(response.contains("not recognized"));
+        let response = fmcw.send_cli_command("frameCfg 0 2 16 0 0 1").unwrap();
+        assert!(response.contains("Error"));
+    }
+
+    #[test]
+    fn streams_frames_once_configured() {
+        let (_device, fmcw) = simulated_fmcw();
+        let params = config().radar_parameters().unwrap();
+
+        // Nothing is sent before `sensorStart`
+        assert!(fmcw.receive_bytes().unwrap().is_empty());
+
+        fmcw.send_config().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(20);
+        let mut bytes = vec![];
+        let mut frames = vec![];
+        while frames.len() < 3 && Instant::now() < deadline {
+            bytes.extend(fmcw.receive_bytes().unwrap());
+            if !bytes.is_empty() {
+                frames.extend(translate_tlv(&mut bytes));
+            }
+        }
+        assert!(frames.len() >= 3, "only {} frames received", frames.len());
+        for pair in frames.windows(2) {
+            assert_eq!(pair[1].frame_num, pair[0].frame_num + 1);
+        }
+        for frame in &frames {
+            assert!(!frame.pointcloud.as_ref().unwrap().is_empty());
+            assert_eq!(
+                frame.range_profile.as_ref().map(Vec::len),
+                Some(params.num_range_bins)
+            );
+        }
+
+        let response = fmcw.send_cli_command("sensorStop").unwrap();
+        assert!(response.contains("Done"));
+    }
+
+    /// Asserts that frames arrive `period_ms` apart, by counting them
+    /// over three seconds, as the data is read a few frames at a time.
+    fn assert_period(rx: &QueueReceiver<Vec<u8>>, period_ms: u64) {
+        // Start at a complete frame
+        receive_frames(rx, 1);
+        let window = Duration::from_secs(3);
+        let end = Instant::now() + window;
+        let mut bytes = vec![];
+        let mut count: u64 = 0;
+        while let Some(wait) = end.checked_duration_since(Instant::now()) {
+            if let Ok(received) = rx.recv_timeout(wait) {
+                bytes.extend(received);
+                count += translate_tlv(&mut bytes).len() as u64;
+            }
+        }
+        let expected = window.as_millis() as u64 / period_ms;
+        assert!(
+            count.abs_diff(expected) <= 4,
+            "{count} frames in {window:?}, expected {expected}"
+        );
+    }
+
+    #[test]
+    fn a_running_fmcw_follows_its_commands() {
+        let (_device, mut fmcw) = simulated_fmcw();
+        let handle = fmcw.handle();
+        let state = fmcw.subscribe_state();
+        let (tx, rx) = bounded("raw", 64, DropPolicy::DropOldest);
+        thread::spawn(move || fmcw.run(tx));
+
+        // Streams at the period of the configuration, 100 ms
+        let frames = receive_frames(&rx, 3);
+        for pair in frames.windows(2) {
+            assert_eq!(pair[1].frame_num, pair[0].frame_num + 1);
+        }
+        assert_period(&rx, 100);
+
+        // Stopping by hand is remembered like the stop command
+        let response = handle.request(FmcwCommand::Cli("sensorStop".to_string()));
+        assert!(response.unwrap().contains("Done"));
+        assert_eq!(*state.borrow(), ConnectionState::Stopped);
+        // A frame which was being sent may still arrive
+        thread::sleep(Duration::from_millis(200));
+        while rx.recv_timeout(Duration::ZERO).is_ok() {}
+        assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+
+        let response = handle.request(FmcwCommand::Cli("sensorStart 0".to_string()));
+        assert!(response.unwrap().contains("Done"));
+        assert_eq!(*state.borrow(), ConnectionState::Streaming);
+        receive_frames(&rx, 2);
+
+        // A new configuration brings its own period
+        let slower = Config {
+            raw_input: config()
+                .raw_input
+                .replace("frameCfg 0 1 16 0 100 1 0", "frameCfg 0 1 16 0 200 1 0"),
+        };
+        handle.request(FmcwCommand::SwitchConfig(slower)).unwrap();
+        assert_period(&rx, 200);
+
+        let invalid = Config {
+            raw_input: "sensorStop\nframeCfg 0 2 16 0 0 1\n".to_string(),
+        };
+        assert!(handle.request(FmcwCommand::SwitchConfig(invalid)).is_err());
+    }
+
+    /// Waits for `count` frames on `rx`, from bytes received after the
+    /// call, panicking if they do not arrive in time.
+    fn receive_frames(rx: &QueueReceiver<Vec<u8>>, count: usize) -> Vec<Frame> {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        let mut bytes = vec![];
+        let mut frames = vec![];
+        while frames.len() < count {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            bytes.extend(rx.recv_timeout(wait).expect("no frames received"));
+            frames.extend(translate_tlv(&mut bytes));
+        }
+        frames
+    }
+
+    #[test]
+    fn reconnects_to_a_device_which_was_unplugged() {
+        // The ports are symlinks, so they can be pointed at another device
+        let directory = std::env::temp_dir().join(format!("reconnect_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let (cfg_link, data_link) = (directory.join("cfg"), directory.join("data"));
+        let plug_in = |device: &SimulatedDevice| {
+            for (link, port) in [
+                (&cfg_link, &device.cfg_port),
+                (&data_link, &device.data_port),
+            ] {
+                let _ = std::fs::remove_file(link);
+                std::os::unix::fs::symlink(port, link).unwrap();
+            }
+        };
+        let spawn = || {
+            SimulatedDevice::spawn(SimulatedFrames::Synthetic(Scene::default()), 921600).unwrap()
+        };
+
+        let device = spawn();
+        plug_in(&device);
+        let mut settings = Settings::default();
+        settings.serial.cfg_port = cfg_link.to_string_lossy().to_string();
+        settings.serial.data_port = data_link.to_string_lossy().to_string();
+        settings.serial.stall_timeout_ms = 1000;
+        settings.serial.reconnect_backoff_ms = 100;
+        settings.serial.reconnect_max_backoff_ms = 200;
+        let mut fmcw = Fmcw::new(Arc::new(settings), config()).unwrap();
+        let state = fmcw.subscribe_state();
+        let (tx, rx) = bounded("raw", 64, DropPolicy::DropOldest);
+        thread::spawn(move || fmcw.run(tx));
+        receive_frames(&rx, 2);
+
+        // Unplug it, and plug in another one a little later
+        drop(device);
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while *state.borrow() != ConnectionState::Lost {
+            assert!(
+                Instant::now() < deadline,
+                "the lost connection went unnoticed"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+        let device = spawn();
+        plug_in(&device);
+
+        while *state.borrow() != ConnectionState::Streaming {
+            assert!(Instant::now() < deadline, "did not reconnect");
+            thread::sleep(Duration::from_millis(10));
+        }
+        while rx.recv_timeout(Duration::ZERO).is_ok() {}
+        // The new device starts counting from the start again
+        let frames = receive_frames(&rx, 2);
+        assert!(frames[0].frame_num < 10, "frame {}", frames[0].frame_num);
+        drop(rx);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,174 @@
+use super::tlv_translator::{db_to_q9, Frame, TlvType, FRAME_HEADER_LEN, MAGIC_WORD};
+
+/// The version of the SDK written into the frame header, 3.6.0.0
+pub const SDK_VERSION: u32 = 0x0306_0000;
+
+/// The platform written into the frame header, the xWR68xx
+pub const PLATFORM_XWR68XX: u32 = 0x000A_6843;
+
+/// The firmware pads every frame to a multiple of this many bytes.
+const FRAME_ALIGNMENT: usize = 32;
+
+/// Encodes a frame into the bytes the FMCW would send for it over the
+/// data port, so that `translate_tlv` decodes it back into `frame`.
+///
+/// The TLVs are written in the order of the out of box demo, only the
+/// parts of the frame which are `Some` are written. As the frame does
+/// not know about the configuration, the heatmaps are written as they
+/// are stored.
+pub fn encode_frame(frame: &Frame) -> Vec<u8> {
+    let mut tlvs: Vec<u8> = vec![];
+    let mut tlv_count: u32 = 0;
+    let mut push_tlv = |tlv_type: TlvType, payload: Vec<u8>| {
+        tlvs.extend_from_slice(&(tlv_type as u32).to_le_bytes());
+        tlvs.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        tlvs.extend_from_slice(&payload);
+        tlv_count += 1;
+    };
+
+    if let Some(ref pc) = frame.pointcloud {
+        push_tlv(
+            TlvType::DetectedPoints,
+            pc.iter().flat_map(|p| p.to_bytes()).collect(),
+        );
+    }
+    if let Some(ref side_info) = frame.side_info {
+        push_tlv(
+            TlvType::SideInforForDetectedPoints,
+            side_info.iter().flat_map(|s| s.to_bytes()).collect(),
+        );
+    }
+    if let Some(ref rp) = frame.range_profile {
+        push_tlv(TlvType::RangeProfile, encode_log_profile(rp));
+    }
+    if let Some(ref np) = frame.noise_profile {
+        push_tlv(TlvType::NoiseFloorProfile, encode_log_profile(np));
+    }
+    if let Some(ref heatmap) = frame.azimuth_heatmap {
+        push_tlv(
+            TlvType::AzimuthStaticHeatmap,
+            heatmap.iter().flat_map(|c| c.to_bytes()).collect(),
+        );
+    }
+    if let Some(ref heatmap) = frame.azimuth_elevation_heatmap {
+        push_tlv(
+            TlvType::AzimuthElevationStaticHeatmap,
+            heatmap.iter().flat_map(|c| c.to_bytes()).collect(),
+        );
+    }
+    if let Some(ref heatmap) = frame.range_doppler_heatmap {
+        push_tlv(
+            TlvType::RangeDopplerHeatmap,
+            heatmap.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        );
+    }
+    if let Some(stats) = frame.stats {
+        push_tlv(TlvType::PerformanceStatistics, stats.to_bytes());
+    }
+    if let Some(temperature) = frame.temperature {
+        push_tlv(TlvType::TemperatureStatistics, temperature.to_bytes());
+    }
+
+    let unpadded_len = FRAME_HEADER_LEN + tlvs.len();
+    let total_len = unpadded_len.div_ceil(FRAME_ALIGNMENT) * FRAME_ALIGNMENT;
+    let num_detected_obj = frame.pointcloud.as_ref().map_or(0, |pc| pc.len());
+
+    let mut result: Vec<u8> = Vec::with_capacity(total_len);
+    result.extend_from_slice(&MAGIC_WORD);
+    for field in [
+        SDK_VERSION,
+        total_len as u32,
+        PLATFORM_XWR68XX,
+        frame.frame_num as u32,
+        frame.time_cpu_cycles,
+        num_detected_obj as u32,
+        tlv_count,
+        frame.subframe_num as u32,
+    ] {
+        result.extend_from_slice(&field.to_le_bytes());
+    }
+    result.extend_from_slice(&tlvs);
+    result.resize(total_len, 0);
+    result
+}
+
+/// Encodes a range or noise profile in dB back into the Q9 values
+/// the FMCW sends.
+fn encode_log_profile(profile: &[f64]) -> Vec<u8> {
+    profile
+        .iter()
+        .flat_map(|db| db_to_q9(*db).to_le_bytes())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_reader::{Config, GuiMonitor};
+    use crate::scene::{Scene, SceneGenerator};
+    use crate::tlv_translator::{q9_to_db, translate_tlv};
+
+    /// A frame with every TLV, its profiles already rounded to what Q9
+    /// can hold so it survives the round trip unchanged.
+    fn full_frame(frame_num: usize) -> Frame {
+        let config = Config {
+            raw_input: include_str!("../iwr6843_config.cfg").to_string(),
+        };
+        let outputs = GuiMonitor {
+            detected_points: true,
+            side_info: true,
+            range_profile: true,
+            noise_profile: true,
+            azimuth_heatmap: true,
+            range_doppler_heatmap: true,
+            stats: true,
+        };
+        let params = config.radar_parameters().unwrap();
+        let mut generator = SceneGenerator::new(Scene::default(), params, outputs);
+        let mut frame = generator.nth(frame_num).unwrap();
+        let quantize = |profile: &mut Vec<f64>| {
+            for db in profile.iter_mut() {
+                *db = q9_to_db(db_to_q9(*db));
+            }
+        };
+        frame.range_profile.as_mut().map(quantize);
+        frame.noise_profile.as_mut().map(quantize);
+        frame
+    }
+
+    #[test]
+    fn round_trips_every_tlv() {
+        let frame = full_frame(3);
+        assert!(!frame.pointcloud.as_ref().unwrap().is_empty());
+        let mut bytes = encode_frame(&frame);
+        assert_eq!(translate_tlv(&mut bytes), vec![frame]);
+    }
+
+    #[test]
+    fn round_trips_an_empty_frame() {
+        let mut frame = Frame::empty(42);
+        frame.subframe_num = 1;
+        frame.time_cpu_cycles = 123_456;
+        let mut bytes = encode_frame(&frame);
+        assert_eq!(translate_tlv(&mut bytes), vec![frame]);
+    }
+
+    #[test]
+    fn pads_frames_like_the_firmware() {
+        let bytes = encode_frame(&full_frame(0));
+        assert_eq!(bytes.len() % FRAME_ALIGNMENT, 0);
+        assert_eq!(&bytes[..8], &MAGIC_WORD);
+        let total_len = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+        assert_eq!(total_len as usize, bytes.len());
+    }
+
+    #[test]
+    fn decodes_consecutive_frames_after_garbage() {
+        let frames = [full_frame(0), full_frame(1)];
+        let mut bytes = vec![0xAB; 13];
+        for frame in &frames {
+            bytes.extend(encode_frame(frame));
+        }
+        assert_eq!(translate_tlv(&mut bytes), frames.to_vec());
+    }
+}
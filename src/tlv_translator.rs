@@ -1,28 +1,65 @@
-use crate::file_reader::Settings;
+use crate::file_reader::{Config, Settings};
+use crate::pipeline::{recorders_from_settings, Pipeline};
+use crate::queue::{QueueReceiver, QueueSnapshot};
 
-use super::renderer;
 use serde::{Deserialize, Serialize};
 use std::{
-    fs::File,
-    io::Write,
+    collections::VecDeque,
     sync::{mpsc, Arc},
-    thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-#[derive(Serialize, Deserialize, Clone)]
+/// A single parsed frame of the FMCW.
+///
+/// The heatmaps are stored flat, in the order the FMCW sends them,
+/// their dimensions follow from the configuration (see
+/// `RadarParameters`).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Frame {
-    frame_num: usize,
-    pointcloud: Option<Vec<PointCloudPoint>>,
-    range_profile: Option<Vec<f64>>,
+    pub frame_num: usize,
+    #[serde(default)]
+    pub subframe_num: usize,
+    /// The time the frame was sent, in CPU cycles of the FMCW
+    #[serde(default)]
+    pub time_cpu_cycles: u32,
+    pub pointcloud: Option<Vec<PointCloudPoint>>,
+    pub range_profile: Option<Vec<f64>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub side_info: Option<Vec<SideInfo>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub noise_profile: Option<Vec<f64>>,
+    /// `num_range_bins` rows of `num_virtual_antennas_azimuth` samples
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub azimuth_heatmap: Option<Vec<Cmplx16>>,
+    /// `num_range_bins` rows of `num_virtual_antennas` samples
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub azimuth_elevation_heatmap: Option<Vec<Cmplx16>>,
+    /// `num_range_bins` rows of `num_doppler_bins` log magnitudes, in
+    /// the Q9 format of the range profile. The doppler bins are in FFT
+    /// order, zero velocity first and the negative velocities last.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range_doppler_heatmap: Option<Vec<u16>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<PerformanceStats>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<TemperatureStats>,
 }
 
 impl Frame {
     pub fn empty(frame_num: usize) -> Frame {
         Frame {
             frame_num,
+            subframe_num: 0,
+            time_cpu_cycles: 0,
             pointcloud: None,
             range_profile: None,
+            side_info: None,
+            noise_profile: None,
+            azimuth_heatmap: None,
+            azimuth_elevation_heatmap: None,
+            range_doppler_heatmap: None,
+            stats: None,
+            temperature: None,
         }
     }
 
@@ -33,14 +70,6 @@ impl Frame {
     pub fn set_range_profile(&mut self, rp: Vec<f64>) {
         self.range_profile = Some(rp);
     }
-
-    pub fn render_range_profile(&self) {
-        if let Some(rp) = &self.range_profile {
-            let _ = std::fs::create_dir_all("./plots/range_profile/");
-            let name = format!("./plots/range_profile/{}.png", self.frame_num);
-            renderer::render_range_profile(&rp, name.as_str());
-        }
-    }
 }
 
 /// This struct is supposed to represent pointclouds, via the
@@ -50,7 +79,7 @@ impl Frame {
 /// doppler velocity.
 /// Each of these variables takes up exactly 4 bytes.
 #[repr(C)]
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct PointCloudPoint {
     pub x: f32,
     pub y: f32,
@@ -70,7 +99,13 @@ impl PointCloudPoint {
         // this is actually safe since the data size of Pointcloudpoint
         // (4 x 32 = 128) and pcHelper.data (8 x 16 = 128) are the same
         // AND because both are layed out in c representation
-        return unsafe { pc_helper.pc };
+        unsafe { pc_helper.pc }
+    }
+
+    pub fn to_bytes(self) -> [u8; 16] {
+        let pc_helper = PcHelper { pc: self };
+        // Safe for the same reason as `from_bytes`
+        unsafe { pc_helper.data }
     }
 
     pub fn empty() -> PointCloudPoint {
@@ -83,6 +118,155 @@ impl PointCloudPoint {
     }
 }
 
+/// The signal to noise ratio and the noise of a detected point,
+/// both in units of 0.1 dB, sent in the same order as the points.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SideInfo {
+    pub snr: i16,
+    pub noise: i16,
+}
+
+impl SideInfo {
+    pub fn from_bytes(data: [u8; 4]) -> SideInfo {
+        SideInfo {
+            snr: i16::from_le_bytes([data[0], data[1]]),
+            noise: i16::from_le_bytes([data[2], data[3]]),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; 4] {
+        let [s0, s1] = self.snr.to_le_bytes();
+        let [n0, n1] = self.noise.to_le_bytes();
+        [s0, s1, n0, n1]
+    }
+}
+
+/// A complex sample of a heatmap, sent by the FMCW as the imaginary
+/// part followed by the real part.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Cmplx16 {
+    pub imag: i16,
+    pub real: i16,
+}
+
+impl Cmplx16 {
+    pub fn from_bytes(data: [u8; 4]) -> Cmplx16 {
+        Cmplx16 {
+            imag: i16::from_le_bytes([data[0], data[1]]),
+            real: i16::from_le_bytes([data[2], data[3]]),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; 4] {
+        let [i0, i1] = self.imag.to_le_bytes();
+        let [r0, r1] = self.real.to_le_bytes();
+        [i0, i1, r0, r1]
+    }
+}
+
+/// The timing and CPU load of the FMCW, all times in microseconds
+/// and loads in percent.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PerformanceStats {
+    pub inter_frame_processing_time: u32,
+    pub transmit_output_time: u32,
+    pub inter_frame_processing_margin: u32,
+    pub inter_chirp_processing_margin: u32,
+    pub active_frame_cpu_load: u32,
+    pub inter_frame_cpu_load: u32,
+}
+
+impl PerformanceStats {
+    pub fn from_bytes(data: &[u8]) -> Option<PerformanceStats> {
+        let v = read_u32s::<6>(data)?;
+        Some(PerformanceStats {
+            inter_frame_processing_time: v[0],
+            transmit_output_time: v[1],
+            inter_frame_processing_margin: v[2],
+            inter_chirp_processing_margin: v[3],
+            active_frame_cpu_load: v[4],
+            inter_frame_cpu_load: v[5],
+        })
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        [
+            self.inter_frame_processing_time,
+            self.transmit_output_time,
+            self.inter_frame_processing_margin,
+            self.inter_chirp_processing_margin,
+            self.active_frame_cpu_load,
+            self.inter_frame_cpu_load,
+        ]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect()
+    }
+}
+
+/// The temperature report of the FMCW, the temperatures are in
+/// degrees Celsius and the time in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TemperatureStats {
+    pub report_valid: i32,
+    pub time: u32,
+    pub rx: [u16; 4],
+    pub tx: [u16; 3],
+    pub pm: u16,
+    pub digital: [u16; 2],
+}
+
+impl TemperatureStats {
+    pub const SIZE: usize = 28;
+
+    pub fn from_bytes(data: &[u8]) -> Option<TemperatureStats> {
+        if data.len() < TemperatureStats::SIZE {
+            return None;
+        }
+        let [report_valid, time] = read_u32s::<2>(data)?;
+        let t: Vec<u16> = data[8..TemperatureStats::SIZE]
+            .chunks_exact(2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        Some(TemperatureStats {
+            report_valid: report_valid as i32,
+            time,
+            rx: [t[0], t[1], t[2], t[3]],
+            tx: [t[4], t[5], t[6]],
+            pm: t[7],
+            digital: [t[8], t[9]],
+        })
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut result: Vec<u8> = vec![];
+        result.extend_from_slice(&self.report_valid.to_le_bytes());
+        result.extend_from_slice(&self.time.to_le_bytes());
+        let temps = self
+            .rx
+            .iter()
+            .chain(self.tx.iter())
+            .chain(std::iter::once(&self.pm))
+            .chain(self.digital.iter());
+        for t in temps {
+            result.extend_from_slice(&t.to_le_bytes());
+        }
+        result
+    }
+}
+
+/// Reads `N` little endian `u32` values from the start of `data`.
+fn read_u32s<const N: usize>(data: &[u8]) -> Option<[u32; N]> {
+    if data.len() < N * 4 {
+        return None;
+    }
+    let mut result = [0u32; N];
+    for (i, value) in result.iter_mut().enumerate() {
+        *value = u32::from_le_bytes(data[i * 4..i * 4 + 4].try_into().ok()?);
+    }
+    Some(result)
+}
+
 /// The header part has 40 Bytes (320 bits) of data seperated into:
 /// 8   -   Magic Word
 /// 4   -   Version
@@ -145,27 +329,19 @@ impl FrameHeader {
         unsafe { self.headers.subframe_number }.try_into().unwrap()
     }
 
-    fn tlv_count(&self) -> usize {
+    fn time(&self) -> u32 {
         // Union field access is ALWAYS specified as unsafe
         // This access is safe, since the types are stored as
         // C represented arrays, meaning the data is just thrown
         // on a large heap.
-        unsafe { self.headers.num_tlv }.try_into().unwrap()
-    }
-
-    fn obj_count(&self) -> usize {
-        // Union field access is ALWAYS specified as unsafe
-        // This access is safe, since the types are stored as
-        // C represented arrays, meaning the data is just thrown
-        // on a large heap.
-        unsafe { self.headers.num_detected_obj }.try_into().unwrap()
+        unsafe { self.headers.time }
     }
 }
 
 /// Enum describing the different TLV frame types
 /// as defined in the [specifications of TI](https://dev.ti.com/tirex/explore/content/radar_toolbox_2_30_00_12/software_docs/Understanding_UART_Data_Output_Format.html#statistics)
-#[derive(Clone, Copy)]
-enum TlvType {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TlvType {
     DetectedPoints = 1,
     RangeProfile = 2,
     NoiseFloorProfile = 3,
@@ -200,7 +376,7 @@ impl TlvType {
             9 => TlvType::TemperatureStatistics,
             _ => panic!("This code should be unreachable."),
         };
-        return Some(result);
+        Some(result)
     }
 }
 
@@ -220,15 +396,15 @@ impl TlvHeader {
     ///
     /// # Arguments
     /// * `input`:  a reference to a byte vector contaiing
-    ///             the data from which a TLV header should
-    ///             be extracted
+    ///   the data from which a TLV header should
+    ///   be extracted
     ///
     /// # Returns
     /// * `None`:   if it was not possible to extract 8 bytes
-    ///          from the input, the input is unchanged
+    ///   from the input, the input is unchanged
     /// * If parsing is succesfull it returns `Some(TlvHeader)`
-    ///         and removed the 8 bytes from which it was extracted
-    ///         from the input.
+    ///   and removed the 8 bytes from which it was extracted
+    ///   from the input.
     fn extract_tlv_header(input: &mut Vec<u8>) -> Option<TlvHeader> {
         if input.len() < 8 {
             return None;
@@ -258,74 +434,208 @@ impl TlvHeader {
     }
 }
 
-fn is_magic(input: &Vec<u8>, index: usize) -> bool {
-    const MAGIC_WORD: [u8; 8] = [0x02, 0x01, 0x04, 0x03, 0x06, 0x05, 0x08, 0x07];
+/// The 8 bytes with which every frame of the FMCW starts.
+pub const MAGIC_WORD: [u8; 8] = [0x02, 0x01, 0x04, 0x03, 0x06, 0x05, 0x08, 0x07];
+
+/// The size of the frame header, in bytes.
+pub const FRAME_HEADER_LEN: usize = 40;
+
+/// The longest frame that is accepted, far beyond what the FMCW sends
+/// with every output enabled. A header claiming more is corrupt, and
+/// would otherwise have the stream buffered forever waiting for it.
+pub const MAX_FRAME_LEN: usize = 1 << 20;
 
+/// The clock of the CPU whose cycles are counted in the time field of
+/// the frame header.
+pub const CPU_CLOCK_HZ: f64 = 200e6;
+
+fn is_magic(input: &[u8], index: usize) -> bool {
     let input_size = input.len();
     if input_size < (index + 8) {
         return false;
     }
-    &input[index..(index + 8)] == MAGIC_WORD
+    input[index..(index + 8)] == MAGIC_WORD
+}
+
+/// Splits a raw capture into frames, at every magic word.
+pub fn split_frames(data: &[u8]) -> Vec<Vec<u8>> {
+    let starts: Vec<usize> = (0..data.len().saturating_sub(7))
+        .filter(|&i| data[i..i + 8] == MAGIC_WORD)
+        .collect();
+    starts
+        .iter()
+        .enumerate()
+        .map(|(n, &start)| {
+            let end = starts.get(n + 1).copied().unwrap_or(data.len());
+            data[start..end].to_vec()
+        })
+        .collect()
+}
+
+/// Reads the time field, in CPU cycles, from the header at the start
+/// of `frame`.
+pub fn frame_time(frame: &[u8]) -> Option<u32> {
+    Some(read_header(frame)?.time())
+}
+
+/// Reads the frame number from the header at the start of `frame`.
+pub fn frame_number(frame: &[u8]) -> Option<u32> {
+    Some(read_header(frame)?.frame_num() as u32)
+}
+
+/// Commands to a running `parse_stream`, each answered through its
+/// `reply` sender.
+pub enum ParserCommand {
+    /// Start (`Some(true)`), stop (`Some(false)`) or toggle (`None`)
+    /// recording, with the configuration the sensor runs with now.
+    /// Replies whether it is recording afterwards.
+    Record {
+        enabled: Option<bool>,
+        config: Config,
+        reply: mpsc::Sender<Result<bool, std::io::Error>>,
+    },
+    Stats {
+        reply: mpsc::Sender<StreamStats>,
+    },
+    /// Stop parsing, the recorders and sinks are finished and
+    /// `parse_stream` returns
+    Stop,
+}
+
+/// Statistics of the stream handled by `parse_stream`.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct StreamStats {
+    pub bytes_received: u64,
+    pub frames_parsed: u64,
+    pub last_frame_num: Option<usize>,
+    /// Frames per second over the last `FRAME_RATE_WINDOW`
+    pub frame_rate_hz: f64,
+    pub recording: bool,
+    /// The queue of the raw data and those of the sinks
+    pub queues: Vec<QueueSnapshot>,
 }
 
+/// The window over which `StreamStats::frame_rate_hz` is measured.
+const FRAME_RATE_WINDOW: Duration = Duration::from_secs(5);
+
 /// Parses data which is provided, in packets, along the
 /// channel receiver `rx`.
 ///
-/// **NOTE** This function never returns. It should be
-/// called as a new thread.
+/// When `recording.save_raw_data` is set, the received bytes are
+/// written to a new recording together with `config` and the settings.
+/// The parsed frames are logged as JSON with `recording.save_frames`
+/// and into an MCAP file with `recording.save_mcap`. Recording can be
+/// started and stopped later on through `commands`, when none of these
+/// are set a raw recording is started.
+///
+/// The recorders are added to `pipeline` (see `recorders_from_settings`),
+/// which records every parsed frame as decoded, and then passes it
+/// through its processors on its way to the sinks, such as the IPC
+/// server and the MQTT publisher.
+///
+/// Returns once all senders of `rx` have been dropped, e.g. when
+/// a replayed file has ended, or on a `ParserCommand::Stop`. It should
+/// be called as a new thread.
 pub fn parse_stream(
-    rx: mpsc::Receiver<Vec<u8>>,
-    ipc_tx: mpsc::Sender<Frame>,
+    rx: QueueReceiver<Vec<u8>>,
+    mut pipeline: Pipeline,
     settings: Arc<Settings>,
-) -> ! {
+    config: Config,
+    commands: mpsc::Receiver<ParserCommand>,
+) {
     let mut byte_stream: Vec<u8> = vec![];
-
-    // If there is an error in reading the file we WILL crash, this
-    // is not ideal behaviour
-    let raw_data_file: Option<File> = if settings.raw_data_save {
-        Some(File::create("./output_tls.dat").unwrap())
-    } else {
-        None
-    };
-    let frame_file: Option<File> = if settings.raw_data_save {
-        Some(File::create("./frame_output.json").unwrap())
-    } else {
-        None
+    for recorder in recorders_from_settings(&settings, &config) {
+        pipeline.add_recorder(recorder);
+    }
+    let mut stats = StreamStats {
+        bytes_received: 0,
+        frames_parsed: 0,
+        last_frame_num: None,
+        frame_rate_hz: 0.0,
+        recording: false,
+        queues: vec![],
     };
-    loop {
-        // Add all new received packages to the byte stream
-        let mut received: bool = false;
-        if let Ok(new_bytes) = rx.recv() {
-            byte_stream.append(&mut new_bytes.clone());
-            received = true;
-
-            if let Some(ref f) = raw_data_file {
-                let mut file: &File = f;
-                _ = file.write_all(&new_bytes);
+    let mut frame_times: VecDeque<Instant> = VecDeque::new();
+
+    'stream: loop {
+        // Commands are handled even while no data arrives
+        while let Ok(command) = commands.try_recv() {
+            match command {
+                ParserCommand::Record {
+                    enabled,
+                    config,
+                    reply,
+                } => {
+                    let enable = enabled.unwrap_or(!pipeline.is_recording());
+                    if enable && !pipeline.is_recording() {
+                        let mut settings = (*settings).clone();
+                        let recording = &mut settings.recording;
+                        if !(recording.save_raw_data
+                            || recording.save_frames
+                            || recording.save_mcap)
+                        {
+                            recording.save_raw_data = true;
+                        }
+                        for recorder in recorders_from_settings(&settings, &config) {
+                            pipeline.add_recorder(recorder);
+                        }
+                    } else if !enable {
+                        pipeline.stop_recording();
+                    }
+                    _ = reply.send(if enable && !pipeline.is_recording() {
+                        Err(std::io::Error::other("could not start recording"))
+                    } else {
+                        Ok(pipeline.is_recording())
+                    });
+                }
+                ParserCommand::Stats { reply } => {
+                    while frame_times
+                        .front()
+                        .is_some_and(|t| t.elapsed() > FRAME_RATE_WINDOW)
+                    {
+                        frame_times.pop_front();
+                    }
+                    stats.frame_rate_hz = match (frame_times.front(), frame_times.back()) {
+                        (Some(first), Some(last)) if frame_times.len() > 1 => {
+                            (frame_times.len() - 1) as f64
+                                / last.duration_since(*first).as_secs_f64().max(1e-3)
+                        }
+                        _ => 0.0,
+                    };
+                    stats.recording = pipeline.is_recording();
+                    stats.queues = std::iter::once(rx.stats())
+                        .chain(pipeline.queue_stats())
+                        .map(|q| q.snapshot())
+                        .collect();
+                    _ = reply.send(stats.clone());
+                }
+                ParserCommand::Stop => break 'stream,
             }
         }
 
-        // Process received bytes
-        if received {
-            println!(
-                "Received packages, bytestream length = {}",
-                byte_stream.len()
-            );
-            // Process the byte stream
-            for frame in translate_tlv(&mut byte_stream) {
-                if let Some(ref f) = frame_file {
-                    let mut file: &File = f;
-                    let data = format!("{},", serde_json::to_string(&frame.clone()).unwrap());
-                    _ = file.write_all(&data.as_bytes());
-                }
-                _ = ipc_tx.send(frame);
+        // Add all new received packages to the byte stream
+        let new_bytes = match rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(v) => v,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        byte_stream.extend_from_slice(&new_bytes);
+        stats.bytes_received += new_bytes.len() as u64;
+        pipeline.write_chunk(&new_bytes);
+
+        // Process the byte stream
+        for frame in translate_tlv(&mut byte_stream) {
+            stats.frames_parsed += 1;
+            stats.last_frame_num = Some(frame.frame_num);
+            frame_times.push_back(Instant::now());
+            if frame_times.len() > 1000 {
+                frame_times.pop_front();
             }
-        } else {
-            println!("Did not receive packages");
-            // If there where no packets to be received, sleep for ⅒   second
-            thread::sleep(Duration::from_millis(100));
+            pipeline.push(frame);
         }
     }
+    pipeline.finish();
+    println!("The byte stream has ended");
 }
 
 /// The function takes a `TLV byte array` as input and
@@ -334,11 +644,11 @@ pub fn parse_stream(
 /// # Arguments
 ///
 /// * `tlv_bytes` - A mutable reference to an array of bytes
-///                 representing the tlv bytes which should
-///                 be parsed. All data which is consumed
-///                 (and thus translated into a point cloud
-///                 ) will be removed from the `tlv_bytes`
-///                 variable in this process will
+///   representing the tlv bytes which should
+///   be parsed. All data which is consumed
+///   (and thus translated into a point cloud
+///   ) will be removed from the `tlv_bytes`
+///   variable in this process will
 ///
 /// # Returns
 /// A tuple containing:
@@ -350,22 +660,76 @@ pub fn translate_tlv(input: &mut Vec<u8>) -> Vec<Frame> {
         println!("`translate_tlv` was called with an input of size {}, try calling it with an input of *at least* size 8", input_size);
         return vec![];
     }
-    let mut magic_indexes: Vec<usize> = Vec::new();
-    for i in 0..(input_size - 8) {
-        if is_magic(input, i) {
-            magic_indexes.push(i);
+    let mut result: Vec<Frame> = vec![];
+    while let Some(decoded) = decode_frame(input) {
+        if let Ok(frame) = decoded {
+            result.push(frame);
         }
     }
+    result
+}
 
-    let mut result: Vec<Frame> = vec![];
+/// A problem in the TLV data, after which decoding continues at the
+/// next magic word.
+#[derive(Debug)]
+pub enum TlvError {
+    /// Bytes in front of a magic word were skipped, e.g. the tail of a
+    /// frame the stream started halfway through
+    Skipped(usize),
+    /// A frame header which claims to be shorter than itself, or longer
+    /// than `MAX_FRAME_LEN`
+    CorruptHeader { frame_num: usize },
+    /// Reading the TLV data failed
+    Io(std::io::Error),
+}
 
-    for _mi in magic_indexes {
-        if let Some(frame) = read_frame(input) {
-            result.push(frame);
+impl std::fmt::Display for TlvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TlvError::Skipped(n) => write!(f, "skipped {} bytes which are not part of a frame", n),
+            TlvError::CorruptHeader { frame_num } => {
+                write!(f, "the header of frame {} is corrupt", frame_num)
+            }
+            TlvError::Io(e) => write!(f, "reading the TLV data failed: {}", e),
         }
     }
+}
 
-    result
+impl std::error::Error for TlvError {}
+
+/// Decodes the first frame in `input`, removing it and everything in
+/// front of it. Returns an error for bytes which had to be skipped,
+/// and `None` once the rest of `input` holds no complete frame.
+pub fn decode_frame(input: &mut Vec<u8>) -> Option<Result<Frame, TlvError>> {
+    // A frame can only start at a magic word, anything in front of
+    // it (e.g. the tail of a frame we started receiving halfway
+    // through) is dropped.
+    match (0..input.len().saturating_sub(7)).find(|&i| is_magic(input, i)) {
+        Some(0) => {}
+        Some(i) => {
+            input.drain(0..i);
+            return Some(Err(TlvError::Skipped(i)));
+        }
+        None => {
+            // Keep the last bytes, they might be the start of a magic word
+            let keep_from = input.len().saturating_sub(7);
+            input.drain(0..keep_from);
+            return (keep_from > 0).then_some(Err(TlvError::Skipped(keep_from)));
+        }
+    }
+
+    // A header which claims to be shorter than itself or longer than
+    // any frame is corrupt, skip past its magic word
+    if let Some(header) = read_header(input) {
+        if !(FRAME_HEADER_LEN..=MAX_FRAME_LEN).contains(&header.frame_len()) {
+            input.drain(0..8);
+            return Some(Err(TlvError::CorruptHeader {
+                frame_num: header.frame_num(),
+            }));
+        }
+    }
+
+    read_frame(input).map(Ok)
 }
 
 /// A function for reading and  processing a single frame of our data.
@@ -377,6 +741,7 @@ pub fn translate_tlv(input: &mut Vec<u8>) -> Vec<Frame> {
 /// An option of a tuple containing:
 /// * A `PointCloud` object for this single frame
 /// * A `usize`, representing the number of bytes from the input consumed
+///
 /// Or `None` if the frame is not complete.
 fn read_frame(data: &mut Vec<u8>) -> Option<Frame> {
     let header: FrameHeader = read_header(data)?;
@@ -389,26 +754,15 @@ fn read_frame(data: &mut Vec<u8>) -> Option<Frame> {
         // We can read the full frame so can drop the header
         data.drain(0..40);
     }
-    println!(
-        "Currently handeling frame nuber {}, with {} objects and {} tlv frames",
-        header.frame_num(),
-        header.obj_count(),
-        header.tlv_count()
-    );
     // remove 40 from the drainage size as we already removed the header
     let raw_frame: Vec<u8> = data.drain(0..(frame_len - 40)).collect();
     Some(parse_frame(header, raw_frame))
 }
 
 fn parse_frame(frame_header: FrameHeader, mut data: Vec<u8>) -> Frame {
-    // Remove the frame header
-    println!(
-        "Subframe num: {}, tlv count: {}",
-        frame_header.subframe_num(),
-        frame_header.tlv_count()
-    );
-
     let mut frame = Frame::empty(frame_header.frame_num());
+    frame.subframe_num = frame_header.subframe_num();
+    frame.time_cpu_cycles = frame_header.time();
 
     while let Some(tlv_header) = TlvHeader::extract_tlv_header(&mut data) {
         if tlv_header.tlv_len() > data.len() {
@@ -425,26 +779,39 @@ fn parse_frame(frame_header: FrameHeader, mut data: Vec<u8>) -> Frame {
                 let range_profile: Vec<f64> = parse_raw_range_profile(raw_tlv_data);
                 frame.set_range_profile(range_profile)
             }
-            Some(TlvType::NoiseFloorProfile) => {}
-            Some(TlvType::AzimuthStaticHeatmap) => {}
-            Some(TlvType::RangeDopplerHeatmap) => {}
-            Some(TlvType::PerformanceStatistics) => {}
-            Some(TlvType::SideInforForDetectedPoints) => {}
-            Some(TlvType::AzimuthElevationStaticHeatmap) => {}
-            Some(TlvType::TemperatureStatistics) => {}
+            Some(TlvType::NoiseFloorProfile) => {
+                frame.noise_profile = Some(parse_raw_range_profile(raw_tlv_data));
+            }
+            Some(TlvType::AzimuthStaticHeatmap) => {
+                frame.azimuth_heatmap = Some(parse_complex_heatmap(&raw_tlv_data));
+            }
+            Some(TlvType::RangeDopplerHeatmap) => {
+                frame.range_doppler_heatmap = Some(parse_u16s(&raw_tlv_data));
+            }
+            Some(TlvType::PerformanceStatistics) => {
+                frame.stats = PerformanceStats::from_bytes(&raw_tlv_data);
+            }
+            Some(TlvType::SideInforForDetectedPoints) => {
+                frame.side_info = Some(parse_side_info(&raw_tlv_data));
+            }
+            Some(TlvType::AzimuthElevationStaticHeatmap) => {
+                frame.azimuth_elevation_heatmap = Some(parse_complex_heatmap(&raw_tlv_data));
+            }
+            Some(TlvType::TemperatureStatistics) => {
+                frame.temperature = TemperatureStats::from_bytes(&raw_tlv_data);
+            }
             None => break,
         }
     }
-    frame.render_range_profile();
-    return frame;
+    frame
 }
 
 fn parse_detected_points(data: Vec<u8>) -> Vec<PointCloudPoint> {
     let mut result: Vec<PointCloudPoint> = vec![];
     //
     // Each point takes up 16 bytes, so we want to itterate over every point
-    for _ in 0..(data.len() / 16) {
-        let raw: [u8; 16] = match data[..16].try_into() {
+    for point in data.chunks_exact(16) {
+        let raw: [u8; 16] = match point.try_into() {
             Ok(v) => v,
             Err(_) => {
                 println!("Error when casting detected point data");
@@ -453,7 +820,7 @@ fn parse_detected_points(data: Vec<u8>) -> Vec<PointCloudPoint> {
         };
         result.push(PointCloudPoint::from_bytes(raw));
     }
-    return result;
+    result
 }
 
 fn parse_raw_range_profile(data: Vec<u8>) -> Vec<f64> {
@@ -463,21 +830,45 @@ fn parse_raw_range_profile(data: Vec<u8>) -> Vec<f64> {
     // a vector (or array) containing the new values.
     // https://en.wikipedia.org/wiki/Q_(number_format)
 
-    // Change the byte string into a list of u16s
-    let even_indexed = data.iter().step_by(2);
-    let uneven_indexed = data.iter().skip(1).step_by(2);
-    even_indexed
-        .zip(uneven_indexed)
-        .map(|(lower, upper)| (((*lower as u16) << 8) | *upper as u16))
-        // Now to do q9 encoding according to the following formula P[db] = 20 * log10( 2.^(logMagRange/2^9) )
-        // Accoring to this forum post https://e2e.ti.com/support/sensors-group/sensors/f/sensors-forum/806905/linux-iwr1443boost-interpreting-data-log-magnitude-range-and-doppler-heatmap
-        .map(|log_mag_range| 20.0 * f64::log10(2f64.powf(log_mag_range as f64 / 2.0f64.powi(9))))
+    // Change the byte string into a list of u16s, the FMCW sends
+    // these little endian
+    parse_u16s(&data).into_iter().map(q9_to_db).collect()
+}
+
+/// Now to do q9 encoding according to the following formula P[db] = 20 * log10( 2.^(logMagRange/2^9) )
+/// Accoring to this forum post https://e2e.ti.com/support/sensors-group/sensors/f/sensors-forum/806905/linux-iwr1443boost-interpreting-data-log-magnitude-range-and-doppler-heatmap
+pub fn q9_to_db(log_mag_range: u16) -> f64 {
+    20.0 * f64::log10(2f64.powf(log_mag_range as f64 / 2.0f64.powi(9)))
+}
+
+/// The inverse of `q9_to_db`, rounding to the nearest Q9 value.
+pub fn db_to_q9(db: f64) -> u16 {
+    (db / (20.0 * 2f64.log10()) * 2.0f64.powi(9))
+        .round()
+        .clamp(0.0, u16::MAX as f64) as u16
+}
+
+fn parse_u16s(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .collect()
+}
+
+fn parse_side_info(data: &[u8]) -> Vec<SideInfo> {
+    data.chunks_exact(4)
+        .map(|b| SideInfo::from_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+fn parse_complex_heatmap(data: &[u8]) -> Vec<Cmplx16> {
+    data.chunks_exact(4)
+        .map(|b| Cmplx16::from_bytes([b[0], b[1], b[2], b[3]]))
         .collect()
 }
 
 /// Attempts to read a header located at the the top of the input,
 /// This is a non-destructive operation, returning an `Option<Header>`
-fn read_header(input: &Vec<u8>) -> Option<FrameHeader> {
+fn read_header(input: &[u8]) -> Option<FrameHeader> {
     if input.len() < 40 {
         return None;
     }
@@ -488,3 +879,30 @@ fn read_header(input: &Vec<u8>) -> Option<FrameHeader> {
     let headers: FrameHeader = FrameHeader::new(data);
     Some(headers)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tlv_encoder::encode_frame;
+
+    #[test]
+    fn a_huge_frame_length_is_skipped() {
+        let mut corrupt = encode_frame(&Frame::empty(1));
+        corrupt[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut input = corrupt;
+        input.extend(encode_frame(&Frame::empty(2)));
+
+        let decoded: Vec<_> = std::iter::from_fn(|| decode_frame(&mut input)).collect();
+        assert!(matches!(
+            decoded[0],
+            Err(TlvError::CorruptHeader { frame_num: 1 })
+        ));
+        let frames: Vec<usize> = decoded
+            .into_iter()
+            .filter_map(|d| d.ok())
+            .map(|f| f.frame_num)
+            .collect();
+        assert_eq!(frames, vec![2]);
+        assert!(input.is_empty());
+    }
+}
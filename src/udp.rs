@@ -0,0 +1,484 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+use super::async_fmcw::AsyncSink;
+use super::encoding::{decode_binary, encode_binary};
+use super::file_reader::UdpSettings;
+use super::queue::QueueReceiver;
+use super::tlv_translator::Frame;
+
+/// Starts every datagram.
+pub const DATAGRAM_MAGIC: &[u8; 4] = b"IWRU";
+
+/// The size of the header in front of every fragment.
+pub const DATAGRAM_HEADER_SIZE: usize = 20;
+
+/// The largest payload of a UDP datagram over IPv4.
+const MAX_UDP_PAYLOAD: usize = 65507;
+
+/// The largest frame sent or reassembled. The length in a datagram is
+/// not trusted beyond it, the frames of the sensor are far smaller.
+const MAX_FRAME_BYTES: usize = 4 << 20;
+
+/// How many frames are kept waiting for their missing fragments, an
+/// older frame which is still incomplete is counted as lost.
+const MAX_PENDING: u32 = 16;
+
+/// A jump of the sequence number this large means the sender was
+/// restarted, rather than that this many frames were lost.
+const RESTART_GAP: u32 = 1000;
+
+/// How often `receive_udp` reports the received and lost frames.
+const REPORT_PERIOD: Duration = Duration::from_secs(5);
+
+/// The header of a datagram, all little endian:
+///
+/// - `DATAGRAM_MAGIC`
+/// - the sequence number of the frame, counting the frames sent (`u32`)
+/// - the length of the whole frame in the binary encoding (`u32`)
+/// - where this fragment starts in the frame (`u32`)
+/// - the index of this fragment and the number of fragments (`u16` each)
+struct DatagramHeader {
+    sequence: u32,
+    length: usize,
+    offset: usize,
+    fragment: usize,
+    fragments: usize,
+}
+
+impl DatagramHeader {
+    fn to_bytes(&self) -> [u8; DATAGRAM_HEADER_SIZE] {
+        let mut bytes = [0u8; DATAGRAM_HEADER_SIZE];
+        bytes[0..4].copy_from_slice(DATAGRAM_MAGIC);
+        bytes[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes[8..12].copy_from_slice(&(self.length as u32).to_le_bytes());
+        bytes[12..16].copy_from_slice(&(self.offset as u32).to_le_bytes());
+        bytes[16..18].copy_from_slice(&(self.fragment as u16).to_le_bytes());
+        bytes[18..20].copy_from_slice(&(self.fragments as u16).to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(data: &[u8]) -> Option<DatagramHeader> {
+        if data.len() < DATAGRAM_HEADER_SIZE || &data[0..4] != DATAGRAM_MAGIC {
+            return None;
+        }
+        let u32_at = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+        let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        Some(DatagramHeader {
+            sequence: u32_at(4),
+            length: u32_at(8) as usize,
+            offset: u32_at(12) as usize,
+            fragment: u16_at(16) as usize,
+            fragments: u16_at(18) as usize,
+        })
+    }
+}
+
+/// Splits frames in the binary encoding into datagrams of at most
+/// `udp.max_datagram_bytes` each, numbering the frames.
+struct Fragmenter {
+    max_payload: usize,
+    sequence: u32,
+}
+
+impl Fragmenter {
+    fn new(settings: &UdpSettings) -> Result<Fragmenter, Error> {
+        if settings.max_datagram_bytes <= DATAGRAM_HEADER_SIZE
+            || settings.max_datagram_bytes > MAX_UDP_PAYLOAD
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "udp.max_datagram_bytes must be between {} and {}",
+                    DATAGRAM_HEADER_SIZE + 1,
+                    MAX_UDP_PAYLOAD
+                ),
+            ));
+        }
+        Ok(Fragmenter {
+            max_payload: settings.max_datagram_bytes - DATAGRAM_HEADER_SIZE,
+            sequence: 0,
+        })
+    }
+
+    fn datagrams(&mut self, frame: &Frame) -> Result<Vec<Vec<u8>>, Error> {
+        let data = encode_binary(frame);
+        let fragments = data.len().div_ceil(self.max_payload);
+        if fragments > u16::MAX as usize || data.len() > MAX_FRAME_BYTES {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("a frame of {} bytes needs too many datagrams", data.len()),
+            ));
+        }
+        let sequence = self.sequence;
+        self.sequence = self.sequence.wrapping_add(1);
+
+        let datagrams = data
+            .chunks(self.max_payload)
+            .enumerate()
+            .map(|(fragment, piece)| {
+                let header = DatagramHeader {
+                    sequence,
+                    length: data.len(),
+                    offset: fragment * self.max_payload,
+                    fragment,
+                    fragments,
+                };
+                let mut datagram = Vec::with_capacity(DATAGRAM_HEADER_SIZE + piece.len());
+                datagram.extend_from_slice(&header.to_bytes());
+                datagram.extend_from_slice(piece);
+                datagram
+            })
+            .collect();
+        Ok(datagrams)
+    }
+}
+
+/// Sends frames in the binary encoding as UDP datagrams, split into
+/// fragments of at most `udp.max_datagram_bytes` each.
+pub struct UdpSender {
+    socket: UdpSocket,
+    target: SocketAddrV4,
+    fragmenter: Fragmenter,
+}
+
+impl UdpSender {
+    pub fn bind(settings: &UdpSettings) -> Result<UdpSender, Error> {
+        let group = parse_address("udp.group", &settings.group)?;
+        let fragmenter = Fragmenter::new(settings)?;
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        configure_socket(&socket, group, settings)?;
+        Ok(UdpSender {
+            socket,
+            target: SocketAddrV4::new(group, settings.port),
+            fragmenter,
+        })
+    }
+
+    /// Sends a frame, returning the number of datagrams it took.
+    pub fn send_frame(&mut self, frame: &Frame) -> Result<usize, Error> {
+        let datagrams = self.fragmenter.datagrams(frame)?;
+        for datagram in &datagrams {
+            self.socket.send_to(datagram, self.target)?;
+        }
+        Ok(datagrams.len())
+    }
+}
+
+/// The same as `UdpSender` on a tokio socket, a sink of `run_async`.
+pub struct AsyncUdpSender {
+    socket: tokio::net::UdpSocket,
+    target: SocketAddrV4,
+    fragmenter: Fragmenter,
+}
+
+impl AsyncUdpSender {
+    pub fn bind(settings: &UdpSettings) -> Result<AsyncUdpSender, Error> {
+        let group = parse_address("udp.group", &settings.group)?;
+        let fragmenter = Fragmenter::new(settings)?;
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        configure_socket(&socket, group, settings)?;
+        socket.set_nonblocking(true)?;
+        Ok(AsyncUdpSender {
+            socket: tokio::net::UdpSocket::from_std(socket)?,
+            target: SocketAddrV4::new(group, settings.port),
+            fragmenter,
+        })
+    }
+}
+
+impl AsyncSink for AsyncUdpSender {
+    async fn send(&mut self, frame: &Frame) -> Result<(), Error> {
+        for datagram in self.fragmenter.datagrams(frame)? {
+            self.socket.send_to(&datagram, self.target).await?;
+        }
+        Ok(())
+    }
+}
+
+fn configure_socket(
+    socket: &UdpSocket,
+    group: Ipv4Addr,
+    settings: &UdpSettings,
+) -> Result<(), Error> {
+    if group.is_multicast() {
+        socket.set_multicast_ttl_v4(settings.ttl)?;
+        socket.set_multicast_loop_v4(settings.loopback)?;
+    } else {
+        socket.set_ttl(settings.ttl)?;
+    }
+    Ok(())
+}
+
+/// Sends all frames provided through `frames` to `udp.group`, see
+/// `UdpSender`. A frame which cannot be sent is reported and skipped.
+///
+/// Returns once all senders of `frames` have been dropped, or with an
+/// error if the socket could not be set up.
+pub fn udp_sender(frames: QueueReceiver<Frame>, settings: &UdpSettings) -> Result<(), Error> {
+    let mut sender = UdpSender::bind(settings)?;
+    println!(
+        "Sending frames to udp://{}:{}",
+        settings.group, settings.port
+    );
+    for frame in frames.iter() {
+        if let Err(e) = sender.send_frame(&frame) {
+            eprintln!("Could not send frame {} over UDP: {}", frame.frame_num, e);
+        }
+    }
+    Ok(())
+}
+
+/// Counts of what a `Reassembler` received.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReceiveStats {
+    pub datagrams: u64,
+    /// The frames which were received completely
+    pub frames: u64,
+    /// The frames of which at least one fragment never arrived
+    pub lost: u64,
+    /// Fragments of frames which were already complete or lost
+    pub late: u64,
+    /// Datagrams which are not fragments of a frame
+    pub invalid: u64,
+}
+
+/// A frame of which some fragments have arrived.
+struct Partial {
+    data: Vec<u8>,
+    received: Vec<bool>,
+    missing: usize,
+}
+
+/// Puts the frames sent by a `UdpSender` back together from their
+/// datagrams, which may arrive out of order, twice or not at all.
+///
+/// The last `MAX_PENDING` frames are kept until all their fragments
+/// arrived, a frame falling out of that window is counted as lost.
+pub struct Reassembler {
+    /// The frames in the window by their sequence number, `None` for
+    /// those no fragment arrived of yet
+    pending: BTreeMap<u32, Option<Partial>>,
+    newest: Option<u32>,
+    stats: ReceiveStats,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler {
+            pending: BTreeMap::new(),
+            newest: None,
+            stats: ReceiveStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> ReceiveStats {
+        self.stats
+    }
+
+    /// Adds a datagram, returning the frame in the binary encoding once
+    /// it is complete.
+    pub fn push(&mut self, datagram: &[u8]) -> Option<Vec<u8>> {
+        self.stats.datagrams += 1;
+        let header = match DatagramHeader::from_bytes(datagram) {
+            Some(h) if h.fragment < h.fragments => h,
+            _ => {
+                self.stats.invalid += 1;
+                return None;
+            }
+        };
+        let payload = &datagram[DATAGRAM_HEADER_SIZE..];
+        // The buffer for the frame is allocated by this length
+        let max_length = header.fragments * (MAX_UDP_PAYLOAD - DATAGRAM_HEADER_SIZE);
+        if header.length > max_length.min(MAX_FRAME_BYTES)
+            || header.offset + payload.len() > header.length
+        {
+            self.stats.invalid += 1;
+            return None;
+        }
+        let sequence = header.sequence;
+
+        match self.newest {
+            Some(newest) if sequence.abs_diff(newest) < RESTART_GAP => {
+                if sequence > newest {
+                    for skipped in newest + 1..=sequence {
+                        self.pending.insert(skipped, None);
+                    }
+                    self.newest = Some(sequence);
+                }
+            }
+            _ => {
+                // The first frame, or the sender was restarted
+                self.pending.clear();
+                self.pending.insert(sequence, None);
+                self.newest = Some(sequence);
+            }
+        }
+        let oldest_kept = self.newest.unwrap().saturating_sub(MAX_PENDING - 1);
+        while let Some(entry) = self.pending.first_entry() {
+            if *entry.key() >= oldest_kept {
+                break;
+            }
+            entry.remove();
+            self.stats.lost += 1;
+        }
+
+        let Some(entry) = self.pending.get_mut(&sequence) else {
+            self.stats.late += 1;
+            return None;
+        };
+        let partial = entry.get_or_insert_with(|| Partial {
+            data: vec![0; header.length],
+            received: vec![false; header.fragments],
+            missing: header.fragments,
+        });
+        if partial.data.len() != header.length || partial.received.len() != header.fragments {
+            self.stats.invalid += 1;
+            return None;
+        }
+        if !partial.received[header.fragment] {
+            partial.data[header.offset..header.offset + payload.len()].copy_from_slice(payload);
+            partial.received[header.fragment] = true;
+            partial.missing -= 1;
+        }
+        if partial.missing > 0 {
+            return None;
+        }
+        self.stats.frames += 1;
+        self.pending
+            .remove(&sequence)
+            .flatten()
+            .map(|partial| partial.data)
+    }
+}
+
+/// Joins `udp.group` and prints every frame received, and every
+/// `REPORT_PERIOD` how many frames were received and lost. Runs until
+/// the process is stopped.
+pub fn receive_udp(settings: &UdpSettings) -> Result<(), Error> {
+    let group = parse_address("udp.group", &settings.group)?;
+    let interface = parse_address("udp.interface", &settings.interface)?;
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, settings.port))?;
+    if group.is_multicast() {
+        socket.join_multicast_v4(&group, &interface)?;
+    }
+    socket.set_read_timeout(Some(REPORT_PERIOD))?;
+    println!(
+        "Receiving frames on udp://{}:{}",
+        settings.group, settings.port
+    );
+
+    let mut buffer = vec![0u8; MAX_UDP_PAYLOAD];
+    let mut reassembler = Reassembler::new();
+    let mut last_report = Instant::now();
+    loop {
+        match socket.recv_from(&mut buffer) {
+            Ok((length, _)) => {
+                if let Some(data) = reassembler.push(&buffer[..length]) {
+                    match decode_binary(&data) {
+                        Ok(frame) => println!(
+                            "Frame {}: {} points, {} bytes",
+                            frame.frame_num,
+                            frame.pointcloud.as_ref().map_or(0, |pc| pc.len()),
+                            data.len()
+                        ),
+                        Err(e) => eprintln!("Could not decode a received frame: {}", e),
+                    }
+                }
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e),
+        }
+        if last_report.elapsed() >= REPORT_PERIOD {
+            last_report = Instant::now();
+            let stats = reassembler.stats();
+            let expected = (stats.frames + stats.lost).max(1);
+            println!(
+                "Received {} frames in {} datagrams, lost {} ({:.1} %), {} late and {} invalid datagrams",
+                stats.frames,
+                stats.datagrams,
+                stats.lost,
+                stats.lost as f64 * 100.0 / expected as f64,
+                stats.late,
+                stats.invalid
+            );
+        }
+    }
+}
+
+fn parse_address(name: &str, address: &str) -> Result<Ipv4Addr, Error> {
+    address.parse().map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} should be an IPv4 address, not \"{}\"", name, address),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The datagrams of `count` frames of a few fragments each.
+    fn datagrams(count: usize) -> Vec<(Vec<u8>, Vec<Vec<u8>>)> {
+        let mut fragmenter = Fragmenter::new(&UdpSettings::default()).unwrap();
+        (0..count)
+            .map(|i| {
+                let mut frame = Frame::empty(i);
+                frame.set_range_profile((0..1000).map(|bin| bin as f64).collect());
+                (encode_binary(&frame), fragmenter.datagrams(&frame).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reassembles_fragments_in_any_order() {
+        let mut reassembler = Reassembler::new();
+        for (frame, mut datagrams) in datagrams(2) {
+            assert!(datagrams.len() > 2);
+            datagrams.reverse();
+            let last = datagrams.pop().unwrap();
+            for datagram in &datagrams {
+                assert_eq!(reassembler.push(datagram), None);
+            }
+            // A duplicate does not complete the frame
+            assert_eq!(reassembler.push(&datagrams[0]), None);
+            assert_eq!(reassembler.push(&last), Some(frame));
+        }
+        let stats = reassembler.stats();
+        assert_eq!((stats.frames, stats.lost, stats.invalid), (2, 0, 0));
+    }
+
+    #[test]
+    fn a_lost_fragment_loses_only_its_frame() {
+        let mut reassembler = Reassembler::new();
+        let mut complete = 0;
+        for (i, (_, datagrams)) in datagrams(MAX_PENDING as usize + 1).iter().enumerate() {
+            let skip = if i == 0 { 1 } else { 0 };
+            for datagram in &datagrams[skip..] {
+                complete += reassembler.push(datagram).is_some() as u64;
+            }
+        }
+        let stats = reassembler.stats();
+        assert_eq!(complete, MAX_PENDING as u64);
+        assert_eq!((stats.frames, stats.lost), (MAX_PENDING as u64, 1));
+    }
+
+    #[test]
+    fn an_untrusted_length_is_not_allocated() {
+        let header = DatagramHeader {
+            sequence: 0,
+            length: u32::MAX as usize,
+            offset: 0,
+            fragment: 0,
+            fragments: 1,
+        };
+        let mut datagram = header.to_bytes().to_vec();
+        datagram.extend_from_slice(&[0; 100]);
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.push(&datagram), None);
+        assert_eq!(reassembler.stats().invalid, 1);
+    }
+}